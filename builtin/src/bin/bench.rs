@@ -0,0 +1,82 @@
+use builtin::{ilqaa, ion, mqlss, nisq, raa, scmr};
+use serde_json::{self, json};
+use solver::backend::take_phase_timings;
+use solver::structures::{rounded_for_output, CompilerResult, GateImplementation, PreflightError};
+use solver::utils::{self, IOError};
+use std::time::{Duration, Instant};
+
+/// Runs one onepass solve and returns its cost and wall-clock time.
+fn time_solve<G: GateImplementation>(
+    solve: impl FnOnce() -> Result<CompilerResult<G>, Vec<PreflightError>>,
+) -> Result<(f64, Duration), IOError> {
+    let start = Instant::now();
+    let res = solve().map_err(IOError::PreflightErr)?;
+    Ok((res.cost, start.elapsed()))
+}
+
+/// Solves `circ_path` with `solver` on the architecture its `run-*` binary would pick by
+/// default, sized to the circuit.
+fn bench_once(solver: &str, circ_path: &str) -> Result<(f64, Duration), IOError> {
+    match solver {
+        "nisq" => {
+            let circ = utils::try_extract_gates(circ_path, &["CX"])?;
+            let side = (circ.qubits.len() as f64).sqrt().ceil() as usize;
+            let arch = nisq::NisqArchitecture::new(utils::grid_graph(side, side, false));
+            time_solve(|| nisq::nisq_solve(&circ, &arch, None))
+        }
+        "scmr" => {
+            let circ = utils::try_extract_gates(circ_path, &["CX", "T", "Measure"])?;
+            let arch = scmr::compact_layout(circ.qubits.len());
+            time_solve(|| scmr::scmr_solve(&circ, &arch))
+        }
+        "ilq" => {
+            let circ = utils::try_extract_gates(circ_path, &["T", "CX"])?;
+            let arch = ilqaa::compact_layout(circ.qubits.len(), 3);
+            time_solve(|| ilqaa::ilq_solve(&circ, &arch))
+        }
+        "mqlss" => {
+            let circ = utils::try_extract_gates(circ_path, &["Pauli"])?;
+            let arch = mqlss::compact_layout(circ.qubits.len());
+            time_solve(|| mqlss::mqlss_solve(&circ, &arch))
+        }
+        "raa" => {
+            let circ = utils::try_extract_gates(circ_path, &["CX"])?;
+            let size = (circ.gates.len() as f64).sqrt().ceil() as usize;
+            let arch = raa::RaaArchitecture { width: size, height: size };
+            time_solve(|| raa::raa_solve(&circ, &arch))
+        }
+        "ion" => {
+            let circ = utils::try_extract_gates(circ_path, &["CX"])?;
+            let arch = ion::IonArch::new(circ.qubits.len().div_ceil(2).max(2), 1);
+            time_solve(|| ion::ion_solve(&circ, &arch))
+        }
+        _ => Err(IOError::InputErr),
+    }
+}
+
+fn main() -> Result<(), IOError> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 || args.len() > 4 {
+        println!("Usage: bench <nisq|scmr|ilq|mqlss|raa|ion> <circuit> [runs]");
+        return Err(IOError::InputErr);
+    }
+    let runs: usize = args.get(3).map_or(1, |r| r.parse().expect("runs should be usize"));
+    for run in 0..runs {
+        take_phase_timings();
+        let (cost, total) = bench_once(&args[1], &args[2])?;
+        let phases = take_phase_timings();
+        // one JSON object per run, times in seconds
+        let line = json!({
+            "solver": args[1],
+            "circuit": args[2],
+            "run": run,
+            "cost": rounded_for_output(cost),
+            "total": total.as_secs_f64(),
+            "isomorphism": phases.isomorphism.as_secs_f64(),
+            "annealing": phases.annealing.as_secs_f64(),
+            "routing": phases.routing.as_secs_f64(),
+        });
+        println!("{}", line);
+    }
+    Ok(())
+}
@@ -1,15 +1,14 @@
-use builtin::ilqaa::{ilq_solve, ilq_solve_joint_optimize_parallel};
+use builtin::ilqaa::{ilq_solve, ilq_solve_joint_optimize_parallel, ILQGateImplementation};
 use petgraph::dot::{Config, Dot};
 use serde_json;
 use solver::utils::{self, IOError};
-use solver::structures::Architecture;
-fn run_ilq(
-    circ_path: &str,
+use solver::structures::{Circuit, CompilerResult};
+fn compile_ilq(
+    circ: &Circuit,
     arch_type: &str,
     stack_depth_arg: &str,
     solve_mode: &str,
-) -> Result<(), IOError> {
-    let circ = utils::extract_gates(circ_path, &["T", "CX"]);
+) -> Result<CompilerResult<ILQGateImplementation>, IOError> {
     let stack_depth = stack_depth_arg
         .parse()
         .expect("stack depth should be usize");
@@ -25,17 +24,32 @@ fn run_ilq(
         )),
         _ => Err(IOError::InputErr),
     }?;
-    let res = match solve_mode {
-        "--onepass" => Ok(ilq_solve(&circ, &arch)),
-        "--joint-optimize-par" => Ok(ilq_solve_joint_optimize_parallel(&circ, &arch)),
+    match solve_mode {
+        "--onepass" => ilq_solve(circ, &arch).map_err(IOError::PreflightErr),
+        "--joint-optimize-par" => ilq_solve_joint_optimize_parallel(circ, &arch).map_err(IOError::PreflightErr),
         _ => Err(IOError::InputErr),
-    }?;
+    }
+}
+fn run_ilq(
+    circ_path: &str,
+    arch_type: &str,
+    stack_depth_arg: &str,
+    solve_mode: &str,
+) -> Result<(), IOError> {
+    let circ = utils::try_extract_gates(circ_path, &["T", "CX"])?;
+    let res = compile_ilq(&circ, arch_type, stack_depth_arg, solve_mode)?;
     serde_json::to_writer(std::io::stdout(), &res).map_err(IOError::OutputErr)
 }
 fn main() -> Result<(), IOError> {
     let args: Vec<String> = std::env::args().collect();
     if args.len() != 5 {
         println!("Usage: run-ilq <circuit> <arch> <stack-depth> <mode>");
+        println!("       run-ilq --jsonl <arch> <stack-depth> <mode>  (one JSON circuit per stdin line)");
+    }
+    if args[1] == "--jsonl" {
+        return utils::compile_jsonl(std::io::stdin().lock(), std::io::stdout().lock(), |circ| {
+            compile_ilq(circ, &args[2], &args[3], &args[4])
+        });
     }
     run_ilq(&args[1], &args[2], &args[3], &args[4])
 }
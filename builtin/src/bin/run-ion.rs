@@ -1,20 +1,22 @@
-use builtin::ion::{ion_solve, ion_solve_joint_optimize_parallel, IonArch};
+use builtin::ion::{ion_solve, ion_solve_joint_optimize_parallel, IonArch, IonGateImplementation};
 use serde_json;
+use solver::structures::{Circuit, CompilerResult};
 use solver::utils::{self, IOError};
 
-fn run_ion(circ_path: &str, width_arg: &str, solve_mode: &str) -> Result<(), IOError> {
-    let circ = utils::extract_gates(circ_path, &["CX"]);
+fn compile_ion(circ: &Circuit, width_arg: &str, solve_mode: &str) -> Result<CompilerResult<IonGateImplementation>, IOError> {
     let width = width_arg.parse().expect("width arg should be usize");
     let trap_size = circ.qubits.len().div_ceil(2*width).max(2);
-    let arch = IonArch {
-        trap_size,
-        width
-    };
-    let res = match solve_mode {
-        "--onepass" => Ok(ion_solve(&circ, &arch)),
-        "--joint-optimize-par" => Ok(ion_solve_joint_optimize_parallel(&circ, &arch)),
+    let arch = IonArch::new(trap_size, width);
+    match solve_mode {
+        "--onepass" => ion_solve(circ, &arch).map_err(IOError::PreflightErr),
+        "--joint-optimize-par" => ion_solve_joint_optimize_parallel(circ, &arch).map_err(IOError::PreflightErr),
         _ => Err(IOError::InputErr),
-    }?;
+    }
+}
+
+fn run_ion(circ_path: &str, width_arg: &str, solve_mode: &str) -> Result<(), IOError> {
+    let circ = utils::try_extract_gates(circ_path, &["CX"])?;
+    let res = compile_ion(&circ, width_arg, solve_mode)?;
     serde_json::to_writer(std::io::stdout(), &res).map_err(IOError::OutputErr)
 }
 
@@ -22,6 +24,12 @@ fn main() -> Result<(), IOError> {
     let args: Vec<String> = std::env::args().collect();
     if args.len() != 4 {
         println!("Usage: run-ilq <circuit> <trap-size> <mode>");
+        println!("       run-ion --jsonl <trap-size> <mode>  (one JSON circuit per stdin line)");
+    }
+    if args[1] == "--jsonl" {
+        return utils::compile_jsonl(std::io::stdin().lock(), std::io::stdout().lock(), |circ| {
+            compile_ion(circ, &args[2], &args[3])
+        });
     }
     run_ion(&args[1], &args[2], &args[3])
 }
@@ -1,21 +1,38 @@
-use builtin::mqlss;
-use serde_json;
+use builtin::mqlss::{self, MQLSSGateImplementation};
+use serde_json::{self, json};
+use solver::structures::{Circuit, CompilerResult};
 use solver::utils::{self, IOError};
 
-fn run_mqlss(circ_path: &str, arch_type: &str) -> Result<(), IOError> {
-    let circ = utils::extract_gates(circ_path, &["Pauli"]);
+fn compile_mqlss(circ: &Circuit, arch_type: &str) -> Result<CompilerResult<MQLSSGateImplementation>, IOError> {
     let arch = match arch_type {
         "compact" => Ok(builtin::mqlss::compact_layout(circ.qubits.len())),
         "square_sparse" => Ok(builtin::mqlss::square_sparse_layout(circ.qubits.len())),
         _ => Err(IOError::InputErr),
     }?;
-    let res = mqlss::mqlss_solve_joint_optimize_parallel(&circ, &arch);
-    serde_json::to_writer(std::io::stdout(), &res).map_err(IOError::OutputErr)
+    mqlss::mqlss_solve_joint_optimize_parallel(circ, &arch).map_err(IOError::PreflightErr)
+}
+
+fn run_mqlss(circ_path: &str, arch_type: &str) -> Result<(), IOError> {
+    let circ = utils::try_extract_gates(circ_path, &["Pauli"])?;
+    let res = compile_mqlss(&circ, arch_type)?;
+    let mut out = serde_json::to_value(&res).map_err(IOError::OutputErr)?;
+    // cells each rotation's tree used, for drawing it onto the lattice
+    out["gate_footprints"] = json!(res.gate_footprints());
+    serde_json::to_writer(std::io::stdout(), &out).map_err(IOError::OutputErr)
 }
 fn main() -> Result<(), IOError> {
     let args: Vec<String> = std::env::args().collect();
     if args.len() != 3 {
         println!("Usage: run-mqlss <circuit> <arch>");
+        println!("       run-mqlss --jsonl <arch>  (one JSON circuit per stdin line)");
     }
-    run_mqlss(&args[1], &args[2])
+    let res = if args[1] == "--jsonl" {
+        utils::compile_jsonl(std::io::stdin().lock(), std::io::stdout().lock(), |circ| {
+            compile_mqlss(circ, &args[2])
+        })
+    } else {
+        run_mqlss(&args[1], &args[2])
+    };
+    eprintln!("Steiner trees evaluated: {}", utils::steiner_trees_evaluated());
+    res
 }
@@ -1,32 +1,196 @@
+use std::collections::BTreeMap;
 use std::fs::File;
+use std::io::Write;
+use std::path::Path;
 
-use solver::utils::{self, graph_from_json_entry, IOError};
-use builtin::nisq::{self, nisq_solve, nisq_solve_cached_heuristic, nisq_solve_joint_optimize, nisq_solve_joint_optimize_parallel, nisq_solve_sabre, nisq_solve_sabre_par};
-use serde_json::{self, Value};
-
-
-fn run_nisq(circ_path: &str, arch_path : &str, solve_mode : &str) -> Result<(), IOError> {
-    let circ = utils::extract_cnots(circ_path);
-    let file = File::open(arch_path).expect("Opening architecture file");
-    let parsed: Value = serde_json::from_reader(file)
-        .expect("Parsing architecture file");
-    let g = graph_from_json_entry(parsed["graph"].clone());
-    let arch = nisq::NisqArchitecture::new(g);
-    let res =   match solve_mode {
-        "--sabre" => Ok(nisq_solve_sabre(&circ, &arch)),
-        "--onepass" => Ok(nisq_solve(&circ, &arch)),
-        "--fast-heuristic" => Ok(nisq_solve_cached_heuristic(&circ, &arch)),
-        "--sabre-par" => Ok(nisq_solve_sabre_par(&circ, &arch)),
-        "--joint-optimize" => Ok(nisq_solve_joint_optimize(&circ, &arch)),
-        "--joint-optimize-par" => Ok(nisq_solve_joint_optimize_parallel(&circ, &arch)),
+use serde::Deserialize;
+use solver::backend::{Progress, RouteProgress};
+use solver::structures::{rounded_for_output, Circuit, CompilerResult, InitialMapStrategy, RegionConstraints};
+use solver::utils::{self, add_isolated_locations, directed_graph_from_edges, weighted_graph_from_edges, ArchEdges, IOError};
+use builtin::nisq::{self, nisq_plan_mapping, nisq_solve, nisq_solve_cached_heuristic, nisq_solve_with_init, nisq_solve_joint_optimize, nisq_solve_joint_optimize_parallel, nisq_solve_in_regions, nisq_solve_sabre, nisq_solve_sabre_in_regions, nisq_solve_sabre_par, NisqArchitecture, NisqGateImplementation};
+use serde_json::{self, json};
+
+
+/// An architecture file: `{"graph": ..., "directed": bool, "tunable": ..., "coupler_budget": n,
+/// "num_nodes": n}`, where only `graph` is required. `num_nodes` (alias `qubit_count`)
+/// declares locations `0..num_nodes`, so locations without any edge can still be listed.
+#[derive(Deserialize)]
+struct ArchFile {
+    graph: ArchEdges,
+    #[serde(default, alias = "qubit_count")]
+    num_nodes: Option<usize>,
+    #[serde(default)]
+    directed: bool,
+    #[serde(default)]
+    tunable: Option<ArchEdges>,
+    #[serde(default)]
+    coupler_budget: Option<usize>,
+}
+
+/// Reports a malformed architecture file on stderr.
+fn arch_err(arch_path : &str, reason : impl std::fmt::Display) -> IOError {
+    eprintln!("error: malformed architecture file {} ({})", arch_path, reason);
+    IOError::InputErr
+}
+
+fn nisq_arch(arch_path : &str) -> Result<NisqArchitecture, IOError> {
+    let file = File::open(arch_path).map_err(|e| arch_err(arch_path, e))?;
+    let parsed: ArchFile = serde_json::from_reader(file).map_err(|e| arch_err(arch_path, e))?;
+    let edges = parsed.graph.edges().map_err(|e| arch_err(arch_path, e))?;
+    let num_nodes = parsed.num_nodes.unwrap_or(0);
+    if let Some(l) = edges.iter().flat_map(|(a, b, _)| [a, b]).find(|l| parsed.num_nodes.is_some() && l.get_index() >= num_nodes) {
+        return Err(arch_err(arch_path, format!("location {} is outside num_nodes {}", l.get_index(), num_nodes)));
+    }
+    let arch = if parsed.directed {
+        let mut couplings = directed_graph_from_edges(edges.iter().map(|(a, b, _)| (*a, *b)).collect());
+        add_isolated_locations(&mut couplings, num_nodes);
+        nisq::NisqArchitecture::new_directed(couplings)
+    } else {
+        let mut weighted = weighted_graph_from_edges(edges);
+        add_isolated_locations(&mut weighted, num_nodes);
+        nisq::NisqArchitecture::new_weighted(weighted)
+    };
+    let Some(tunable) = parsed.tunable else {
+        return Ok(arch);
+    };
+    let budget = parsed.coupler_budget.ok_or_else(|| arch_err(arch_path, "tunable couplers need a coupler_budget"))?;
+    let couplers: Vec<_> = tunable.edges().map_err(|e| arch_err(arch_path, e))?.into_iter().map(|(a, b, _)| (a, b)).collect();
+    Ok(arch.with_tunable_couplers(&couplers, budget))
+}
+
+fn compile_nisq(circ: &Circuit, arch : &NisqArchitecture, solve_mode : &str, init : &Option<InitialMapStrategy>, regions : &Option<RegionConstraints>, progress : Option<&Progress>) -> Result<CompilerResult<NisqGateImplementation>, IOError> {
+    if let Some(regions) = regions {
+        return match (solve_mode, init) {
+            ("--onepass", _) => nisq_solve_in_regions(circ, arch, init.as_ref().unwrap_or(&InitialMapStrategy::Parallel), regions, progress).map_err(IOError::PreflightErr),
+            ("--sabre", None) => nisq_solve_sabre_in_regions(circ, arch, regions, progress).map_err(IOError::PreflightErr),
+            _ => Err(IOError::InputErr),
+        };
+    }
+    if let Some(init) = init {
+        // the other modes pick their own starting maps
+        return match solve_mode {
+            "--onepass" => nisq_solve_with_init(circ, arch, init, progress).map_err(IOError::PreflightErr),
+            _ => Err(IOError::InputErr),
+        };
+    }
+    match solve_mode {
+        "--sabre" => nisq_solve_sabre(circ, arch, progress).map_err(IOError::PreflightErr),
+        "--onepass" => nisq_solve(circ, arch, progress).map_err(IOError::PreflightErr),
+        "--fast-heuristic" => nisq_solve_cached_heuristic(circ, arch, progress).map_err(IOError::PreflightErr),
+        "--sabre-par" => nisq_solve_sabre_par(circ, arch, progress).map_err(IOError::PreflightErr),
+        "--joint-optimize" => nisq_solve_joint_optimize(circ, arch, progress).map_err(IOError::PreflightErr),
+        "--joint-optimize-par" => nisq_solve_joint_optimize_parallel(circ, arch, progress).map_err(IOError::PreflightErr),
         _ => Err(IOError::InputErr)
-    }?;
+    }
+}
+
+fn compile_nisq_file(circ_path: &Path, arch : &NisqArchitecture, solve_mode : &str, init : &Option<InitialMapStrategy>, regions : &Option<RegionConstraints>, progress : Option<&Progress>) -> Result<CompilerResult<NisqGateImplementation>, IOError> {
+    let circ = utils::try_extract_gates(circ_path.to_str().ok_or(IOError::InputErr)?, &["CX", "CCX", "Measure"])?;
+    compile_nisq(&circ, arch, solve_mode, init, regions, progress)
+}
+
+/// Prints the initial map `--onepass` would start from and its heuristic cost, skipping routing.
+fn run_nisq_map_only(circ_path: &str, arch_path : &str) -> Result<(), IOError> {
+    let arch = nisq_arch(arch_path)?;
+    let circ = utils::try_extract_gates(circ_path, &["CX", "CCX", "Measure"])?;
+    let (map, cost) = nisq_plan_mapping(&circ, &arch);
+    let map: BTreeMap<usize, usize> = map.iter().map(|(q, l)| (q.get_index(), l.get_index())).collect();
+    serde_json::to_writer(std::io::stdout(), &json!({"map": map, "heuristic_cost": rounded_for_output(cost)})).map_err(IOError::OutputErr)
+}
+
+fn run_nisq(circ_path: &str, arch_path : &str, solve_mode : &str, init : &Option<InitialMapStrategy>, regions : &Option<RegionConstraints>, progress : Option<&Progress>) -> Result<(), IOError> {
+    if solve_mode == "--map-only" {
+        // this plans with the default search, so an --init or --regions override would be ignored
+        return match (init, regions) {
+            (None, None) => run_nisq_map_only(circ_path, arch_path),
+            _ => Err(IOError::InputErr),
+        };
+    }
+    let arch = nisq_arch(arch_path)?;
+    let res = compile_nisq_file(Path::new(circ_path), &arch, solve_mode, init, regions, progress)?;
     serde_json::to_writer(std::io::stdout(), &res).map_err(IOError::OutputErr)
 }
+
+/// Compiles every `.qasm` or gzipped `.qasm.gz` file in `dir` (in name order) against one
+/// architecture, writing one `{"file", "cost", "stats"}` line per circuit.
+fn run_nisq_batch(dir : &str, arch_path : &str, solve_mode : &str, init : &Option<InitialMapStrategy>, regions : &Option<RegionConstraints>, progress : Option<&Progress>) -> Result<(), IOError> {
+    let arch = nisq_arch(arch_path)?;
+    let mut circ_paths: Vec<_> = std::fs::read_dir(dir)
+        .map_err(|_| IOError::InputErr)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| p.is_file() && p.to_str().is_some_and(|s| s.ends_with(".qasm") || s.ends_with(".qasm.gz")))
+        .collect();
+    circ_paths.sort();
+    let mut output = std::io::stdout().lock();
+    for circ_path in circ_paths {
+        let res = compile_nisq_file(&circ_path, &arch, solve_mode, init, regions, progress)?;
+        let file = circ_path.file_name().unwrap().to_string_lossy();
+        let record = json!({"file": file, "cost": rounded_for_output(res.cost), "stats": res.stats()});
+        serde_json::to_writer(&mut output, &record).map_err(IOError::OutputErr)?;
+        writeln!(output)
+            .and_then(|_| output.flush())
+            .map_err(|e| IOError::OutputErr(serde_json::Error::io(e)))?;
+    }
+    Ok(())
+}
+
+fn run_nisq_jsonl(arch_path : &str, solve_mode : &str, init : &Option<InitialMapStrategy>, regions : &Option<RegionConstraints>, progress : Option<&Progress>) -> Result<(), IOError> {
+    let arch = nisq_arch(arch_path)?;
+    utils::compile_jsonl(std::io::stdin().lock(), std::io::stdout().lock(), |circ| compile_nisq(circ, &arch, solve_mode, init, regions, progress))
+}
+
+/// Removes a `--progress` flag from `args`, returning whether there was one.
+fn take_progress_flag(args : &mut Vec<String>) -> bool {
+    let before = args.len();
+    args.retain(|a| a != "--progress");
+    args.len() != before
+}
+
+/// Removes a `--regions <file>` from `args` and reads the region constraints it names, if
+/// there is one.
+fn take_regions_flag(args : &mut Vec<String>) -> Result<Option<RegionConstraints>, IOError> {
+    let Some(i) = args.iter().position(|a| a == "--regions") else {
+        return Ok(None);
+    };
+    if i + 1 >= args.len() {
+        return Err(IOError::InputErr);
+    }
+    let path = args.remove(i + 1);
+    args.remove(i);
+    utils::region_constraints_from_file(&path).map(Some)
+}
+
+/// Removes a trailing `--init <strategy>` from `args`, if there is one.
+fn take_init_flag(args : &mut Vec<String>) -> Result<Option<InitialMapStrategy>, IOError> {
+    if args.len() < 3 || args[args.len() - 2] != "--init" {
+        return Ok(None);
+    }
+    let name = args.pop().unwrap();
+    args.pop();
+    InitialMapStrategy::from_name(&name).map(Some).ok_or(IOError::InputErr)
+}
+
 fn main() -> Result<(), IOError>  {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 4 {
-    println!("Usage: run-nisq <circuit> <arch> <solve-mode>");
+    let mut args: Vec<String> = std::env::args().collect();
+    // one JSON line per routing step, kept off stdout where the result goes
+    let stderr_progress = |p: RouteProgress| eprintln!("{}", json!(p));
+    let progress: Option<&Progress> = if take_progress_flag(&mut args) { Some(&stderr_progress) } else { None };
+    let regions = take_regions_flag(&mut args)?;
+    let init = take_init_flag(&mut args)?;
+    if args.len() != 4 && !(args.len() == 5 && args[1] == "--batch") {
+    println!("Usage: run-nisq <circuit> <arch> <solve-mode> [--init <strategy>]");
+    println!("       run-nisq --jsonl <arch> <solve-mode> [--init <strategy>]  (one JSON circuit per stdin line)");
+    println!("       run-nisq --batch <dir> <arch> <solve-mode> [--init <strategy>]  (every .qasm or .qasm.gz in dir, one JSON line each)");
+    println!("  --init random|identity|isomorphism|anneal picks the starting map of --onepass");
+    println!("  solve-mode --map-only prints the starting map of --onepass and its heuristic cost without routing");
+    println!("  --progress writes a JSON line per routing step to stderr");
+    println!("  --regions <file> starts each listed qubit in its region, {{\"qubit\": [locations]}}; --onepass and --sabre only");
 }
-    run_nisq(&args[1], &args[2], &args[3])
+    if args[1] == "--jsonl" {
+        return run_nisq_jsonl(&args[2], &args[3], &init, &regions, progress);
+    }
+    if args[1] == "--batch" {
+        return run_nisq_batch(&args[2], &args[3], &args[4], &init, &regions, progress);
+    }
+    run_nisq(&args[1], &args[2], &args[3], &init, &regions, progress)
 }
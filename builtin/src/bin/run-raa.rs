@@ -1,24 +1,35 @@
+use solver::structures::{Circuit, CompilerResult};
 use solver::utils::{self, IOError};
-use builtin::raa::{self, raa_joint_optimize_parallel, raa_solve, raa_solve_sabre};
+use builtin::raa::{self, raa_joint_optimize_parallel, raa_solve, raa_solve_sabre, RaaGateImplementation};
 use serde_json;
 
 
-fn run_raa(circ_path: &str, solve_mode : &str) -> Result<(), IOError> {
-    let circ = utils::extract_cnots(circ_path);
+fn compile_raa(circ: &Circuit, solve_mode : &str) -> Result<CompilerResult<RaaGateImplementation>, IOError> {
     let size = (circ.gates.len() as f64).sqrt().ceil() as usize;
     let arch = raa::RaaArchitecture { width : size, height : size};
-    let res =   match solve_mode {
-        "--sabre" => Ok(raa_solve_sabre(&circ, &arch)),
-        "--onepass" => Ok(raa_solve(&circ, &arch)),
-        "--joint-optimize-par" => Ok(raa_joint_optimize_parallel(&circ, &arch)),
+    match solve_mode {
+        "--sabre" => raa_solve_sabre(circ, &arch).map_err(IOError::PreflightErr),
+        "--onepass" => raa_solve(circ, &arch).map_err(IOError::PreflightErr),
+        "--joint-optimize-par" => raa_joint_optimize_parallel(circ, &arch).map_err(IOError::PreflightErr),
         _ => Err(IOError::InputErr)
-    }?;
+    }
+}
+
+fn run_raa(circ_path: &str, solve_mode : &str) -> Result<(), IOError> {
+    let circ = utils::try_extract_gates(circ_path, &["CX"])?;
+    let res = compile_raa(&circ, solve_mode)?;
     serde_json::to_writer(std::io::stdout(), &res).map_err(IOError::OutputErr)
 }
 fn main() -> Result<(), IOError>  {
     let args: Vec<String> = std::env::args().collect();
     if args.len() != 3 {
     println!("Usage: run-raa <circuit> <arch>");
+    println!("       run-raa --jsonl <solve-mode>  (one JSON circuit per stdin line)");
 }
+    if args[1] == "--jsonl" {
+        return utils::compile_jsonl(std::io::stdin().lock(), std::io::stdout().lock(), |circ| {
+            compile_raa(circ, &args[2])
+        });
+    }
     run_raa(&args[1], &args[2])
 }
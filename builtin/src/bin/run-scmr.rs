@@ -1,26 +1,59 @@
-use builtin::scmr::{scmr_solve, scmr_solve_joint_optimize_parallel, scmr_solve_par};
-use serde_json;
+use builtin::scmr::{scmr_solve, scmr_solve_joint_optimize_parallel, scmr_solve_par, ScmrArchitecture, ScmrGateImplementation};
+use serde_json::{self, json};
+use solver::structures::{Circuit, CompilerResult};
 use solver::utils::{self, IOError};
 
-fn run_scmr(circ_path: &str, arch_type: &str, solve_mode: &str) -> Result<(), IOError> {
-    let circ = utils::extract_scmr_gates(circ_path);
-    let arch = match arch_type {
+fn scmr_arch(circ: &Circuit, arch_type: &str) -> Result<ScmrArchitecture, IOError> {
+    match arch_type {
         "compact" => Ok(builtin::scmr::compact_layout(circ.qubits.len())),
         "square_sparse" => Ok(builtin::scmr::square_sparse_layout(circ.qubits.len())),
         _ => Err(IOError::InputErr),
-    }?;
-    let res = match solve_mode {
-        "--onepass" => Ok(scmr_solve(&circ, &arch)),
-        "--parallel" => Ok(scmr_solve_par(&circ, &arch)),
-        "--joint-optimize-par" => Ok(scmr_solve_joint_optimize_parallel(&circ, &arch)),
+    }
+}
+
+fn compile_scmr(circ: &Circuit, arch_type: &str, solve_mode: &str) -> Result<CompilerResult<ScmrGateImplementation>, IOError> {
+    let arch = scmr_arch(circ, arch_type)?;
+    match solve_mode {
+        "--onepass" => scmr_solve(circ, &arch).map_err(IOError::PreflightErr),
+        "--parallel" => scmr_solve_par(circ, &arch).map_err(IOError::PreflightErr),
+        "--joint-optimize-par" => scmr_solve_joint_optimize_parallel(circ, &arch).map_err(IOError::PreflightErr),
         _ => Err(IOError::InputErr),
-    }?;
-    serde_json::to_writer(std::io::stdout(), &res).map_err(IOError::OutputErr)
+    }
+}
+
+fn run_scmr(circ_path: &str, arch_type: &str, solve_mode: &str, render: bool) -> Result<(), IOError> {
+    let circ = utils::try_extract_gates(circ_path, &["CX", "T", "Measure"])?;
+    let res = compile_scmr(&circ, arch_type, solve_mode)?;
+    if render {
+        let arch = scmr_arch(&circ, arch_type)?;
+        for (i, step) in res.steps.iter().enumerate() {
+            eprintln!("step {}\n{}\n", i, arch.render_step(step));
+        }
+    }
+    let mut out = serde_json::to_value(&res).map_err(IOError::OutputErr)?;
+    // cells each gate's path used, for drawing it onto the lattice
+    out["gate_footprints"] = json!(res.gate_footprints());
+    serde_json::to_writer(std::io::stdout(), &out).map_err(IOError::OutputErr)
+}
+/// Removes a `--render` flag from `args`, returning whether there was one.
+fn take_render_flag(args: &mut Vec<String>) -> bool {
+    let before = args.len();
+    args.retain(|a| a != "--render");
+    args.len() != before
 }
+
 fn main() -> Result<(), IOError> {
-    let args: Vec<String> = std::env::args().collect();
+    let mut args: Vec<String> = std::env::args().collect();
+    let render = take_render_flag(&mut args);
     if args.len() != 4 {
-        println!("Usage: run-scmr <circuit> <arch> <mode>");
+        println!("Usage: run-scmr <circuit> <arch> <mode> [--render]");
+        println!("       run-scmr --jsonl <arch> <mode>  (one JSON circuit per stdin line)");
+        println!("  --render draws the lattice after every step to stderr");
+    }
+    if args[1] == "--jsonl" {
+        return utils::compile_jsonl(std::io::stdin().lock(), std::io::stdout().lock(), |circ| {
+            compile_scmr(circ, &args[2], &args[3])
+        });
     }
-    run_scmr(&args[1], &args[2], &args[3])
+    run_scmr(&args[1], &args[2], &args[3], render)
 }
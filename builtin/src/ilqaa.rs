@@ -4,14 +4,17 @@ use petgraph::{graph::NodeIndex, Graph};
 use serde::Serialize;
 use solver::{
     backend::{solve, solve_joint_optimize_parallel},
+    config::CONFIG,
     structures::{
-        Architecture, Circuit, CompilerResult, Gate, GateImplementation, Location, Operation,
-        QubitMap, Step, Transition,
+        Architecture, Circuit, CompilerResult, Gate, GateImplementation, GateType,
+        validate_circuit, InitialMapStrategy, Location, Operation, PreflightError, QubitMap, Step,
+        Transition,
     },
-    utils::{all_paths, horizontal_neighbors, vertical_neighbors},
+    utils::{all_paths_capped, horizontal_neighbors, remove_locations, vertical_neighbors},
 };
 
-const CODE_DISTANCE: usize = 11;
+/// Code distance the layouts start from.
+const DEFAULT_CODE_DISTANCE: usize = 11;
 
 #[derive(Clone, Debug)]
 pub struct ILQArch {
@@ -20,6 +23,12 @@ pub struct ILQArch {
     pub height: usize,
     pub alg_qubits: Vec<Location>,
     pub magic_state_qubits: Vec<Location>,
+    /// Cells left out of the routing graph, e.g. those outside the region given to `restrict`.
+    pub excluded: Vec<Location>,
+    /// Rounds a lattice-surgery step takes; set from the target logical error rate.
+    pub code_distance: usize,
+    /// Cost charged per transversal CX in a step; zero treats them as free.
+    pub transversal_cost: f64,
 }
 
 impl Architecture for ILQArch {
@@ -35,9 +44,34 @@ impl Architecture for ILQArch {
     ) {
         return self.get_graph();
     }
+    fn supported_gate_types(&self) -> Vec<GateType> {
+        vec![GateType::CX, GateType::T]
+    }
+    fn blocked_locations(&self) -> Vec<Location> {
+        self.magic_state_qubits.clone()
+    }
 }
 
 impl ILQArch {
+    /// A layout with every cell routable, the default code distance and free transversal CX.
+    pub fn new(
+        stack_depth: usize,
+        width: usize,
+        height: usize,
+        alg_qubits: Vec<Location>,
+        magic_state_qubits: Vec<Location>,
+    ) -> Self {
+        ILQArch {
+            stack_depth,
+            width,
+            height,
+            alg_qubits,
+            magic_state_qubits,
+            excluded: vec![],
+            code_distance: DEFAULT_CODE_DISTANCE,
+            transversal_cost: 0.0,
+        }
+    }
     fn get_graph(&self) -> (Graph<Location, ()>, HashMap<Location, NodeIndex>) {
         let mut g = Graph::new();
         let mut index_map = HashMap::new();
@@ -115,8 +149,23 @@ impl ILQArch {
                 }
             }
         }
+        remove_locations(&mut g, &mut index_map, &self.excluded);
         return (g, index_map);
     }
+    /// This layout cut down to the stack slots in `allowed`, keeping their locations: qubit
+    /// slots and magic states outside it are dropped and paths can't leave it.
+    pub fn restrict(&self, allowed: &HashSet<Location>) -> Self {
+        let inside = |locs: &Vec<Location>| locs.iter().filter(|l| allowed.contains(l)).copied().collect();
+        ILQArch {
+            alg_qubits: inside(&self.alg_qubits),
+            magic_state_qubits: inside(&self.magic_state_qubits),
+            excluded: (0..self.width * self.height * self.stack_depth)
+                .map(Location::new)
+                .filter(|l| !allowed.contains(l) || self.excluded.contains(l))
+                .collect(),
+            ..self.clone()
+        }
+    }
 }
 
 pub fn compact_layout(alg_qubit_count: usize, stack_depth: usize) -> ILQArch {
@@ -165,13 +214,7 @@ pub fn compact_layout(alg_qubit_count: usize, stack_depth: usize) -> ILQArch {
     for i in (1..perimeter.len()).step_by(2) {
         magic_state_qubits.push(perimeter[i]);
     }
-    return ILQArch {
-        width,
-        height,
-        alg_qubits,
-        magic_state_qubits,
-        stack_depth,
-    };
+    ILQArch::new(stack_depth, width, height, alg_qubits, magic_state_qubits)
 }
 
 pub fn square_sparse_layout(alg_qubit_count: usize, stack_depth: usize) -> ILQArch {
@@ -229,13 +272,7 @@ pub fn square_sparse_layout(alg_qubit_count: usize, stack_depth: usize) -> ILQAr
     for i in (1..perimeter.len()).step_by(2) {
         magic_state_qubits.push(perimeter[i]);
     }
-    return ILQArch {
-        width,
-        height,
-        alg_qubits,
-        magic_state_qubits,
-        stack_depth,
-    };
+    ILQArch::new(stack_depth, width, height, alg_qubits, magic_state_qubits)
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Serialize, Clone)]
@@ -243,7 +280,15 @@ pub enum ILQGateImplementation {
     Transversal { ctrl: Location, tar: Location },
     LatticeSurgery { path: Vec<Location> },
 }
-impl GateImplementation for ILQGateImplementation {}
+impl GateImplementation for ILQGateImplementation {
+    fn occupied_locations(&self) -> Vec<Location> {
+        match self {
+            // acts within one stack, leaving the routing space free
+            ILQGateImplementation::Transversal { .. } => vec![],
+            ILQGateImplementation::LatticeSurgery { path } => path.clone(),
+        }
+    }
+}
 type ILQStep = Step<ILQGateImplementation>;
 #[derive(Debug)]
 struct IdTransition;
@@ -267,16 +312,22 @@ fn ilq_transitions(_step: &ILQStep) -> Vec<IdTransition> {
     return vec![IdTransition];
 }
 
-pub fn ilq_step_cost(step: &ILQStep, _arch: &ILQArch) -> f64 {
+pub fn ilq_step_cost(step: &ILQStep, arch: &ILQArch) -> f64 {
+    let transversal_count = step
+        .implemented_gates
+        .iter()
+        .filter(|g| matches!(g.implementation, ILQGateImplementation::Transversal { .. }))
+        .count();
+    let transversal = (transversal_count as f64) * arch.transversal_cost;
     if step.implemented_gates.iter().any(|g| {
         matches!(
             g.implementation,
             ILQGateImplementation::LatticeSurgery { .. }
         )
     }) {
-        return CODE_DISTANCE as f64;
+        arch.code_distance as f64 + transversal
     } else {
-        return 1.0;
+        1.0 + transversal
     }
 }
 
@@ -285,7 +336,7 @@ fn ilq_implement_gate(
     arch: &ILQArch,
     gate: &Gate,
 ) -> Box<dyn Iterator<Item = ILQGateImplementation>> {
-    if gate.operation == Operation::CX
+    if *gate.base_operation() == Operation::CX
         && (step.map[&gate.qubits[0]].get_index() / arch.stack_depth)
             == (step.map[&gate.qubits[1]].get_index() / arch.stack_depth)
     {
@@ -294,23 +345,10 @@ fn ilq_implement_gate(
             tar: step.map[&gate.qubits[1]],
         }));
     } else {
-        let mut paths: Vec<_> = Vec::new();
-        for gate in &step.implemented_gates {
-            if let ILQGateImplementation::LatticeSurgery { path } = &gate.implementation {
-                paths.extend(path);
-            }
-        }
-
-        let mapped: Vec<_> = step.map.values().cloned().collect();
-        let magic_states = arch.magic_state_qubits.clone();
-        let blocked = mapped
-            .into_iter()
-            .chain(magic_states.into_iter())
-            .chain(paths.into_iter())
-            .collect();
+        let blocked = step.blocked_locations(arch);
         let mut starts = Vec::new();
         let mut ends = Vec::new();
-        match &gate.operation {
+        match gate.base_operation() {
             Operation::CX => {
                 let (cpos, tpos) = (step.map[&gate.qubits[0]], step.map[&gate.qubits[1]]);
                 for k in 0..arch.stack_depth{
@@ -345,17 +383,31 @@ fn ilq_implement_gate(
             _ => {},
         };
         Box::new(
-            all_paths(arch, starts, ends, blocked)
+            all_paths_capped(
+                arch,
+                starts,
+                ends,
+                blocked,
+                CONFIG.load().path_enumeration_cap,
+                Some(
+                    CONFIG.load()
+                        .path_enumeration_max_length
+                        .unwrap_or(arch.width + arch.height + arch.stack_depth),
+                ),
+            )
                 .map(|p| ILQGateImplementation::LatticeSurgery { path: p }),
         )
     }
 }
 
-fn mapping_heuristic(_a: &ILQArch, c: &Circuit, m: &QubitMap) -> f64 {
+/// Counts the CX gates of `c` whose qubits `m` places in different stacks, i.e. that
+/// can't be done transversally.
+pub fn mapping_heuristic(a: &ILQArch, c: &Circuit, m: &QubitMap) -> f64 {
     let mut cost = 0;
     for gate in &c.gates {
-        if gate.operation == Operation::CX
-            && m[&gate.qubits[0]].get_index() / 4 != m[&gate.qubits[1]].get_index() / 4
+        if *gate.base_operation() == Operation::CX
+            && m[&gate.qubits[0]].get_index() / a.stack_depth
+                != m[&gate.qubits[1]].get_index() / a.stack_depth
         {
             cost += 1;
         }
@@ -363,8 +415,9 @@ fn mapping_heuristic(_a: &ILQArch, c: &Circuit, m: &QubitMap) -> f64 {
     return cost as f64;
 }
 
-pub fn ilq_solve(c: &Circuit, a: &ILQArch) -> CompilerResult<ILQGateImplementation> {
-    return solve(
+pub fn ilq_solve(c: &Circuit, a: &ILQArch) -> Result<CompilerResult<ILQGateImplementation>, Vec<PreflightError>> {
+    validate_circuit(c, a)?;
+    Ok(solve(
         c,
         a,
         &ilq_transitions,
@@ -372,15 +425,18 @@ pub fn ilq_solve(c: &Circuit, a: &ILQArch) -> CompilerResult<ILQGateImplementati
         ilq_step_cost,
         Some(mapping_heuristic),
         true,
-    );
+        &HashMap::new(),
+        &InitialMapStrategy::Parallel,
+        None,
+    ))
 }
 
 pub fn ilq_solve_joint_optimize_parallel(
     c: &Circuit,
     a: &ILQArch,
-) -> CompilerResult<ILQGateImplementation> {
-    println!("arch {:?}", a);
-    return solve_joint_optimize_parallel(
+) -> Result<CompilerResult<ILQGateImplementation>, Vec<PreflightError>> {
+    validate_circuit(c, a)?;
+    Ok(solve_joint_optimize_parallel(
         c,
         a,
         &ilq_transitions,
@@ -388,5 +444,7 @@ pub fn ilq_solve_joint_optimize_parallel(
         ilq_step_cost,
         Some(mapping_heuristic),
         true,
-    );
+        &HashMap::new(),
+        None,
+    ))
 }
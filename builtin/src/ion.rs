@@ -1,4 +1,5 @@
 use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, LazyLock, Mutex};
 
 use itertools::{any, Itertools};
 use petgraph::{graph::NodeIndex, Graph};
@@ -6,10 +7,10 @@ use serde::Serialize;
 use solver::{
     backend::{solve, solve_joint_optimize_parallel},
     structures::{
-        Architecture, Circuit, CompilerResult, Gate, GateImplementation, Location, Qubit, Step,
-        Transition,
+        Architecture, Circuit, CompilerResult, Gate, GateImplementation, GateType,
+        validate_circuit, InitialMapStrategy, Location, PreflightError, Qubit, Step, Transition,
     },
-    utils::swap_keys,
+    utils::{all_pairs_distances, swap_keys},
 };
 
 const MERGE_COST: f64 = 80e-6;
@@ -25,6 +26,13 @@ pub struct IonArch {
     pub width: usize,
 }
 
+type DistanceMatrix = HashMap<(Location, Location), usize>;
+type DistanceCache = HashMap<(usize, usize), Arc<DistanceMatrix>>;
+
+/// All-pairs trap distances, shared by every `IonArch` of the same shape.
+static DISTANCES: LazyLock<Mutex<DistanceCache>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
 impl Architecture for IonArch {
     fn locations(&self) -> Vec<Location> {
         return self.get_trap_positions();
@@ -38,9 +46,33 @@ impl Architecture for IonArch {
     ) {
         return self.get_graph();
     }
+    fn distance_matrix(&self) -> HashMap<(Location, Location), usize> {
+        (*self.distances()).clone()
+    }
+    fn supported_gate_types(&self) -> Vec<GateType> {
+        vec![GateType::CX]
+    }
 }
 
 impl IonArch {
+    pub fn new(trap_size: usize, width: usize) -> Self {
+        IonArch { trap_size, width }
+    }
+
+    fn distances(&self) -> Arc<DistanceMatrix> {
+        let mut cache = DISTANCES.lock().unwrap();
+        cache
+            .entry((self.trap_size, self.width))
+            .or_insert_with(|| Arc::new(all_pairs_distances(&self.get_graph().0)))
+            .clone()
+    }
+
+    fn distance(&self, from: &Location, to: &Location) -> usize {
+        *self.distances().get(&(*from, *to)).unwrap_or_else(|| {
+            panic!("Disconnected graph. No path found from {:?} to {:?}", from, to)
+        })
+    }
+
     fn get_trap_positions(&self) -> Vec<Location> {
         return (0..self.width * 2 * self.trap_size)
             .map(Location::new)
@@ -203,13 +235,15 @@ pub struct IonTransition {
     pairs: Vec<(Location, Location)>,
 }
 
-fn get_pair_cost(pair: (Location, Location), arch: &IonArch) -> f64 {
+/// What shuttling an ion between the two ends of `pair` costs: the split and merge, a
+/// junction and segment per column crossed, and an inner swap at each end off the trap edge.
+pub fn get_pair_cost(pair: (Location, Location), arch: &IonArch) -> f64 {
     let mut cost = 0.0;
     // all pairs have these at the end points
     cost += SPLIT_COST + SEGMENT_COST + SEGMENT_COST + MERGE_COST;
     let (col_a, col_b) = (
         pair.0.get_index() / (2 * arch.trap_size),
-        pair.0.get_index() / (2 * arch.trap_size),
+        pair.1.get_index() / (2 * arch.trap_size),
     );
     // counting junctions
     let junction_count = usize::abs_diff(col_a, col_b)+1;
@@ -218,7 +252,8 @@ fn get_pair_cost(pair: (Location, Location), arch: &IonArch) -> f64 {
         if col_a == 0 || col_a == arch.width - 1 {
             y_count += 1;
         }
-        if col_b == 0 || col_b == arch.width - 1 {
+        // a pair within one column only crosses that column's junction once
+        if col_b != col_a && (col_b == 0 || col_b == arch.width - 1) {
             y_count += 1;
         }
         let x_count = junction_count - y_count;
@@ -245,7 +280,7 @@ impl Transition<IonGateImplementation, IonArch> for IonTransition {
     }
 
     fn repr(&self) -> String {
-        let arch = &IonArch { trap_size: 2, width: 3 };
+        let arch = &IonArch::new(2, 3);
         return format!("{:?}, cost : {:?}", self, self.cost(arch));
     }
 
@@ -336,31 +371,19 @@ fn ion_implement_gate(
 }
 
 fn mapping_heuristic(arch: &IonArch, c: &Circuit, map: &HashMap<Qubit, Location>) -> f64 {
-    let (graph, index_map) = arch.get_graph();
     // println!("map : {:?}", map);
     // println!("locations : {:?}", arch.get_trap_positions());
     let mut cost = 0;
     for gate in &c.gates {
         let (cpos, tpos) = (map.get(&gate.qubits[0]), map.get(&gate.qubits[1]));
-        let (cind, tind) = (index_map[cpos.unwrap()], index_map[tpos.unwrap()]);
-        let sp_res = petgraph::algo::astar(&graph, cind, |n| n == tind, |_| 1, |_| 0);
-
-        match sp_res {
-            Some((c, _)) => {
-                cost += c;
-                //  println!("gate: {:?}, distance {:?}", gate, c)
-            }
-            None => panic!(
-                "Disconnected graph. No path found from {:?} to {:?}",
-                cpos, tpos
-            ),
-        }
+        cost += arch.distance(cpos.unwrap(), tpos.unwrap());
     }
     return cost as f64;
 }
 
-pub fn ion_solve(c: &Circuit, a: &IonArch) -> CompilerResult<IonGateImplementation> {
-    return solve(
+pub fn ion_solve(c: &Circuit, a: &IonArch) -> Result<CompilerResult<IonGateImplementation>, Vec<PreflightError>> {
+    validate_circuit(c, a)?;
+    Ok(solve(
         c,
         a,
         &|s| ion_transitions(a, s),
@@ -368,13 +391,17 @@ pub fn ion_solve(c: &Circuit, a: &IonArch) -> CompilerResult<IonGateImplementati
         |_s, _a| 0.0,
         Some(mapping_heuristic),
         false,
-    );
+        &HashMap::new(),
+        &InitialMapStrategy::Parallel,
+        None,
+    ))
 }
 pub fn ion_solve_joint_optimize_parallel(
     c: &Circuit,
     a: &IonArch,
-) -> CompilerResult<IonGateImplementation> {
-    return solve_joint_optimize_parallel(
+) -> Result<CompilerResult<IonGateImplementation>, Vec<PreflightError>> {
+    validate_circuit(c, a)?;
+    Ok(solve_joint_optimize_parallel(
         c,
         a,
         &|s| ion_transitions(a, s),
@@ -382,5 +409,7 @@ pub fn ion_solve_joint_optimize_parallel(
         |_s, _a| 0.0,
         Some(mapping_heuristic),
         false,
-    );
+        &HashMap::new(),
+        None,
+    ))
 }
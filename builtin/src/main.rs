@@ -1,14 +1,21 @@
 use builtin::{ilqaa, ion, mqlss, nisq, raa, scmr};
 use petgraph::dot::{Config, Dot};
+use itertools::Itertools;
+use solver::backend;
+use solver::config::{set_config, CostNorm, RoutingObjective, SolverConfig, CONFIG};
 use solver::utils;
-use solver::structures::Architecture;
+use solver::structures::{
+    circuit_from_gates, Architecture, Circuit, Gate, GateImplementation, GateType, ImplementedGate, InitialMapStrategy, Location,
+    Operation, PreflightError, Qubit, QubitMap, Step, Transition,
+};
+use std::collections::{HashMap, HashSet};
 
 fn nisq_test() {
     let circ = utils::extract_cnots("/home/abtin/qmrsl/circuits/3_17_13.qasm");
-    let g = utils::graph_from_file("/home/abtin/qmrsl/arch.txt");
+    let g = utils::graph_from_file("/home/abtin/qmrsl/arch.txt").unwrap();
     let gp = utils::path_graph(3);
     let arch = nisq::NisqArchitecture::new(gp);
-    let res = nisq::nisq_solve_sabre_par(&circ, &arch);
+    let res = nisq::nisq_solve_sabre_par(&circ, &arch, None).unwrap();
     println!(
         "{:?}, {:?}, {:?}",
         res.cost, res.transitions, res.steps[0].map
@@ -21,19 +28,19 @@ fn raa_test() {
         width: 3,
         height: 2,
     };
-    println!("{:?}", raa::raa_joint_optimize_parallel(&circ, &arch));
+    println!("{:?}", raa::raa_joint_optimize_parallel(&circ, &arch).unwrap());
 }
 
 fn scmr_test() {
     let circ = utils::extract_scmr_gates("/home/abtin/qmrsl/circuits/3_17_13.qasm");
     let arch = scmr::compact_layout(circ.qubits.len());
-    println!("{:?}", scmr::scmr_solve_par(&circ, &arch).cost);
+    println!("{:?}", scmr::scmr_solve_par(&circ, &arch).unwrap().cost);
 }
 
 fn ilq_test() {
     let circ = utils::extract_gates("/home/abtin/qmrsl/circuits/3_17_13.qasm", &["T", "CX"]);
     let arch = ilqaa::compact_layout(circ.qubits.len(), 3);
-    println!("{:?}", ilqaa::ilq_solve(&circ, &arch).cost);
+    println!("{:?}", ilqaa::ilq_solve(&circ, &arch).unwrap().cost);
 }
 
 fn mqlss_test() {
@@ -42,22 +49,2130 @@ fn mqlss_test() {
     let arch = mqlss::square_sparse_layout(circ.qubits.len());
     println!(
         "{:?}",
-        mqlss::mqlss_solve_joint_optimize_parallel(&circ, &arch).cost
+        mqlss::mqlss_solve_joint_optimize_parallel(&circ, &arch).unwrap().cost
     );
 }
 
 fn ion_test() {
     let circ = utils::extract_gates("/home/abtin/qmrsl/circuits/3_17_13.qasm", &["CX"]);
-    let arch = ion::IonArch {
-        width: 1,
-        trap_size: 2,
-    };
+    let arch = ion::IonArch::new(2, 1);
     let graph = arch.graph().0;
     println!("{:?}", Dot::with_config(&graph, &[Config::EdgeNoLabel]));
-    println!("{:?}", ion::ion_solve(&circ, &arch));
+    println!("{:?}", ion::ion_solve(&circ, &arch).unwrap());
+}
+
+fn cx(a: usize, b: usize, id: usize) -> Gate {
+    Gate {
+        operation: Operation::CX,
+        qubits: vec![Qubit::new(a), Qubit::new(b)],
+        id,
+    }
+}
+
+/// `gates` CX gates on `n` qubits, drawn from a fixed linear congruential sequence.
+fn pseudo_random_circuit(n: usize, gates: usize, seed: u64) -> Circuit {
+    let mut state = seed;
+    let mut next = || {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (state >> 33) as usize
+    };
+    let mut gs = Vec::new();
+    while gs.len() < gates {
+        let (a, b) = (next() % n, next() % n);
+        if a != b {
+            gs.push(cx(a, b, gs.len()));
+        }
+    }
+    circuit_from_gates(&gs)
+}
+
+fn all_pairs_circuit(n: usize) -> Circuit {
+    let gs: Vec<_> = (0..n)
+        .tuple_combinations()
+        .enumerate()
+        .map(|(id, (a, b))| cx(a, b, id))
+        .collect();
+    circuit_from_gates(&gs)
+}
+
+/// Checks the quality ordering of the nisq solve modes: every result verifies,
+/// `solve_joint_optimize_parallel` returns a valid result no worse than its first chain
+/// alone (by step count under the depth objective), and the same one on every run under
+/// a seed.
+fn joint_optimize_test() {
+    let circ = pseudo_random_circuit(6, 16, 5);
+    let arch = nisq::NisqArchitecture::new(utils::grid_graph(2, 3, false));
+    let mut config = SolverConfig::clone(&CONFIG.load());
+    config.seed = Some(2);
+    // start every chain from a random map rather than racing the isomorphism search
+    config.isom_search_timeout = 0;
+    config.mapping_search_cool_rate = 0.9;
+    config.parallel_searches = 1;
+    set_config(config.clone());
+    let first_chain = nisq::nisq_solve_joint_optimize_parallel(&circ, &arch, None).unwrap();
+    config.parallel_searches = 4;
+    set_config(config.clone());
+    let joint = nisq::nisq_solve_joint_optimize_parallel(&circ, &arch, None).unwrap();
+    let again = nisq::nisq_solve_joint_optimize_parallel(&circ, &arch, None).unwrap();
+    utils::verify(&joint, &circ, &arch).expect("invalid joint optimization result");
+    assert!(!joint.partial);
+    assert!(joint.cost <= first_chain.cost);
+    assert_eq!(joint.cost, again.cost);
+    assert_eq!(joint.steps[0].map, again.steps[0].map);
+
+    // under the depth objective the chains keep their shallowest result, not their cheapest
+    config.routing_objective = RoutingObjective::DepthMinimizing;
+    config.parallel_searches = 1;
+    set_config(config.clone());
+    let first_chain = nisq::nisq_solve_joint_optimize_parallel(&circ, &arch, None).unwrap();
+    config.parallel_searches = 4;
+    set_config(config);
+    let joint = nisq::nisq_solve_joint_optimize_parallel(&circ, &arch, None).unwrap();
+    assert!(joint.steps.len() <= first_chain.steps.len());
+}
+
+/// Runs `solve` on its own thread and fails if it takes longer than `limit`.
+fn within<T: Send + 'static>(limit: std::time::Duration, mode: &str, solve: impl FnOnce() -> T + Send + 'static) -> T {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || sender.send(solve()));
+    receiver.recv_timeout(limit).unwrap_or_else(|_| panic!("{} took longer than {:?}", mode, limit))
+}
+
+/// SABRE costs no more than one-pass summed over each architecture's circuits,
+/// and joint optimization costs no more than one-pass on each circuit. The seed is
+/// fixed so the comparison is reproducible, a faster `mapping_search_cool_rate`
+/// keeps the joint optimization short, and each mode gets a time limit.
+fn solve_mode_comparison_test() {
+    let mut config = SolverConfig::clone(&CONFIG.load());
+    config.seed = Some(3);
+    config.mapping_search_cool_rate = 0.9;
+    config.isom_search_timeout = 1;
+    set_config(config);
+    let limit = std::time::Duration::from_secs(60);
+    let archs = [
+        ("line", nisq::NisqArchitecture::new(utils::path_graph(7))),
+        ("grid", nisq::NisqArchitecture::new(utils::grid_graph(3, 3, false))),
+        ("heavy_hex", nisq::heavy_hex_layout(3)),
+    ];
+    let circuits = [
+        pseudo_random_circuit(6, 16, 7),
+        all_pairs_circuit(5),
+        pseudo_random_circuit(7, 20, 11),
+    ];
+    for (name, arch) in &archs {
+        let (mut onepass_total, mut sabre_total) = (0.0, 0.0);
+        for circ in &circuits {
+            let (c, a) = (circ.clone(), arch.clone());
+            let onepass = within(limit, "one-pass", move || nisq::nisq_solve(&c, &a, None).unwrap());
+            let (c, a) = (circ.clone(), arch.clone());
+            let sabre = within(limit, "SABRE", move || nisq::nisq_solve_sabre(&c, &a, None).unwrap());
+            let (c, a) = (circ.clone(), arch.clone());
+            let joint = within(limit, "joint optimization", move || {
+                nisq::nisq_solve_joint_optimize_parallel(&c, &a, None).unwrap()
+            });
+            for res in [&onepass, &sabre, &joint] {
+                utils::verify(res, circ, arch).expect("invalid nisq result");
+            }
+            assert!(
+                joint.cost <= onepass.cost,
+                "{}: joint optimization ({}) worse than one-pass ({})",
+                name,
+                joint.cost,
+                onepass.cost
+            );
+            onepass_total += onepass.cost;
+            sabre_total += sabre.cost;
+        }
+        assert!(
+            sabre_total <= onepass_total,
+            "{}: SABRE ({}) worse than one-pass ({}) in total",
+            name,
+            sabre_total,
+            onepass_total
+        );
+    }
+}
+
+/// A QASM file with only declarations parses to an empty circuit, and every
+/// nisq solve mode turns it into a single empty step at no cost.
+fn empty_circuit_test() {
+    let path = std::env::temp_dir().join("declarations_only.qasm");
+    std::fs::write(&path, "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[4];\ncreg c[4];\n")
+        .unwrap();
+    let circ = utils::extract_cnots(path.to_str().unwrap());
+    assert!(circ.gates.is_empty() && circ.qubits.is_empty());
+    assert_eq!(utils::path_graph(0).node_count(), 0);
+    assert_eq!(utils::path_graph(1).edge_count(), 0);
+    let arch = nisq::NisqArchitecture::new(utils::path_graph(4));
+    let results = [
+        nisq::nisq_solve(&circ, &arch, None).unwrap(),
+        nisq::nisq_solve_sabre(&circ, &arch, None).unwrap(),
+        nisq::nisq_solve_joint_optimize_parallel(&circ, &arch, None).unwrap(),
+    ];
+    for res in &results {
+        utils::verify(res, &circ, &arch).expect("invalid nisq result");
+        assert_eq!(res.steps.len(), 1);
+        assert!(res.transitions.is_empty());
+        assert_eq!(res.cost, 0.0);
+    }
+}
+
+/// On a fully connected device every gate is already adjacent, so one-pass
+/// routing should never take anything but identity transitions.
+fn all_to_all_test() {
+    for n in [2, 5, 8] {
+        let arch = nisq::all_to_all_layout(n);
+        for circ in [pseudo_random_circuit(n, 25, n as u64), all_pairs_circuit(n)] {
+            let res = nisq::nisq_solve(&circ, &arch, None).unwrap();
+            utils::verify(&res, &circ, &arch).expect("invalid nisq result");
+            assert_eq!(res.stats().num_nontrivial_transitions, 0, "K_{} needed swaps", n);
+        }
+    }
+}
+
+/// `extract_pbc` reads rotation angles and measurement signs, pads every axis with
+/// identities up to the circuit's qubit count, and keeps only the non-identity qubits.
+fn pbc_parser_test() {
+    use solver::structures::PauliTerm::{PauliI as I, PauliX as X, PauliY as Y, PauliZ as Z};
+    let path = std::env::temp_dir().join("parser_test.pbc");
+    let program = "Rot[1/8] X0 Z2\nM[-] Y1 X3\n// not an instruction\nM[+] Z0\nRot[-1/4] Y3 Z1 X0\n";
+    std::fs::write(&path, program).unwrap();
+    let circ = utils::extract_pbc(path.to_str().unwrap());
+    let expected = [
+        (Operation::PauliRot { axis: vec![X, I, Z, I], angle: (1, 8) }, vec![0, 2]),
+        (Operation::PauliMeasurement { sign: false, axis: vec![I, Y, I, X] }, vec![1, 3]),
+        (Operation::PauliMeasurement { sign: true, axis: vec![Z, I, I, I] }, vec![0]),
+        (Operation::PauliRot { axis: vec![X, Z, I, Y], angle: (-1, 4) }, vec![0, 1, 3]),
+    ];
+    assert_eq!(circ.gates.len(), expected.len());
+    for (id, (gate, (operation, qubits))) in circ.gates.iter().zip(expected).enumerate() {
+        assert_eq!(gate.id, id);
+        assert_eq!(gate.operation, operation);
+        assert_eq!(gate.qubits, qubits.into_iter().map(Qubit::new).collect::<Vec<_>>());
+    }
+    assert_eq!(circ.qubits.len(), 4);
+}
+
+/// Trimming the perimeter a first routing left unused gives a layout with fewer cells
+/// that still routes the same circuit.
+fn scmr_layout_tuning_test() {
+    let circ = pseudo_random_circuit(6, 12, 3);
+    let base = scmr::compact_layout(6);
+    let first = scmr::scmr_solve(&circ, &base).unwrap();
+    let tuned = scmr::trim_unused_perimeter(&scmr::compact_layout_tuned(6, 1), &first);
+    let res = scmr::scmr_solve(&circ, &tuned).unwrap();
+    utils::verify(&res, &circ, &tuned).expect("invalid scmr result on tuned layout");
+    assert!(
+        tuned.width * tuned.height < base.width * base.height,
+        "tuned layout has {} cells, compact has {}",
+        tuned.width * tuned.height,
+        base.width * base.height
+    );
+}
+
+/// `k_shortest_paths` on a 3x3 grid: blocking the centre leaves exactly the two routes
+/// around it, extra starts compete on length, and paths come out cheapest first.
+fn k_shortest_paths_test() {
+    let arch = nisq::NisqArchitecture::new(utils::grid_graph(3, 3, false));
+    let locs = |ids: &[usize]| ids.iter().map(|i| Location::new(*i)).collect::<Vec<_>>();
+    let around = utils::k_shortest_paths(&arch, locs(&[0]), locs(&[8]), locs(&[4]), 3);
+    assert_eq!(around.len(), 2);
+    assert!(around.contains(&locs(&[0, 1, 2, 5, 8])));
+    assert!(around.contains(&locs(&[0, 3, 6, 7, 8])));
+    let nearer_start = utils::k_shortest_paths(&arch, locs(&[0, 5]), locs(&[8]), locs(&[4]), 2);
+    assert_eq!(nearer_start, vec![locs(&[5, 8]), around[0].clone()]);
+    let open = utils::k_shortest_paths(&arch, locs(&[0]), locs(&[8]), vec![], 8);
+    assert_eq!(open.len(), 8);
+    assert!(open.iter().map(|p| p.len()).tuple_windows().all(|(a, b)| a <= b));
+    assert!(open[..6].iter().all(|p| p.len() == 5) && open[6].len() == 7);
+    assert!(open.iter().all_unique());
+    assert!(utils::k_shortest_paths(&arch, locs(&[0]), locs(&[8]), vec![], 0).is_empty());
+}
+
+/// `all_paths_capped` yields no more than `max_paths` routes even across a 15x15 grid, and never
+/// one longer than `max_length`.
+fn all_paths_cap_test() {
+    let arch = nisq::NisqArchitecture::new(utils::grid_graph(15, 15, false));
+    let (start, end) = (vec![Location::new(0)], vec![Location::new(224)]);
+    let capped: Vec<_> = utils::all_paths_capped(&arch, start.clone(), end.clone(), vec![], 50, Some(33)).collect();
+    assert_eq!(capped.len(), 50);
+    assert!(capped.iter().all(|p| p.len() <= 33 && p[0] == start[0] && p[p.len() - 1] == end[0]));
+    let shortest: Vec<_> = utils::all_paths_capped(&arch, start, end, vec![], 50, Some(29)).collect();
+    assert_eq!(shortest.len(), 50);
+    assert!(shortest.iter().all(|p| p.len() == 29));
+    let small = nisq::NisqArchitecture::new(utils::grid_graph(3, 3, false));
+    let (s, e) = (vec![Location::new(0)], vec![Location::new(8)]);
+    assert_eq!(utils::all_paths_capped(&small, s.clone(), e.clone(), vec![], usize::MAX, Some(5)).count(), 6);
+    assert_eq!(utils::all_paths_capped(&small, s, e, vec![], 0, None).count(), 0);
+}
+
+/// A small `raa_shuttle_radius` leaves fewer post-gate moves than an unbounded one, and
+/// every move stays within the radius of the gate site.
+fn raa_shuttle_radius_test() {
+    let arch = raa::RaaArchitecture { width: 4, height: 4 };
+    let res = raa::raa_solve(&pseudo_random_circuit(6, 12, 3), &arch).unwrap();
+    let step = res.steps.iter().find(|s| !s.implemented_gates.is_empty()).unwrap();
+    let unbounded = raa::raa_transitions_dyn_map(step, &arch, None).len();
+    let near = raa::raa_transitions_dyn_map(step, &arch, Some(1)).len();
+    let pinned = raa::raa_transitions_dyn_map(step, &arch, Some(0)).len();
+    println!("moves: unbounded {unbounded}, radius 1 {near}, radius 0 {pinned}");
+    assert!(pinned < near && near < unbounded);
+    assert_eq!(raa::raa_transitions_dyn_map(step, &arch, Some(arch.width + arch.height)).len(), unbounded);
+}
+
+/// With one gate shuttling along AOD row 2, relocating another gate's atoms out of row 1
+/// must not carry them across row 2, while staying below it is fine.
+fn raa_aod_order_test() {
+    let arch = raa::RaaArchitecture { width: 4, height: 4 };
+    let at = |i: usize| Location::new(i);
+    let map = [(0, 2), (1, 6), (2, 9), (3, 13)].into_iter().map(|(q, l)| (Qubit::new(q), at(l))).collect();
+    let implemented_gates = [(cx(0, 1, 0), 2, 6), (cx(2, 3, 1), 9, 13)]
+        .into_iter()
+        .map(|(gate, src, dst)| ImplementedGate { gate, implementation: raa::RaaGateImplementation::new(at(src), at(dst)) })
+        .collect();
+    let step = Step { map, implemented_gates };
+    let moves: Vec<String> = raa::raa_transitions_dyn_map(&step, &arch, None).iter().map(|m| m.repr()).collect();
+    let has = |m: &str| moves.iter().any(|r| r == m);
+    // (column 3, row 1) -> (column 3, row 3) crosses the row-2 shuttle; -> row 0 does not
+    assert!(!has("RELOCATE Qubit(2) Location(15)") && !has("RELOCATE_SLM Qubit(3) Location(15)"));
+    assert!(has("RELOCATE Qubit(2) Location(12)") && has("RELOCATE_SLM Qubit(3) Location(12)"));
+    // returning the AOD atom to its own site is always allowed
+    assert!(has("RELOCATE Qubit(0) Location(2)") && has("RELOCATE Qubit(2) Location(9)"));
+}
+
+/// Row 0 holds three idle atoms and another gate shuttles far away in column 3, so with
+/// `raa_line_shifts` set the whole row can move up by one site in a single transition that
+/// costs one shuttle plus handing each idle atom to the AOD. The gate's SLM atom stays put.
+fn raa_row_move_test() {
+    let arch = raa::RaaArchitecture { width: 4, height: 4 };
+    let at = |i: usize| Location::new(i);
+    let map = [(0, 0), (1, 4), (2, 8), (3, 15), (4, 14)].into_iter().map(|(q, l)| (Qubit::new(q), at(l))).collect();
+    let implemented_gates = [ImplementedGate { gate: cx(3, 4, 0), implementation: raa::RaaGateImplementation::new(at(15), at(14)) }]
+        .into_iter()
+        .collect();
+    let step = Step { map, implemented_gates };
+    let mut config = SolverConfig::clone(&CONFIG.load());
+    config.raa_line_shifts = false;
+    set_config(config.clone());
+    assert!(raa::raa_transitions_dyn_map(&step, &arch, None).iter().all(|m| m.repr().starts_with("RELOCATE")));
+    config.raa_line_shifts = true;
+    set_config(config);
+    let moves = raa::raa_transitions_dyn_map(&step, &arch, None);
+    let find = |r: &str| moves.iter().find(|m| m.repr() == r);
+    let shift = find("SHIFT_ROW 0 +1").expect("no single-transition row shift");
+    let shifted = shift.apply(&step);
+    for (q, l) in [(0, 1), (1, 5), (2, 9), (3, 15), (4, 14)] {
+        assert_eq!(shifted.map[&Qubit::new(q)], at(l));
+    }
+    let one_site = find("RELOCATE Qubit(3) Location(13)").unwrap();
+    let transfer = find("RELOCATE_SLM Qubit(4) Location(13)").unwrap().cost(&arch) - one_site.cost(&arch);
+    assert!((shift.cost(&arch) - (one_site.cost(&arch) + 3.0 * transfer)).abs() < 1e-12);
+    // row 0 can't leave the grid, column 3 is pinned by the gate's shuttle, and row 2 only
+    // holds the gate's SLM atom
+    assert!(find("SHIFT_ROW 0 -1").is_none() && find("SHIFT_COL 2 +1").is_none());
+    assert!(find("SHIFT_ROW 2 -1").is_none());
+    assert!(raa::raa_transitions_dyn_map(&step, &arch, Some(0)).iter().all(|m| m.repr().starts_with("RELOCATE")));
+}
+
+/// Lattice-surgery steps cost the arch's code distance and every transversal CX adds
+/// `transversal_cost`, so a nonzero transversal cost raises the total.
+fn ilq_cost_tuning_test() {
+    let circ = pseudo_random_circuit(6, 20, 5);
+    let arch = ilqaa::compact_layout(circ.qubits.len(), 3);
+    let tuned = ilqaa::ILQArch { code_distance: 5, transversal_cost: 0.25, ..arch.clone() };
+    let res = ilqaa::ilq_solve(&circ, &tuned).unwrap();
+    let mut transversal_total = 0;
+    for step in &res.steps {
+        let transversal = step
+            .implemented_gates
+            .iter()
+            .filter(|g| matches!(g.implementation, ilqaa::ILQGateImplementation::Transversal { .. }))
+            .count();
+        transversal_total += transversal;
+        let base = if transversal < step.implemented_gates.len() { 5.0 } else { 1.0 };
+        assert_eq!(ilqaa::ilq_step_cost(step, &tuned), base + 0.25 * transversal as f64);
+    }
+    println!("cost {} with {} transversal CX", res.cost, transversal_total);
+    assert!(transversal_total > 0);
+    let free = ilqaa::ILQArch { transversal_cost: 0.0, ..tuned.clone() };
+    let step_costs = |a: &ilqaa::ILQArch| res.steps.iter().map(|s| ilqaa::ilq_step_cost(s, a)).sum::<f64>();
+    assert!(step_costs(&tuned) > step_costs(&free));
+}
+
+/// A circuit built in memory gets sequential ids and its qubit set, and routes like a
+/// parsed one.
+fn circuit_builder_test() {
+    let q = Qubit::new;
+    let mut circ = Circuit::new();
+    assert_eq!(circ.add_cx(q(0), q(1)), 0);
+    assert_eq!(circ.add_t(q(2)), 1);
+    assert_eq!(circ.add_cx(q(1), q(3)), 2);
+    assert_eq!(circ.add_cx(q(0), q(3)), 3);
+    assert_eq!(circ.qubits, (0..4).map(q).collect());
+    assert_eq!(circ.get_front_layer().iter().map(|g| g.id).collect::<Vec<_>>(), vec![0, 1]);
+    circ.remove_gates(&vec![circ.gates[3].clone()]);
+    assert_eq!(circ.add_t(q(3)), 3);
+    circ.remove_gates(&vec![circ.gates[1].clone()]);
+    assert_eq!(circ.add_t(q(2)), 4);
+
+    let mut cnots = Circuit::new();
+    for (a, b) in [(0, 1), (1, 2), (0, 2), (2, 3)] {
+        cnots.add_cx(q(a), q(b));
+    }
+    let res = nisq::nisq_solve(&cnots, &nisq::NisqArchitecture::new(utils::path_graph(4)), None).unwrap();
+    assert_eq!(res.assert_implements_all(&cnots), Ok(()));
+}
+
+/// CX gates sharing a control and T gates on that control commute, so with commutation
+/// the whole prefix on qubit 0 is in the front layer; a gate that targets qubit 0 or
+/// follows a CX target still waits. Routed steps never put two gates on one qubit.
+fn commuting_front_layer_test() {
+    let q = Qubit::new;
+    let mut circ = Circuit::new();
+    circ.add_cx(q(0), q(1));
+    circ.add_cx(q(0), q(2));
+    circ.add_t(q(0));
+    circ.add_cx(q(0), q(3));
+    circ.add_t(q(1));
+    circ.add_cx(q(2), q(0));
+    circ.add_cx(q(0), q(4));
+    circ.add_cx(q(5), q(6));
+    let ids = |gates: Vec<Gate>| gates.iter().map(|g| g.id).collect::<Vec<_>>();
+    assert_eq!(ids(circ.front_layer(false)), vec![0, 7]);
+    assert_eq!(ids(circ.front_layer(true)), vec![0, 1, 2, 3, 7]);
+
+    let cnots = circuit_from_gates(&circ.gates.iter().filter(|g| g.qubits.len() == 2).cloned().collect::<Vec<_>>());
+    let res = nisq::nisq_solve(&cnots, &nisq::all_to_all_layout(7), None).unwrap();
+    assert_eq!(res.assert_implements_all(&cnots), Ok(()));
+    for step in &res.steps {
+        assert!(step.gates().iter().flat_map(|g| g.qubits.clone()).all_unique());
+    }
+}
+
+/// With as many qubits as locations there is no open location to move into, so the map
+/// search can only swap; every routed map must stay one qubit per location.
+fn fully_occupied_test() {
+    let circ = all_pairs_circuit(5);
+    let arch = nisq::NisqArchitecture::new(utils::path_graph(5));
+    assert_eq!(arch.locations().len(), circ.qubits.len());
+    for res in [nisq::nisq_solve(&circ, &arch, None).unwrap(), nisq::nisq_solve_sabre(&circ, &arch, None).unwrap()] {
+        assert_eq!(utils::verify(&res, &circ, &arch), Ok(()));
+        for step in &res.steps {
+            assert!(step.map.values().all_unique());
+        }
+    }
+}
+
+/// After removing a mix of locations (the last node, repeats, one not in the graph),
+/// every remaining `loc_to_node` entry still names its own node.
+fn remove_locations_test() {
+    let arch = nisq::NisqArchitecture::new(utils::grid_graph(4, 4, false));
+    let (mut graph, mut loc_to_node) = arch.graph();
+    let blocked: Vec<_> = [15, 0, 7, 7, 3, 99, 14, 5].into_iter().map(Location::new).collect();
+    utils::remove_locations(&mut graph, &mut loc_to_node, &blocked);
+    assert_eq!(graph.node_count(), 10);
+    assert_eq!(loc_to_node.len(), 10);
+    for (loc, node) in &loc_to_node {
+        assert_eq!(graph[*node], *loc);
+    }
+    for loc in &blocked {
+        assert!(!loc_to_node.contains_key(loc));
+    }
+    let rest: Vec<_> = loc_to_node.keys().copied().collect();
+    utils::remove_locations(&mut graph, &mut loc_to_node, &rest);
+    assert_eq!((graph.node_count(), loc_to_node.len()), (0, 0));
+}
+
+/// Edge weights count the two-qubit gates on each pair, in both directions, and the
+/// JSON dump lists each pair once.
+fn interaction_graph_test() {
+    let q = Qubit::new;
+    let mut circ = Circuit::new();
+    circ.add_cx(q(0), q(1));
+    circ.add_cx(q(1), q(0));
+    circ.add_t(q(2));
+    circ.add_cx(q(2), q(1));
+    circ.add_cx(q(0), q(1));
+    circ.add_t(q(3));
+    let g = utils::build_interaction_graph(&circ);
+    assert_eq!(g.node_count(), 4);
+    assert_eq!(g.edge_count(), 4);
+    for e in g.edge_indices() {
+        let (a, b) = g.edge_endpoints(e).unwrap();
+        let pair = [g[a].get_index(), g[b].get_index()];
+        let expected = if pair.contains(&2) { 1 } else { 3 };
+        assert_eq!(g[e], expected, "{:?}", pair);
+    }
+    assert_eq!(utils::to_edge_list_json(&g), "[[0,1,3],[1,2,1]]");
+}
+
+fn interaction_core_test() {
+    let q = Qubit::new;
+    let mut circ = Circuit::new();
+    // a busy triangle on 0, 1, 2 hanging off a sparse chain 3 - 4 - 5 - 6
+    for (a, b) in [(0, 1), (1, 2), (2, 0), (1, 0), (2, 1), (0, 2)] {
+        circ.add_cx(q(a), q(b));
+    }
+    circ.add_cx(q(2), q(3));
+    for (a, b) in [(3, 4), (4, 5), (5, 6)] {
+        circ.add_cx(q(a), q(b));
+    }
+    let core = utils::densest_interaction_core(&circ);
+    assert_eq!(core.iter().map(|q| q.get_index()).sorted().collect_vec(), vec![0, 1, 2]);
+
+    let mut pairs = Circuit::new();
+    pairs.add_cx(q(2), q(3));
+    pairs.add_cx(q(0), q(1));
+    let core = utils::densest_interaction_core(&pairs);
+    assert_eq!(core.iter().map(|q| q.get_index()).sorted().collect_vec(), vec![0, 1]);
+
+    let mut lone = Circuit::new();
+    lone.add_t(q(0));
+    assert!(utils::densest_interaction_core(&lone).is_empty());
+}
+
+/// When the full isomorphism search times out, `isom_core_fallback` still embeds the
+/// densest part of the circuit: here a busy 4-cycle, next to an odd 25-cycle that no grid
+/// can hold. Annealing is switched off so the start map is what comes out.
+fn core_fallback_test() {
+    let q = Qubit::new;
+    let mut circ = Circuit::new();
+    for _ in 0..3 {
+        for (a, b) in [(0, 1), (1, 2), (2, 3), (3, 0)] {
+            circ.add_cx(q(a), q(b));
+        }
+    }
+    for i in 4..28 {
+        circ.add_cx(q(i), q(i + 1));
+    }
+    circ.add_cx(q(28), q(4));
+    // the incremental search stops short of the last gate
+    circ.add_cx(q(0), q(1));
+    let arch = nisq::NisqArchitecture::new(utils::grid_graph(6, 6, false));
+    let (graph, loc_to_node) = arch.graph();
+    let core_embedded = |map: &QubitMap| {
+        [(0, 1), (1, 2), (2, 3), (3, 0)].iter().all(|(a, b)| {
+            graph.contains_edge(loc_to_node[&map[&q(*a)]], loc_to_node[&map[&q(*b)]])
+        })
+    };
+    let mut config = SolverConfig::clone(&CONFIG.load());
+    config.seed = Some(0);
+    config.isom_search_timeout = 2;
+    config.mapping_search_initial_temp = config.mapping_search_term_temp;
+    config.parallel_searches = 1;
+    config.bfs_anneal_start = false;
+    config.isom_core_fallback = true;
+    set_config(config.clone());
+    let start = std::time::Instant::now();
+    let (map, _) = nisq::nisq_plan_mapping(&circ, &arch);
+    assert!(core_embedded(&map));
+    // the core search only gets what the full search left of the budget
+    assert!(start.elapsed() < std::time::Duration::from_secs(3));
+    config.isom_core_fallback = false;
+    set_config(config);
+    let (map, _) = nisq::nisq_plan_mapping(&circ, &arch);
+    assert!(!core_embedded(&map));
+}
+
+fn routing_equivalence_test() {
+    use nisq::SwapDecomposition;
+    let q = Qubit::new;
+    let mut circ = Circuit::new();
+    circ.add_cx(q(0), q(1));
+    circ.add_cx(q(0), q(2));
+    circ.add_cx(q(1), q(2));
+    circ.add_cx(q(2), q(0));
+    let arch = nisq::NisqArchitecture::new(utils::path_graph(3));
+    let res = nisq::nisq_solve(&circ, &arch, None).unwrap();
+    assert!(nisq::check_routing_equivalence(&circ, &res));
+    let swap = res
+        .steps
+        .windows(2)
+        .position(|w| w[0].map != w[1].map)
+        .expect("a triangle on a path needs a swap");
+
+    // dropping a swap leaves later gates acting on the wrong locations
+    let (before, after) = (&res.steps[swap].map, &res.steps[swap + 1].map);
+    let (a, b) = before.iter().find(|(q, l)| after[q] != **l).map(|(q, l)| (*l, after[q])).unwrap();
+    let mut dropped = res.clone();
+    for step in &mut dropped.steps[swap + 1..] {
+        for l in step.map.values_mut() {
+            if *l == a {
+                *l = b;
+            } else if *l == b {
+                *l = a;
+            }
+        }
+    }
+    assert!(!nisq::check_routing_equivalence(&circ, &dropped));
+
+    // nor can a map change by more than one swap, here a rotation of the whole path
+    let mut rotated = res.clone();
+    rotated.steps[1].map = res.steps[0].map.iter().map(|(q, l)| (*q, Location::new((l.get_index() + 1) % 3))).collect();
+    assert!(!nisq::check_routing_equivalence(&circ, &rotated));
+    assert_eq!(rotated.swap_decomposition(&arch), Err(0));
+    let mut res = res;
+
+    // the same steps don't implement a circuit whose gates come in another order
+    let mut reordered = Circuit::new();
+    for gate in circ.gates.iter().rev() {
+        reordered.add_gate(gate.operation.clone(), gate.qubits.clone());
+    }
+    let ids: HashMap<usize, usize> = circ
+        .gates
+        .iter()
+        .map(|g| g.id)
+        .zip(reordered.gates.iter().rev().map(|g| g.id))
+        .collect();
+    for step in &mut res.steps {
+        step.implemented_gates = step
+            .implemented_gates
+            .drain()
+            .map(|mut g| {
+                g.gate.id = ids[&g.gate.id];
+                g
+            })
+            .collect();
+    }
+    assert!(!nisq::check_routing_equivalence(&reordered, &res));
+}
+
+fn identity_init_test() {
+    let q = Qubit::new;
+    let mut circ = Circuit::new();
+    circ.add_cx(q(0), q(3));
+    circ.add_cx(q(2), q(1));
+    let arch = nisq::NisqArchitecture::new(utils::path_graph(5));
+    let init = InitialMapStrategy::from_name("identity").unwrap();
+    let res = nisq::nisq_solve_with_init(&circ, &arch, &init, None).unwrap();
+    let natural: HashMap<Qubit, Location> = (0..4).map(|i| (q(i), Location::new(i))).collect();
+    assert_eq!(res.steps[0].map, natural);
+    assert!(InitialMapStrategy::from_name("sabre").is_none());
+
+    // qubit 1 must start at the far end, so it leaves its natural place; the rest keep theirs
+    let regions = HashMap::from([(q(1), HashSet::from([Location::new(4)]))]);
+    let res = nisq::nisq_solve_in_regions(&circ, &arch, &init, &regions, None).unwrap();
+    assert_eq!(res.steps[0].map[&q(1)], Location::new(4));
+    for i in [0, 2, 3] {
+        assert_eq!(res.steps[0].map[&q(i)], Location::new(i));
+    }
+}
+
+fn provided_map_test() {
+    let q = Qubit::new;
+    let mut circ = Circuit::new();
+    circ.add_cx(q(0), q(1));
+    circ.add_cx(q(2), q(1));
+    circ.add_cx(q(2), q(3));
+    circ.add_cx(q(1), q(0));
+    let arch = nisq::NisqArchitecture::new(utils::path_graph(4));
+    // a chain on a path: every CX is already on an edge
+    let optimal: HashMap<Qubit, Location> = (0..4).map(|i| (q(i), Location::new(i))).collect();
+    let res = nisq::nisq_solve_with_map(&circ, &arch, &optimal).unwrap();
+    assert_eq!(res.steps[0].map, optimal);
+    assert_eq!(res.stats().num_nontrivial_transitions, 0);
+    let res = nisq::nisq_solve_sabre_with_map(&circ, &arch, &optimal).unwrap();
+    assert_eq!(res.stats().num_nontrivial_transitions, 0);
+
+    let mut off_device = optimal.clone();
+    off_device.insert(q(3), Location::new(9));
+    let err = nisq::nisq_solve_with_map(&circ, &arch, &off_device).unwrap_err();
+    assert_eq!(err.unknown_locations, vec![(q(3), Location::new(9))]);
+    assert!(err.unmapped.is_empty() && err.over_capacity.is_empty());
+
+    let mut stacked = optimal.clone();
+    stacked.remove(&q(3));
+    stacked.insert(q(2), Location::new(1));
+    let err = nisq::nisq_solve_sabre_with_map(&circ, &arch, &stacked).unwrap_err();
+    assert_eq!(err.unmapped, vec![q(3)]);
+    assert_eq!(err.over_capacity, vec![Location::new(1)]);
+}
+
+/// Each step lasts as long as its longest shuttle, and the makespan adds up the steps.
+fn raa_makespan_test() {
+    let arch = raa::RaaArchitecture { width: 3, height: 3 };
+    let at = |i: usize| Location::new(i);
+    let step = |moves: Vec<(Gate, usize, usize)>| Step {
+        map: HashMap::new(),
+        implemented_gates: moves
+            .into_iter()
+            .map(|(gate, src, dst)| ImplementedGate { gate, implementation: raa::RaaGateImplementation::new(at(src), at(dst)) })
+            .collect(),
+    };
+    // one spacing and two spacings side by side, then a diagonal across the whole grid
+    let steps = vec![
+        step(vec![(cx(0, 1, 0), 0, 1), (cx(2, 3, 1), 3, 5)]),
+        step(vec![(cx(0, 3, 2), 0, 8)]),
+        step(vec![]),
+    ];
+    let move_time = |dist: f64| f64::sqrt(2.5 * 6.0e-6 * dist / 2750.0);
+    let expected = move_time(2.0) + move_time(f64::sqrt(8.0));
+    assert!((raa::raa_makespan(&steps, &arch) - expected).abs() < 1e-12);
+
+    // after the gate both atoms sit on site 1; the AOD atom relocates to 2 and the SLM
+    // atom, handed to the AOD and back, relocates to 4, both one spacing away
+    let q = Qubit::new;
+    let mut gate_step = step(vec![(cx(0, 1, 0), 0, 1)]);
+    gate_step.map = HashMap::from([(q(0), at(0)), (q(1), at(1))]);
+    let mut aod_moved = step(vec![]);
+    aod_moved.map = HashMap::from([(q(0), at(2)), (q(1), at(1))]);
+    let aod_relocation = vec![gate_step.clone(), aod_moved];
+    let expected = move_time(1.0) + move_time(1.0);
+    assert!((raa::raa_makespan(&aod_relocation, &arch) - expected).abs() < 1e-12);
+    let mut both_moved = step(vec![]);
+    both_moved.map = HashMap::from([(q(0), at(2)), (q(1), at(4))]);
+    let slm_relocation = vec![gate_step, both_moved];
+    let expected = move_time(1.0) + move_time(1.0) + 2.0 * 15.0e-6;
+    assert!((raa::raa_makespan(&slm_relocation, &arch) - expected).abs() < 1e-12);
+
+    let circ = pseudo_random_circuit(6, 12, 3);
+    let res = raa::raa_solve(&circ, &arch).unwrap();
+    assert_eq!(res.makespan, Some(raa::raa_makespan(&res.steps, &arch)));
+}
+
+fn plan_mapping_test() {
+    let q = Qubit::new;
+    let mut circ = Circuit::new();
+    circ.add_cx(q(0), q(1));
+    circ.add_cx(q(1), q(2));
+    circ.add_cx(q(2), q(0));
+    circ.add_cx(q(3), q(1));
+    let arch = nisq::NisqArchitecture::new(utils::path_graph(5));
+    let (map, cost) = nisq::nisq_plan_mapping(&circ, &arch);
+    assert_eq!(map.len(), 4);
+    assert_eq!(map.values().unique().count(), 4);
+    // a triangle can't sit on a path, so the plan has to pay for at least one CX
+    assert!(cost > 0.0);
+    // with a seed, planning draws the same map the full solve routes from
+    if CONFIG.load().seed.is_some() {
+        assert_eq!(nisq::nisq_solve(&circ, &arch, None).unwrap().steps[0].map, map);
+    }
+}
+
+/// Every routed gate gets a footprint, and for SCMR it is the path the gate was routed along.
+fn gate_footprints_test() {
+    let circ = pseudo_random_circuit(6, 12, 3);
+    let arch = scmr::compact_layout(6);
+    let res = scmr::scmr_solve(&circ, &arch).unwrap();
+    let footprints = res.gate_footprints();
+    assert_eq!(footprints.keys().sorted().copied().collect::<Vec<_>>(), (0..circ.gates.len()).collect::<Vec<_>>());
+    for step in &res.steps {
+        for g in &step.implemented_gates {
+            assert_eq!(footprints[&g.gate.id], g.implementation.path().unwrap_or_default());
+        }
+    }
+}
+
+/// A cap below the edge count still yields a valid routing, and one above it routes
+/// exactly as the uncapped search.
+fn candidate_cap_test() {
+    let circ = pseudo_random_circuit(9, 30, 5);
+    let graph = utils::grid_graph(3, 3, true);
+    let edges = graph.edge_count();
+    let arch = nisq::NisqArchitecture::new(graph);
+    let mut config = SolverConfig::clone(&CONFIG.load());
+    config.seed = Some(5);
+    let solve_with_cap = |config: &mut solver::config::SolverConfig, cap: Option<usize>| {
+        config.max_candidate_transitions = cap;
+        set_config(config.clone());
+        nisq::nisq_solve_with_init(&circ, &arch, &InitialMapStrategy::Identity, None).unwrap()
+    };
+    let uncapped = solve_with_cap(&mut config, None);
+    let above = solve_with_cap(&mut config, Some(edges + 1));
+    assert_eq!(serde_json::to_string(&above).unwrap(), serde_json::to_string(&uncapped).unwrap());
+    let capped = solve_with_cap(&mut config, Some(2));
+    utils::verify(&capped, &circ, &arch).expect("invalid routing with capped transitions");
+}
+
+/// A Toffoli on a path with one triangle at its far end has to be routed into the triangle,
+/// and `ccx` lines parse as one three-qubit gate rather than a CX on their controls.
+fn toffoli_test() {
+    let path = std::env::temp_dir().join("toffoli.qasm");
+    let program = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[5];\nccx q[0],q[1],q[2];\ncx q[3],q[4];\n";
+    std::fs::write(&path, program).unwrap();
+    let circ = utils::extract_nisq_gates(path.to_str().unwrap());
+    assert_eq!(circ.gates.len(), 2);
+    assert_eq!(circ.gates[0].operation, Operation::CCX);
+    assert_eq!(circ.gates[0].qubits, vec![Qubit::new(0), Qubit::new(1), Qubit::new(2)]);
+    assert_eq!(utils::extract_cnots(path.to_str().unwrap()).gates.len(), 1);
+
+    let mut graph = utils::path_graph(5);
+    graph.add_edge(2.into(), 4.into(), ());
+    graph.add_edge(4.into(), 2.into(), ());
+    let triangle = [Location::new(2), Location::new(3), Location::new(4)];
+    let arch = nisq::NisqArchitecture::new(graph);
+    let res = nisq::nisq_solve_with_init(&circ, &arch, &InitialMapStrategy::Identity, None).unwrap();
+    utils::verify(&res, &circ, &arch).expect("invalid routing of a Toffoli");
+    assert!(nisq::check_routing_equivalence(&circ, &res));
+    let ccx = res.steps.iter().flat_map(|s| &s.implemented_gates).find(|g| g.gate.id == 0).unwrap();
+    assert!(ccx.implementation.occupied_locations().iter().all(|l| triangle.contains(l)));
+    assert_eq!(ccx.implementation.occupied_locations().len(), 3);
+
+    // without a triangle no placement can host the Toffoli, so preflight rejects it
+    let path = nisq::NisqArchitecture::new(utils::path_graph(5));
+    assert!(!path.supported_gate_types().contains(&GateType::CCX));
+    let errors = nisq::nisq_solve(&circ, &path, None).unwrap_err();
+    assert!(errors.contains(&PreflightError::UnsupportedOperation { gate_id: 0, gate_type: GateType::CCX }));
+}
+
+/// A chain of CX gates on a line architecture is laid out along the line, so every gate
+/// is already adjacent before routing.
+fn bfs_initial_map_test() {
+    let q = Qubit::new;
+    let mut chain = Circuit::new();
+    for i in 0..5 {
+        chain.add_cx(q(i), q(i + 1));
+    }
+    let arch = nisq::NisqArchitecture::new(utils::path_graph(6));
+    let map = backend::bfs_initial_map(&chain, &arch);
+    assert_eq!(map.len(), 6);
+    assert_eq!(map.values().unique().count(), 6);
+    for i in 0..5 {
+        assert_eq!(map[&q(i)].get_index().abs_diff(map[&q(i + 1)].get_index()), 1);
+    }
+    let res = nisq::nisq_solve_with_map(&chain, &arch, &map).unwrap();
+    assert_eq!(res.stats().num_nontrivial_transitions, 0);
+}
+
+/// Locations no edge mentions stay in the graph after the ones that do, which keep the
+/// order the edges list them in, but the router never places qubits on them. Malformed
+/// graphs are reported rather than panicking.
+fn isolated_locations_test() {
+    let graph = serde_json::json!([[2, 3], [0, 1], [1, 2]]);
+    let wrapped = utils::graph_from_json_entry(graph, Some(6)).unwrap();
+    assert_eq!(wrapped.node_count(), 6);
+    assert_eq!(wrapped.edge_count(), 6);
+    let order: Vec<usize> = wrapped.node_weights().map(|l| l.get_index()).collect();
+    assert_eq!(order, vec![2, 3, 0, 1, 4, 5]);
+    let gap = utils::graph_from_json_entry(serde_json::json!([[0, 1], [1, 3]]), None).unwrap();
+    assert_eq!(gap.node_count(), 4);
+    assert_eq!(gap.node_weights().last(), Some(&Location::new(2)));
+    assert_eq!(gap.neighbors(3.into()).count(), 0);
+    assert!(utils::graph_from_json_entry(serde_json::json!([[0, 1], [1, 3]]), Some(3)).is_err());
+    assert!(utils::graph_from_json_entry(serde_json::json!([[0, "a"]]), None).is_err());
+    assert!(utils::graph_from_json_entry(serde_json::json!({"x": [1]}), None).is_err());
+
+    // the same file layout run-nisq reads
+    let path = std::env::temp_dir().join("isolated_arch.json");
+    for key in ["num_nodes", "qubit_count"] {
+        std::fs::write(&path, format!(r#"{{"graph": [[2, 3], [0, 1], [1, 2]], "{}": 6}}"#, key)).unwrap();
+        let from_file = utils::graph_from_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(from_file.node_weights().map(|l| l.get_index()).collect::<Vec<_>>(), order);
+    }
+
+    let arch = nisq::NisqArchitecture::new(wrapped);
+    assert_eq!(arch.locations().into_iter().sorted_by_key(|l| l.get_index()).collect::<Vec<_>>(), (0..4).map(Location::new).collect::<Vec<_>>());
+    let mut chain = Circuit::new();
+    for i in 0..3 {
+        chain.add_cx(Qubit::new(i), Qubit::new(i + 1));
+    }
+    let res = nisq::nisq_solve(&chain, &arch, None).unwrap();
+    utils::verify(&res, &chain, &arch).expect("invalid routing with isolated locations");
+}
+
+/// A T gate left in a circuit handed to the NISQ or RAA router is reported by id before
+/// any routing, instead of being silently dropped or panicking mid-route.
+fn unsupported_gates_test() {
+    let q = Qubit::new;
+    let mut circ = Circuit::new();
+    circ.add_cx(q(0), q(1));
+    circ.add_t(q(1));
+    circ.add_cx(q(1), q(2));
+    let expected = vec![PreflightError::UnsupportedOperation { gate_id: 1, gate_type: GateType::T }];
+    let nisq_arch = nisq::NisqArchitecture::new(utils::path_graph(3));
+    assert_eq!(nisq::nisq_solve(&circ, &nisq_arch, None).unwrap_err(), expected);
+    assert_eq!(nisq::nisq_solve_sabre(&circ, &nisq_arch, None).unwrap_err(), expected);
+    let raa_arch = raa::RaaArchitecture { width: 2, height: 2 };
+    assert_eq!(raa::raa_solve(&circ, &raa_arch).unwrap_err(), expected);
+
+    circ.remove_gates(&vec![circ.gates[1].clone()]);
+    assert!(nisq::nisq_solve(&circ, &nisq_arch, None).is_ok());
+}
+
+/// A solve given a progress callback reports every routing step in order, ending with no
+/// gates left; a solve without one reports nothing.
+fn route_progress_test() {
+    let seen = std::sync::Mutex::new(Vec::new());
+    let sink = |p| seen.lock().unwrap().push(p);
+    let circ = pseudo_random_circuit(6, 12, 3);
+    let arch = nisq::NisqArchitecture::new(utils::path_graph(6));
+    let res = nisq::nisq_solve_with_init(&circ, &arch, &InitialMapStrategy::Identity, Some(&sink)).unwrap();
+    let reports = seen.lock().unwrap().clone();
+    assert_eq!(reports.len(), res.steps.len() - 1);
+    assert!(reports.windows(2).all(|w: &[backend::RouteProgress]| w[0].steps_taken + 1 == w[1].steps_taken));
+    let last = reports.last().unwrap();
+    assert_eq!((last.gates_remaining, last.running_cost), (0, res.cost));
+
+    nisq::nisq_solve_with_init(&circ, &arch, &InitialMapStrategy::Identity, None).unwrap();
+    assert_eq!(seen.lock().unwrap().len(), reports.len());
+}
+
+/// Hash iteration order differs between maps and between runs, so a seeded solve
+/// repeated in one process only serializes the same when nothing order-sensitive reads
+/// a `HashSet` or `HashMap` unsorted. Run with `seed` set in config.json.
+fn deterministic_solve_test() {
+    assert!(CONFIG.load().seed.is_some(), "set seed in config.json");
+    let circ = pseudo_random_circuit(8, 30, 11);
+    let scmr_arch = scmr::compact_layout(circ.qubits.len());
+    let raa_arch = raa::RaaArchitecture { width: 4, height: 4 };
+    let scmr_json = || serde_json::to_string(&scmr::scmr_solve(&circ, &scmr_arch).unwrap()).unwrap();
+    let raa_json = || serde_json::to_string(&raa::raa_solve(&circ, &raa_arch).unwrap()).unwrap();
+    assert_eq!(scmr_json(), scmr_json());
+    assert_eq!(raa_json(), raa_json());
+}
+
+/// A T gate drawing its state from the factory at the given location.
+#[derive(Clone, Debug, serde::Serialize, Hash, PartialEq, Eq)]
+struct FactoryUse(Location);
+
+impl GateImplementation for FactoryUse {
+    fn occupied_locations(&self) -> Vec<Location> {
+        vec![self.0]
+    }
+}
+
+/// Two T gates compete for factory 0, which the more critical one tries first though it
+/// could also use factory 1. Packing it first leaves the other gate without a factory;
+/// exploring orders implements both.
+fn max_step_all_orders_test() {
+    let t = |q: usize, id: usize| Gate { operation: Operation::T, qubits: vec![Qubit::new(q)], id };
+    let executable = vec![t(1, 1), t(0, 0)];
+    let crit_table = [2, 1];
+    let arch = nisq::NisqArchitecture::new(utils::path_graph(2));
+    let factories = |gate: &Gate| if gate.id == 0 { vec![0, 1] } else { vec![0] };
+    let implement_gate = |step: &Step<FactoryUse>, _: &nisq::NisqArchitecture, gate: &Gate| {
+        let taken: Vec<Location> = step.implemented_gates.iter().map(|g| g.implementation.0).collect();
+        factories(gate)
+            .into_iter()
+            .map(|l| FactoryUse(Location::new(l)))
+            .filter(|f| !taken.contains(&f.0))
+            .collect::<Vec<_>>()
+    };
+    let empty = || Step { map: HashMap::new(), implemented_gates: Default::default() };
+
+    let mut greedy = empty();
+    greedy.max_step(&vec![t(0, 0), t(1, 1)], &arch, &implement_gate);
+    assert_eq!(greedy.gates().len(), 1);
+
+    let mut explored = empty();
+    let mut rng = utils::search_rng(0);
+    explored.max_step_all_orders(&executable, &arch, implement_gate, &crit_table, 10.0, 0.1, 0.9, &mut rng);
+    let used: HashMap<usize, Location> =
+        explored.implemented_gates.iter().map(|g| (g.gate.id, g.implementation.0)).collect();
+    assert_eq!(used, HashMap::from([(0, Location::new(1)), (1, Location::new(0))]));
+}
+
+/// Solves on the left half of a device cut down with `restrict`: every placement and
+/// every routed cell stays in that half.
+fn restrict_test() {
+    let touched = |timeline: Vec<Vec<(usize, usize)>>, footprints: HashMap<usize, Vec<Location>>| {
+        let placed = timeline.into_iter().flatten().map(|(_, l)| Location::new(l));
+        placed.chain(footprints.into_values().flatten()).collect::<Vec<_>>()
+    };
+    let circ = pseudo_random_circuit(4, 16, 9);
+
+    // columns 0 and 1 of a 4x4 grid
+    let allowed: HashSet<Location> = (0..16).filter(|i| i % 4 < 2).map(Location::new).collect();
+    let nisq_arch = nisq::NisqArchitecture::new(utils::grid_graph(4, 4, false)).restrict(&allowed);
+    let res = nisq::nisq_solve(&circ, &nisq_arch, None).unwrap();
+    assert!(touched(res.placement_timeline(), res.gate_footprints()).iter().all(|l| allowed.contains(l)));
+
+    // columns 0 to 4 of a 9-wide lattice, holding four qubit slots and their factories
+    let full = scmr::compact_layout(8);
+    let allowed: HashSet<Location> =
+        (0..full.width * full.height).filter(|i| i % full.width < 5).map(Location::new).collect();
+    let scmr_arch = full.restrict(&allowed);
+    assert_eq!(scmr_arch.alg_qubits.len(), 4);
+    let res = scmr::scmr_solve(&circ, &scmr_arch).unwrap();
+    assert!(touched(res.placement_timeline(), res.gate_footprints()).iter().all(|l| allowed.contains(l)));
+}
+
+/// Swap counts between maps on four qubits: a 2-cycle takes one swap, a 3-cycle two,
+/// and a chain ending at a free location one per qubit moved.
+fn map_distance_test() {
+    let map = |locs: [usize; 4]| -> QubitMap {
+        locs.iter().enumerate().map(|(q, l)| (Qubit::new(q), Location::new(*l))).collect()
+    };
+    let start = map([0, 1, 2, 3]);
+    assert_eq!(utils::map_distance(&start, &start), 0);
+    assert_eq!(utils::map_distance(&start, &map([1, 0, 2, 3])), 1);
+    assert_eq!(utils::map_distance(&start, &map([1, 2, 0, 3])), 2);
+    assert_eq!(utils::map_distance(&start, &map([1, 2, 0, 4])), 3);
+    assert_eq!(utils::map_distance(&start, &map([0, 1, 3, 5])), 2);
+}
+
+/// A circuit reads the same from a `.qasm` file and from a gzipped copy of it.
+fn gzip_qasm_test() {
+    use std::io::Write;
+    let qasm = "OPENQASM 2.0;\nqreg q[3];\ncx q[0], q[1];\nt q[2];\nccx q[0], q[1], q[2];\ntdg q[1];\n";
+    let dir = std::env::temp_dir();
+    let plain = dir.join("gzip_qasm_test.qasm");
+    let gzipped = dir.join("gzip_qasm_test.qasm.gz");
+    std::fs::write(&plain, qasm).unwrap();
+    let mut encoder = flate2::write::GzEncoder::new(std::fs::File::create(&gzipped).unwrap(), flate2::Compression::default());
+    encoder.write_all(qasm.as_bytes()).unwrap();
+    encoder.finish().unwrap();
+    let (plain, gzipped) = (plain.to_str().unwrap(), gzipped.to_str().unwrap());
+    for extract in [utils::extract_cnots, utils::extract_nisq_gates, utils::extract_scmr_gates] {
+        let (a, b) = (extract(plain), extract(gzipped));
+        assert!(!a.gates.is_empty());
+        assert_eq!((a.gates, a.qubits), (b.gates, b.qubits));
+    }
+}
+
+/// Device sizes and degrees, and a circuit with more qubits than the device has
+/// locations refused before any map is drawn.
+fn oversized_circuit_test() {
+    let grid = nisq::NisqArchitecture::new(utils::grid_graph(3, 3, false));
+    assert_eq!((grid.num_locations(), grid.max_degree()), (9, 4));
+    let line = nisq::NisqArchitecture::new(utils::path_graph(4));
+    assert_eq!((line.num_locations(), line.max_degree()), (4, 2));
+    assert_eq!(nisq::heavy_hex_layout(3).max_degree(), 3);
+
+    let circ = pseudo_random_circuit(6, 10, 2);
+    let too_few = PreflightError::TooFewLocations { qubits: 6, locations: 4 };
+    assert_eq!(nisq::nisq_solve(&circ, &line, None).unwrap_err(), vec![too_few.clone()]);
+    assert_eq!(nisq::nisq_solve_sabre(&circ, &line, None).unwrap_err(), vec![too_few]);
+    assert!(nisq::nisq_solve(&circ, &grid, None).is_ok());
+}
+
+/// Each cost normalization on all-zero costs, which score 0 rather than NaN, and on a
+/// single nonzero cost, which `DropZeros` passes through at full weight.
+fn cost_norm_test() {
+    let weights = [1.0, 2.0, 0.5, 1.0];
+    let all_zero: Vec<(f64, f64)> = weights.iter().map(|w| (*w, 0.0)).collect();
+    let single: Vec<(f64, f64)> = weights.iter().zip([0.0, 3.0, 0.0, 0.0]).map(|(w, v)| (*w, v)).collect();
+    for mode in [CostNorm::DropZeros, CostNorm::Softmax, CostNorm::Fixed] {
+        assert_eq!(utils::normalize_cost(all_zero.clone(), mode), 0.0);
+    }
+    assert_eq!(utils::normalize_cost(single.clone(), CostNorm::DropZeros), 3.0);
+    assert_eq!(utils::normalize_cost(single.clone(), CostNorm::Fixed), 6.0);
+    let softmax_weight = 2.0f64.exp() / weights.iter().map(|w| w.exp()).sum::<f64>();
+    assert!((utils::normalize_cost(single, CostNorm::Softmax) - 3.0 * softmax_weight).abs() < 1e-12);
+}
+
+/// Appending a gate keeps every step and transition of the previous result; editing a gate
+/// in the middle, even under its old id, keeps at most the steps before it. Both results
+/// still verify.
+fn incremental_resolve_test() {
+    let arch = nisq::NisqArchitecture::new(utils::grid_graph(3, 3, false));
+    let circ = pseudo_random_circuit(6, 20, 4);
+    let prev = nisq::nisq_solve(&circ, &arch, None).unwrap();
+    let step_json = |s: &Step<nisq::NisqGateImplementation>| serde_json::to_string(s).unwrap();
+
+    let mut gates = circ.gates.clone();
+    gates.push(cx(0, 5, gates.len()));
+    let appended = circuit_from_gates(&gates);
+    let res = nisq::nisq_resolve_incremental(&prev, &appended, &arch).unwrap();
+    utils::verify(&res, &appended, &arch).expect("invalid incremental result");
+    assert!(res.steps.len() >= prev.steps.len());
+    for (old, new) in prev.steps.iter().zip(&res.steps) {
+        assert_eq!(step_json(old), step_json(new));
+    }
+    assert_eq!(res.transitions[..prev.transitions.len()], prev.transitions[..]);
+    assert!(res.cost >= prev.cost - 1e-9);
+
+    let mut gates = circ.gates.clone();
+    gates[10] = cx(gates[10].qubits[1].get_index(), gates[10].qubits[0].get_index(), 10);
+    let edited = circuit_from_gates(&gates);
+    let res = nisq::nisq_resolve_incremental(&prev, &edited, &arch).unwrap();
+    utils::verify(&res, &edited, &arch).expect("invalid incremental result");
+    assert_eq!(step_json(&prev.steps[0]), step_json(&res.steps[0]));
+    // the edited gate kept its id, but not the step that routed it the old way
+    let routed = res.steps.iter().flat_map(|s| &s.implemented_gates).find(|g| g.gate.id == 10).unwrap();
+    assert_eq!(routed.gate.qubits, gates[10].qubits);
+}
+
+/// On sparse layouts the `_within` neighbor variants only hand out cells in the routing
+/// graph: a cell cut out of the lattice is dropped, and so are factories next to factories.
+fn sparse_neighbors_test() {
+    let mut scmr_arch = scmr::square_sparse_layout(4);
+    let (w, h) = (scmr_arch.width, scmr_arch.height);
+    let q0 = scmr_arch.alg_qubits[0];
+    let below = Location::new(q0.get_index() + w);
+    scmr_arch.set_excluded([scmr_arch.excluded(), &[below]].concat());
+    let valid = scmr_arch.routing_cells();
+    // worked out once per layout
+    assert!(std::ptr::eq(valid, scmr_arch.routing_cells()));
+    let graph_cells: HashSet<Location> = scmr_arch.graph().1.into_keys().collect();
+    assert!(valid.is_subset(&graph_cells));
+    assert_eq!(utils::vertical_neighbors(q0, w, h).len(), 2);
+    assert_eq!(utils::vertical_neighbors_within(q0, w, h, valid), vec![Location::new(q0.get_index() - w)]);
+    for m in scmr_arch.factory_locations() {
+        assert!(utils::horizontal_neighbors_within(m, w, valid).iter().all(|n| graph_cells.contains(n) && *n != m));
+        assert!(!valid.contains(&m));
+    }
+    let circ = pseudo_random_circuit(4, 12, 3);
+    let res = scmr::scmr_solve(&circ, &scmr_arch).unwrap();
+    assert!(res.gate_footprints().values().flatten().all(|l| *l != below));
+
+    let mut mqlss_arch = mqlss::square_sparse_layout(4);
+    let (w, h) = (mqlss_arch.width, mqlss_arch.height);
+    let q0 = mqlss_arch.alg_qubits[0];
+    mqlss_arch.set_excluded([mqlss_arch.excluded(), &[Location::new(q0.get_index() - 1)]].concat());
+    let valid = mqlss_arch.routing_cells();
+    assert_eq!(utils::horizontal_neighbors_within(q0, w, valid), vec![Location::new(q0.get_index() + 1)]);
+    assert_eq!(utils::vertical_neighbors_within(q0, w, h, valid).len(), 2);
+    for m in mqlss_arch.magic_state_qubits() {
+        assert!(utils::horizontal_neighbors_within(*m, w, valid).iter().all(|n| !mqlss_arch.magic_state_qubits().contains(n)));
+    }
+}
+
+/// Every SWAP a NISQ result inserts comes out as three CX gates that really swap its two
+/// locations; on a one-way chain the outer two follow the coupling and the middle one is
+/// marked reversed.
+fn swap_decomposition_test() {
+    use nisq::SwapDecomposition;
+    // (control, target, reversed) of each CX, read back from its serialized form
+    let cxs = |gates: &Vec<nisq::NisqGateImplementation>| -> Vec<(usize, usize, bool)> {
+        gates
+            .iter()
+            .map(|g| {
+                let v = serde_json::to_value(g).unwrap();
+                let edge = &v["edge"];
+                (edge[0].as_u64().unwrap() as usize, edge[1].as_u64().unwrap() as usize, v["reversed"].as_bool().unwrap())
+            })
+            .collect()
+    };
+    let mut chain = petgraph::Graph::new();
+    let nodes: Vec<_> = (0..4).map(|i| chain.add_node(Location::new(i))).collect();
+    for i in 1..4 {
+        chain.add_edge(nodes[i - 1], nodes[i], ());
+    }
+    let cases = [
+        (nisq::NisqArchitecture::new(utils::grid_graph(3, 3, false)), pseudo_random_circuit(6, 20, 4)),
+        (nisq::NisqArchitecture::new_directed(chain), pseudo_random_circuit(4, 12, 5)),
+    ];
+    for (directed, (arch, circ)) in cases.iter().enumerate() {
+        let res = nisq::nisq_solve(circ, arch, None).unwrap();
+        let swaps = res.steps.windows(2).filter(|w| w[0].map != w[1].map).count();
+        assert!(swaps > 0);
+        let decomposition = res.swap_decomposition(arch).unwrap();
+        assert_eq!(decomposition.len(), res.transitions.len());
+        assert_eq!(decomposition.iter().map(|d| d.len()).sum::<usize>(), 3 * swaps);
+        for (i, gates) in decomposition.iter().enumerate() {
+            if gates.is_empty() {
+                assert_eq!(res.steps[i].map, res.steps[i + 1].map);
+                continue;
+            }
+            let gates = cxs(gates);
+            let (a, b) = (gates[0].0, gates[0].1);
+            let mut values: HashMap<usize, usize> = [(a, 0b01), (b, 0b10)].into_iter().collect();
+            for (c, t, _) in &gates {
+                values.insert(*t, values[t] ^ values[c]);
+            }
+            assert_eq!((values[&a], values[&b]), (0b10, 0b01));
+            if directed == 1 {
+                assert!(gates.iter().all(|(c, t, reversed)| *reversed == (c > t)));
+                assert_eq!(gates.iter().filter(|g| g.2).count(), 1);
+            } else {
+                assert!(gates.iter().all(|g| !g.2));
+            }
+        }
+    }
+}
+
+/// On these circuits greedy cost-minimizing routing takes more steps than routing for depth,
+/// which places the most gates it can at every step; both results still verify. The grid
+/// has spare locations: on a full one both objectives tend to find the same moves.
+fn depth_objective_test() {
+    let mut config = SolverConfig::clone(&CONFIG.load());
+    config.seed = Some(1);
+    set_config(config);
+    let arch = nisq::NisqArchitecture::new(utils::grid_graph(3, 4, false));
+    let steps = |objective| -> usize {
+        let mut total = 0;
+        for seed in 0..8 {
+            let circ = pseudo_random_circuit(9, 40, seed);
+            let res = nisq::nisq_solve_with_objective(&circ, &arch, &InitialMapStrategy::Identity, objective).unwrap();
+            utils::verify(&res, &circ, &arch).expect("invalid nisq result");
+            assert!(nisq::check_routing_equivalence(&circ, &res));
+            total += res.steps.len();
+        }
+        total
+    };
+    // the objectives often route alike, but depth has to come out ahead overall
+    assert!(steps(RoutingObjective::DepthMinimizing) < steps(RoutingObjective::CostMinimizing));
+}
+
+fn region_constraints_test() {
+    let q = Qubit::new;
+    let circ = pseudo_random_circuit(6, 20, 3);
+    let arch = nisq::NisqArchitecture::new(utils::grid_graph(3, 3, false));
+    let region: HashSet<Location> = HashSet::from([Location::new(7), Location::new(8)]);
+    let regions = HashMap::from([(q(0), region.clone())]);
+    let mut starts = vec![
+        InitialMapStrategy::Random,
+        InitialMapStrategy::Isomorphism,
+        InitialMapStrategy::Anneal,
+        InitialMapStrategy::Parallel,
+    ];
+    starts.extend((0..8).map(InitialMapStrategy::RandomSeeded));
+    for init in &starts {
+        let res = nisq::nisq_solve_in_regions(&circ, &arch, init, &regions, None).unwrap();
+        assert!(region.contains(&res.steps[0].map[&q(0)]), "{:?} left the region", init);
+    }
+    let res = nisq::nisq_solve_sabre_in_regions(&circ, &arch, &regions, None).unwrap();
+    assert!(region.contains(&res.steps[0].map[&q(0)]));
+
+    // three qubits can't share two locations
+    let crowded: HashMap<Qubit, HashSet<Location>> = (0..3).map(|i| (q(i), region.clone())).collect();
+    let err = nisq::nisq_solve_in_regions(&circ, &arch, &InitialMapStrategy::Parallel, &crowded, None).unwrap_err();
+    assert_eq!(err.len(), 1);
+    assert!(matches!(&err[0], PreflightError::InfeasibleRegions(qs) if qs.len() == 1));
+}
+
+fn beam_width_one_test() {
+    let arch = nisq::NisqArchitecture::new(utils::grid_graph(3, 3, false));
+    for seed in 0..4 {
+        let circ = pseudo_random_circuit(9, 40, seed);
+        let mut config = SolverConfig::clone(&CONFIG.load());
+        config.seed = Some(seed);
+        config.routing_beam_width = None;
+        set_config(config.clone());
+        let greedy = nisq::nisq_solve_with_init(&circ, &arch, &InitialMapStrategy::Identity, None).unwrap();
+        config.routing_beam_width = Some(1);
+        set_config(config);
+        let beam = nisq::nisq_solve_with_init(&circ, &arch, &InitialMapStrategy::Identity, None).unwrap();
+        assert_eq!(beam.transitions, greedy.transitions);
+        assert_eq!(beam.cost, greedy.cost);
+        assert!(!beam.partial);
+    }
+}
+
+fn pauli_preflight_test() {
+    use solver::structures::PauliTerm::{PauliI as I, PauliX as X, PauliZ as Z};
+    let q = Qubit::new;
+    // axes padded with identities, as the parsers write them
+    let rot = Gate {
+        operation: Operation::PauliRot { axis: vec![X, I, Z], angle: (1, 8) },
+        qubits: vec![q(0), q(2)],
+        id: 0,
+    };
+    let meas = Gate {
+        operation: Operation::PauliMeasurement { sign: true, axis: vec![I, Z, I] },
+        qubits: vec![q(1)],
+        id: 1,
+    };
+    let arch = mqlss::compact_layout(3);
+    let circ = circuit_from_gates(&[rot.clone(), meas]);
+    assert_eq!(circ.validate_against(&arch), Ok(()));
+
+    let mut short = rot;
+    short.qubits = vec![q(0)];
+    let circ = circuit_from_gates(&[short]);
+    let expected = vec![PreflightError::PauliAxisMismatch { gate_id: 0, pauli_terms: 2, qubits: 1 }];
+    assert_eq!(circ.validate_against(&arch).unwrap_err(), expected);
+
+    let zero = Gate {
+        operation: Operation::PauliRot { axis: vec![X, I, Z], angle: (1, 0) },
+        qubits: vec![q(0), q(2)],
+        id: 0,
+    };
+    let circ = circuit_from_gates(&[zero]);
+    let expected = vec![PreflightError::ZeroDenominator(0)];
+    assert_eq!(mqlss::mqlss_solve(&circ, &arch).unwrap_err(), expected);
+}
+
+/// A π/4 rotation takes two magic states, so it reaches two distinct factory ports.
+fn quarter_rotation_ports_test() {
+    use solver::structures::PauliTerm::PauliZ as Z;
+    let rot = Gate {
+        operation: Operation::PauliRot { axis: vec![Z], angle: (1, 4) },
+        qubits: vec![Qubit::new(0)],
+        id: 0,
+    };
+    let circ = circuit_from_gates(&[rot]);
+    let arch = mqlss::compact_layout(4);
+    let res = mqlss::mqlss_solve(&circ, &arch).unwrap();
+    let used = res.steps[0].implemented_gates.iter().next().unwrap().implementation.occupied_locations();
+    let ports = used
+        .iter()
+        .filter(|l| {
+            arch.magic_state_qubits().iter().any(|m| {
+                m.get_index() / arch.width == l.get_index() / arch.width
+                    && m.get_index().abs_diff(l.get_index()) == 1
+            })
+        })
+        .count();
+    assert!(ports >= 2, "π/4 rotation used {} factory ports", ports);
+}
+
+fn mqlss_identity_terms_test() {
+    let path = std::env::temp_dir().join("identity_terms.pbc");
+    std::fs::write(&path, "XIZ_(1/8);\nIZZ_(1/8);\nZIIX_(1/4);\n").unwrap();
+    let circ = utils::extract_gates(path.to_str().unwrap(), &["Pauli"]);
+    let arch = mqlss::compact_layout(circ.qubits.len());
+    let res = mqlss::mqlss_solve(&circ, &arch).expect("identity terms failed preflight");
+    assert_eq!(res.assert_implements_all(&circ), Ok(()));
+}
+
+fn optimal_routing_test() {
+    let q = Qubit::new;
+    let mut circ = Circuit::new();
+    circ.add_cx(q(0), q(1));
+    circ.add_cx(q(1), q(2));
+    circ.add_cx(q(0), q(2));
+    // a triangle on a path needs exactly one swap
+    let arch = nisq::NisqArchitecture::new(utils::path_graph(3));
+    let res = nisq::nisq_solve_optimal(&circ, &arch).unwrap();
+    utils::verify(&res, &circ, &arch).expect("invalid optimal routing");
+    assert_eq!(res.cost, 1.0);
+
+    let mut config = SolverConfig::clone(&CONFIG.load());
+    config.optimal_routing_max_qubits = 2;
+    set_config(config.clone());
+    let err = nisq::nisq_solve_optimal(&circ, &arch).unwrap_err();
+    assert_eq!(err, nisq::OptimalRoutingError::TooManyQubits { qubits: 3, max: 2 });
+
+    config.optimal_routing_max_qubits = 8;
+    config.optimal_routing_max_states = 1;
+    set_config(config);
+    let err = nisq::nisq_solve_optimal(&circ, &arch).unwrap_err();
+    assert_eq!(err, nisq::OptimalRoutingError::StateBudgetExceeded(1));
+}
+
+fn anneal_restarts_test() {
+    let arch = nisq::NisqArchitecture::new(utils::grid_graph(3, 3, false));
+    let mut config = SolverConfig::clone(&CONFIG.load());
+    // no isomorphism map, so the plan is whatever annealing finds
+    config.isom_search_timeout = 0;
+    for seed in 0..3 {
+        let circ = pseudo_random_circuit(9, 30, seed);
+        config.seed = Some(seed);
+        config.parallel_searches = 1;
+        set_config(config.clone());
+        let (_, single) = nisq::nisq_plan_mapping(&circ, &arch);
+        // the first chain repeats the single one, so the best of all can only match or beat it
+        config.parallel_searches = 4;
+        set_config(config.clone());
+        let (_, restarted) = nisq::nisq_plan_mapping(&circ, &arch);
+        assert!(restarted <= single, "restarts cost {} > single chain {}", restarted, single);
+    }
+}
+
+fn lookahead_benchmark_test() {
+    // the extended set is what runs once the front layer has, not the front layer again
+    let mut ladder = Circuit::new();
+    for i in 0..3 {
+        ladder.add_cx(Qubit::new(i), Qubit::new(i + 1));
+    }
+    let ids = |gates: Vec<Gate>| gates.iter().map(|g| g.id).collect::<Vec<_>>();
+    assert_eq!(ids(ladder.get_front_layer()), vec![0]);
+    assert_eq!(ids(ladder.extended_layer()), vec![1]);
+
+    // a deep 20-qubit circuit on a line, where front-only routing pays for short-sighted swaps
+    let arch = nisq::NisqArchitecture::new(utils::path_graph(20));
+    let swaps = |weight: f64| -> usize {
+        let mut config = SolverConfig::clone(&CONFIG.load());
+        config.lookahead_weight = weight;
+        let mut total = 0;
+        for seed in 0..3 {
+            config.seed = Some(seed);
+            set_config(config.clone());
+            let circ = pseudo_random_circuit(20, 150, seed);
+            let res = nisq::nisq_solve_with_init(&circ, &arch, &InitialMapStrategy::Identity, None).unwrap();
+            total += res.stats().num_nontrivial_transitions;
+        }
+        total
+    };
+    let front_only = swaps(0.0);
+    let lookahead = swaps(0.5);
+    assert!(lookahead < front_only, "lookahead took {} swaps, front-only {}", lookahead, front_only);
+}
+
+/// A circuit whose every layer already runs under the initial map is laid out in place:
+/// one step per layer and no transition that moves a qubit.
+fn in_place_route_test() {
+    let q = Qubit::new;
+    let mut circ = Circuit::new();
+    for i in [0, 2, 4, 1, 3, 0, 2, 4] {
+        circ.add_cx(q(i), q(i + 1));
+    }
+    let arch = nisq::NisqArchitecture::new(utils::path_graph(6));
+    let map: HashMap<Qubit, Location> = (0..6).map(|i| (q(i), Location::new(i))).collect();
+    let res = nisq::nisq_solve_with_map(&circ, &arch, &map).unwrap();
+    assert_eq!(res.assert_implements_all(&circ), Ok(()));
+    assert_eq!(res.steps.len(), circ.layers().count());
+    assert_eq!(res.transitions.len(), res.steps.len() - 1);
+    assert!(res.steps.iter().all(|s| s.map == map));
+    assert_eq!(res.stats().num_nontrivial_transitions, 0);
+    assert!(!res.partial);
+}
+
+/// With `output_precision` set, costs differing only past the kept figures serialize alike.
+fn output_precision_test() {
+    let arch = raa::RaaArchitecture { width: 3, height: 3 };
+    let res = raa::raa_solve(&pseudo_random_circuit(6, 12, 3), &arch).unwrap();
+    let mut nudged = res.clone();
+    nudged.cost = res.cost * (1.0 + 1e-9);
+    nudged.makespan = res.makespan.map(|m| m * (1.0 + 1e-9));
+    let json = |r: &solver::structures::CompilerResult<raa::RaaGateImplementation>| serde_json::to_string(r).unwrap();
+    let mut config = SolverConfig::clone(&CONFIG.load());
+    config.output_precision = None;
+    set_config(config.clone());
+    assert_ne!(json(&res), json(&nudged));
+    config.output_precision = Some(6);
+    set_config(config);
+    assert_eq!(json(&res), json(&nudged));
+}
+
+/// Fidelity weights are scaled to hop units: a device with one error rate on every edge
+/// plans exactly like the unweighted one, and an unrated edge costs the mean rated edge.
+fn fidelity_units_test() {
+    let mut config = SolverConfig::clone(&CONFIG.load());
+    config.seed = Some(7);
+    set_config(config);
+    let at = Location::new;
+    let edges: Vec<_> = (1..6).map(|i| (at(i - 1), at(i), Some(0.02))).collect();
+    let weighted = nisq::NisqArchitecture::new_weighted(utils::weighted_graph_from_edges(edges));
+    assert!(weighted.weighted_graph().edge_weights().all(|w| *w == 1.0));
+    let plain = nisq::NisqArchitecture::new(utils::path_graph(6));
+    let circ = pseudo_random_circuit(6, 20, 2);
+    assert_eq!(nisq::nisq_plan_mapping(&circ, &weighted), nisq::nisq_plan_mapping(&circ, &plain));
+
+    let mixed = utils::weighted_graph_from_edges(vec![
+        (at(0), at(1), Some(0.01)),
+        (at(1), at(2), Some(0.03)),
+        (at(2), at(3), None),
+    ]);
+    let rated = (utils::fidelity_weight(0.01) + utils::fidelity_weight(0.03)) / 2.0;
+    let unrated = mixed.find_edge(2.into(), 3.into()).unwrap();
+    assert!((mixed[unrated] - rated).abs() < 1e-15);
+}
+
+/// The heuristic boost jumps to `stall_boost` after `stall_window` gate-free steps and
+/// decays once gates run again. Under `CostNorm::Fixed` routing can stand still for good
+/// without it; with the boost switched on it finishes.
+fn stall_boost_test() {
+    let mut config = SolverConfig::clone(&CONFIG.load());
+    config.stall_boost = 1.0;
+    set_config(config);
+    let idle = Step::<FactoryUse> { map: HashMap::new(), implemented_gates: HashSet::new() };
+    let mut busy = idle.clone();
+    busy.implemented_gates.insert(ImplementedGate {
+        gate: Gate { operation: Operation::T, qubits: vec![Qubit::new(0)], id: 0 },
+        implementation: FactoryUse(Location::new(0)),
+    });
+    assert!(CONFIG.load().stall_boost > 0.0);
+    let stalled = vec![idle.clone(); CONFIG.load().stall_window.max(1)];
+    let boost = backend::next_gamma_boost(0.0, &stalled);
+    assert_eq!(boost, CONFIG.load().stall_boost);
+    let mut moving = stalled.clone();
+    moving.push(busy);
+    let decayed = backend::next_gamma_boost(boost, &moving);
+    assert_eq!(decayed, boost * CONFIG.load().stall_decay);
+    assert!(decayed < boost);
+
+    let arch = nisq::NisqArchitecture::new(utils::grid_graph(4, 4, false));
+    let circ = pseudo_random_circuit(16, 60, 0);
+    let mut config = SolverConfig::clone(&CONFIG.load());
+    config.cost_norm = CostNorm::Fixed;
+    config.solve_timeout = Some(2);
+    config.seed = Some(0);
+    set_config(config.clone());
+    let boosted = nisq::nisq_solve_with_init(&circ, &arch, &InitialMapStrategy::Identity, None).unwrap();
+    assert!(!boosted.partial);
+    utils::verify(&boosted, &circ, &arch).expect("invalid boosted result");
+    config.stall_boost = 0.0;
+    set_config(config);
+    let fixed = nisq::nisq_solve_with_init(&circ, &arch, &InitialMapStrategy::Identity, None).unwrap();
+    assert!(fixed.partial);
+}
+
+/// A zero `solve_timeout` stops routing at once, greedy, beam or SABRE, with a partial
+/// result instead of running on.
+fn solve_timeout_test() {
+    let arch = nisq::NisqArchitecture::new(utils::path_graph(6));
+    let circ = all_pairs_circuit(6);
+    let mut config = SolverConfig::clone(&CONFIG.load());
+    config.solve_timeout = Some(0);
+    config.seed = Some(0);
+    set_config(config.clone());
+    let greedy = nisq::nisq_solve_with_init(&circ, &arch, &InitialMapStrategy::Identity, None).unwrap();
+    assert!(greedy.partial);
+    assert!(greedy.assert_implements_all(&circ).is_err());
+    let sabre = nisq::nisq_solve_sabre(&circ, &arch, None).unwrap();
+    assert!(sabre.partial);
+    config.routing_beam_width = Some(4);
+    set_config(config);
+    let beam = nisq::nisq_solve_with_init(&circ, &arch, &InitialMapStrategy::Identity, None).unwrap();
+    assert!(beam.partial);
+}
+
+/// Measurements reach the solvers that route them, and the other extractions refuse a
+/// measuring circuit instead of dropping its measurements; so does a measurement into an
+/// undeclared or too small classical register.
+fn measurement_extraction_test() {
+    let path = std::env::temp_dir().join("measurement_extraction_test.qasm");
+    let path = path.to_str().unwrap();
+    let write = |program: &str| std::fs::write(path, program).unwrap();
+    write("OPENQASM 2.0;\nqreg q[2];\ncreg c[1];\ncx q[0],q[1];\nmeasure q[1] -> c[0];\nif (c==1) cx q[0],q[1];\n");
+    for circ in [utils::extract_nisq_gates(path), utils::extract_scmr_gates(path)] {
+        assert_eq!(circ.gates.len(), 3);
+        assert_eq!(circ.gates[1].operation, Operation::Measure { creg_bit: 0 });
+    }
+    // dropping the measurement would leave the `if` testing a bit nothing writes
+    assert!(matches!(utils::try_extract_gates(path, &["CX"]), Err(utils::IOError::InputErr)));
+    let arch = nisq::NisqArchitecture::new(utils::path_graph(2));
+    let circ = utils::extract_nisq_gates(path);
+    utils::verify(&nisq::nisq_solve(&circ, &arch, None).unwrap(), &circ, &arch).expect("invalid routing");
+
+    // trailing measurements are skipped by the CX-only extractors
+    write("OPENQASM 2.0;\nqreg q[2];\ncreg c[2];\ncx q[0],q[1];\nmeasure q[0] -> c[0];\nmeasure q -> c;\n");
+    assert_eq!(utils::extract_cnots(path).gates.len(), 1);
+    let circ = utils::extract_nisq_gates(path);
+    let bits: Vec<_> = circ.gates[1..].iter().map(|g| (g.qubits[0].get_index(), g.operation.clone())).collect();
+    assert_eq!(
+        bits,
+        vec![
+            (0, Operation::Measure { creg_bit: 0 }),
+            (0, Operation::Measure { creg_bit: 0 }),
+            (1, Operation::Measure { creg_bit: 1 }),
+        ]
+    );
+
+    for program in [
+        "OPENQASM 2.0;\nqreg q[1];\nmeasure q[0] -> c[0];\n",
+        "OPENQASM 2.0;\nqreg q[1];\ncreg c[1];\nmeasure q[0] -> c[1];\n",
+        "OPENQASM 2.0;\nqreg q[2];\nif (c==1) cx q[0],q[1];\n",
+        "OPENQASM 2.0;\nqreg q[2];\ncreg c[1];\nmeasure q -> c;\n",
+    ] {
+        write(program);
+        assert!(matches!(utils::try_extract_gates(path, &["CX", "Measure"]), Err(utils::IOError::InputErr)));
+    }
+}
+
+/// With one T and one CCZ factory, T gates draw their states from the T factory only,
+/// a Toffoli routes past the CCZ factory, and a layout without CCZ factories refuses it.
+fn factory_kinds_test() {
+    let mut arch = scmr::compact_layout(4);
+    // one factory on the top row and one on the bottom row, so no cell borders both
+    let bottom_row = arch.width * (arch.height - 1);
+    let t_factory = arch.factories()[0].0;
+    let ccz_factory = arch.factories().iter().map(|(l, _)| *l).find(|l| l.get_index() > bottom_row).unwrap();
+    arch.set_factories(vec![(t_factory, scmr::FactoryKind::T), (ccz_factory, scmr::FactoryKind::CCZ)]);
+    let valid = arch.routing_cells();
+    let t_ends = utils::horizontal_neighbors_within(t_factory, arch.width, valid);
+    let ccz_ends = utils::horizontal_neighbors_within(ccz_factory, arch.width, valid);
+    let t = |q: usize, id: usize| Gate { operation: Operation::T, qubits: vec![Qubit::new(q)], id };
+    let ccx = Gate { operation: Operation::CCX, qubits: vec![Qubit::new(0), Qubit::new(1), Qubit::new(3)], id: 4 };
+    let circ = circuit_from_gates(&[t(0, 0), t(1, 1), t(2, 2), t(3, 3), ccx, t(2, 5)]);
+    let res = scmr::scmr_solve(&circ, &arch).unwrap();
+    utils::verify(&res, &circ, &arch).expect("invalid routing with typed factories");
+    for implemented in res.steps.iter().flat_map(|s| &s.implemented_gates) {
+        let path = implemented.implementation.path().unwrap();
+        if implemented.gate.operation == Operation::T {
+            assert!(t_ends.contains(path.last().unwrap()));
+            assert!(!ccz_ends.contains(path.last().unwrap()));
+        } else {
+            assert!(path.iter().any(|l| ccz_ends.contains(l)));
+        }
+    }
+    let t_only = scmr::compact_layout(4);
+    assert!(scmr::scmr_solve(&circ, &t_only).is_err());
+}
+
+/// Traps on a line holding two qubits each: a CX runs between the qubits of one trap, and
+/// a move carries a qubit into a neighboring trap with room.
+#[derive(Clone)]
+struct TrapLine(usize);
+
+impl Architecture for TrapLine {
+    fn locations(&self) -> Vec<Location> {
+        (0..self.0).map(Location::new).collect()
+    }
+    fn graph(&self) -> (petgraph::Graph<Location, ()>, HashMap<Location, petgraph::graph::NodeIndex>) {
+        let graph = utils::path_graph(self.0);
+        let index_map = graph.node_indices().map(|n| (graph[n], n)).collect();
+        (graph, index_map)
+    }
+    fn capacity(&self, _loc: Location) -> usize {
+        2
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize, Hash, PartialEq, Eq)]
+struct InTrap(Location);
+
+impl GateImplementation for InTrap {}
+
+#[derive(Debug)]
+struct TrapMove(Option<(Qubit, Location)>);
+
+impl Transition<InTrap, TrapLine> for TrapMove {
+    fn apply(&self, step: &Step<InTrap>) -> Step<InTrap> {
+        let mut map = step.map.clone();
+        if let Some((q, l)) = self.0 {
+            map.insert(q, l);
+        }
+        Step { map, implemented_gates: HashSet::new() }
+    }
+    fn repr(&self) -> String {
+        match self.0 {
+            Some((q, l)) => format!("move {:?} to {:?}", q, l),
+            None => "id".to_string(),
+        }
+    }
+    fn cost(&self, _arch: &TrapLine) -> f64 {
+        if self.0.is_some() { 1.0 } else { 0.0 }
+    }
+}
+
+/// Two qubits mapped into one capacity-2 trap run their CX there without a move, and a CX
+/// across traps is routed by moves that never overfill a trap.
+fn capacity_test() {
+    let arch = TrapLine(3);
+    let transitions = |step: &Step<InTrap>| {
+        let mut moves = vec![TrapMove(None)];
+        for (q, l) in &step.map {
+            for n in [l.get_index().wrapping_sub(1), l.get_index() + 1] {
+                if n < arch.0 && utils::has_room(&arch, &step.map, Location::new(n)) {
+                    moves.push(TrapMove(Some((*q, Location::new(n)))));
+                }
+            }
+        }
+        moves
+    };
+    let implement = |step: &Step<InTrap>, _arch: &TrapLine, gate: &Gate| {
+        let (a, b) = (step.map[&gate.qubits[0]], step.map[&gate.qubits[1]]);
+        (a == b).then_some(InTrap(a))
+    };
+    let heuristic: fn(&TrapLine, &Circuit, &QubitMap) -> f64 = |_, c, map| {
+        c.gates.iter().map(|g| map[&g.qubits[0]].get_index().abs_diff(map[&g.qubits[1]].get_index()) as f64).sum()
+    };
+    let solve = |circ: &Circuit, map: &QubitMap| {
+        backend::solve(
+            circ,
+            &arch,
+            &transitions,
+            &implement,
+            |_, _| 1.0,
+            Some(heuristic),
+            false,
+            &HashMap::new(),
+            &InitialMapStrategy::Provided(map.clone()),
+            None,
+        )
+    };
+    let q = Qubit::new;
+    let shared: QubitMap = [(q(0), 0), (q(1), 0), (q(2), 1), (q(3), 1)].into_iter().map(|(q, l)| (q, Location::new(l))).collect();
+    let mut circ = Circuit::new();
+    circ.add_cx(q(0), q(1));
+    circ.add_cx(q(2), q(3));
+    let res = solve(&circ, &shared);
+    utils::verify(&res, &circ, &arch).expect("invalid routing on shared traps");
+    assert_eq!(res.steps.len(), 1);
+    assert!(res.transitions.is_empty());
+
+    let apart: QubitMap = [(q(0), 0), (q(1), 1), (q(2), 1), (q(3), 2)].into_iter().map(|(q, l)| (q, Location::new(l))).collect();
+    let mut circ = Circuit::new();
+    circ.add_cx(q(0), q(3));
+    let res = solve(&circ, &apart);
+    utils::verify(&res, &circ, &arch).expect("invalid routing across traps");
+    assert!(res.stats().num_nontrivial_transitions > 0);
+}
+
+/// Every column a shuttle crosses adds one junction and its segment: a pair spanning three
+/// inner columns costs two junction segments more than a pair kept within one column, and
+/// swapping an inner end column for an edge one trades an X junction for a Y junction.
+fn ion_pair_cost_test() {
+    let arch = ion::IonArch::new(2, 5);
+    // offset 0 and 1 of a column's top trap, so both ends are inner or outer alike
+    let at = |col: usize, offset: usize| Location::new(col * 2 * arch.trap_size + offset);
+    let cost = |a: usize, b: usize| ion::get_pair_cost((at(a, 0), at(b, 1)), &arch);
+    let segment = cost(1, 2) - cost(1, 1);
+    assert!(segment > 0.0);
+    assert!((cost(1, 3) - cost(1, 1) - 2.0 * segment).abs() < 1e-12);
+    assert_eq!(cost(1, 3), cost(3, 1));
+    assert!(cost(0, 2) < cost(1, 3));
+}
+
+/// With `stack_depth` 2, locations 4 and 5 share a stack, so a CX between them counts as
+/// transversal, while 1 and 2 sit in different stacks even though a depth of 4 would group them.
+fn ilq_stack_depth_heuristic_test() {
+    let arch = ilqaa::ILQArch::new(2, 2, 2, (0..8).map(Location::new).collect(), vec![]);
+    let mut circ = Circuit::new();
+    circ.add_cx(Qubit::new(0), Qubit::new(1));
+    let placed = |a: usize, b: usize| -> QubitMap {
+        [(Qubit::new(0), Location::new(a)), (Qubit::new(1), Location::new(b))].into_iter().collect()
+    };
+    assert_eq!(ilqaa::mapping_heuristic(&arch, &circ, &placed(4, 5)), 0.0);
+    assert_eq!(ilqaa::mapping_heuristic(&arch, &circ, &placed(1, 2)), 1.0);
+}
+
+/// Relocating the SLM atom of a gate to a site costs the same shuttle as relocating its AOD
+/// atom there, plus the transfer into the AOD and back.
+fn raa_slm_transfer_cost_test() {
+    let arch = raa::RaaArchitecture { width: 4, height: 4 };
+    let at = |i: usize| Location::new(i);
+    let map = [(0, 5), (1, 6)].into_iter().map(|(q, l)| (Qubit::new(q), at(l))).collect();
+    let implemented_gates =
+        [ImplementedGate { gate: cx(0, 1, 0), implementation: raa::RaaGateImplementation::new(at(5), at(6)) }].into_iter().collect();
+    let step = Step { map, implemented_gates };
+    let moves = raa::raa_transitions_dyn_map(&step, &arch, None);
+    let cost_of = |r: String| moves.iter().find(|m| m.repr() == r).map(|m| m.cost(&arch));
+    let mut compared = 0;
+    for dst in arch.locations() {
+        let aod = cost_of(format!("RELOCATE Qubit(0) {:?}", dst));
+        let slm = cost_of(format!("RELOCATE_SLM Qubit(1) {:?}", dst));
+        if let (Some(aod), Some(slm)) = (aod, slm) {
+            assert!((slm - aod - raa::slm_transfer_cost()).abs() < 1e-12);
+            compared += 1;
+        }
+    }
+    assert!(compared > 0);
+    assert!(raa::slm_transfer_cost() > 0.0);
+}
+
+/// Two JSON circuits on two lines come back as two result lines, in input order.
+fn compile_jsonl_test() {
+    let arch = nisq::NisqArchitecture::new(utils::path_graph(3));
+    let circuits = [vec![cx(0, 1, 0)], vec![cx(0, 1, 0), cx(1, 2, 1), cx(0, 2, 2)]];
+    let input: String = circuits.iter().map(|gates| format!("{}\n", serde_json::json!({ "gates": gates }))).collect();
+    let mut output: Vec<u8> = Vec::new();
+    utils::compile_jsonl(std::io::Cursor::new(input), &mut output, |circ| {
+        Ok(nisq::nisq_solve(circ, &arch, None).unwrap())
+    })
+    .unwrap();
+    let lines: Vec<&str> = std::str::from_utf8(&output).unwrap().lines().collect();
+    assert_eq!(lines.len(), 2);
+    for (line, gates) in lines.iter().zip(&circuits) {
+        let res: serde_json::Value = serde_json::from_str(line).unwrap();
+        let implemented: usize = res["steps"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| s["implemented_gates"].as_array().unwrap().len())
+            .sum();
+        assert_eq!(implemented, gates.len());
+    }
+}
+
+/// With `record_candidate_counts` set, an scmr result lists every routed gate once, with
+/// as many candidates as `scmr_implement_gate_alt` offers on its step's map.
+fn candidate_counts_test() {
+    let circ = pseudo_random_circuit(4, 8, 1);
+    let arch = scmr::compact_layout(4);
+    let mut config = SolverConfig::clone(&CONFIG.load());
+    config.record_candidate_counts = true;
+    set_config(config);
+    let res = scmr::scmr_solve(&circ, &arch).unwrap();
+    let mut direct = Vec::new();
+    for step in &res.steps {
+        let empty = Step { map: step.map.clone(), implemented_gates: HashSet::new() };
+        for gate in step.gates().into_iter().sorted_by_key(|g| g.id) {
+            direct.push((gate.id, scmr::scmr_implement_gate_alt(&empty, &arch, &gate).count()));
+        }
+    }
+    assert_eq!(res.candidate_counts.len(), circ.gates.len());
+    assert!(res.candidate_counts.iter().all(|(_, count)| *count > 0));
+    assert_eq!(res.candidate_counts, direct);
+}
+
+/// `RandomSeeded(42)` starts both solves from the same map, and not from the one
+/// annealing finds.
+fn random_seeded_map_test() {
+    let circ = pseudo_random_circuit(6, 16, 2);
+    let arch = nisq::NisqArchitecture::new(utils::grid_graph(3, 3, false));
+    let start = |strategy: InitialMapStrategy| {
+        nisq::nisq_solve_with_init(&circ, &arch, &strategy, None).unwrap().steps[0].map.clone()
+    };
+    let seeded = start(InitialMapStrategy::RandomSeeded(42));
+    assert_eq!(seeded, start(InitialMapStrategy::RandomSeeded(42)));
+    assert_ne!(seeded, start(InitialMapStrategy::Anneal));
+}
+
+/// On a 3x3 lattice with the control at 3, the target at 1 and another patch at 0, the
+/// default orientation has to go around the bottom (five cells) while the reverse one meets
+/// at cell 4; with `cx_both_orientations` the shortest candidate is that one-cell route.
+fn cx_both_orientations_test() {
+    let at = Location::new;
+    let mut arch = scmr::ScmrArchitecture::new(3, 3, vec![at(0), at(1), at(3)], vec![]);
+    let map = [(0, 3), (1, 1), (2, 0)].into_iter().map(|(q, l)| (Qubit::new(q), at(l))).collect();
+    let step = Step { map, implemented_gates: HashSet::new() };
+    let shortest = |arch: &scmr::ScmrArchitecture| {
+        scmr::scmr_implement_gate_alt(&step, arch, &cx(0, 1, 0)).next().unwrap().path().unwrap()
+    };
+    assert_eq!(shortest(&arch).len(), 5);
+    arch.cx_both_orientations = true;
+    assert_eq!(shortest(&arch), vec![at(4)]);
+}
+
+/// The same CX costs more with two idle patches beside it than with none once
+/// `idle_patch_cost` is set, and the same without it.
+fn idle_patch_cost_test() {
+    let at = Location::new;
+    let mut arch = scmr::ScmrArchitecture::new(3, 3, vec![at(0), at(2), at(6), at(8)], vec![]);
+    let gate = cx(0, 1, 0);
+    let step_with = |placed: &[usize]| -> Step<scmr::ScmrGateImplementation> {
+        let map: QubitMap = placed.iter().enumerate().map(|(q, l)| (Qubit::new(q), at(*l))).collect();
+        let empty = Step { map: map.clone(), implemented_gates: HashSet::new() };
+        let implementation = scmr::scmr_implement_gate_alt(&empty, &arch, &gate).next().unwrap();
+        Step { map, implemented_gates: [ImplementedGate { gate: gate.clone(), implementation }].into_iter().collect() }
+    };
+    let busy = step_with(&[0, 2]);
+    let idling = step_with(&[0, 2, 6, 8]);
+    assert_eq!(scmr::scmr_step_cost(&busy, &arch), scmr::scmr_step_cost(&idling, &arch));
+    arch.idle_patch_cost = 0.5;
+    assert!(scmr::scmr_step_cost(&idling, &arch) > scmr::scmr_step_cost(&busy, &arch));
+}
+
+/// On a four-location path with q0, q1, q2 placed in order and CX(q0, q1), CX(q0, q2),
+/// every placed node carries its qubit, the adjacent pair is highlighted and the pair
+/// two hops apart gets its own dashed routing edge.
+fn placement_to_dot_test() {
+    let arch = nisq::NisqArchitecture::new(utils::path_graph(4));
+    let circ = circuit_from_gates(&[cx(0, 1, 0), cx(0, 2, 1)]);
+    let map: QubitMap = (0..3).map(|i| (Qubit::new(i), Location::new(i))).collect();
+    let dot = utils::placement_to_dot(&circ, &arch, &map);
+    for i in 0..3 {
+        assert!(dot.contains(&format!("L{} [label=\"q{}\"];", i, i)));
+    }
+    assert!(dot.contains("L3 [label=\"\", style=dashed];"));
+    assert!(dot.contains("L0 -- L1 [color=blue, penwidth=2];"));
+    assert!(dot.contains("L1 -- L2;"));
+    assert!(dot.contains("L0 -- L2 [color=red, style=dashed, constraint=false];"));
+}
+
+/// A GHZ ladder, each CX controlled by the previous target, takes one layer per CX; the
+/// fan-out version doubling the entangled qubits each round takes one per round.
+fn circuit_layers_test() {
+    let ladder = circuit_from_gates(&(0..5).map(|i| cx(i, i + 1, i)).collect::<Vec<_>>());
+    let layers = utils::circuit_to_layers(&ladder);
+    assert_eq!(layers.len(), 5);
+    assert!(layers.iter().all(|layer| layer.len() == 1));
+    assert_eq!(utils::circuit_depth(&ladder), 5);
+    let fan_out = circuit_from_gates(&[cx(0, 1, 0), cx(0, 2, 1), cx(1, 3, 2), cx(0, 4, 3), cx(1, 5, 4), cx(2, 6, 5), cx(3, 7, 6)]);
+    let layers = utils::circuit_to_layers(&fan_out);
+    assert_eq!(layers.iter().map(|layer| layer.len()).collect::<Vec<_>>(), vec![1, 2, 4]);
+    assert_eq!(utils::circuit_depth(&fan_out), 3);
+}
+
+/// Distance-3 and distance-5 heavy-hex lattices have (5d^2 - 2d - 1)/2 qubits and
+/// 3d^2 - 2d - 1 couplings, each stored in both directions, and no qubit has more than
+/// three neighbors.
+fn heavy_hex_test() {
+    for (d, qubits, couplings) in [(3, 19, 20), (5, 57, 64)] {
+        let g = utils::heavy_hex_graph(d);
+        assert_eq!(g.node_count(), qubits);
+        assert_eq!(g.edge_count(), 2 * couplings);
+        assert!(g.node_indices().all(|n| g.neighbors(n).count() <= 3));
+    }
+}
+
+/// A 3x4 grid has 17 couplings and its torus 24; on a 2x3 torus the vertical wraparound
+/// would repeat the existing edges, leaving 9. Each coupling is stored in both directions.
+fn grid_graph_test() {
+    for (rows, cols, periodic, couplings) in [(3, 4, false, 17), (3, 4, true, 24), (2, 3, false, 7), (2, 3, true, 9)] {
+        let g = utils::grid_graph(rows, cols, periodic);
+        assert_eq!(g.node_count(), rows * cols);
+        assert_eq!(g.edge_count(), 2 * couplings, "{}x{} periodic={}", rows, cols, periodic);
+    }
+}
+
+/// A malformed config file gets a warning, a missing one only the info line; both fall
+/// back to the defaults.
+fn load_config_test() {
+    let dir = std::env::temp_dir().join("load_config_test");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.json");
+    let load = |path: &std::path::Path| {
+        let mut log = Vec::new();
+        let config = solver::config::load_config(path.to_str().unwrap(), &mut log);
+        (config, String::from_utf8(log).unwrap())
+    };
+    std::fs::write(&path, "{ \"alpha\": 2.0,").unwrap();
+    let (config, log) = load(&path);
+    assert!(log.starts_with("warning: malformed"), "{}", log);
+    assert_eq!(config.alpha, SolverConfig::default().alpha);
+    std::fs::remove_file(&path).unwrap();
+    let (_, log) = load(&path);
+    assert!(log.starts_with("info: no") && !log.contains("warning"), "{}", log);
+    assert_eq!(log.lines().count(), 1);
+}
+
+/// On a fully occupied three-qubit path every transition is one SWAP, so the swap
+/// circuit has one gate per non-identity transition, each between qubits that sat on
+/// neighboring locations before it.
+fn swap_circuit_test() {
+    let arch = nisq::NisqArchitecture::new(utils::path_graph(3));
+    let circ = circuit_from_gates(&[cx(0, 2, 0), cx(1, 0, 1), cx(2, 1, 2), cx(0, 2, 3), cx(1, 2, 4)]);
+    let res = nisq::nisq_solve_with_init(&circ, &arch, &InitialMapStrategy::Identity, None).unwrap();
+    let swaps = res.swap_circuit();
+    assert!(!swaps.gates.is_empty());
+    assert!(swaps.gates.iter().all(|g| g.operation == Operation::SWAP));
+    assert_eq!(swaps.gates.len(), res.stats().num_nontrivial_transitions);
+    let (graph, index) = arch.graph();
+    let moved = res.steps.iter().tuple_windows().filter(|(before, after)| before.map != after.map);
+    for (swap, (before, _)) in swaps.gates.iter().zip(moved) {
+        let (a, b) = (before.map[&swap.qubits[0]], before.map[&swap.qubits[1]]);
+        assert!(graph.contains_edge(index[&a], index[&b]));
+    }
+}
+
+/// An adjacency map listing each undirected edge from both ends gives the same graph,
+/// node order included, as the edge list naming it once, whether read from an entry or
+/// from a file.
+fn adjacency_graph_test() {
+    let listed = serde_json::json!([[0, 1], [1, 2], [1, 3]]);
+    let adjacency = serde_json::json!({"0": [1], "1": [0, 2, 3], "2": [1], "3": [1]});
+    let shape = |g: &petgraph::Graph<Location, ()>| {
+        let nodes: Vec<Location> = g.node_weights().copied().collect();
+        let edges: HashSet<(Location, Location)> =
+            g.edge_indices().map(|e| g.edge_endpoints(e).unwrap()).map(|(a, b)| (g[a], g[b])).collect();
+        (nodes, edges, g.edge_count())
+    };
+    let from_list = shape(&utils::graph_from_json_entry(listed.clone(), None).unwrap());
+    assert_eq!(from_list, shape(&utils::graph_from_json_entry(adjacency.clone(), None).unwrap()));
+    let path = std::env::temp_dir().join("adjacency_arch.json");
+    for graph in [listed, adjacency] {
+        std::fs::write(&path, serde_json::json!({ "graph": graph }).to_string()).unwrap();
+        assert_eq!(shape(&utils::graph_from_file(path.to_str().unwrap()).unwrap()), from_list);
+    }
+}
+
+/// With `max_gate_distance` 2, a CX between the ends of a three-location path runs in
+/// place, costing more than a CX between neighbors; at distance 1 it needs a swap. The CX
+/// goes second because routing prices the first step before any gate is placed.
+fn virtual_gate_test() {
+    let arch = nisq::NisqArchitecture::new(utils::path_graph(3));
+    // each qubit on the location of the same index
+    let map: QubitMap = (0..3).map(|i| (Qubit::new(i), Location::new(i))).collect();
+    let solve = |second: Gate| {
+        let circ = circuit_from_gates(&[cx(0, 1, 0), second]);
+        nisq::nisq_solve_with_init(&circ, &arch, &InitialMapStrategy::Provided(map.clone()), None).unwrap()
+    };
+    let mut config = SolverConfig::clone(&CONFIG.load());
+    config.max_gate_distance = 1;
+    set_config(config.clone());
+    assert!(solve(cx(0, 2, 1)).stats().num_nontrivial_transitions > 0);
+    config.max_gate_distance = 2;
+    set_config(config.clone());
+    let far = solve(cx(0, 2, 1));
+    assert_eq!(far.steps.len(), 2);
+    assert_eq!(far.stats().num_nontrivial_transitions, 0);
+    let near = solve(cx(1, 2, 1));
+    assert!((far.cost - near.cost - config.virtual_gate_cost).abs() < 1e-12);
+    assert!(config.virtual_gate_cost > 0.0);
+}
+
+/// With a budget of one tunable coupler, CX gates on the tunable edges 0-1 and 2-3 of a
+/// path land in different steps, while one on the always-on edge 4-5 can join either; a
+/// budget of two lets all three share a step.
+fn tunable_couplers_test() {
+    let at = Location::new;
+    let couplers = [(at(0), at(1)), (at(2), at(3))];
+    let circ = circuit_from_gates(&[cx(0, 1, 0), cx(2, 3, 1), cx(4, 5, 2)]);
+    let map: QubitMap = (0..6).map(|i| (Qubit::new(i), at(i))).collect();
+    let solve = |budget: usize| {
+        let arch = nisq::NisqArchitecture::new(utils::path_graph(6)).with_tunable_couplers(&couplers, budget);
+        let res = nisq::nisq_solve_with_init(&circ, &arch, &InitialMapStrategy::Provided(map.clone()), None).unwrap();
+        utils::verify(&res, &circ, &arch).expect("invalid routing with tunable couplers");
+        res
+    };
+    let limited = solve(1);
+    for step in &limited.steps {
+        let on_couplers = step.gates().iter().filter(|g| g.id != 2).count();
+        assert!(on_couplers <= 1);
+    }
+    assert_eq!(limited.steps.len(), 2);
+    assert_eq!(solve(2).steps.len(), 1);
+}
+
+/// A routed result covers its circuit; dropping the step implementing gate 1 reports id 1
+/// missing, and implementing gate 0 twice reports it duplicated.
+fn gate_coverage_test() {
+    let arch = nisq::NisqArchitecture::new(utils::path_graph(3));
+    let circ = circuit_from_gates(&[cx(0, 1, 0), cx(1, 2, 1), cx(0, 1, 2)]);
+    let res = nisq::nisq_solve_with_init(&circ, &arch, &InitialMapStrategy::Identity, None).unwrap();
+    assert_eq!(res.assert_implements_all(&circ), Ok(()));
+
+    let mut dropped = res.clone();
+    for step in &mut dropped.steps {
+        step.implemented_gates.retain(|g| g.gate.id != 1);
+    }
+    let err = dropped.assert_implements_all(&circ).unwrap_err();
+    assert_eq!(err.missing, vec![1]);
+    assert!(err.duplicated.is_empty() && err.unknown.is_empty());
+
+    let mut doubled = res.clone();
+    let first = res.steps.iter().flat_map(|s| &s.implemented_gates).find(|g| g.gate.id == 0).unwrap().clone();
+    doubled.steps.push(Step { map: res.steps[0].map.clone(), implemented_gates: [first].into_iter().collect() });
+    let err = doubled.assert_implements_all(&circ).unwrap_err();
+    assert_eq!(err.duplicated, vec![0]);
+    assert!(err.missing.is_empty());
+}
+
+/// `gate_criticality` streams to the same value the full table holds, for the front layer
+/// and every later gate, and knows no gate the circuit doesn't have.
+fn gate_criticality_test() {
+    let circ = pseudo_random_circuit(6, 30, 7);
+    let table = utils::build_criticality_table(&circ);
+    let front = circ.get_front_layer();
+    assert!(!front.is_empty());
+    for gate in front.iter().chain(&circ.gates) {
+        assert_eq!(utils::gate_criticality(&circ, gate.id), Some(table[gate.id]));
+    }
+    assert_eq!(utils::gate_criticality(&circ, circ.gates.len()), None);
+}
+
+/// Builds the generator for the problem description at `qmrl`, as `./qmrl debug` does,
+/// and runs the generated compiler with `--onepass --init identity` on `qasm` over the
+/// coupling graph `edges`, returning its result.
+fn run_generated(qmrl: &std::path::Path, qasm: &str, edges: serde_json::Value) -> serde_json::Value {
+    let root = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap();
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let built = std::process::Command::new(cargo)
+        .args(["build", "--bin", "generator"])
+        .env("QMRL_PATH", qmrl)
+        .current_dir(root)
+        .status()
+        .unwrap();
+    assert!(built.success(), "generator failed to build for {}", qmrl.display());
+    let dir = std::env::temp_dir().join("run_generated");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("circuit.qasm"), qasm).unwrap();
+    std::fs::write(dir.join("graph.json"), serde_json::json!({ "graph": edges }).to_string()).unwrap();
+    // the temporary directory has no config.json, so the compiler runs on the defaults
+    let out = std::process::Command::new(root.join("target/debug/generator"))
+        .args(["circuit.qasm", "graph.json", "--onepass", "--init", "identity"])
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    serde_json::from_slice(&out.stdout).unwrap()
+}
+
+/// A CX between the ends of a three-qubit path, then one back onto its control.
+const GENERATED_TEST_QASM: &str = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[3];\ncx q[0],q[2];\ncx q[1],q[0];\n";
+
+/// Under nisq-gate-step-cost.qmrl a step costs 0.5 when it implements gate 0 and 1.0
+/// otherwise, and each SWAP 1.0. Gate 0 needs a SWAP first, so it lands after the first
+/// step, which routing prices before placing any gate.
+fn generated_step_cost_test() {
+    let qmrl = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../problem-descriptions/nisq-gate-step-cost.qmrl");
+    let res = run_generated(&qmrl, GENERATED_TEST_QASM, serde_json::json!([[0, 1], [1, 2]]));
+    let steps = res["steps"].as_array().unwrap();
+    let has_gate_0 = |step: &serde_json::Value| step["implemented_gates"].as_array().unwrap().iter().any(|g| g["gate"]["id"] == 0);
+    assert!(!has_gate_0(&steps[0]) && steps[1..].iter().any(has_gate_0));
+    let step_costs: f64 = 1.0 + steps[1..].iter().map(|s| if has_gate_0(s) { 0.5 } else { 1.0 }).sum::<f64>();
+    let swaps = res["stats"]["num_nontrivial_transitions"].as_u64().unwrap() as f64;
+    assert!((res["cost"].as_f64().unwrap() - (step_costs + swaps)).abs() < 1e-9);
+}
+
+/// Under nisq-fold-cost.qmrl a SWAP costs 0.5 for each of its two locations, folded over
+/// a vector of them, the no-op transition nothing, and every step the default 1.0.
+fn generated_fold_cost_test() {
+    let qmrl = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../problem-descriptions/nisq-fold-cost.qmrl");
+    let res = run_generated(&qmrl, GENERATED_TEST_QASM, serde_json::json!([[0, 1], [1, 2]]));
+    let steps = res["steps"].as_array().unwrap().len() as f64;
+    let swaps = res["stats"]["num_nontrivial_transitions"].as_u64().unwrap() as f64;
+    assert!(swaps > 0.0);
+    assert!((res["cost"].as_f64().unwrap() - (steps + swaps)).abs() < 1e-9);
+}
+
+/// The fold binder parses with or without spaces around its bars and comma: a copy of
+/// nisq-fold-cost.qmrl written `| x , acc |` compiles to the same result.
+fn fold_binder_spacing_test() {
+    let qmrl = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../problem-descriptions/nisq-fold-cost.qmrl");
+    let original = std::fs::read_to_string(&qmrl).unwrap();
+    assert!(original.contains("|x,acc|"));
+    let spaced = std::env::temp_dir().join("nisq-fold-cost-spaced.qmrl");
+    std::fs::write(&spaced, original.replace("|x,acc|", "| x , acc |")).unwrap();
+    let edges = serde_json::json!([[0, 1], [1, 2]]);
+    let mut spaced_res = run_generated(&spaced, GENERATED_TEST_QASM, edges.clone());
+    let mut res = run_generated(&qmrl, GENERATED_TEST_QASM, edges);
+    // which way round a SWAP's edge is printed varies from run to run, so only the
+    // steps, maps and costs are compared
+    spaced_res.as_object_mut().unwrap().remove("transitions");
+    res.as_object_mut().unwrap().remove("transitions");
+    assert_eq!(spaced_res, res);
 }
 
 fn main() {
+    // fold_binder_spacing_test();
+    // generated_fold_cost_test();
+    // generated_step_cost_test();
+    // gate_criticality_test();
+    // gate_coverage_test();
+    // tunable_couplers_test();
+    // virtual_gate_test();
+    // adjacency_graph_test();
+    // swap_circuit_test();
+    // load_config_test();
+    // grid_graph_test();
+    // heavy_hex_test();
+    // circuit_layers_test();
+    // placement_to_dot_test();
+    // idle_patch_cost_test();
+    // cx_both_orientations_test();
+    // random_seeded_map_test();
+    // candidate_counts_test();
+    // compile_jsonl_test();
+    // raa_slm_transfer_cost_test();
+    // ilq_stack_depth_heuristic_test();
+    // ion_pair_cost_test();
+    // core_fallback_test();
+    // solve_timeout_test();
+    // joint_optimize_test();
+    // quarter_rotation_ports_test();
+    // capacity_test();
+    // factory_kinds_test();
+    // measurement_extraction_test();
+    // stall_boost_test();
+    // fidelity_units_test();
+    // output_precision_test();
+    // in_place_route_test();
+    // lookahead_benchmark_test();
+    // anneal_restarts_test();
+    // optimal_routing_test();
+    // mqlss_identity_terms_test();
+    // pauli_preflight_test();
+    // beam_width_one_test();
+    // region_constraints_test();
+    // depth_objective_test();
+    // swap_decomposition_test();
+    // sparse_neighbors_test();
+    // incremental_resolve_test();
+    // cost_norm_test();
+    // oversized_circuit_test();
+    // gzip_qasm_test();
+    // map_distance_test();
+    // restrict_test();
+    // max_step_all_orders_test();
+    // deterministic_solve_test();
+    // route_progress_test();
+    // unsupported_gates_test();
+    // isolated_locations_test();
+    // bfs_initial_map_test();
+    // toffoli_test();
+    // candidate_cap_test();
+    // gate_footprints_test();
+    // plan_mapping_test();
+    // raa_makespan_test();
+    // provided_map_test();
+    // identity_init_test();
+    // routing_equivalence_test();
+    // interaction_core_test();
+    // interaction_graph_test();
+    // remove_locations_test();
+    // fully_occupied_test();
+    // commuting_front_layer_test();
+    // circuit_builder_test();
+    // ilq_cost_tuning_test();
+    // raa_row_move_test();
+    // raa_aod_order_test();
+    // raa_shuttle_radius_test();
+    // all_paths_cap_test();
+    // k_shortest_paths_test();
+    // scmr_layout_tuning_test();
+    // pbc_parser_test();
+    // all_to_all_test();
+    // empty_circuit_test();
+    // solve_mode_comparison_test();
     // nisq_test();
     // scmr_test();
     // raa_test();
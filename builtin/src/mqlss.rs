@@ -10,13 +10,20 @@ use solver::{
     structures::*,
     utils::*,
 };
+use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::sync::OnceLock;
 #[derive(Clone)]
 pub struct MQLSSArchitecture {
     pub width: usize,
     pub height: usize,
     pub alg_qubits: Vec<Location>,
-    pub magic_state_qubits: Vec<Location>,
+    magic_state_qubits: Vec<Location>,
+    excluded: Vec<Location>,
+    /// `routing_cells`, worked out on first use and dropped by `set_magic_state_qubits`
+    /// and `set_excluded`.
+    routing_cells_cache: OnceLock<HashSet<Location>>,
 }
 
 impl Architecture for MQLSSArchitecture {
@@ -32,8 +39,40 @@ impl Architecture for MQLSSArchitecture {
     ) {
         return self.get_graph();
     }
+    fn supported_gate_types(&self) -> Vec<GateType> {
+        vec![GateType::PauliRot, GateType::PauliMeasurement]
+    }
+    fn blocked_locations(&self) -> Vec<Location> {
+        self.magic_state_qubits.clone()
+    }
 }
 impl MQLSSArchitecture {
+    /// A layout with every cell routable.
+    pub fn new(width: usize, height: usize, alg_qubits: Vec<Location>, magic_state_qubits: Vec<Location>) -> Self {
+        MQLSSArchitecture {
+            width,
+            height,
+            alg_qubits,
+            magic_state_qubits,
+            excluded: vec![],
+            routing_cells_cache: OnceLock::new(),
+        }
+    }
+    pub fn magic_state_qubits(&self) -> &[Location] {
+        &self.magic_state_qubits
+    }
+    pub fn set_magic_state_qubits(&mut self, magic_state_qubits: Vec<Location>) {
+        self.magic_state_qubits = magic_state_qubits;
+        self.routing_cells_cache = OnceLock::new();
+    }
+    /// Cells left out of the routing graph, e.g. those outside the region given to `restrict`.
+    pub fn excluded(&self) -> &[Location] {
+        &self.excluded
+    }
+    pub fn set_excluded(&mut self, excluded: Vec<Location>) {
+        self.excluded = excluded;
+        self.routing_cells_cache = OnceLock::new();
+    }
     fn get_graph(
         &self,
     ) -> (
@@ -81,8 +120,34 @@ impl MQLSSArchitecture {
                 }
             }
         }
+        remove_locations(&mut g, &mut index_map, &self.excluded);
         return (g, index_map);
     }
+    /// Cells a tree may use as a terminal: the grid minus `excluded` cells and magic states.
+    pub fn routing_cells(&self) -> &HashSet<Location> {
+        self.routing_cells_cache.get_or_init(|| {
+            (0..self.width * self.height)
+                .map(Location::new)
+                .filter(|l| !self.excluded.contains(l) && !self.magic_state_qubits.contains(l))
+                .collect()
+        })
+    }
+    /// This layout cut down to the cells in `allowed`, keeping their locations: qubit slots
+    /// and magic states outside it are dropped and trees can't leave it.
+    pub fn restrict(&self, allowed: &HashSet<Location>) -> Self {
+        let inside = |locs: &Vec<Location>| locs.iter().filter(|l| allowed.contains(l)).copied().collect();
+        MQLSSArchitecture {
+            width: self.width,
+            height: self.height,
+            alg_qubits: inside(&self.alg_qubits),
+            magic_state_qubits: inside(&self.magic_state_qubits),
+            excluded: (0..self.width * self.height)
+                .map(Location::new)
+                .filter(|l| !allowed.contains(l) || self.excluded.contains(l))
+                .collect(),
+            routing_cells_cache: OnceLock::new(),
+        }
+    }
 }
 #[derive(Debug, Serialize, Clone, Hash, PartialEq, Eq)]
 pub struct MQLSSGateImplementation {
@@ -112,12 +177,7 @@ pub fn compact_layout(alg_qubit_count: usize) -> MQLSSArchitecture {
     for i in (1..perimeter.len()).step_by(2) {
         magic_state_qubits.push(perimeter[i]);
     }
-    return MQLSSArchitecture {
-        width,
-        height,
-        alg_qubits,
-        magic_state_qubits,
-    };
+    MQLSSArchitecture::new(width, height, alg_qubits, magic_state_qubits)
 }
 
 pub fn square_sparse_layout(alg_qubit_count: usize) -> MQLSSArchitecture {
@@ -147,14 +207,13 @@ pub fn square_sparse_layout(alg_qubit_count: usize) -> MQLSSArchitecture {
     for i in (1..perimeter.len()).step_by(2) {
         magic_state_qubits.push(perimeter[i]);
     }
-    return MQLSSArchitecture {
-        width,
-        height,
-        alg_qubits,
-        magic_state_qubits,
-    };
+    MQLSSArchitecture::new(width, height, alg_qubits, magic_state_qubits)
+}
+impl GateImplementation for MQLSSGateImplementation {
+    fn occupied_locations(&self) -> Vec<Location> {
+        self.used_nodes.clone()
+    }
 }
-impl GateImplementation for MQLSSGateImplementation {}
 #[derive(Debug)]
 struct IdTransition;
 type MQLSSStep = Step<MQLSSGateImplementation>;
@@ -178,8 +237,80 @@ fn mqlss_transitions(_step: &MQLSSStep) -> Vec<IdTransition> {
     return vec![IdTransition];
 }
 
-fn mqlsss_step_cost(_step: &MQLSSStep, _arch: &MQLSSArchitecture) -> f64 {
-    return 1.0;
+/// Magic states consumed by a rotation through `angle` π. Multiples of π/8 decompose exactly: π/2 is a Pauli and needs none, π/4
+/// takes two and odd multiples one. Finer angles are synthesized from Clifford+T at about
+/// three T gates per bit of precision beyond π/8.
+fn rotation_t_count((numerator, denominator): (isize, usize)) -> usize {
+    let (mut a, mut b) = (numerator.unsigned_abs(), denominator);
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    let (n, d) = (numerator / a.max(1) as isize, denominator / a.max(1));
+    if 8 % d == 0 {
+        return match (n * (8 / d) as isize).rem_euclid(4) {
+            0 => 0,
+            2 => 2,
+            _ => 1,
+        };
+    }
+    let bits = d.next_power_of_two().trailing_zeros() as usize;
+    3 * (bits.max(4) - 3) + 1
+}
+
+/// A step lasts one cycle, or as many as its rotation with the largest T-count needs to
+/// inject its magic states.
+fn mqlsss_step_cost(step: &MQLSSStep, _arch: &MQLSSArchitecture) -> f64 {
+    let t_count = step
+        .implemented_gates
+        .iter()
+        .map(|ig| match ig.gate.base_operation() {
+            Operation::PauliRot { angle, .. } => rotation_t_count(*angle),
+            _ => 0,
+        })
+        .max()
+        .unwrap_or(0);
+    t_count.max(1) as f64
+}
+
+thread_local! {
+    /// Steiner trees found for the map currently being routed on this thread; a
+    /// step on a different map starts a fresh memo.
+    static STEINER_MEMO: RefCell<(QubitMap, Rc<RefCell<SteinerMemo>>)> =
+        RefCell::new((HashMap::new(), Rc::new(RefCell::new(HashMap::new()))));
+}
+
+fn grid_distance(a: Location, b: Location, width: usize) -> usize {
+    let (a, b) = (a.get_index(), b.get_index());
+    (a % width).abs_diff(b % width) + (a / width).abs_diff(b / width)
+}
+
+/// One terminal group per magic state, each of separate cells beside its own share of the
+/// factories, nearest the gate first; a π/4 rotation takes two.
+fn magic_state_terminals(
+    step: &MQLSSStep,
+    arch: &MQLSSArchitecture,
+    gate: &Gate,
+    ports: usize,
+    valid: &HashSet<Location>,
+) -> Vec<Vec<Location>> {
+    let gate_locs: Vec<Location> = gate.qubits.iter().map(|q| step.map[q]).collect();
+    let distance =
+        |m: &Location| gate_locs.iter().map(|l| grid_distance(*m, *l, arch.width)).min();
+    let factories: Vec<Location> =
+        arch.magic_state_qubits.iter().copied().sorted_by_key(distance).collect();
+    let mut claimed = HashSet::new();
+    (0..ports)
+        .map(|port| {
+            factories
+                .iter()
+                .skip(port)
+                .step_by(ports)
+                .flat_map(|m| horizontal_neighbors_within(*m, arch.width, valid))
+                .filter(|l| claimed.insert(*l))
+                .sorted_by_key(distance)
+                .collect()
+        })
+        .collect()
 }
 
 fn mqlss_implement_gate(
@@ -187,54 +318,42 @@ fn mqlss_implement_gate(
     arch: &MQLSSArchitecture,
     gate: &Gate,
 ) -> impl Iterator<Item = MQLSSGateImplementation> {
-    let mut blocked = Vec::new();
-    for loc in &arch.magic_state_qubits {
-        assert!(!arch.alg_qubits.clone().into_iter().any(|l| l == *loc));
-        blocked.push(*loc);
-    }
-    for loc in step.map.values().into_iter() {
-        blocked.push(*loc);
-    }
-    for loc in step
-        .implemented_gates
-        .iter()
-        .map(|x| x.implementation.used_nodes.clone())
-        .flatten()
-    {
-        blocked.push(loc);
-    }
+    assert!(!arch.magic_state_qubits.iter().any(|l| arch.alg_qubits.contains(l)));
+    let blocked = step.blocked_locations(arch);
+    let valid = arch.routing_cells();
     let mut qubit_terminals = vec![];
-    match &gate.operation {
-        Operation::PauliRot { axis, angle } if *angle == (1, 8) || *angle == (-1, 8) => {
-            let msf_neighbors = arch
-                .magic_state_qubits
-                .clone()
-                .into_iter()
-                .map(|m| horizontal_neighbors(m, arch.width))
-                .flatten()
-                .collect();
-            qubit_terminals.push(msf_neighbors);
-
+    let mut ports = 0;
+    match gate.base_operation() {
+        Operation::PauliRot { axis, angle } => {
+            ports = rotation_t_count(*angle).min(arch.magic_state_qubits.len());
             for i in 0..gate.qubits.len() {
-                match axis[i] {
+                match axis[gate.qubits[i].get_index()] {
                     PauliTerm::PauliX => {
-                        qubit_terminals
-                            .push(horizontal_neighbors(step.map[&gate.qubits[i]], arch.width));
+                        qubit_terminals.push(horizontal_neighbors_within(
+                            step.map[&gate.qubits[i]],
+                            arch.width,
+                            valid,
+                        ));
                     }
                     PauliTerm::PauliY => {
-                        qubit_terminals.push(vertical_neighbors(
+                        qubit_terminals.push(vertical_neighbors_within(
                             step.map[&gate.qubits[i]],
                             arch.width,
                             arch.height,
+                            valid,
+                        ));
+                        qubit_terminals.push(horizontal_neighbors_within(
+                            step.map[&gate.qubits[i]],
+                            arch.width,
+                            valid,
                         ));
-                        qubit_terminals
-                            .push(horizontal_neighbors(step.map[&gate.qubits[i]], arch.width));
                     }
                     PauliTerm::PauliZ => {
-                        qubit_terminals.push(vertical_neighbors(
+                        qubit_terminals.push(vertical_neighbors_within(
                             step.map[&gate.qubits[i]],
                             arch.width,
                             arch.height,
+                            valid,
                         ));
                     }
                     PauliTerm::PauliI => {}
@@ -243,25 +362,33 @@ fn mqlss_implement_gate(
         }
         Operation::PauliMeasurement { sign: _, axis } => {
             for i in 0..gate.qubits.len() {
-                match axis[i] {
+                match axis[gate.qubits[i].get_index()] {
                     PauliTerm::PauliX => {
-                        qubit_terminals
-                            .push(horizontal_neighbors(step.map[&gate.qubits[i]], arch.width));
+                        qubit_terminals.push(horizontal_neighbors_within(
+                            step.map[&gate.qubits[i]],
+                            arch.width,
+                            valid,
+                        ));
                     }
                     PauliTerm::PauliY => {
-                        qubit_terminals.push(vertical_neighbors(
+                        qubit_terminals.push(vertical_neighbors_within(
                             step.map[&gate.qubits[i]],
                             arch.width,
                             arch.height,
+                            valid,
+                        ));
+                        qubit_terminals.push(horizontal_neighbors_within(
+                            step.map[&gate.qubits[i]],
+                            arch.width,
+                            valid,
                         ));
-                        qubit_terminals
-                            .push(horizontal_neighbors(step.map[&gate.qubits[i]], arch.width));
                     }
                     PauliTerm::PauliZ => {
-                        qubit_terminals.push(vertical_neighbors(
+                        qubit_terminals.push(vertical_neighbors_within(
                             step.map[&gate.qubits[i]],
                             arch.width,
                             arch.height,
+                            valid,
                         ));
                     }
                     PauliTerm::PauliI => {}
@@ -275,13 +402,30 @@ fn mqlss_implement_gate(
             )
         }
     }
-    steiner_trees(arch, qubit_terminals, blocked)
+    let memo = STEINER_MEMO.with(|m| {
+        let mut m = m.borrow_mut();
+        if m.0 != step.map {
+            *m = (step.map.clone(), Rc::new(RefCell::new(HashMap::new())));
+        }
+        m.1.clone()
+    });
+    // fall back to fewer magic state ports, down to one, when no tree reaches them all
+    (ports.min(1)..=ports)
+        .rev()
+        .map(|p| {
+            let mut terminals = magic_state_terminals(step, arch, gate, p, valid);
+            terminals.extend(qubit_terminals.iter().cloned());
+            steiner_trees_memo(arch, terminals, blocked.clone(), memo.clone())
+        })
+        .collect::<Vec<_>>()
         .into_iter()
+        .flatten()
         .map(|x| MQLSSGateImplementation { used_nodes: x })
 }
 
-pub fn mqlss_solve(c: &Circuit, a: &MQLSSArchitecture) -> CompilerResult<MQLSSGateImplementation> {
-    return solve(
+pub fn mqlss_solve(c: &Circuit, a: &MQLSSArchitecture) -> Result<CompilerResult<MQLSSGateImplementation>, Vec<PreflightError>> {
+    validate_circuit(c, a)?;
+    Ok(solve(
         c,
         a,
         &mqlss_transitions,
@@ -289,14 +433,18 @@ pub fn mqlss_solve(c: &Circuit, a: &MQLSSArchitecture) -> CompilerResult<MQLSSGa
         mqlsss_step_cost,
         None,
         true,
-    );
+        &HashMap::new(),
+        &InitialMapStrategy::Parallel,
+        None,
+    ))
 }
 
 pub fn mqlss_solve_joint_optimize_parallel(
     c: &Circuit,
     a: &MQLSSArchitecture,
-) -> CompilerResult<MQLSSGateImplementation> {
-    return solve_joint_optimize_parallel(
+) -> Result<CompilerResult<MQLSSGateImplementation>, Vec<PreflightError>> {
+    validate_circuit(c, a)?;
+    Ok(solve_joint_optimize_parallel(
         c,
         a,
         &mqlss_transitions,
@@ -304,5 +452,7 @@ pub fn mqlss_solve_joint_optimize_parallel(
         mqlsss_step_cost,
         None,
         true,
-    );
+        &HashMap::new(),
+        None,
+    ))
 }
@@ -1,13 +1,26 @@
 use petgraph::{graph::NodeIndex, Graph};
 use serde::Serialize;
-use solver::backend::{sabre_solve, sabre_solve_parallel, solve, solve_joint_optimize, solve_joint_optimize_parallel, solve_with_cached_heuristic};
+use solver::backend::{plan_mapping, Progress, resolve_incremental, sabre_solve, sabre_solve_parallel, sabre_solve_with_map, solve, solve_joint_optimize, solve_joint_optimize_parallel, solve_with_cached_heuristic, solve_with_map, solve_with_objective};
+use solver::config::{RoutingObjective, CONFIG};
 use solver::structures::*;
-use solver::utils::Move;
-use std::collections::{HashMap, HashSet};
+use solver::utils::{all_pairs_distances, all_pairs_weighted_distances, complete_graph, heavy_hex_graph, Move};
+use itertools::Itertools;
+use std::cmp::Reverse;
+use std::iter::zip;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 #[derive(Clone)]
 pub struct NisqArchitecture {
     graph: Graph<Location, ()>,
     index_map: HashMap<Location, NodeIndex>,
+    distances: HashMap<(Location, Location), usize>,
+    weighted: Graph<Location, f64>,
+    route_costs: HashMap<(Location, Location), f64>,
+    directed: bool,
+    couplings: HashSet<(Location, Location)>,
+    tunable: HashSet<(Location, Location)>,
+    coupler_budget: usize,
+    /// Mutually coupled location triples, where a three-qubit gate can run.
+    triangles: Vec<[Location; 3]>,
 }
 impl NisqArchitecture {
     pub fn new(graph: Graph<Location, ()>) -> Self {
@@ -15,24 +28,159 @@ impl NisqArchitecture {
         for ind in graph.node_indices() {
             index_map.insert(graph[ind], ind);
         }
-        return NisqArchitecture { graph, index_map };
+        let distances = all_pairs_distances(&graph);
+        let weighted = graph.map(|_, loc| *loc, |_, _| 1.0);
+        let route_costs = all_pairs_weighted_distances(&weighted);
+        let coupled = |a: NodeIndex, b: NodeIndex| graph.contains_edge(a, b) || graph.contains_edge(b, a);
+        let mut triangles = Vec::new();
+        for a in graph.node_indices() {
+            for b in graph.neighbors_undirected(a).filter(|b| a < *b).unique() {
+                for c in graph.neighbors_undirected(b).filter(|c| b < *c).unique() {
+                    if coupled(a, c) {
+                        triangles.push([graph[a], graph[b], graph[c]]);
+                    }
+                }
+            }
+        }
+        NisqArchitecture {
+            graph,
+            index_map,
+            distances,
+            weighted,
+            route_costs,
+            directed: false,
+            couplings: HashSet::new(),
+            tunable: HashSet::new(),
+            coupler_budget: 0,
+            triangles,
+        }
+    }
+    /// Builds an architecture that routes by edge weight (e.g. `fidelity_weight`
+    /// of the two-qubit error rate) instead of hop count. The weights are scaled
+    /// to a mean of one, so an average edge costs a hop and route costs stay in
+    /// the hop units of the step costs and `max_gate_distance`.
+    pub fn new_weighted(weighted: Graph<Location, f64>) -> Self {
+        let mean = weighted.edge_weights().sum::<f64>() / weighted.edge_count().max(1) as f64;
+        let weighted = if mean > 0.0 { weighted.map(|_, loc| *loc, |_, w| w / mean) } else { weighted };
+        let mut arch = NisqArchitecture::new(weighted.map(|_, loc| *loc, |_, _| ()));
+        arch.route_costs = all_pairs_weighted_distances(&weighted);
+        arch.weighted = weighted;
+        arch
+    }
+    /// Builds an architecture whose CX gates may only run along the edges of
+    /// `couplings`, in control -> target direction. SWAPs are still allowed
+    /// either way along a coupling.
+    pub fn new_directed(couplings: Graph<Location, ()>) -> Self {
+        let mut graph = couplings.clone();
+        let mut allowed = HashSet::new();
+        for edge in couplings.edge_indices() {
+            let (source, target) = couplings.edge_endpoints(edge).unwrap();
+            allowed.insert((couplings[source], couplings[target]));
+            graph.update_edge(target, source, ());
+        }
+        let mut arch = NisqArchitecture::new(graph);
+        arch.directed = true;
+        arch.couplings = allowed;
+        arch
+    }
+    /// Marks `couplers` as tunable: at most `budget` of them can carry a gate in
+    /// any one step. The remaining edges stay always-on and unrestricted.
+    pub fn with_tunable_couplers(mut self, couplers: &[(Location, Location)], budget: usize) -> Self {
+        for (a, b) in couplers {
+            self.tunable.insert((*a, *b));
+            self.tunable.insert((*b, *a));
+        }
+        self.coupler_budget = budget;
+        self
+    }
+    /// Whether a gate on `edge` still fits in `step`'s tunable-coupler budget.
+    fn coupler_available(&self, step: &NisqStep, edge: (Location, Location)) -> bool {
+        if !self.tunable.contains(&edge) {
+            return true;
+        }
+        let active = step
+            .implemented_gates
+            .iter()
+            .filter(|g| g.implementation.distance == 1 && self.tunable.contains(&g.implementation.edge))
+            .count();
+        active < self.coupler_budget
+    }
+    /// The induced sub-architecture on `allowed`: only those locations and the couplings
+    /// between them, with their weights, directions and tunable couplers kept.
+    pub fn restrict(&self, allowed: &HashSet<Location>) -> Self {
+        let inside = |(a, b): &&(Location, Location)| allowed.contains(a) && allowed.contains(b);
+        let weighted = self.weighted.filter_map(|_, l| allowed.contains(l).then_some(*l), |_, w| Some(*w));
+        let mut arch = NisqArchitecture::new_weighted(weighted);
+        arch.directed = self.directed;
+        arch.couplings = self.couplings.iter().filter(inside).copied().collect();
+        arch.tunable = self.tunable.iter().filter(inside).copied().collect();
+        arch.coupler_budget = self.coupler_budget;
+        arch
     }
     pub fn get_graph(&self) -> &Graph<Location, ()> {
         return &self.graph;
     }
+    fn distance(&self, from: &Location, to: &Location) -> usize {
+        *self.distances.get(&(*from, *to)).unwrap_or_else(|| {
+            panic!("Disconnected graph. No path found from {:?} to {:?}", from, to)
+        })
+    }
+    /// Heuristic cost of bringing `from` and `to` together. A pair already within
+    /// `max_gate_distance` needs no routing, so it counts as a single hop.
+    fn route_cost(&self, from: &Location, to: &Location) -> f64 {
+        let cost = *self.route_costs.get(&(*from, *to)).unwrap_or_else(|| {
+            panic!("Disconnected graph. No path found from {:?} to {:?}", from, to)
+        });
+        let hops = self.distance(from, to);
+        if self.allows_virtual_gates() && hops > 1 && hops <= CONFIG.load().max_gate_distance {
+            return cost / hops as f64;
+        }
+        cost
+    }
+    fn allows_virtual_gates(&self) -> bool {
+        !self.directed && CONFIG.load().max_gate_distance > 1
+    }
+}
+
+/// IBM-style heavy-hex device for the distance-`distance` heavy-hex code.
+pub fn heavy_hex_layout(distance: usize) -> NisqArchitecture {
+    NisqArchitecture::new(heavy_hex_graph(distance))
+}
+
+/// Fully connected device on `n` locations, so every CX is directly implementable.
+pub fn all_to_all_layout(n: usize) -> NisqArchitecture {
+    NisqArchitecture::new(complete_graph(n))
 }
 
 impl Architecture for NisqArchitecture {
     fn locations(&self) -> Vec<Location> {
         let mut locations = Vec::new();
         for node in self.graph.node_indices() {
-            locations.push(self.graph[node]);
+            // isolated locations stay in the graph so location indices match the architecture
+            // file, but an uncoupled location can't take part in any gate, so no qubit is
+            // placed there
+            if self.graph.neighbors_undirected(node).next().is_some() {
+                locations.push(self.graph[node]);
+            }
         }
         return locations;
     }
     fn graph(&self) -> (Graph<Location, ()>, HashMap<Location, NodeIndex>) {
         return (self.graph.clone(), self.index_map.clone());
     }
+    fn distance_matrix(&self) -> HashMap<(Location, Location), usize> {
+        self.distances.clone()
+    }
+    fn weighted_graph(&self) -> Graph<Location, f64> {
+        self.weighted.clone()
+    }
+    /// CCX only where some triangle can host all three operands.
+    fn supported_gate_types(&self) -> Vec<GateType> {
+        if self.triangles.is_empty() {
+            return vec![GateType::CX, GateType::Measure];
+        }
+        vec![GateType::CX, GateType::CCX, GateType::Measure]
+    }
 }
 
 fn swap_on_edge(
@@ -56,9 +204,37 @@ struct NisqTrans {
 #[derive(Clone, Debug, Serialize, Hash, PartialEq, Eq)]
 pub struct NisqGateImplementation {
     edge: (Location, Location),
+    /// Run against the coupling direction, wrapped in Hadamards.
+    reversed: bool,
+    /// Hops between the operands; above 1 only when `max_gate_distance` allows it.
+    distance: usize,
+    /// Locations of the operands after the first two, for gates on three or more qubits.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    others: Vec<Location>,
 }
 
-impl GateImplementation for NisqGateImplementation {}
+/// Extra cost of the four H gates needed to flip a CX onto a reversed coupling.
+const H_SANDWICH_COST: f64 = 0.1;
+
+impl GateImplementation for NisqGateImplementation {
+    fn path(&self) -> Option<Vec<Location>> {
+        // a distant CX acts across the gap rather than along a routed path
+        if self.distance > 1 {
+            return None;
+        }
+        if self.edge.0 == self.edge.1 {
+            return Some(vec![self.edge.0]);
+        }
+        Some([self.edge.0, self.edge.1].into_iter().chain(self.others.iter().copied()).collect())
+    }
+    /// Both ends of the CX, whether or not it is routed along an edge.
+    fn occupied_locations(&self) -> Vec<Location> {
+        if self.edge.0 == self.edge.1 {
+            return vec![self.edge.0];
+        }
+        [self.edge.0, self.edge.1].into_iter().chain(self.others.iter().copied()).collect()
+    }
+}
 
 type NisqStep = Step<NisqGateImplementation>;
 
@@ -82,6 +258,116 @@ impl Transition<NisqGateImplementation, NisqArchitecture> for NisqTrans {
     }
 }
 
+/// The locations a SWAP exchanged going from map `before` to map `after`, read off the
+/// qubits that moved: `Some(None)` when none did, `None` when the change isn't one SWAP.
+fn swapped_edge(before: &QubitMap, after: &QubitMap) -> Option<Option<(Location, Location)>> {
+    if before.len() != after.len() || before.keys().any(|q| !after.contains_key(q)) {
+        return None;
+    }
+    let moved: Vec<(Location, Location)> = before
+        .iter()
+        .filter(|(q, l)| after[q] != **l)
+        .map(|(q, l)| (*l, after[q]))
+        .sorted_by_key(|(from, _)| from.get_index())
+        .collect();
+    let occupied = |l: &Location| before.values().any(|x| x == l);
+    match moved[..] {
+        [] => Some(None),
+        [(a, b)] if !occupied(&b) => Some(Some((a, b))),
+        [(a, b), (c, d)] if a == d && b == c => Some(Some((a, b))),
+        _ => None,
+    }
+}
+
+/// Whether `res` is a faithful routing of `orig`. Requires each step's map to follow from the
+/// one before by a single SWAP or none, every implemented gate to be one of `orig`'s with
+/// the same logical qubits, acting on the locations those qubits hold in its step, and each
+/// logical qubit to see its gates in the same order as in `orig`.
+pub fn check_routing_equivalence(orig: &Circuit, res: &CompilerResult<NisqGateImplementation>) -> bool {
+    if res.steps.is_empty() {
+        return orig.gates.is_empty();
+    }
+    if res.transitions.len() + 1 != res.steps.len() {
+        return false;
+    }
+    let originals: HashMap<usize, &Gate> = orig.gates.iter().map(|g| (g.id, g)).collect();
+    let mut routed_order: HashMap<Qubit, Vec<usize>> = HashMap::new();
+    for (i, step) in res.steps.iter().enumerate() {
+        if i > 0 && swapped_edge(&res.steps[i - 1].map, &step.map).is_none() {
+            return false;
+        }
+        let map = &step.map;
+        // gates within a step run together, so order them by id for the per-qubit sequences
+        let gates = step.implemented_gates.iter().sorted_by_key(|g| g.gate.id);
+        let mut busy = HashSet::new();
+        for ImplementedGate { gate, implementation } in gates {
+            if originals.get(&gate.id).map(|g| &g.qubits) != Some(&gate.qubits) {
+                return false;
+            }
+            let qubits = &gate.qubits;
+            let operands: Option<Vec<Location>> = qubits.iter().map(|q| map.get(q).copied()).collect();
+            let expected = match operands.as_deref() {
+                Some([l]) => Some(((*l, *l), vec![])),
+                Some([c, t, rest @ ..]) => Some(((*c, *t), rest.to_vec())),
+                _ => None,
+            };
+            if expected != Some((implementation.edge, implementation.others.clone())) {
+                return false;
+            }
+            for q in qubits {
+                if !busy.insert(*q) {
+                    return false;
+                }
+                routed_order.entry(*q).or_default().push(gate.id);
+            }
+        }
+    }
+    let mut original_order: HashMap<Qubit, Vec<usize>> = HashMap::new();
+    for gate in &orig.gates {
+        for q in &gate.qubits {
+            original_order.entry(*q).or_default().push(gate.id);
+        }
+    }
+    routed_order == original_order
+}
+
+/// The three CX gates realizing a SWAP on `edge`. On a directed architecture the outer two
+/// run along the coupling and the middle one is `reversed` unless the edge is coupled both
+/// ways; the identity transition is empty.
+fn swap_as_cxs(edge: (Location, Location), arch: &NisqArchitecture) -> Vec<NisqGateImplementation> {
+    let (a, b) = edge;
+    if a == b {
+        return vec![];
+    }
+    let (c, t) = if arch.directed && !arch.couplings.contains(&(a, b)) { (b, a) } else { (a, b) };
+    let cx = |edge, reversed| NisqGateImplementation { edge, reversed, distance: 1, others: vec![] };
+    let middle_reversed = arch.directed && !arch.couplings.contains(&(t, c));
+    vec![cx((c, t), false), cx((t, c), middle_reversed), cx((c, t), false)]
+}
+
+/// Device-level view of the SWAPs a NISQ result inserts.
+pub trait SwapDecomposition {
+    /// For each transition, the CX gates that carry out its SWAP on `arch`, found from the
+    /// maps of the steps on either side: three per SWAP, none for a transition that leaves
+    /// the map unchanged. Fails with the index of the first transition whose maps aren't
+    /// one SWAP apart.
+    fn swap_decomposition(&self, arch: &NisqArchitecture) -> Result<Vec<Vec<NisqGateImplementation>>, usize>;
+}
+
+impl SwapDecomposition for CompilerResult<NisqGateImplementation> {
+    fn swap_decomposition(&self, arch: &NisqArchitecture) -> Result<Vec<Vec<NisqGateImplementation>>, usize> {
+        self
+            .steps
+            .windows(2)
+            .enumerate()
+            .map(|(i, w)| match swapped_edge(&w[0].map, &w[1].map) {
+                Some(edge) => Ok(edge.map_or(vec![], |edge| swap_as_cxs(edge, arch))),
+                None => Err(i),
+            })
+            .collect()
+    }
+}
+
 fn nisq_transitions(step: &NisqStep, arch: &NisqArchitecture) -> Vec<NisqTrans> {
     let mut transitions = Vec::new();
     transitions.push(NisqTrans {
@@ -104,47 +390,126 @@ fn nisq_implement_gate(
     gate: &Gate,
 ) -> Vec<NisqGateImplementation> {
     let graph = arch.get_graph();
+    if gate.qubits.len() == 1 {
+        // measurements act in place, so they never need routing
+        return match step.map.get(&gate.qubits[0]) {
+            Some(pos) => vec![NisqGateImplementation { edge: (*pos, *pos), reversed: false, distance: 0, others: vec![] }],
+            None => vec![],
+        };
+    }
+    if gate.qubits.len() > 2 {
+        return nisq_implement_clique_gate(step, arch, gate);
+    }
     let (cpos, tpos) = (step.map.get(&gate.qubits[0]), step.map.get(&gate.qubits[1]));
     match (cpos, tpos) {
+        (Some(cpos), Some(tpos)) if !arch.coupler_available(step, (*cpos, *tpos)) => vec![],
+        (Some(cpos), Some(tpos)) if arch.directed => {
+            if arch.couplings.contains(&(*cpos, *tpos)) {
+                vec![NisqGateImplementation { edge: (*cpos, *tpos), reversed: false, distance: 1, others: vec![] }]
+            } else if arch.couplings.contains(&(*tpos, *cpos)) {
+                vec![NisqGateImplementation { edge: (*cpos, *tpos), reversed: true, distance: 1, others: vec![] }]
+            } else {
+                vec![]
+            }
+        }
         (Some(cpos), Some(tpos))
             if graph.contains_edge(arch.index_map[cpos], arch.index_map[tpos]) =>
         {
             vec![NisqGateImplementation {
                 edge: (*cpos, *tpos),
+                reversed: false,
+                distance: 1,
+                others: vec![],
+            }]
+        }
+        (Some(cpos), Some(tpos))
+            if arch.allows_virtual_gates() && arch.distance(cpos, tpos) <= CONFIG.load().max_gate_distance =>
+        {
+            vec![NisqGateImplementation {
+                edge: (*cpos, *tpos),
+                reversed: false,
+                distance: arch.distance(cpos, tpos),
+                others: vec![],
             }]
         }
         _ => vec![],
     }
 }
 
-fn nisq_step_cost(_step: &NisqStep, _arch: &NisqArchitecture) -> f64 {
-    0.0
+/// A gate on three or more qubits, such as a Toffoli, runs only with every pair of its
+/// operands on coupled locations.
+fn nisq_implement_clique_gate(
+    step: &NisqStep,
+    arch: &NisqArchitecture,
+    gate: &Gate,
+) -> Vec<NisqGateImplementation> {
+    let Some(locations) = gate.qubits.iter().map(|q| step.map.get(q).copied()).collect::<Option<Vec<_>>>() else {
+        return vec![];
+    };
+    let coupled = |a: &Location, b: &Location| {
+        if arch.directed {
+            return arch.couplings.contains(&(*a, *b)) || arch.couplings.contains(&(*b, *a));
+        }
+        arch.get_graph().contains_edge(arch.index_map[a], arch.index_map[b])
+    };
+    let clique = locations
+        .iter()
+        .tuple_combinations()
+        .all(|(a, b)| coupled(a, b) && arch.coupler_available(step, (*a, *b)));
+    if !clique {
+        return vec![];
+    }
+    vec![NisqGateImplementation {
+        edge: (locations[0], locations[1]),
+        reversed: false,
+        distance: 1,
+        others: locations[2..].to_vec(),
+    }]
 }
 
-fn mapping_heuristic(arch: &NisqArchitecture, c: &Circuit, map: &HashMap<Qubit, Location>) -> f64 {
-    let graph = arch.get_graph();
-    let mut cost = 0;
-    for gate in &c.gates {
-        let (cpos, tpos) = (map.get(&gate.qubits[0]), map.get(&gate.qubits[1]));
-        let (cind, tind) = (arch.index_map[cpos.unwrap()], arch.index_map[tpos.unwrap()]);
-        let sp_res = petgraph::algo::astar(graph, cind, |n| n == tind, |_| 1, |_| 0);
+fn nisq_step_cost(step: &NisqStep, _arch: &NisqArchitecture) -> f64 {
+    let reversed = step.implemented_gates.iter().filter(|g| g.implementation.reversed).count();
+    let extra_hops: usize = step
+        .implemented_gates
+        .iter()
+        .map(|g| g.implementation.distance.saturating_sub(1))
+        .sum();
+    reversed as f64 * H_SANDWICH_COST + extra_hops as f64 * CONFIG.load().virtual_gate_cost
+}
 
-        match sp_res {
-            Some((c, _)) => {cost += c;
-                //  println!("gate: {:?}, distance {:?}", gate, c)
-                 }
-            None => panic!(
-                "Disconnected graph. No path found from {:?} to {:?}",
-                cpos, tpos
-            ),
+/// Routing cost of `gate` under `map`. A three-qubit gate pays for carrying each operand to
+/// its own corner of the nearest triangle, since pairwise distances alone can't tell moves
+/// toward a triangle from moves along a path; anything else sums over its operand pairs.
+fn gate_route_cost(arch: &NisqArchitecture, gate: &Gate, map: &QubitMap) -> f64 {
+    let operands: Vec<Location> = gate.qubits.iter().map(|q| map[q]).collect();
+    if operands.len() == 3 && !arch.triangles.is_empty() {
+        let mut best = f64::INFINITY;
+        for triangle in &arch.triangles {
+            let costs: Vec<[f64; 3]> =
+                operands.iter().map(|l| triangle.map(|corner| arch.route_cost(l, &corner))).collect();
+            for corners in (0..3).permutations(3) {
+                best = best.min(zip(&costs, corners).map(|(c, corner)| c[corner]).sum());
+            }
         }
+        return best;
+    }
+    operands
+        .iter()
+        .tuple_combinations()
+        .map(|(a, b)| arch.route_cost(a, b))
+        .sum()
+}
+
+fn mapping_heuristic(arch: &NisqArchitecture, c: &Circuit, map: &HashMap<Qubit, Location>) -> f64 {
+    let mut cost = 0.0;
+    for gate in c.gates.iter().filter(|g| g.qubits.len() >= 2) {
+        cost += gate_route_cost(arch, gate, map);
     }
-    return cost as f64;
+    cost
 }
 
 fn delta_on_move(map: &QubitMap, chosen_move: Move, c: &Circuit, arch: &NisqArchitecture) -> f64 {
-    let mut delta = 0;
-    let graph = arch.get_graph();
+    let mut delta = 0.0;
     let mut new_map = map.clone();
     let mut moved_qubits = vec![];
     match chosen_move {
@@ -161,39 +526,22 @@ fn delta_on_move(map: &QubitMap, chosen_move: Move, c: &Circuit, arch: &NisqArch
             moved_qubits.push(qubit);
         }
     }
-    for gate in &c.gates {
+    for gate in c.gates.iter().filter(|g| g.qubits.len() >= 2) {
         let modified = moved_qubits.iter().any(|x| gate.qubits.contains(x));
         if modified {
-            let (cpos_old, tpos_old) = (map.get(&gate.qubits[0]), map.get(&gate.qubits[1]));
-            let (cind_old, tind_old) = (
-                arch.index_map[cpos_old.unwrap()],
-                arch.index_map[tpos_old.unwrap()],
-            );
-            let sp_res_old =
-                petgraph::algo::astar(graph, cind_old, |n| n == tind_old, |_| 1, |_| 0);
-            let (cpos_new, tpos_new) = (new_map.get(&gate.qubits[0]), new_map.get(&gate.qubits[1]));
-            let (cind_new, tind_new) = (
-                arch.index_map[cpos_new.unwrap()],
-                arch.index_map[tpos_new.unwrap()],
-            );
-            let sp_res_new =
-                petgraph::algo::astar(graph, cind_new, |n| n == tind_new, |_| 1, |_| 0);
-            match (sp_res_new, sp_res_old) {
-                (None, None) => panic!("disconnected graph in computing mapping heuristic"),
-                (None, Some(_)) => panic!("disconnected graph in computing mapping heuristic"),
-                (Some(_), None) => panic!("disconnected graph in computing mapping heuristic"),
-                (Some((c_new, _)), Some((c_old, _))) => delta += c_new - c_old,
-            }
+            delta += gate_route_cost(arch, gate, &new_map) - gate_route_cost(arch, gate, map);
         }
     }
-    return delta as f64;
+    delta
 }
 
 pub fn nisq_solve_sabre(
     c: &Circuit,
     a: &NisqArchitecture,
-) -> CompilerResult<NisqGateImplementation> {
-    return sabre_solve(
+    progress: Option<&Progress>,
+) -> Result<CompilerResult<NisqGateImplementation>, Vec<PreflightError>> {
+    validate_circuit(c, a)?;
+    Ok(sabre_solve(
         c,
         a,
         &|s| nisq_transitions(s, a),
@@ -201,14 +549,37 @@ pub fn nisq_solve_sabre(
         nisq_step_cost,
         Some(mapping_heuristic),
         false,
-    );
+        &HashMap::new(),
+        progress,
+    ))
+}
+
+/// `nisq_solve_sabre` refining the caller's `map` rather than a searched one.
+pub fn nisq_solve_sabre_with_map(
+    c: &Circuit,
+    a: &NisqArchitecture,
+    map: &QubitMap,
+) -> Result<CompilerResult<NisqGateImplementation>, InitialMapError> {
+    sabre_solve_with_map(
+        c,
+        a,
+        &|s| nisq_transitions(s, a),
+        &nisq_implement_gate,
+        nisq_step_cost,
+        Some(mapping_heuristic),
+        false,
+        map,
+        None,
+    )
 }
 
 pub fn nisq_solve_sabre_par(
     c: &Circuit,
     a: &NisqArchitecture,
-) -> CompilerResult<NisqGateImplementation> {
-    return sabre_solve_parallel(
+    progress: Option<&Progress>,
+) -> Result<CompilerResult<NisqGateImplementation>, Vec<PreflightError>> {
+    validate_circuit(c, a)?;
+    Ok(sabre_solve_parallel(
         c,
         a,
         &|s| nisq_transitions(s, a),
@@ -216,10 +587,141 @@ pub fn nisq_solve_sabre_par(
         nisq_step_cost,
         Some(mapping_heuristic),
         false,
-    );
+        &HashMap::new(),
+        progress,
+    ))
+}
+pub fn nisq_solve(c: &Circuit, a: &NisqArchitecture, progress: Option<&Progress>) -> Result<CompilerResult<NisqGateImplementation>, Vec<PreflightError>> {
+    nisq_solve_with_init(c, a, &InitialMapStrategy::Parallel, progress)
 }
-pub fn nisq_solve(c: &Circuit, a: &NisqArchitecture) -> CompilerResult<NisqGateImplementation> {
-    return solve(
+
+/// The initial map `nisq_solve` would route from and its mapping heuristic cost.
+pub fn nisq_plan_mapping(c: &Circuit, a: &NisqArchitecture) -> (QubitMap, f64) {
+    plan_mapping(c, a, mapping_heuristic, &HashMap::new())
+}
+
+/// `nisq_solve` starting from the map `initial_map` picks.
+pub fn nisq_solve_with_init(
+    c: &Circuit,
+    a: &NisqArchitecture,
+    initial_map: &InitialMapStrategy,
+    progress: Option<&Progress>,
+) -> Result<CompilerResult<NisqGateImplementation>, Vec<PreflightError>> {
+    validate_circuit(c, a)?;
+    Ok(solve(
+        c,
+        a,
+        &|s| nisq_transitions(s, a),
+        &nisq_implement_gate,
+        nisq_step_cost,
+        Some(mapping_heuristic),
+        false,
+        &HashMap::new(),
+        initial_map,
+        progress,
+    ))
+}
+
+/// `nisq_solve_with_init` routing for `objective` instead of the configured one, e.g. for
+/// fewest steps, which for NISQ is the depth of the routed circuit.
+pub fn nisq_solve_with_objective(
+    c: &Circuit,
+    a: &NisqArchitecture,
+    initial_map: &InitialMapStrategy,
+    objective: RoutingObjective,
+) -> Result<CompilerResult<NisqGateImplementation>, Vec<PreflightError>> {
+    validate_circuit(c, a)?;
+    Ok(solve_with_objective(
+        c,
+        a,
+        &|s| nisq_transitions(s, a),
+        &nisq_implement_gate,
+        nisq_step_cost,
+        Some(mapping_heuristic),
+        false,
+        &HashMap::new(),
+        initial_map,
+        objective,
+        None,
+    ))
+}
+
+/// `nisq_solve_with_init` starting each qubit of `regions` on one of its listed locations.
+/// Fails if no starting map fits every qubit in its region.
+pub fn nisq_solve_in_regions(
+    c: &Circuit,
+    a: &NisqArchitecture,
+    initial_map: &InitialMapStrategy,
+    regions: &RegionConstraints,
+    progress: Option<&Progress>,
+) -> Result<CompilerResult<NisqGateImplementation>, Vec<PreflightError>> {
+    validate_circuit(c, a)?;
+    check_region_constraints(c, a, regions)?;
+    Ok(solve(
+        c,
+        a,
+        &|s| nisq_transitions(s, a),
+        &nisq_implement_gate,
+        nisq_step_cost,
+        Some(mapping_heuristic),
+        false,
+        regions,
+        initial_map,
+        progress,
+    ))
+}
+
+/// `nisq_solve_sabre` starting each qubit of `regions` on one of its listed locations.
+pub fn nisq_solve_sabre_in_regions(
+    c: &Circuit,
+    a: &NisqArchitecture,
+    regions: &RegionConstraints,
+    progress: Option<&Progress>,
+) -> Result<CompilerResult<NisqGateImplementation>, Vec<PreflightError>> {
+    validate_circuit(c, a)?;
+    check_region_constraints(c, a, regions)?;
+    Ok(sabre_solve(
+        c,
+        a,
+        &|s| nisq_transitions(s, a),
+        &nisq_implement_gate,
+        nisq_step_cost,
+        Some(mapping_heuristic),
+        false,
+        regions,
+        progress,
+    ))
+}
+
+/// `nisq_solve` routing from the caller's `map` rather than a searched one.
+pub fn nisq_solve_with_map(
+    c: &Circuit,
+    a: &NisqArchitecture,
+    map: &QubitMap,
+) -> Result<CompilerResult<NisqGateImplementation>, InitialMapError> {
+    solve_with_map(
+        c,
+        a,
+        &|s| nisq_transitions(s, a),
+        &nisq_implement_gate,
+        nisq_step_cost,
+        Some(mapping_heuristic),
+        false,
+        map,
+        None,
+    )
+}
+
+/// `nisq_solve` for an edited circuit, keeping the steps of `prev` up to the first
+/// edited gate and routing only the rest.
+pub fn nisq_resolve_incremental(
+    prev: &CompilerResult<NisqGateImplementation>,
+    c: &Circuit,
+    a: &NisqArchitecture,
+) -> Result<CompilerResult<NisqGateImplementation>, Vec<PreflightError>> {
+    validate_circuit(c, a)?;
+    Ok(resolve_incremental(
+        prev,
         c,
         a,
         &|s| nisq_transitions(s, a),
@@ -227,11 +729,192 @@ pub fn nisq_solve(c: &Circuit, a: &NisqArchitecture) -> CompilerResult<NisqGateI
         nisq_step_cost,
         Some(mapping_heuristic),
         false,
-    );
+        &HashMap::new(),
+        None,
+    ))
+}
+
+type SearchKey = (Vec<(usize, usize)>, Vec<usize>);
+
+fn search_key(map: &QubitMap, remaining: &Circuit) -> SearchKey {
+    let mut pairs: Vec<_> = map
+        .iter()
+        .map(|(q, l)| (q.get_index(), l.get_index()))
+        .collect();
+    pairs.sort();
+    (pairs, remaining.gates.iter().map(|g| g.id).collect())
+}
+
+/// Splits off every gate runnable on `map` without further swaps, one layer per step.
+fn executable_layers(
+    map: &QubitMap,
+    remaining: &mut Circuit,
+    arch: &NisqArchitecture,
+) -> Vec<HashSet<ImplementedGate<NisqGateImplementation>>> {
+    let mut layers = Vec::new();
+    loop {
+        let step = Step {
+            map: map.clone(),
+            implemented_gates: HashSet::new(),
+        };
+        let mut layer = HashSet::new();
+        for gate in remaining.get_front_layer() {
+            if let Some(implementation) = nisq_implement_gate(&step, arch, &gate).pop() {
+                layer.insert(ImplementedGate {
+                    gate,
+                    implementation,
+                });
+            }
+        }
+        if layer.is_empty() {
+            return layers;
+        }
+        remaining.remove_gates(&layer.iter().map(|ig| ig.gate.clone()).collect());
+        layers.push(layer);
+    }
+}
+
+/// Twice a lower bound on the swaps left: each front-layer gate at distance `d` needs `d - 1`
+/// more swaps, and one swap moves two qubits closer.
+fn front_layer_bound(
+    map: &QubitMap,
+    remaining: &Circuit,
+    arch: &NisqArchitecture,
+) -> usize {
+    let mut bound = 0;
+    for gate in remaining.get_front_layer().iter().filter(|g| g.qubits.len() == 2) {
+        bound += arch.distance(&map[&gate.qubits[0]], &map[&gate.qubits[1]]) - 1;
+    }
+    bound
+}
+
+/// Why `nisq_solve_optimal` gave no result; `nisq_solve` still routes such circuits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OptimalRoutingError {
+    Preflight(Vec<PreflightError>),
+    /// The circuit has more qubits than `optimal_routing_max_qubits`.
+    TooManyQubits { qubits: usize, max: usize },
+    /// The search explored `optimal_routing_max_states` states without finishing.
+    StateBudgetExceeded(usize),
+    /// No sequence of swaps implements every gate.
+    Unroutable,
+}
+
+/// Routes `c` from the map chosen by `nisq_solve` with the fewest possible swaps, using A*
+/// over `(map, remaining gates)` states. Refuses circuits with more qubits than
+/// `optimal_routing_max_qubits`, and gives up after `optimal_routing_max_states` states.
+pub fn nisq_solve_optimal(
+    c: &Circuit,
+    a: &NisqArchitecture,
+) -> Result<CompilerResult<NisqGateImplementation>, OptimalRoutingError> {
+    if c.qubits.len() > CONFIG.load().optimal_routing_max_qubits {
+        return Err(OptimalRoutingError::TooManyQubits {
+            qubits: c.qubits.len(),
+            max: CONFIG.load().optimal_routing_max_qubits,
+        });
+    }
+    let start_map = nisq_solve(c, a, None).map_err(OptimalRoutingError::Preflight)?.steps[0].map.clone();
+    let mut start_remaining = c.clone();
+    executable_layers(&start_map, &mut start_remaining, a);
+
+    // the frontier holds (twice the A* priority, index into `states`)
+    let mut frontier = BinaryHeap::new();
+    let mut states = Vec::new();
+    let mut best_swaps = HashMap::new();
+    let mut parents: HashMap<SearchKey, (SearchKey, (Location, Location))> = HashMap::new();
+    let start_key = search_key(&start_map, &start_remaining);
+    best_swaps.insert(start_key.clone(), 0);
+    frontier.push(Reverse((
+        front_layer_bound(&start_map, &start_remaining, a),
+        0,
+    )));
+    states.push((start_key.clone(), start_map.clone(), start_remaining, 0));
+    let mut goal = None;
+    while let Some(Reverse((_, index))) = frontier.pop() {
+        let (key, map, remaining, swaps) = states[index].clone();
+        if remaining.gates.is_empty() {
+            goal = Some(key);
+            break;
+        }
+        if swaps > best_swaps[&key] {
+            continue;
+        }
+        let step = Step {
+            map: map.clone(),
+            implemented_gates: HashSet::new(),
+        };
+        for trans in nisq_transitions(&step, a) {
+            if trans.edge.0 == trans.edge.1 {
+                continue;
+            }
+            let next_map = swap_on_edge(&map, trans.edge);
+            let mut next_remaining = remaining.clone();
+            executable_layers(&next_map, &mut next_remaining, a);
+            let next_key = search_key(&next_map, &next_remaining);
+            if best_swaps.get(&next_key).is_some_and(|s| *s <= swaps + 1) {
+                continue;
+            }
+            best_swaps.insert(next_key.clone(), swaps + 1);
+            parents.insert(next_key.clone(), (key.clone(), trans.edge));
+            let priority =
+                2 * (swaps + 1) + front_layer_bound(&next_map, &next_remaining, a);
+            frontier.push(Reverse((priority, states.len())));
+            states.push((next_key, next_map, next_remaining, swaps + 1));
+        }
+        if states.len() > CONFIG.load().optimal_routing_max_states {
+            return Err(OptimalRoutingError::StateBudgetExceeded(CONFIG.load().optimal_routing_max_states));
+        }
+    }
+    let mut key = goal.ok_or(OptimalRoutingError::Unroutable)?;
+    let mut swaps = Vec::new();
+    while key != start_key {
+        let (parent, edge) = parents[&key].clone();
+        swaps.push(edge);
+        key = parent;
+    }
+    swaps.reverse();
+
+    let mut steps = Vec::new();
+    let mut transitions = Vec::new();
+    let mut remaining = c.clone();
+    let mut map = start_map;
+    for i in 0..=swaps.len() {
+        if i > 0 {
+            let trans = NisqTrans { edge: swaps[i - 1] };
+            map = swap_on_edge(&map, trans.edge);
+            transitions.push(trans.repr());
+        }
+        let mut layers = executable_layers(&map, &mut remaining, a).into_iter();
+        steps.push(Step {
+            map: map.clone(),
+            implemented_gates: layers.next().unwrap_or_default(),
+        });
+        for layer in layers {
+            transitions.push(
+                NisqTrans {
+                    edge: (Location::new(0), Location::new(0)),
+                }
+                .repr(),
+            );
+            steps.push(Step {
+                map: map.clone(),
+                implemented_gates: layer,
+            });
+        }
+    }
+    Ok(CompilerResult {
+        steps,
+        transitions,
+        cost: swaps.len() as f64,
+        candidate_counts: Vec::new(),
+        partial: false,
+        makespan: None,
+    })
 }
 
-pub fn nisq_solve_cached_heuristic(c: &Circuit, a: &NisqArchitecture) -> CompilerResult<NisqGateImplementation> {
-    return solve_with_cached_heuristic(
+pub fn nisq_solve_cached_heuristic(c: &Circuit, a: &NisqArchitecture, progress: Option<&Progress>) -> Result<CompilerResult<NisqGateImplementation>, Vec<PreflightError>> {
+    validate_circuit(c, a)?;
+    Ok(solve_with_cached_heuristic(
         c,
         a,
         &|s| nisq_transitions(s, a),
@@ -240,11 +923,14 @@ pub fn nisq_solve_cached_heuristic(c: &Circuit, a: &NisqArchitecture) -> Compile
         Some(mapping_heuristic),
         |map, mv| delta_on_move(map, mv, c, a),
         false,
-    );
+        &HashMap::new(),
+        progress,
+    ))
 }
 
-pub fn nisq_solve_joint_optimize(c: &Circuit, a: &NisqArchitecture) -> CompilerResult<NisqGateImplementation> {
-    return solve_joint_optimize(
+pub fn nisq_solve_joint_optimize(c: &Circuit, a: &NisqArchitecture, progress: Option<&Progress>) -> Result<CompilerResult<NisqGateImplementation>, Vec<PreflightError>> {
+    validate_circuit(c, a)?;
+    Ok(solve_joint_optimize(
         c,
         a,
         &|s| nisq_transitions(s, a),
@@ -252,12 +938,15 @@ pub fn nisq_solve_joint_optimize(c: &Circuit, a: &NisqArchitecture) -> CompilerR
         nisq_step_cost,
         Some(mapping_heuristic),
         false,
-        0
-    );
+        &HashMap::new(),
+        0,
+        progress,
+    ))
 }
 
-pub fn nisq_solve_joint_optimize_parallel(c: &Circuit, a: &NisqArchitecture) -> CompilerResult<NisqGateImplementation> {
-    return solve_joint_optimize_parallel(
+pub fn nisq_solve_joint_optimize_parallel(c: &Circuit, a: &NisqArchitecture, progress: Option<&Progress>) -> Result<CompilerResult<NisqGateImplementation>, Vec<PreflightError>> {
+    validate_circuit(c, a)?;
+    Ok(solve_joint_optimize_parallel(
         c,
         a,
         &|s| nisq_transitions(s, a),
@@ -265,5 +954,7 @@ pub fn nisq_solve_joint_optimize_parallel(c: &Circuit, a: &NisqArchitecture) ->
         nisq_step_cost,
         Some(mapping_heuristic),
         false,
-    );
+        &HashMap::new(),
+        progress,
+    ))
 }
\ No newline at end of file
@@ -1,10 +1,13 @@
+use itertools::Itertools;
 use serde::Serialize;
 
 use solver::{
     backend::{sabre_solve, solve, solve_joint_optimize_parallel},
+    config::CONFIG,
     structures::*,
 };
 use std::collections::{HashMap, HashSet};
+use std::iter::zip;
 
 const ACCELERATION_CONST: f64 = 2750.0;
 const ATOM_TRANSFER_FIDELITY: f64 = 0.999;
@@ -82,6 +85,9 @@ impl Architecture for RaaArchitecture {
         }
         return (g, index_map);
     }
+    fn supported_gate_types(&self) -> Vec<GateType> {
+        vec![GateType::CX]
+    }
 }
 struct IdTransition;
 #[derive(Clone, Debug, Serialize, PartialEq, Eq, Hash)]
@@ -90,7 +96,22 @@ pub struct RaaGateImplementation {
     dst: Location,
 }
 
-impl GateImplementation for RaaGateImplementation {}
+impl RaaGateImplementation {
+    /// The AOD atom at `src` is carried onto the SLM atom at `dst`.
+    pub fn new(src: Location, dst: Location) -> Self {
+        RaaGateImplementation { src, dst }
+    }
+}
+
+impl GateImplementation for RaaGateImplementation {
+    /// The AOD atom's site and the SLM site it is carried onto.
+    fn occupied_locations(&self) -> Vec<Location> {
+        if self.src == self.dst {
+            return vec![self.src];
+        }
+        vec![self.src, self.dst]
+    }
+}
 
 type RaaStep = Step<RaaGateImplementation>;
 
@@ -114,9 +135,11 @@ fn raa_transitions() -> Vec<IdTransition> {
     return vec![IdTransition];
 }
 #[derive(Clone, Debug)]
-struct RaaMove {
+pub struct RaaMove {
     qubit: Qubit,
     dst: Location,
+    /// The moved qubit sits in an SLM trap and has to be handed to the AOD and back.
+    from_slm: bool,
     cost: f64,
 }
 
@@ -128,6 +151,9 @@ impl Transition<RaaGateImplementation, RaaArchitecture> for RaaMove {
         return new_step;
     }
     fn repr(&self) -> String {
+        if self.from_slm {
+            return format!("RELOCATE_SLM {:?} {:?}", self.qubit, self.dst);
+        }
         format!("RELOCATE {:?} {:?}", self.qubit, self.dst)
     }
 
@@ -136,11 +162,205 @@ impl Transition<RaaGateImplementation, RaaArchitecture> for RaaMove {
     }
 }
 
-fn raa_transitions_dyn_map(step: &RaaStep, arch: &RaaArchitecture) -> Vec<RaaMove> {
+/// Shifts every mapped qubit on AOD row `row` to row `row + delta` in one shuttle.
+#[derive(Clone, Debug)]
+pub struct RaaRowMove {
+    row: usize,
+    delta: isize,
+    /// The shifted qubits and where they land.
+    moved: Vec<(Qubit, Location)>,
+    cost: f64,
+}
+
+/// Shifts every mapped qubit on AOD column `col` to column `col + delta` in one shuttle.
+#[derive(Clone, Debug)]
+pub struct RaaColMove {
+    col: usize,
+    delta: isize,
+    /// The shifted qubits and where they land.
+    moved: Vec<(Qubit, Location)>,
+    cost: f64,
+}
+
+fn relocate_all(step: &RaaStep, moved: &[(Qubit, Location)]) -> RaaStep {
+    let mut new_step = step.clone();
+    for (q, dst) in moved {
+        new_step.map.insert(*q, *dst);
+    }
+    new_step.implemented_gates = HashSet::new();
+    new_step
+}
+
+impl Transition<RaaGateImplementation, RaaArchitecture> for RaaRowMove {
+    fn apply(&self, step: &RaaStep) -> RaaStep {
+        relocate_all(step, &self.moved)
+    }
+    fn repr(&self) -> String {
+        format!("SHIFT_ROW {} {:+}", self.row, self.delta)
+    }
+
+    fn cost(&self, _arch: &RaaArchitecture) -> f64 {
+        self.cost
+    }
+}
+
+impl Transition<RaaGateImplementation, RaaArchitecture> for RaaColMove {
+    fn apply(&self, step: &RaaStep) -> RaaStep {
+        relocate_all(step, &self.moved)
+    }
+    fn repr(&self) -> String {
+        format!("SHIFT_COL {} {:+}", self.col, self.delta)
+    }
+
+    fn cost(&self, _arch: &RaaArchitecture) -> f64 {
+        self.cost
+    }
+}
+
+/// The transitions `raa_transitions_dyn_map` offers: single-qubit relocations and whole
+/// row/column shifts.
+#[derive(Clone, Debug)]
+pub enum RaaTransition {
+    Move(RaaMove),
+    RowMove(RaaRowMove),
+    ColMove(RaaColMove),
+}
+
+impl Transition<RaaGateImplementation, RaaArchitecture> for RaaTransition {
+    fn apply(&self, step: &RaaStep) -> RaaStep {
+        match self {
+            RaaTransition::Move(t) => t.apply(step),
+            RaaTransition::RowMove(t) => t.apply(step),
+            RaaTransition::ColMove(t) => t.apply(step),
+        }
+    }
+    fn repr(&self) -> String {
+        match self {
+            RaaTransition::Move(t) => t.repr(),
+            RaaTransition::RowMove(t) => t.repr(),
+            RaaTransition::ColMove(t) => t.repr(),
+        }
+    }
+
+    fn cost(&self, arch: &RaaArchitecture) -> f64 {
+        match self {
+            RaaTransition::Move(t) => t.cost(arch),
+            RaaTransition::RowMove(t) => t.cost(arch),
+            RaaTransition::ColMove(t) => t.cost(arch),
+        }
+    }
+}
+
+/// Straight-line distance between two sites, in trap spacings.
+fn euclidean_distance(src: Location, dst: Location, arch: &RaaArchitecture) -> f64 {
+    let src_coords = (src.get_index() / arch.height, src.get_index() % arch.height);
+    let dst_coords = (dst.get_index() / arch.height, dst.get_index() % arch.height);
+    f64::sqrt(
+        (src_coords.0 as f64 - dst_coords.0 as f64).powi(2)
+            + (src_coords.1 as f64 - dst_coords.1 as f64).powi(2),
+    )
+}
+
+/// Seconds to shuttle an atom across `dist` trap spacings.
+fn move_time(dist: f64) -> f64 {
+    f64::sqrt(2.5 * RYDBERG_RADIUS * dist / ACCELERATION_CONST)
+}
+
+/// Decoherence cost of shuttling an AOD atom from `src` to `dst`.
+fn shuttle_cost(src: Location, dst: Location, arch: &RaaArchitecture) -> f64 {
+    let move_time = move_time(euclidean_distance(src, dst, arch));
+    -f64::ln(1.0 - move_time / T2)
+}
+
+/// Total shuttle time of `steps` in seconds. The AOD moves all of a step's atoms at once,
+/// so each step takes as long as its longest move, and so does each relocation or line
+/// shift between two steps, read off the qubits whose sites change. A gate's AOD atom
+/// leaves from the SLM site it was carried onto, and any other atom pays the transfers
+/// into the AOD and back.
+pub fn raa_makespan(steps: &[Step<RaaGateImplementation>], arch: &RaaArchitecture) -> f64 {
+    let gate_time = |step: &RaaStep| {
+        let max_dist = step
+            .implemented_gates
+            .iter()
+            .map(|g| euclidean_distance(g.implementation.src, g.implementation.dst, arch))
+            .fold(0.0, f64::max);
+        move_time(max_dist)
+    };
+    let transition_time = |before: &RaaStep, after: &RaaStep| {
+        let mut max_time: f64 = 0.0;
+        for (q, dst) in &after.map {
+            let Some(src) = before.map.get(q).filter(|src| *src != dst) else {
+                continue;
+            };
+            let gate_dst = before
+                .implemented_gates
+                .iter()
+                .find(|g| g.implementation.src == *src)
+                .map(|g| g.implementation.dst);
+            let from_slm = gate_dst.is_none();
+            let time = move_time(euclidean_distance(gate_dst.unwrap_or(*src), *dst, arch))
+                + if from_slm { 2.0 * ATOM_TRANSFER_TIME } else { 0.0 };
+            max_time = max_time.max(time);
+        }
+        max_time
+    };
+    let transitions: f64 = steps.iter().tuple_windows().map(|(b, a)| transition_time(b, a)).sum();
+    steps.iter().map(gate_time).sum::<f64>() + transitions
+}
+
+/// Extra cost of moving an SLM atom: a transfer into the AOD and one back out.
+pub fn slm_transfer_cost() -> f64 {
+    -2.0 * f64::ln(ATOM_TRANSFER_FIDELITY)
+        - f64::ln(1.0 - 2.0 * ATOM_TRANSFER_TIME / T2)
+}
+
+/// Grid distance between two sites, in trap spacings along each axis.
+fn manhattan_distance(a: Location, b: Location, arch: &RaaArchitecture) -> usize {
+    let (a, b) = (a.get_index(), b.get_index());
+    (a / arch.height).abs_diff(b / arch.height) + (a % arch.height).abs_diff(b % arch.height)
+}
+
+/// `(column, row)` of a site, in the order `consistent` expects.
+fn coords(loc: Location, arch: &RaaArchitecture) -> (usize, usize) {
+    (loc.get_index() / arch.height, loc.get_index() % arch.height)
+}
+
+/// AOD row and column displacements of the gates in `step`, other than `skip`.
+fn committed_displacements(
+    step: &RaaStep,
+    arch: &RaaArchitecture,
+    skip: Option<&RaaGateImplementation>,
+) -> (HashMap<usize, usize>, HashMap<usize, usize>) {
+    let mut row_displacements = HashMap::new();
+    let mut col_displacements = HashMap::new();
+    // two gates can share a row or column, and the later one wins, so go in id order
+    for gi in step.implemented_gates.iter().sorted_by_key(|gi| gi.gate.id) {
+        if Some(&gi.implementation) == skip {
+            continue;
+        }
+        let src = coords(gi.implementation.src, arch);
+        let dst = coords(gi.implementation.dst, arch);
+        row_displacements.insert(src.1, dst.1);
+        col_displacements.insert(src.0, dst.0);
+    }
+    (row_displacements, col_displacements)
+}
+
+/// Relocations of either atom of each gate in `step` to a free site, keeping only sites
+/// within `radius` (Manhattan) of the gate when a radius is given, followed by the row and
+/// column shifts of `raa_line_shifts` when `raa_line_shifts` is set in config. A relocation has to keep the AOD rows and columns
+/// of the step's other gates ordered (see `consistent`); the AOD atom can always return to
+/// the site it came from.
+pub fn raa_transitions_dyn_map(
+    step: &Step<RaaGateImplementation>,
+    arch: &RaaArchitecture,
+    radius: Option<usize>,
+) -> Vec<RaaTransition> {
     let mut moves = Vec::new();
     let impls = step
         .implemented_gates
         .iter()
+        .sorted_by_key(|gi| gi.gate.id)
         .map(|gi| gi.implementation.clone());
     for raa_move in impls {
         let aod_qubit = step
@@ -155,30 +375,105 @@ fn raa_transitions_dyn_map(step: &RaaStep, arch: &RaaArchitecture) -> Vec<RaaMov
             .find(|(_q, l)| *l == &raa_move.dst)
             .unwrap()
             .0;
-        for dst in arch.locations() {
-            if !(step.map.values().any(|v| v == &dst && v != &raa_move.src)) {
-                let src_coords = (
-                    step.map.get(slm_qubit).unwrap().get_index() / arch.height,
-                    step.map.get(slm_qubit).unwrap().get_index() % arch.height,
-                );
-                let dst_coords = (dst.get_index() / arch.height, dst.get_index() % arch.height);
-                let dist = f64::sqrt(
-                    (src_coords.0 as f64 - dst_coords.0 as f64).powi(2)
-                        + (src_coords.1 as f64 - dst_coords.1 as f64).powi(2),
-                );
-                let move_time = f64::sqrt(2.5 * RYDBERG_RADIUS * dist / ACCELERATION_CONST);
-                let cost = -f64::ln(1.0 - move_time / T2);
-                moves.push(RaaMove {
+        let in_reach = |dst: &Location| {
+            radius.is_none_or(|r| manhattan_distance(raa_move.dst, *dst, arch) <= r)
+        };
+        let (row_displacements, col_displacements) =
+            committed_displacements(step, arch, Some(&raa_move));
+        for dst in arch.locations().into_iter().filter(in_reach) {
+            let shuttle = (coords(raa_move.dst, arch), coords(dst, arch));
+            let ordered = consistent(shuttle, &row_displacements, &col_displacements);
+            // the AOD atom sits at the SLM site after the gate, leaving its own site free
+            if (ordered || dst == raa_move.src)
+                && !(step.map.values().any(|v| v == &dst && v != &raa_move.src))
+            {
+                moves.push(RaaTransition::Move(RaaMove {
                     qubit: *aod_qubit,
                     dst,
-                    cost,
-                });
+                    from_slm: false,
+                    cost: shuttle_cost(raa_move.dst, dst, arch),
+                }));
+            }
+            if ordered && !step.map.values().any(|v| v == &dst) {
+                moves.push(RaaTransition::Move(RaaMove {
+                    qubit: *slm_qubit,
+                    dst,
+                    from_slm: true,
+                    cost: shuttle_cost(raa_move.dst, dst, arch) + slm_transfer_cost(),
+                }));
             }
         }
     }
+    if CONFIG.load().raa_line_shifts {
+        moves.extend(raa_line_shifts(step, arch, radius));
+    }
     return moves;
 }
 
+/// Shifts of a whole AOD row or column by a fixed offset, for the cost of a single shuttle.
+/// Only atoms held in the AOD move: the AOD atoms of the step's gates ride along for free,
+/// idle atoms on the line are handed over from the SLM and back for `slm_transfer_cost`
+/// each, and the SLM atoms the gates land on stay put. Each shifted qubit has to land on a
+/// free site, keep the step's AOD rows and columns ordered (see `consistent`) and, when
+/// `radius` is given, move at most that many sites.
+fn raa_line_shifts(step: &RaaStep, arch: &RaaArchitecture, radius: Option<usize>) -> Vec<RaaTransition> {
+    let (row_displacements, col_displacements) = committed_displacements(step, arch, None);
+    let occupied: HashSet<Location> = step.map.values().copied().collect();
+    let aod_sites: HashSet<Location> = step.implemented_gates.iter().map(|g| g.implementation.src).collect();
+    let slm_sites: HashSet<Location> = step.implemented_gates.iter().map(|g| g.implementation.dst).collect();
+    let at = |col: usize, row: usize| Location::new(col * arch.height + row);
+    let mut shifts = Vec::new();
+    for (rows, lines) in [(true, arch.height), (false, arch.width)] {
+        for line in 0..lines {
+            let on_line: Vec<(Qubit, (usize, usize))> = step
+                .map
+                .iter()
+                .filter(|(_, l)| !slm_sites.contains(l))
+                .map(|(q, l)| (*q, coords(*l, arch)))
+                .filter(|(_, (col, row))| if rows { *row == line } else { *col == line })
+                .sorted_by_key(|(q, _)| q.get_index())
+                .collect();
+            if on_line.is_empty() {
+                continue;
+            }
+            let transfers = on_line.iter().filter(|(_, (col, row))| !aod_sites.contains(&at(*col, *row))).count();
+            for target in 0..lines {
+                if target == line || radius.is_some_and(|r| target.abs_diff(line) > r) {
+                    continue;
+                }
+                let shuttles: Vec<((usize, usize), (usize, usize))> = on_line
+                    .iter()
+                    .map(|(_, (col, row))| {
+                        if rows {
+                            ((*col, *row), (*col, target))
+                        } else {
+                            ((*col, *row), (target, *row))
+                        }
+                    })
+                    .collect();
+                let blocked = shuttles.iter().any(|(_, (col, row))| occupied.contains(&at(*col, *row)))
+                    || !shuttles
+                        .iter()
+                        .all(|s| consistent(*s, &row_displacements, &col_displacements));
+                if blocked {
+                    continue;
+                }
+                let moved = zip(&on_line, &shuttles).map(|((q, _), (_, (col, row)))| (*q, at(*col, *row))).collect();
+                let ((src_col, src_row), (dst_col, dst_row)) = shuttles[0];
+                let cost = shuttle_cost(at(src_col, src_row), at(dst_col, dst_row), arch)
+                    + transfers as f64 * slm_transfer_cost();
+                let delta = target as isize - line as isize;
+                shifts.push(if rows {
+                    RaaTransition::RowMove(RaaRowMove { row: line, delta, moved, cost })
+                } else {
+                    RaaTransition::ColMove(RaaColMove { col: line, delta, moved, cost })
+                });
+            }
+        }
+    }
+    shifts
+}
+
 fn consistent(
     shuttle: ((usize, usize), (usize, usize)),
     row_displacements: &HashMap<usize, usize>,
@@ -187,10 +482,11 @@ fn consistent(
     let (src, dst) = shuttle;
     let (src_col, src_row) = src;
     let (dst_col, dst_row) = dst;
+    // two lines can land on one, so invert in key order to keep the same one each run
     let inverse_col_displacements: HashMap<usize, usize> =
-        col_displacements.iter().map(|(k, v)| (*v, *k)).collect();
+        col_displacements.iter().sorted().map(|(k, v)| (*v, *k)).collect();
     let inverse_row_displacements: HashMap<usize, usize> =
-        row_displacements.iter().map(|(k, v)| (*v, *k)).collect();
+        row_displacements.iter().sorted().map(|(k, v)| (*v, *k)).collect();
     if col_displacements.contains_key(&src_col) && col_displacements[&src_col] != dst_col {
         return false;
     } else if inverse_col_displacements.contains_key(&dst_col)
@@ -260,7 +556,7 @@ fn raa_implement_gate(
     );
     let mut row_displacements: HashMap<usize, usize> = HashMap::new();
     let mut col_displacements: HashMap<usize, usize> = HashMap::new();
-    let existing_moves = step.implemented_gates.iter().map(|g| {
+    let existing_moves = step.implemented_gates.iter().sorted_by_key(|g| g.gate.id).map(|g| {
         (
             (
                 g.implementation.src.get_index() / arch.height,
@@ -336,31 +632,42 @@ fn raa_step_cost(step: &RaaStep, arch: &RaaArchitecture) -> f64 {
     return cost;
 }
 
-pub fn raa_solve(c: &Circuit, arch: &RaaArchitecture) -> CompilerResult<RaaGateImplementation> {
-    solve(
+pub fn raa_solve(c: &Circuit, arch: &RaaArchitecture) -> Result<CompilerResult<RaaGateImplementation>, Vec<PreflightError>> {
+    validate_circuit(c, arch)?;
+    let mut res = solve(
         c,
         arch,
-        &|s| raa_transitions_dyn_map(s, arch),
+        &|s| raa_transitions_dyn_map(s, arch, CONFIG.load().raa_shuttle_radius),
         &raa_implement_gate,
         raa_step_cost,
         None,
         true,
-    )
+        &HashMap::new(),
+        &InitialMapStrategy::Parallel,
+        None,
+    );
+    res.makespan = Some(raa_makespan(&res.steps, arch));
+    Ok(res)
 }
 
 pub fn raa_solve_sabre(
     c: &Circuit,
     arch: &RaaArchitecture,
-) -> CompilerResult<RaaGateImplementation> {
-    sabre_solve(
+) -> Result<CompilerResult<RaaGateImplementation>, Vec<PreflightError>> {
+    validate_circuit(c, arch)?;
+    let mut res = sabre_solve(
         c,
         arch,
-        &|s| raa_transitions_dyn_map(s, arch),
+        &|s| raa_transitions_dyn_map(s, arch, CONFIG.load().raa_shuttle_radius),
         &raa_implement_gate,
         raa_step_cost,
         None,
         true,
-    )
+        &HashMap::new(),
+        None,
+    );
+    res.makespan = Some(raa_makespan(&res.steps, arch));
+    Ok(res)
 }
 
 
@@ -388,14 +695,19 @@ fn mapping_heuristic(arch: &RaaArchitecture, c: &Circuit, map: &HashMap<Qubit, L
 pub fn raa_joint_optimize_parallel(
     c: &Circuit,
     arch: &RaaArchitecture,
-) -> CompilerResult<RaaGateImplementation> {
-    solve_joint_optimize_parallel(
+) -> Result<CompilerResult<RaaGateImplementation>, Vec<PreflightError>> {
+    validate_circuit(c, arch)?;
+    let mut res = solve_joint_optimize_parallel(
         c,
         arch,
-        &|s| raa_transitions_dyn_map(s, arch),
+        &|s| raa_transitions_dyn_map(s, arch, CONFIG.load().raa_shuttle_radius),
         &raa_implement_gate,
         raa_step_cost,
         Some(mapping_heuristic),
         true,
-    )
+        &HashMap::new(),
+        None,
+    );
+    res.makespan = Some(raa_makespan(&res.steps, arch));
+    Ok(res)
 }
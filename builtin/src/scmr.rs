@@ -1,19 +1,43 @@
 use std::{
     collections::{HashMap, HashSet},
     iter::empty,
+    sync::OnceLock,
 };
 
 use itertools::{sorted, Itertools};
 use petgraph::{algo::all_simple_paths, graph::NodeIndex, Graph};
 use serde::Serialize;
 
-use solver::{backend::{solve, solve_joint_optimize, solve_joint_optimize_parallel, solve_parallel}, structures::*, utils::*};
+use solver::{backend::{solve, solve_joint_optimize_parallel, solve_parallel}, config::CONFIG, structures::*, utils::*};
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FactoryKind {
+    /// Distills T states, consumed by T gates and pi/8 Pauli rotations.
+    T,
+    /// Distills CCZ states for Toffoli-type gates.
+    CCZ,
+}
+
 #[derive(Debug, Serialize, Clone)]
 pub struct ScmrArchitecture {
     pub width: usize,
     pub height: usize,
     pub alg_qubits: Vec<Location>,
-    pub magic_state_qubits: Vec<Location>,
+    factories: Vec<(Location, FactoryKind)>,
+    excluded: Vec<Location>,
+    /// Also try routing CX from a horizontal neighbor of the control to a vertical neighbor of
+    /// the target, keeping whichever orientation gives the shorter path.
+    pub cx_both_orientations: bool,
+    /// Error charged per idle patch per round; zero leaves the step cost at one per step.
+    pub idle_patch_cost: f64,
+    /// Stabilizer rounds in one step, usually the code distance.
+    pub step_duration: f64,
+    /// Weight of the congestion charge, which grows with the ratio of routing cells a step
+    /// fills to those it leaves free; zero, the layouts' default, ignores congestion.
+    pub congestion_weight: f64,
+    /// `routing_cells`, worked out on first use and dropped by `set_factories` and
+    /// `set_excluded`.
+    #[serde(skip)]
+    routing_cells_cache: OnceLock<HashSet<Location>>,
 }
 
 impl Architecture for ScmrArchitecture {
@@ -24,8 +48,81 @@ impl Architecture for ScmrArchitecture {
     fn graph(&self) -> (Graph<Location, ()>, HashMap<Location, NodeIndex>) {
         return self.get_graph();
     }
+    fn supported_gate_types(&self) -> Vec<GateType> {
+        let mut types = vec![GateType::CX, GateType::T, GateType::Measure];
+        if !self.factories_of(FactoryKind::CCZ).is_empty() {
+            types.push(GateType::CCX);
+        }
+        types
+    }
+    fn blocked_locations(&self) -> Vec<Location> {
+        self.factory_locations()
+    }
 }
 impl ScmrArchitecture {
+    /// A layout with every cell routable and the default tuning: CX in one orientation, no
+    /// idle or congestion charge, and one round per step.
+    pub fn new(width: usize, height: usize, alg_qubits: Vec<Location>, factories: Vec<(Location, FactoryKind)>) -> Self {
+        ScmrArchitecture {
+            width,
+            height,
+            alg_qubits,
+            factories,
+            excluded: vec![],
+            cx_both_orientations: false,
+            idle_patch_cost: 0.0,
+            step_duration: 1.0,
+            congestion_weight: 0.0,
+            routing_cells_cache: OnceLock::new(),
+        }
+    }
+    /// `step` drawn on this lattice, with the factories as magic states.
+    pub fn render_step(&self, step: &Step<ScmrGateImplementation>) -> String {
+        render_step_ascii(step, self.width, self.height, &self.factory_locations())
+    }
+    /// The factories and what each produces.
+    pub fn factories(&self) -> &[(Location, FactoryKind)] {
+        &self.factories
+    }
+    pub fn set_factories(&mut self, factories: Vec<(Location, FactoryKind)>) {
+        self.factories = factories;
+        self.routing_cells_cache = OnceLock::new();
+    }
+    /// Cells left out of the routing graph, e.g. those outside the region given to `restrict`.
+    pub fn excluded(&self) -> &[Location] {
+        &self.excluded
+    }
+    pub fn set_excluded(&mut self, excluded: Vec<Location>) {
+        self.excluded = excluded;
+        self.routing_cells_cache = OnceLock::new();
+    }
+    /// Every factory location, whatever it produces.
+    pub fn factory_locations(&self) -> Vec<Location> {
+        self.factories.iter().map(|(loc, _)| *loc).collect()
+    }
+    /// The factory locations, from when every factory made T states.
+    #[deprecated(note = "use `factory_locations` or `factories_of`")]
+    pub fn magic_state_qubits(&self) -> Vec<Location> {
+        self.factory_locations()
+    }
+    /// Cells a path may use as an endpoint: the grid minus `excluded` cells and factories.
+    pub fn routing_cells(&self) -> &HashSet<Location> {
+        self.routing_cells_cache.get_or_init(|| {
+            let factories: HashSet<Location> = self.factory_locations().into_iter().collect();
+            (0..self.width * self.height)
+                .map(Location::new)
+                .filter(|l| !self.excluded.contains(l) && !factories.contains(l))
+                .collect()
+        })
+    }
+    pub fn factories_of(&self, kind: FactoryKind) -> Vec<Location> {
+        self
+            .factories
+            .iter()
+            .filter(|(_, k)| *k == kind)
+            .map(|(loc, _)| *loc)
+            .collect()
+    }
     fn get_graph(&self) -> (Graph<Location, ()>, HashMap<Location, NodeIndex>) {
         let mut g = Graph::new();
         let mut index_map = HashMap::new();
@@ -68,17 +165,39 @@ impl ScmrArchitecture {
                 }
             }
         }
+        remove_locations(&mut g, &mut index_map, &self.excluded);
         return (g, index_map);
     }
+    /// This layout cut down to the cells in `allowed`, keeping their locations: qubit slots
+    /// and factories outside it are dropped and paths can't leave it.
+    pub fn restrict(&self, allowed: &HashSet<Location>) -> Self {
+        ScmrArchitecture {
+            alg_qubits: self.alg_qubits.iter().filter(|l| allowed.contains(l)).copied().collect(),
+            factories: self.factories.iter().filter(|(l, _)| allowed.contains(l)).copied().collect(),
+            excluded: (0..self.width * self.height)
+                .map(Location::new)
+                .filter(|l| !allowed.contains(l) || self.excluded.contains(l))
+                .collect(),
+            routing_cells_cache: OnceLock::new(),
+            ..self.clone()
+        }
+    }
 }
 
 pub fn compact_layout(alg_qubit_count: usize) -> ScmrArchitecture {
+    compact_layout_tuned(alg_qubit_count, 1)
+}
+
+/// Two rows of algorithm qubits separated by `routing_rows` rows of routing space, with
+/// a ring of alternating factories and routing cells around them. `compact_layout` is the
+/// single-row case.
+pub fn compact_layout_tuned(alg_qubit_count: usize, routing_rows: usize) -> ScmrArchitecture {
     let width = (2 * alg_qubit_count.div_ceil(2)) + 1;
-    let height = 5;
+    let height = routing_rows + 4;
     let mut alg_qubits = Vec::new();
     for i in (1..width - 1).step_by(2) {
         alg_qubits.push(Location::new(width + i));
-        alg_qubits.push(Location::new(i + width * 3));
+        alg_qubits.push(Location::new(i + width * (height - 2)));
     }
     let mut perimeter = Vec::new();
     let top_edge = (0..width).map(|i| Location::new(i));
@@ -92,16 +211,71 @@ pub fn compact_layout(alg_qubit_count: usize) -> ScmrArchitecture {
     perimeter.extend(bottom_edge);
     perimeter.extend(left_edge);
     // iterate over every other location on the perimeter
-    let mut magic_state_qubits = Vec::new();
+    let mut factories = Vec::new();
     for i in (1..perimeter.len()).step_by(2) {
-        magic_state_qubits.push(perimeter[i]);
+        factories.push((perimeter[i], FactoryKind::T));
     }
-    return ScmrArchitecture {
-        width,
-        height,
-        alg_qubits,
-        magic_state_qubits,
+    ScmrArchitecture::new(width, height, alg_qubits, factories)
+}
+
+/// Drops the outermost rows and columns of `arch` that `res` never touches: no mapped
+/// qubit, no routing path, and no factory a path ends next to. Locations are renumbered
+/// for the smaller grid, and algorithm qubit slots and factories that fall outside it are
+/// dropped. Every path in `res` stays available on the trimmed layout.
+pub fn trim_unused_perimeter(
+    arch: &ScmrArchitecture,
+    res: &CompilerResult<ScmrGateImplementation>,
+) -> ScmrArchitecture {
+    let mut used: HashSet<Location> = HashSet::new();
+    for step in &res.steps {
+        used.extend(step.map.values());
+        for ig in &step.implemented_gates {
+            used.extend(ig.implementation.occupied_locations());
+        }
+    }
+    for (loc, _) in &arch.factories {
+        if horizontal_neighbors(*loc, arch.width).iter().any(|n| used.contains(n)) {
+            used.insert(*loc);
+        }
+    }
+    let coords = |loc: &Location| (loc.get_index() / arch.width, loc.get_index() % arch.width);
+    let rows_used: HashSet<usize> = used.iter().map(|l| coords(l).0).collect();
+    let cols_used: HashSet<usize> = used.iter().map(|l| coords(l).1).collect();
+    let (mut top, mut bottom) = (0, arch.height);
+    while bottom - top > 1 && !rows_used.contains(&top) {
+        top += 1;
+    }
+    while bottom - top > 1 && !rows_used.contains(&(bottom - 1)) {
+        bottom -= 1;
+    }
+    let (mut left, mut right) = (0, arch.width);
+    while right - left > 1 && !cols_used.contains(&left) {
+        left += 1;
+    }
+    while right - left > 1 && !cols_used.contains(&(right - 1)) {
+        right -= 1;
+    }
+    let width = right - left;
+    let relocate = |loc: &Location| {
+        let (r, c) = coords(loc);
+        if r < top || r >= bottom || c < left || c >= right {
+            return None;
+        }
+        Some(Location::new((r - top) * width + (c - left)))
     };
+    ScmrArchitecture {
+        width,
+        height: bottom - top,
+        alg_qubits: arch.alg_qubits.iter().filter_map(relocate).collect(),
+        factories: arch
+            .factories
+            .iter()
+            .filter_map(|(loc, kind)| relocate(loc).map(|l| (l, *kind)))
+            .collect(),
+        excluded: arch.excluded.iter().filter_map(relocate).collect(),
+        routing_cells_cache: OnceLock::new(),
+        ..arch.clone()
+    }
 }
 
 pub fn square_sparse_layout(alg_qubit_count: usize) -> ScmrArchitecture {
@@ -127,22 +301,24 @@ pub fn square_sparse_layout(alg_qubit_count: usize) -> ScmrArchitecture {
     perimeter.extend(right_edge);
     perimeter.extend(bottom_edge);
     perimeter.extend(left_edge);
-    let mut magic_state_qubits = Vec::new();
+    let mut factories = Vec::new();
     for i in (1..perimeter.len()).step_by(2) {
-        magic_state_qubits.push(perimeter[i]);
+        factories.push((perimeter[i], FactoryKind::T));
     }
-    return ScmrArchitecture {
-        width,
-        height,
-        alg_qubits,
-        magic_state_qubits,
-    };
+    ScmrArchitecture::new(width, height, alg_qubits, factories)
 }
 #[derive(Debug, Serialize, Clone, Hash, PartialEq, Eq)]
 pub struct ScmrGateImplementation {
     path: Vec<Location>,
 }
-impl GateImplementation for ScmrGateImplementation {}
+impl GateImplementation for ScmrGateImplementation {
+    fn path(&self) -> Option<Vec<Location>> {
+        if self.path.is_empty() {
+            return None;
+        }
+        Some(self.path.clone())
+    }
+}
 
 type ScmrStep = Step<ScmrGateImplementation>;
 #[derive(Debug)]
@@ -167,66 +343,79 @@ fn scmr_transitions(_step: &ScmrStep) -> Vec<IdTransition> {
     return vec![IdTransition];
 }
 
-fn scmr_step_cost(_step: &ScmrStep, _arch: &ScmrArchitecture) -> f64 {
-    return 1.0;
+pub fn scmr_step_cost(step: &ScmrStep, arch: &ScmrArchitecture) -> f64 {
+    let active_qubits: HashSet<Qubit> = step
+        .gates()
+        .iter()
+        .flat_map(|g| g.qubits.clone())
+        .collect();
+    let idle_patches = step.map.len() - active_qubits.len();
+    // cells a path may cross: everything but factories and occupied patches
+    let routing_cells = arch.width * arch.height - arch.factories.len() - step.map.len();
+    let used_cells: usize = step
+        .implemented_gates
+        .iter()
+        .map(|ig| ig.implementation.path.len())
+        .sum();
+    let free_cells = routing_cells.saturating_sub(used_cells).max(1);
+    let congestion = used_cells as f64 / free_cells as f64;
+    // decoherence of patches that sit out the step
+    1.0
+        + (idle_patches as f64) * arch.idle_patch_cost * arch.step_duration
+        + congestion * arch.congestion_weight
 }
 
-fn scmr_implement_gate(
+/// Candidate `(starts, ends)` boundary sets for routing `gate`, one per allowed orientation.
+fn gate_endpoints(
     step: &ScmrStep,
     arch: &ScmrArchitecture,
     gate: &Gate,
-) -> Option<ScmrGateImplementation> {
-    let (mut graph, mut loc_to_node) = arch.get_graph();
-    for loc in &arch.magic_state_qubits {
-        assert!(!arch.alg_qubits.clone().into_iter().any(|l| l == *loc));
-        let old_last = graph[graph.node_indices().last().unwrap()];
-        graph.remove_node(loc_to_node[loc]);
-        loc_to_node.insert(old_last, loc_to_node[loc]);
-        loc_to_node.remove(loc);
-    }
-    for loc in step.map.values().into_iter() {
-        let old_last = graph[graph.node_indices().last().unwrap()];
-        graph.remove_node(loc_to_node[loc]);
-        loc_to_node.insert(old_last, loc_to_node[loc]);
-        loc_to_node.remove(loc);
-    }
-    for loc in step
-        .implemented_gates
-        .iter()
-        .map(|x| x.implementation.path.clone())
-        .flatten()
-    {
-        let old_last = graph[graph.node_indices().last().unwrap()];
-        graph.remove_node(loc_to_node[&loc]);
-        loc_to_node.insert(old_last, loc_to_node[&loc]);
-        loc_to_node.remove(&loc);
-    }
-    let (starts, ends) = match &gate.operation {
+) -> Vec<(Vec<Location>, Vec<Location>)> {
+    let valid = arch.routing_cells();
+    match gate.base_operation() {
         Operation::CX => {
             let (cpos, tpos) = (step.map[&gate.qubits[0]], step.map[&gate.qubits[1]]);
-            (
-                vertical_neighbors(cpos, arch.width, arch.height),
-                horizontal_neighbors(tpos, arch.width),
-            )
+            let mut orientations = vec![(
+                vertical_neighbors_within(cpos, arch.width, arch.height, valid),
+                horizontal_neighbors_within(tpos, arch.width, valid),
+            )];
+            if arch.cx_both_orientations {
+                orientations.push((
+                    horizontal_neighbors_within(cpos, arch.width, valid),
+                    vertical_neighbors_within(tpos, arch.width, arch.height, valid),
+                ));
+            }
+            orientations
         }
         Operation::T => {
             let pos = step.map[&gate.qubits[0]];
-            let target_neighbors = vertical_neighbors(pos, arch.width, arch.height);
+            let target_neighbors = vertical_neighbors_within(pos, arch.width, arch.height, valid);
             let msf_neighors = arch
-                .magic_state_qubits
-                .clone()
+                .factories_of(FactoryKind::T)
                 .into_iter()
-                .map(|m| horizontal_neighbors(m, arch.width))
-                .flatten()
+                .flat_map(|m| horizontal_neighbors_within(m, arch.width, valid))
                 .collect();
-            (target_neighbors, msf_neighors)
+            vec![(target_neighbors, msf_neighors)]
         }
-        _ => (vec![], vec![]),
-    };
+        _ => vec![],
+    }
+}
+
+fn shortest_route(
+    step: &ScmrStep,
+    arch: &ScmrArchitecture,
+    starts: &Vec<Location>,
+    ends: &Vec<Location>,
+    also_blocked: &[Location],
+) -> Option<(i32, Vec<Location>)> {
+    let (mut graph, mut loc_to_node) = arch.get_graph();
+    assert!(!arch.factory_locations().iter().any(|l| arch.alg_qubits.contains(l)));
+    remove_locations(&mut graph, &mut loc_to_node, &step.blocked_locations(arch));
+    remove_locations(&mut graph, &mut loc_to_node, also_blocked);
     let mut best: Option<(i32, Vec<NodeIndex>)> = None;
 
-    for start in &starts {
-        for end in &ends {
+    for start in starts {
+        for end in ends {
             if loc_to_node.contains_key(start) && loc_to_node.contains_key(end) {
                 let res = petgraph::algo::astar(
                     &graph,
@@ -243,52 +432,71 @@ fn scmr_implement_gate(
             }
         }
     }
-    return best.map(|(_cost, path)| ScmrGateImplementation {
-        path: path.into_iter().map(|n| graph[n]).collect(),
-    });
+    best.map(|(cost, path)| (cost, path.into_iter().map(|n| graph[n]).collect()))
+}
+
+/// Routes for a Toffoli, one per CCZ factory, shortest first: a single path from the
+/// factory past a vertical neighbor of each control and a horizontal neighbor of the
+/// target. Each order of the operands is tried, every leg the shortest continuation that
+/// avoids the legs before it, and the shortest path kept.
+fn ccx_routes(step: &ScmrStep, arch: &ScmrArchitecture, gate: &Gate) -> Vec<Vec<Location>> {
+    let valid = arch.routing_cells();
+    let [c1, c2, t] = [0, 1, 2].map(|i| step.map[&gate.qubits[i]]);
+    let legs = [
+        vertical_neighbors_within(c1, arch.width, arch.height, valid),
+        vertical_neighbors_within(c2, arch.width, arch.height, valid),
+        horizontal_neighbors_within(t, arch.width, valid),
+    ];
+    let route_in_order = |starts: &Vec<Location>, order: Vec<&Vec<Location>>| {
+        let (_, mut path) = shortest_route(step, arch, starts, order[0], &[])?;
+        for ends in &order[1..] {
+            let (last, before) = path.split_last().unwrap();
+            let (_, leg) = shortest_route(step, arch, &vec![*last], ends, before)?;
+            path.extend(leg.into_iter().skip(1));
+        }
+        Some(path)
+    };
+    arch
+        .factories_of(FactoryKind::CCZ)
+        .into_iter()
+        .filter_map(|factory| {
+            let starts = horizontal_neighbors_within(factory, arch.width, valid);
+            legs
+                .iter()
+                .permutations(3)
+                .filter_map(|order| route_in_order(&starts, order))
+                .min_by_key(|path| path.len())
+        })
+        .sorted_by_key(|path| path.len())
+        .collect()
 }
 
-fn scmr_implement_gate_alt(
+/// Toffoli routes, then the `paths_per_gate` shortest routes of each orientation,
+/// shortest first.
+pub fn scmr_implement_gate_alt(
     step: &ScmrStep,
     arch: &ScmrArchitecture,
     gate: &Gate,
 ) -> impl Iterator<Item = ScmrGateImplementation> {
-    let paths: Vec<_> = step
-        .implemented_gates
-        .iter()
-        .map(|x| x.implementation.path.clone())
-        .flatten()
-        .collect();
-    let mapped: Vec<_> = step.map.values().cloned().collect();
-    let magic_states = arch.magic_state_qubits.clone();
-    let blocked = mapped
-        .into_iter()
-        .chain(magic_states.into_iter())
-        .chain(paths.into_iter())
-        .collect();
-    let (starts, ends) = match &gate.operation {
-        Operation::CX => {
-            let (cpos, tpos) = (step.map[&gate.qubits[0]], step.map[&gate.qubits[1]]);
-            (
-                vertical_neighbors(cpos, arch.width, arch.height),
-                horizontal_neighbors(tpos, arch.width),
-            )
-        }
-        Operation::T => {
-            let pos = step.map[&gate.qubits[0]];
-            let target_neighbors = vertical_neighbors(pos, arch.width, arch.height);
-            let msf_neighors = arch
-                .magic_state_qubits
-                .clone()
-                .into_iter()
-                .map(|m| horizontal_neighbors(m, arch.width))
-                .flatten()
-                .collect();
-            (target_neighbors, msf_neighors)
-        }
-        _ => (vec![], vec![]),
+    let ccx = match gate.base_operation() {
+        Operation::CCX => ccx_routes(step, arch, gate),
+        _ => vec![],
     };
-    all_paths(arch, starts, ends, blocked).map(|p| ScmrGateImplementation { path: p })
+    let blocked = step.blocked_locations(arch);
+    let paths = gate_endpoints(step, arch, gate)
+        .into_iter()
+        .flat_map(|(starts, ends)| {
+            k_shortest_paths(arch, starts, ends, blocked.clone(), CONFIG.load().paths_per_gate)
+        })
+        .sorted_by_key(|path| path.len());
+    // measurements happen in place, so gate_endpoints offers them no routes
+    let in_place = matches!(gate.base_operation(), Operation::Measure { .. })
+        .then(|| ScmrGateImplementation { path: vec![] });
+    in_place.into_iter().chain(
+        ccx.into_iter()
+            .chain(paths)
+            .map(|p| ScmrGateImplementation { path: p }),
+    )
 }
 
 fn mapping_heuristic(arch: &ScmrArchitecture, circ: &Circuit, map: &QubitMap) -> f64 {
@@ -298,8 +506,8 @@ fn mapping_heuristic(arch: &ScmrArchitecture, circ: &Circuit, map: &QubitMap) ->
     }
     let mut overlaps = 0;
     fn get_gate_range(gate: &Gate, arch: &ScmrArchitecture, map: &QubitMap) -> Range {
-        match &gate.operation {
-            Operation::CX => {
+        match gate.base_operation() {
+            Operation::CX | Operation::SWAP => {
                 let (ctrl_x, ctrl_y) = (
                     map[&gate.qubits[0]].get_index() % arch.width,
                     (map[&gate.qubits[0]].get_index() / arch.width),
@@ -329,15 +537,15 @@ fn mapping_heuristic(arch: &ScmrArchitecture, circ: &Circuit, map: &QubitMap) ->
                     (map[&gate.qubits[0]].get_index() / arch.width),
                 );
                 let magic_states_2d = arch
-                    .magic_state_qubits
-                    .iter()
+                    .factories_of(FactoryKind::T)
+                    .into_iter()
                     .map(|s| (s.get_index() % arch.width, s.get_index() / arch.width));
                 let (msf_x, msf_y) = magic_states_2d
                     .min_by_key(|(x, y)| {
                         (*x as isize - qubit_x as isize).abs()
                             + (*y as isize - qubit_y as isize).abs()
                     })
-                    .expect("should not be routing T gates with no magic states");
+                    .expect("should not be routing T gates with no T factories");
                 let x_range = if msf_x < qubit_x {
                     (msf_x, qubit_x)
                 } else {
@@ -353,6 +561,32 @@ fn mapping_heuristic(arch: &ScmrArchitecture, circ: &Circuit, map: &QubitMap) ->
                     y: y_range,
                 };
             }
+            Operation::Measure { .. } => {
+                let (x, y) = (
+                    map[&gate.qubits[0]].get_index() % arch.width,
+                    map[&gate.qubits[0]].get_index() / arch.width,
+                );
+                Range {
+                    x: (x, x),
+                    y: (y, y),
+                }
+            }
+            Operation::Conditioned { .. } => unreachable!(),
+            Operation::CCX => {
+                let coords = |l: Location| (l.get_index() % arch.width, l.get_index() / arch.width);
+                let operands: Vec<(usize, usize)> = gate.qubits.iter().map(|q| coords(map[q])).collect();
+                let factory = arch
+                    .factories_of(FactoryKind::CCZ)
+                    .into_iter()
+                    .map(coords)
+                    .min_by_key(|(x, y)| operands.iter().map(|(qx, qy)| x.abs_diff(*qx) + y.abs_diff(*qy)).sum::<usize>())
+                    .expect("should not be routing CCX gates with no CCZ factories");
+                let cells = || operands.iter().copied().chain([factory]);
+                Range {
+                    x: (cells().map(|c| c.0).min().unwrap(), cells().map(|c| c.0).max().unwrap()),
+                    y: (cells().map(|c| c.1).min().unwrap(), cells().map(|c| c.1).max().unwrap()),
+                }
+            }
             Operation::PauliRot { axis, angle } => panic!("did not expect PauliRot gate"),
             Operation::PauliMeasurement { sign, axis } => {
                 panic!("did not expect PauliMeasure gate")
@@ -368,8 +602,7 @@ fn mapping_heuristic(arch: &ScmrArchitecture, circ: &Circuit, map: &QubitMap) ->
         }
         return true;
     }
-    let mut c = circ.clone();
-    let layers = circuit_to_layers(&mut c);
+    let layers = circuit_to_layers(circ);
     for layer in layers {
         for (g1, g2) in layer.iter().tuple_combinations() {
             let r1 = get_gate_range(g1, arch, map);
@@ -382,8 +615,9 @@ fn mapping_heuristic(arch: &ScmrArchitecture, circ: &Circuit, map: &QubitMap) ->
     return overlaps as f64;
 }
 
-pub fn scmr_solve(c: &Circuit, a: &ScmrArchitecture) -> CompilerResult<ScmrGateImplementation> {
-    return solve(
+pub fn scmr_solve(c: &Circuit, a: &ScmrArchitecture) -> Result<CompilerResult<ScmrGateImplementation>, Vec<PreflightError>> {
+    validate_circuit(c, a)?;
+    Ok(solve(
         c,
         a,
         &scmr_transitions,
@@ -391,11 +625,15 @@ pub fn scmr_solve(c: &Circuit, a: &ScmrArchitecture) -> CompilerResult<ScmrGateI
         scmr_step_cost,
         Some(mapping_heuristic),
         true,
-    );
+        &HashMap::new(),
+        &InitialMapStrategy::Parallel,
+        None,
+    ))
 }
 
-pub fn scmr_solve_par(c: &Circuit, a: &ScmrArchitecture) -> CompilerResult<ScmrGateImplementation> {
-    return solve_parallel(
+pub fn scmr_solve_par(c: &Circuit, a: &ScmrArchitecture) -> Result<CompilerResult<ScmrGateImplementation>, Vec<PreflightError>> {
+    validate_circuit(c, a)?;
+    Ok(solve_parallel(
         c,
         a,
         &scmr_transitions,
@@ -403,11 +641,14 @@ pub fn scmr_solve_par(c: &Circuit, a: &ScmrArchitecture) -> CompilerResult<ScmrG
         scmr_step_cost,
         Some(mapping_heuristic),
         true,
-    );
+        &HashMap::new(),
+        None,
+    ))
 }
 
-pub fn scmr_solve_joint_optimize_parallel(c: &Circuit, a: &ScmrArchitecture) -> CompilerResult<ScmrGateImplementation> {
-        return solve_joint_optimize_parallel(
+pub fn scmr_solve_joint_optimize_parallel(c: &Circuit, a: &ScmrArchitecture) -> Result<CompilerResult<ScmrGateImplementation>, Vec<PreflightError>> {
+    validate_circuit(c, a)?;
+    Ok(solve_joint_optimize_parallel(
         c,
         a,
         &scmr_transitions,
@@ -415,6 +656,7 @@ pub fn scmr_solve_joint_optimize_parallel(c: &Circuit, a: &ScmrArchitecture) ->
         scmr_step_cost,
         Some(mapping_heuristic),
         true,
-    );
-
+        &HashMap::new(),
+        None,
+    ))
 }
\ No newline at end of file
@@ -1,7 +1,8 @@
 #[derive(Debug)]
 pub struct ProblemDefinition {
     pub imp: ImplBlock,
-    pub trans: TransitionBlock,
+    /// One block per kind of move, in the order the file declares them.
+    pub trans: Vec<TransitionBlock>,
     pub arch: Option<ArchitectureBlock>,
     pub step: Option<StepBlock>,
 }
@@ -46,6 +47,7 @@ pub enum Ty {
     LocationTy,
     IntTy,
     FloatTy,
+    BoolTy,
     TupleTy(Vec<Ty>),
     VectorTy(Box<Ty>),
 }
@@ -18,12 +18,14 @@ pub fn emit_program(p: ProblemDefinition) -> TokenStream {
     let define_gate_types = emit_gate_types(&p.imp.routed_gates);
     let define_gi_struct = emit_define_struct(&p.imp.data);
     let define_arch_struct = emit_define_arch_struct(&p.arch);
-    let define_transition_struct = emit_define_struct(&p.trans.data);
+    let define_transition_structs = p.trans.iter().map(|t| emit_define_struct(&t.data));
+    let define_transition_enum = emit_transition_enum(&p.trans);
     let implement_gi_trait = emit_impl_gate(&p.imp.data);
     let implement_gi_getters = emit_impl_gate_methods(&p.imp.data);
     let implement_arch_trait = emit_impl_arch(&p.arch);
     let implement_arch_methods = emit_impl_arch_methods(&p.arch);
-    let implement_trans_trait = emit_impl_trans(&p.trans, &p.imp);
+    let implement_trans_traits = p.trans.iter().map(|t| emit_impl_trans(t, &p.imp));
+    let implement_enum_trans_trait = emit_impl_enum_trans(&p.trans, &p.imp);
     let define_available_transitions = emit_available_transitions(&p.trans, &p.imp);
     let define_realize_gate_function = emit_realize_gate_function(&p.imp);
     let define_solve_function = emit_solve_function(&p.imp);
@@ -36,12 +38,14 @@ pub fn emit_program(p: ProblemDefinition) -> TokenStream {
         #define_gate_types
         #define_gi_struct
         #define_arch_struct
-        #define_transition_struct
+        #(#define_transition_structs)*
+        #define_transition_enum
         #implement_gi_trait
         #implement_gi_getters
         #implement_arch_trait
         #implement_arch_methods
-        #implement_trans_trait
+        #(#implement_trans_traits)*
+        #implement_enum_trans_trait
         #define_available_transitions
         #define_realize_gate_function
         #define_step_cost
@@ -138,11 +142,92 @@ fn emit_define_struct(data: &NamedTuple) -> TokenStream {
         let field_ty: syn::Type = emit_type(ty);
         quote! { #field_name : #field_ty }
     });
+    if !data.fields.iter().any(|(_, ty)| contains_float(ty)) {
+        return quote! {
+            #[derive(Hash, PartialEq, Eq, Clone, serde::Serialize, Debug)]
+            pub struct #struct_name {
+                #(#fields),*
+            }
+        };
+    }
+    // f64 has no Hash or Eq, so compare and hash floats by their bits instead, which
+    // keeps equality reflexive and consistent with the hash
+    let hash_fields = data.fields.iter().map(|(name, ty)| {
+        let field_name = syn::Ident::new(name, Span::call_site());
+        emit_hash_value(quote! { self.#field_name }, ty)
+    });
+    let eq_fields = data.fields.iter().map(|(name, ty)| {
+        let field_name = syn::Ident::new(name, Span::call_site());
+        emit_eq_value(quote! { self.#field_name }, quote! { other.#field_name }, ty)
+    });
     quote! {
-        #[derive(Hash, PartialEq, Eq, Clone, serde::Serialize, Debug)]
+        #[derive(Clone, serde::Serialize, Debug)]
         pub struct #struct_name {
             #(#fields),*
         }
+        impl PartialEq for #struct_name {
+            fn eq(&self, other: &Self) -> bool {
+                true #(&& #eq_fields)*
+            }
+        }
+        impl Eq for #struct_name {}
+        impl std::hash::Hash for #struct_name {
+            fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+                #(#hash_fields)*
+            }
+        }
+    }
+}
+
+fn contains_float(ty: &Ty) -> bool {
+    match ty {
+        Ty::FloatTy => true,
+        Ty::TupleTy(tys) => tys.iter().any(contains_float),
+        Ty::VectorTy(ty) => contains_float(ty),
+        Ty::LocationTy | Ty::IntTy | Ty::BoolTy => false,
+    }
+}
+
+fn emit_eq_value(left: TokenStream, right: TokenStream, ty: &Ty) -> TokenStream {
+    match ty {
+        Ty::FloatTy => quote! { ((#left).to_bits() == (#right).to_bits()) },
+        Ty::TupleTy(tys) => {
+            let elements = tys.iter().enumerate().map(|(i, ty)| {
+                let index = syn::Index::from(i);
+                emit_eq_value(quote! { (#left).#index }, quote! { (#right).#index }, ty)
+            });
+            quote! { (true #(&& #elements)*) }
+        }
+        Ty::VectorTy(ty) => {
+            let element = emit_eq_value(quote! { left }, quote! { right }, ty);
+            quote! {
+                ((#left).len() == (#right).len()
+                    && (#left).iter().zip((#right).iter()).all(|(left, right)| #element))
+            }
+        }
+        Ty::LocationTy | Ty::IntTy | Ty::BoolTy => quote! { ((#left) == (#right)) },
+    }
+}
+
+fn emit_hash_value(value: TokenStream, ty: &Ty) -> TokenStream {
+    match ty {
+        Ty::FloatTy => quote! { std::hash::Hash::hash(&(#value).to_bits(), state); },
+        Ty::TupleTy(tys) => {
+            let elements = tys.iter().enumerate().map(|(i, ty)| {
+                let index = syn::Index::from(i);
+                emit_hash_value(quote! { (#value).#index }, ty)
+            });
+            quote! { #(#elements)* }
+        }
+        Ty::VectorTy(ty) => {
+            let element = emit_hash_value(quote! { item }, ty);
+            quote! {
+                for item in (#value).iter() {
+                    #element
+                }
+            }
+        }
+        Ty::LocationTy | Ty::IntTy | Ty::BoolTy => quote! { std::hash::Hash::hash(&(#value), state); },
     }
 }
 
@@ -171,6 +256,7 @@ fn emit_type(ty: &Ty) -> syn::Type {
         }
         Ty::IntTy => syn::parse_quote!(usize),
         Ty::FloatTy => syn::parse_quote!(f64),
+        Ty::BoolTy => syn::parse_quote!(bool),
     }
 }
 
@@ -249,7 +335,11 @@ fn emit_impl_arch(arch: &Option<ArchitectureBlock>) -> TokenStream {
             quote! {
                     let mut locations = Vec::new();
                     for node in self.graph.node_indices() {
-                        locations.push(self.graph[node]);
+                        // isolated locations stay in the graph so location indices match the
+                        // file, but no gate can reach them, so no qubit is placed there
+                        if self.graph.neighbors_undirected(node).next().is_some() {
+                            locations.push(self.graph[node]);
+                        }
                     }
                     return locations;
             }
@@ -295,7 +385,8 @@ fn emit_impl_arch_methods(arch: &Option<ArchitectureBlock>) -> TokenStream {
     let universal_from_file_body = quote! {
         let file = File::open(path).expect("Opening architecture file");
         let parsed: Value = serde_json::from_reader(file).expect("Parsing architecture file");
-        let graph = graph_from_json_entry(parsed["graph"].clone());
+        let num_nodes = declared_num_nodes(&parsed).expect("Parsing architecture file");
+        let graph = graph_from_json_entry(parsed["graph"].clone(), num_nodes).expect("Parsing architecture graph");
         let mut index_map = HashMap::new();
         for ind in graph.node_indices() {
             index_map.insert(graph[ind], ind);
@@ -345,6 +436,49 @@ fn emit_impl_arch_methods(arch: &Option<ArchitectureBlock>) -> TokenStream {
     };
 }
 
+/// `MyTransition`, with one variant per block wrapping the block's struct.
+fn emit_transition_enum(trans: &[TransitionBlock]) -> TokenStream {
+    let variants = trans.iter().map(|t| {
+        let name = syn::Ident::new(&t.data.name, Span::call_site());
+        quote! { #name(#name) }
+    });
+    quote! {
+        #[derive(Clone, Debug)]
+        enum MyTransition {
+            #(#variants),*
+        }
+    }
+}
+
+/// Dispatches each `Transition` method of `MyTransition` to the wrapped block's impl, so
+/// `repr` reads the same as it would for the block alone.
+fn emit_impl_enum_trans(trans: &[TransitionBlock], imp: &ImplBlock) -> TokenStream {
+    let imp_struct_name = syn::Ident::new(&imp.data.name, Span::call_site());
+    let variants: Vec<Ident> = trans
+        .iter()
+        .map(|t| syn::Ident::new(&t.data.name, Span::call_site()))
+        .collect();
+    quote! {
+        impl Transition<#imp_struct_name, CustomArch> for MyTransition {
+            fn apply(&self, step: &Step<#imp_struct_name>) -> Step<#imp_struct_name> {
+                match self {
+                    #(MyTransition::#variants(t) => t.apply(step)),*
+                }
+            }
+            fn repr(&self) -> String {
+                match self {
+                    #(MyTransition::#variants(t) => t.repr()),*
+                }
+            }
+            fn cost(&self, arch: &CustomArch) -> f64 {
+                match self {
+                    #(MyTransition::#variants(t) => t.cost(arch)),*
+                }
+            }
+        }
+    }
+}
+
 fn emit_impl_trans(t: &TransitionBlock, imp: &ImplBlock) -> TokenStream {
     let trans_struct_name = syn::Ident::new(&t.data.name, Span::call_site());
     let imp_struct_name = syn::Ident::new(&imp.data.name, Span::call_site());
@@ -380,23 +514,53 @@ fn emit_impl_trans(t: &TransitionBlock, imp: &ImplBlock) -> TokenStream {
     }
 }
 
-fn emit_available_transitions(t: &TransitionBlock, imp: &ImplBlock) -> TokenStream {
-    let trans_struct_name = syn::Ident::new(&t.data.name, Span::call_site());
+/// One generator per block, named after it, plus `available_transitions`, which
+/// concatenates them in block order as `MyTransition`s.
+fn emit_available_transitions(trans: &[TransitionBlock], imp: &ImplBlock) -> TokenStream {
     let imp_struct_name = syn::Ident::new(&imp.data.name, Span::call_site());
-    let available_trans_expr = emit_expr(
-        &t.get_transitions,
-        &Context::Free,
-        &trans_struct_name,
-        &imp_struct_name,
-        None,
-    );
-    let trans_struct_name = syn::Ident::new(&t.data.name, Span::call_site());
-    let imp_struct_name = syn::Ident::new(&imp.data.name, Span::call_site());
-    quote! {
-            fn available_transitions(arch : &CustomArch, step : &Step<#imp_struct_name>) -> Vec<#trans_struct_name> {
+    let block_generators = trans.iter().map(|t| {
+        let trans_struct_name = syn::Ident::new(&t.data.name, Span::call_site());
+        let generator_name = block_generator_name(t);
+        let available_trans_expr = emit_expr(
+            &t.get_transitions,
+            &Context::Free,
+            &trans_struct_name,
+            &imp_struct_name,
+            None,
+        );
+        quote! {
+            fn #generator_name(arch : &CustomArch, step : &Step<#imp_struct_name>) -> Vec<#trans_struct_name> {
                #available_trans_expr
             }
+        }
+    });
+    let collect_blocks = trans.iter().map(|t| {
+        let variant = syn::Ident::new(&t.data.name, Span::call_site());
+        let generator_name = block_generator_name(t);
+        quote! {
+            transitions.extend(#generator_name(arch, step).into_iter().map(MyTransition::#variant));
+        }
+    });
+    quote! {
+        #(#block_generators)*
+        fn available_transitions(arch : &CustomArch, step : &Step<#imp_struct_name>) -> Vec<MyTransition> {
+            let mut transitions = Vec::new();
+            #(#collect_blocks)*
+            return transitions;
+        }
+    }
+}
+
+/// `available_<name>_transitions`, with the block's CamelCase name in snake case.
+fn block_generator_name(t: &TransitionBlock) -> Ident {
+    let mut snake = String::new();
+    for (i, c) in t.data.name.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            snake.push('_');
+        }
+        snake.extend(c.to_lowercase());
     }
+    syn::Ident::new(&format!("available_{}_transitions", snake), Span::call_site())
 }
 
 fn emit_realize_gate_function(imp: &ImplBlock) -> TokenStream {
@@ -444,12 +608,14 @@ fn emit_mapping_heuristic() -> TokenStream {
 
 fn emit_step_cost(p: &ProblemDefinition) -> TokenStream {
     let imp_struct_name = syn::Ident::new(&p.imp.data.name, Span::call_site());
-    let trans_struct_name = syn::Ident::new(&p.trans.data.name, Span::call_site());
+    // a step cost has no transition of its own to construct, so any block's name will do
+    let trans_struct_name = syn::Ident::new(&p.trans[0].data.name, Span::call_site());
     let step_cost_body = match &p.step {
         Some(s) => {
+            // my_step_cost is a free function over (step, arch), so Step must not become `self`
             let step_cost_expr = emit_expr(
                 &s.cost,
-                &Context::DataTypeContext(DataType::Step),
+                &Context::Free,
                 &trans_struct_name,
                 &imp_struct_name,
                 None,
@@ -458,14 +624,15 @@ fn emit_step_cost(p: &ProblemDefinition) -> TokenStream {
                 return #step_cost_expr;
             }
         }
+        // without a Step block every step costs one unit, as in the bundled descriptions
         None => {
             quote! {
-                return 0.0;
+                return 1.0;
             }
         }
     };
     quote! {
-        fn custom_step_cost(step: &Step<#imp_struct_name>, arch: &CustomArch) -> f64 {
+        fn my_step_cost(step: &Step<#imp_struct_name>, arch: &CustomArch) -> f64 {
         #step_cost_body
         }
     }
@@ -480,8 +647,8 @@ fn emit_solve_function(imp: &ImplBlock) -> TokenStream {
     let explore_orders = contains_subexpr(&imp.realize, &sub_expr);
     let imp_struct_name = syn::Ident::new(&imp.data.name, Span::call_site());
     quote! {
-        fn my_solve(c : &Circuit, a : &CustomArch) -> CompilerResult<#imp_struct_name> {
-            return backend::solve(c, a, &|s| available_transitions(a, s), &realize_gate, custom_step_cost, Some(mapping_heuristic), #explore_orders);
+        fn my_solve(c : &Circuit, a : &CustomArch, initial_map : &InitialMapStrategy) -> CompilerResult<#imp_struct_name> {
+            return backend::solve(c, a, &|s| available_transitions(a, s), &realize_gate, my_step_cost, Some(mapping_heuristic), #explore_orders, &HashMap::new(), initial_map, None);
     }
     }
 }
@@ -496,7 +663,7 @@ fn emit_sabre_solve_function(imp: &ImplBlock) -> TokenStream {
     let imp_struct_name = syn::Ident::new(&imp.data.name, Span::call_site());
     quote! {
         fn my_sabre_solve(c : &Circuit, a : &CustomArch) -> CompilerResult<#imp_struct_name> {
-            return backend::sabre_solve(c, a, &|s| available_transitions(a, s), &realize_gate, custom_step_cost, Some(mapping_heuristic), #explore_orders);
+            return backend::sabre_solve(c, a, &|s| available_transitions(a, s), &realize_gate, my_step_cost, Some(mapping_heuristic), #explore_orders, &HashMap::new(), None);
     }
     }
 }
@@ -511,7 +678,7 @@ fn emit_joint_optimize_parallel_function(imp: &ImplBlock) -> TokenStream {
     let imp_struct_name = syn::Ident::new(&imp.data.name, Span::call_site());
     quote! {
         fn my_joint_solve_parallel(c : &Circuit, a : &CustomArch) -> CompilerResult<#imp_struct_name> {
-            return backend::solve_joint_optimize_parallel(c, a, &|s| available_transitions(a, s), &realize_gate, custom_step_cost, Some(mapping_heuristic), #explore_orders);
+            return backend::solve_joint_optimize_parallel(c, a, &|s| available_transitions(a, s), &realize_gate, my_step_cost, Some(mapping_heuristic), #explore_orders, &HashMap::new(), None);
     }
     }
 }
@@ -75,7 +75,7 @@ fn test_program() -> ProblemDefinition {
                 els: Box::new(Expr::NoneExpr),
             },
         },
-        trans: TransitionBlock {
+        trans: vec![TransitionBlock {
             data: NamedTuple {
                 name: "Swap".to_string(),
                 fields: vec![(
@@ -139,7 +139,7 @@ fn test_program() -> ProblemDefinition {
                     Expr::Tuple(vec![Expr::LocationLiteral(0), Expr::LocationLiteral(0)]),
                 )])),
             },
-        },
+        }],
         arch: None,
         step: None,
     }
@@ -147,7 +147,13 @@ fn test_program() -> ProblemDefinition {
 
 fn from_file() {
     let path = env::var("QMRL_PATH").unwrap_or("/home/abtin/qmrsl/qmrl/problem-descriptions/nisq.qmrl".to_string());
-    let p = parse::read_file(&path);
+    let p = match parse::read_file(&path) {
+        Ok(p) => p,
+        Err(report) => {
+            eprintln!("{}", report);
+            std::process::exit(1);
+        }
+    };
     let ast = format!("{:?}", p);
     let _ = std::fs::write("debug", ast.as_bytes());
     let out_dir = env::var_os("OUT_DIR").unwrap();
@@ -1,5 +1,6 @@
 use std::collections::binary_heap;
 
+use chumsky::error::SimpleReason;
 use chumsky::prelude::*;
 use text::keyword;
 
@@ -13,7 +14,8 @@ fn type_parser() -> impl Parser<char, ast::Ty, Error = Simple<char>> {
         let atom_ty = just("Location")
             .map(|_| ast::Ty::LocationTy)
             .or(just("Int").map(|_| ast::Ty::IntTy))
-            .or(just("Float").map(|_| ast::Ty::FloatTy));
+            .or(just("Float").map(|_| ast::Ty::FloatTy))
+            .or(just("Bool").map(|_| ast::Ty::BoolTy));
 
         let tuple_ty = type_parser
             .clone()
@@ -283,7 +285,16 @@ fn expr_parser() -> impl Parser<char, ast::Expr, Error = Simple<char>> {
             .padded()
             .ignore_then(expr_parser.clone().padded())
             .then_ignore(just(",").padded())
-            .then_ignore(just("|x, acc| ->").padded())
+            // the emitted closure always binds `x` and `acc`, but the spacing is up to the author
+            .then_ignore(
+                just("|")
+                    .then(keyword("x").padded())
+                    .then(just(","))
+                    .then(keyword("acc").padded())
+                    .then(just("|"))
+                    .then(just("->").padded())
+                    .padded(),
+            )
             .then(expr_parser.clone())
             .then_ignore(just(",").padded())
             .then(expr_parser.clone())
@@ -492,19 +503,32 @@ fn expr_parser() -> impl Parser<char, ast::Expr, Error = Simple<char>> {
 
 fn parser() -> impl Parser<char, ProblemDefinition, Error = Simple<char>> {
     let impl_block = impl_block_parser();
-    let transition_block = trans_block_parser();
+    // each block becomes both a struct and a variant of the generated transition enum
+    let transition_blocks = trans_block_parser().repeated().at_least(1).validate(
+        |blocks: Vec<ast::TransitionBlock>, span, emit| {
+            for (i, block) in blocks.iter().enumerate() {
+                if blocks[..i].iter().any(|b| b.data.name == block.data.name) {
+                    emit(Simple::custom(
+                        span.clone(),
+                        format!("duplicate Transition name '{}'", block.data.name),
+                    ));
+                }
+            }
+            blocks
+        },
+    );
     let architecture_block = arch_block_parser();
     let step_block = step_block_parser();
     let prob_def = {
         impl_block
-            .then(transition_block)
+            .then(transition_blocks)
             .then(architecture_block)
             .then(step_block)
             .map(
-                |(((impl_block, transition_block), architecture_block), step_block)| {
+                |(((impl_block, transition_blocks), architecture_block), step_block)| {
                     ProblemDefinition {
                         imp: impl_block,
-                        trans: transition_block,
+                        trans: transition_blocks,
                         arch: architecture_block,
                         step: step_block,
                     }
@@ -514,10 +538,51 @@ fn parser() -> impl Parser<char, ProblemDefinition, Error = Simple<char>> {
     prob_def
 }
 
-pub(crate) fn read_file(filename: &str) -> ProblemDefinition {
-    let src = std::fs::read_to_string(filename).expect("Reading qmrl file");
-    println!("{:?}", parser().parse(src.clone()).unwrap());
-    return parser()
-        .parse(src)
-        .expect("Failed to parse problem definition");
+pub(crate) fn read_file(filename: &str) -> Result<ProblemDefinition, String> {
+    let src = std::fs::read_to_string(filename)
+        .map_err(|e| format!("{}: could not read problem definition: {}", filename, e))?;
+    parser().parse(src.clone()).map_err(|errors| {
+        errors
+            .iter()
+            .map(|e| format_parse_error(filename, &src, e))
+            .collect::<Vec<_>>()
+            .join("\n")
+    })
+}
+
+/// `file:line:col`, what the parser found and expected there, and the source
+/// line with a caret under the offending character.
+fn format_parse_error(filename: &str, src: &str, e: &Simple<char>) -> String {
+    // spans count chars, not bytes
+    let before: Vec<char> = src.chars().take(e.span().start).collect();
+    let line = before.iter().filter(|c| **c == '\n').count() + 1;
+    let col = before.iter().rev().take_while(|c| **c != '\n').count() + 1;
+    let describe = |c: Option<&char>| match c {
+        Some(c) => format!("{:?}", c),
+        None => "end of input".to_string(),
+    };
+    let message = match e.reason() {
+        SimpleReason::Custom(msg) => msg.clone(),
+        SimpleReason::Unclosed { delimiter, .. } => format!("unclosed delimiter {:?}", delimiter),
+        SimpleReason::Unexpected => {
+            let mut expected: Vec<_> = e.expected().map(|c| describe(c.as_ref())).collect();
+            expected.sort();
+            expected.dedup();
+            if expected.is_empty() {
+                format!("unexpected {}", describe(e.found()))
+            } else {
+                format!("unexpected {}, expected {}", describe(e.found()), expected.join(" or "))
+            }
+        }
+    };
+    let source_line = src.lines().nth(line - 1).unwrap_or("");
+    format!(
+        "{}:{}:{}: {}\n    {}\n    {}^",
+        filename,
+        line,
+        col,
+        message,
+        source_line,
+        " ".repeat(col - 1)
+    )
 }
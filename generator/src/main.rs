@@ -1,25 +1,67 @@
 use solver::utils;
+use solver::structures::{Circuit, CompilerResult, GateImplementation, InitialMapStrategy};
 include!(concat!(env!("OUT_DIR"), "/custom.rs"));
-fn run_custom(circ_path: &str, graph_path: &str, solve_mode: &str) {
+fn compile_custom(
+    circ: &Circuit,
+    arch: &CustomArch,
+    solve_mode: &str,
+    init: &Option<InitialMapStrategy>,
+) -> CompilerResult<impl GateImplementation> {
+    if init.is_some() && solve_mode != "--onepass" {
+        panic!("--init only applies to --onepass");
+    }
+    match solve_mode {
+        "--sabre" => my_sabre_solve(circ, arch),
+        "--onepass" => my_solve(circ, arch, init.as_ref().unwrap_or(&InitialMapStrategy::Parallel)),
+        "--joint_optimize-par" => my_joint_solve_parallel(circ, arch),
+        _ => panic!("Unrecognized solve mode"),
+    }
+}
+
+fn run_custom(circ_path: &str, graph_path: &str, solve_mode: &str, init: &Option<InitialMapStrategy>) {
     let circ = utils::extract_gates(circ_path, GATE_TYPES);
     let arch = CustomArch::from_file(graph_path);
-    let res = match solve_mode {
-        "--sabre" => my_sabre_solve(&circ, &arch),
-        "--onepass" => my_solve(&circ, &arch),
-        "--joint_optimize-par" => my_joint_solve_parallel(&circ, &arch),
-        _ => panic!("Unrecognized solve mode"),
-    };
+    let res = compile_custom(&circ, &arch, solve_mode, init);
     match serde_json::to_writer(std::io::stdout(), &res) {
         Ok(_) => (),
         Err(e) => panic!("Error writing compilation to stdout: {}", e),
     }
 }
 
+fn run_custom_jsonl(graph_path: &str, solve_mode: &str, init: &Option<InitialMapStrategy>) {
+    let arch = CustomArch::from_file(graph_path);
+    let compile = |circ: &Circuit| Ok(compile_custom(circ, &arch, solve_mode, init));
+    match utils::compile_jsonl(std::io::stdin().lock(), std::io::stdout().lock(), compile) {
+        Ok(_) => (),
+        Err(e) => panic!("Error compiling JSON Lines input: {:?}", e),
+    }
+}
+
+/// Removes a trailing `--init <strategy>` from `args`, if there is one.
+fn take_init_flag(args: &mut Vec<String>) -> Option<InitialMapStrategy> {
+    if args.len() < 3 || args[args.len() - 2] != "--init" {
+        return None;
+    }
+    let name = args.pop().unwrap();
+    args.pop();
+    match InitialMapStrategy::from_name(&name) {
+        Some(init) => Some(init),
+        None => panic!("Unrecognized initial mapping strategy {}", name),
+    }
+}
+
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let mut args: Vec<String> = std::env::args().collect();
+    let init = take_init_flag(&mut args);
     if args.len() != 4 {
-        println!("Usage: qmrl <circuit> <graph> --<solve-mode>");
+        println!("Usage: qmrl <circuit> <graph> --<solve-mode> [--init <strategy>]");
+        println!("       qmrl --jsonl <graph> --<solve-mode> [--init <strategy>]  (one JSON circuit per stdin line)");
+        println!("  --init random|identity|isomorphism|anneal picks the starting map of --onepass");
+        return;
+    }
+    if args[1] == "--jsonl" {
+        run_custom_jsonl(&args[2], &args[3], &init);
         return;
     }
-    run_custom(&args[1], &args[2], &args[3]);
+    run_custom(&args[1], &args[2], &args[3], &init);
 }
@@ -1,13 +1,18 @@
-use crate::config::CONFIG;
+use crate::config::{RoutingObjective, CONFIG};
 use crate::structures::*;
 use crate::utils::*;
 use itertools::Itertools;
 use petgraph::graph::NodeIndex;
+use rand::rngs::StdRng;
 use rand::seq::IndexedRandom;
+use rand::{Rng, SeedableRng};
 use rayon::prelude::*;
+use serde::Serialize;
 use signal_hook::consts::{SIGINT, SIGTERM};
 use signal_hook::flag;
-use std::collections::HashSet;
+use petgraph::visit::EdgeRef;
+use std::cmp::Reverse;
+use std::collections::{HashSet, VecDeque};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
@@ -15,21 +20,29 @@ use std::time::Duration;
 use std::time::Instant;
 use std::{collections::HashMap, fmt::Debug};
 
-fn random_map<T: Architecture>(c: &Circuit, arch: &T) -> QubitMap {
-    let mut map = HashMap::new();
-    let mut rng = &mut rand::rng();
-    let locations = arch.locations();
-    let v = locations.choose_multiple(&mut rng, c.qubits.len());
-    for (q, l) in c.qubits.iter().zip(v) {
-        map.insert(*q, *l);
-    }
-    return map;
+fn random_constrained_map<T: Architecture>(
+    c: &Circuit,
+    arch: &T,
+    region_constraints: &RegionConstraints,
+    rng: &mut StdRng,
+) -> QubitMap {
+    randomly_extend_partial_map(c, arch, &HashMap::new(), region_constraints, rng)
+}
+
+fn allowed_at(region_constraints: &RegionConstraints, q: &Qubit, l: &Location) -> bool {
+    region_constraints
+        .get(q)
+        .is_none_or(|region| region.contains(l))
+}
+
+fn satisfies_constraints(map: &QubitMap, region_constraints: &RegionConstraints) -> bool {
+    map.iter().all(|(q, l)| allowed_at(region_constraints, q, l))
 }
 
 fn isomorphism_map<T: Architecture>(c: &Circuit, arch: &T) -> Option<QubitMap> {
     let interact_graph = build_interaction_graph(c);
     let (mut graph, _) = arch.graph();
-    if arch.locations().len() < arch.graph().0.node_count() {
+    if arch.num_locations() < arch.graph().0.node_count() {
         graph = reduced_graph(arch)
     }
     let isom = vf2::subgraph_isomorphisms(&interact_graph, &graph).first();
@@ -41,11 +54,17 @@ fn isomorphism_map<T: Architecture>(c: &Circuit, arch: &T) -> Option<QubitMap> {
     })
 }
 
-fn _isomorphism_map_with_timeout<T: Architecture + Send + Sync + Clone + 'static>(
+/// Runs `isomorphism_map` on a worker thread for at most `timeout`; a zero timeout skips
+/// the search.
+fn isomorphism_map_with_timeout<T: Architecture + Send + Sync + Clone + 'static>(
     c: &Circuit,
     arch: &T,
     timeout: Duration,
 ) -> Option<QubitMap> {
+    let _timer = PhaseTimer::start(Phase::Isomorphism);
+    if timeout.is_zero() {
+        return None;
+    }
     let (tx, rx) = std::sync::mpsc::channel();
     let c_clone = c.clone();
     let arch_clone = arch.clone();
@@ -60,36 +79,264 @@ fn _isomorphism_map_with_timeout<T: Architecture + Send + Sync + Clone + 'static
     }
 }
 
-fn randomly_extend_partial_map<T: Architecture>(c: &Circuit, arch: &T, map: &QubitMap) -> QubitMap {
+fn seeded_random_map<T: Architecture>(
+    c: &Circuit,
+    arch: &T,
+    seed: u64,
+    region_constraints: &RegionConstraints,
+) -> QubitMap {
+    let mut rng = StdRng::seed_from_u64(seed);
+    randomly_extend_partial_map(c, arch, &HashMap::new(), region_constraints, &mut rng)
+}
+
+/// Checks that `map` places every qubit of `c` on one of `arch`'s locations without
+/// exceeding any location's capacity.
+pub fn check_initial_map<A: Architecture>(
+    c: &Circuit,
+    arch: &A,
+    map: &QubitMap,
+) -> Result<(), InitialMapError> {
+    let locations: HashSet<Location> = arch.locations().into_iter().collect();
+    let error = InitialMapError {
+        unmapped: c
+            .qubits
+            .iter()
+            .filter(|q| !map.contains_key(q))
+            .copied()
+            .sorted_by_key(|q| q.get_index())
+            .collect(),
+        unknown_locations: map
+            .iter()
+            .filter(|(_, l)| !locations.contains(l))
+            .map(|(q, l)| (*q, *l))
+            .sorted_by_key(|(q, _)| q.get_index())
+            .collect(),
+        over_capacity: map
+            .values()
+            .counts()
+            .into_iter()
+            .filter(|(l, n)| locations.contains(l) && *n > arch.capacity(**l))
+            .map(|(l, _)| *l)
+            .sorted_by_key(|l| l.get_index())
+            .collect(),
+    };
+    if error.unmapped.is_empty() && error.unknown_locations.is_empty() && error.over_capacity.is_empty() {
+        return Ok(());
+    }
+    Err(error)
+}
+
+/// Qubits in index order on locations in index order. Where that leaves qubits outside
+/// their regions in `region_constraints`, those are placed at random within them around
+/// the rest, or all qubits at random when that doesn't fit.
+fn identity_map<T: Architecture>(
+    c: &Circuit,
+    arch: &T,
+    region_constraints: &RegionConstraints,
+    rng: &mut StdRng,
+) -> QubitMap {
+    let locations: Vec<Location> = arch.locations().into_iter().sorted_by_key(|l| l.get_index()).collect();
+    assert!(
+        c.qubits.len() <= locations.len(),
+        "{} qubits don't fit on {} locations",
+        c.qubits.len(),
+        locations.len()
+    );
+    let natural: QubitMap = c
+        .qubits
+        .iter()
+        .sorted_by_key(|q| q.get_index())
+        .copied()
+        .zip(locations)
+        .collect();
+    if satisfies_constraints(&natural, region_constraints) {
+        return natural;
+    }
+    let kept: QubitMap = natural
+        .into_iter()
+        .filter(|(q, l)| allowed_at(region_constraints, q, l))
+        .collect();
+    let map = randomly_extend_partial_map(c, arch, &kept, region_constraints, rng);
+    if satisfies_constraints(&map, region_constraints) {
+        return map;
+    }
+    random_constrained_map(c, arch, region_constraints, rng)
+}
+
+/// Greedy breadth-first placement. The qubit with the most interaction weight goes on the
+/// best-connected location, then each placed qubit's partners, heaviest first, go next to
+/// it. A partner takes the free neighbor whose own free neighbors best fit the partners it
+/// still has to bring along, so chains stay contiguous rather than boxing themselves in, and
+/// falls back to the nearest free location when no neighbor is free.
+pub fn bfs_initial_map<A: Architecture>(c: &Circuit, arch: &A) -> QubitMap {
+    let interactions = build_interaction_graph(c);
+    let qubit_nodes: HashMap<Qubit, NodeIndex> =
+        interactions.node_indices().map(|n| (interactions[n], n)).collect();
+    let (graph, index_map) = arch.graph();
+    let locations: Vec<Location> = arch.locations().into_iter().sorted_by_key(|l| l.get_index()).collect();
+    assert!(
+        c.qubits.len() <= locations.len(),
+        "{} qubits don't fit on {} locations",
+        c.qubits.len(),
+        locations.len()
+    );
+    let neighbors = |l: &Location| -> Vec<Location> {
+        match index_map.get(l) {
+            Some(n) => graph.neighbors_undirected(*n).map(|m| graph[m]).unique().collect(),
+            None => vec![],
+        }
+    };
+    let partners = |q: &Qubit| -> Vec<(Qubit, usize)> {
+        interactions
+            .edges(qubit_nodes[q])
+            .map(|e| (interactions[e.target()], *e.weight()))
+            .sorted_by_key(|(p, w)| (Reverse(*w), p.get_index()))
+            .collect()
+    };
+    let mut free: HashSet<Location> = locations.iter().copied().collect();
+    let mut map = QubitMap::new();
+    let seeds = c
+        .qubits
+        .iter()
+        .copied()
+        .sorted_by_key(|q| (Reverse(partners(q).iter().map(|(_, w)| w).sum::<usize>()), q.get_index()));
+    for seed in seeds {
+        if map.contains_key(&seed) {
+            continue;
+        }
+        let spot = locations
+            .iter()
+            .filter(|l| free.contains(l))
+            .min_by_key(|l| (Reverse(neighbors(l).len()), l.get_index()))
+            .copied()
+            .unwrap();
+        free.remove(&spot);
+        map.insert(seed, spot);
+        let mut queue = VecDeque::from([seed]);
+        while let Some(q) = queue.pop_front() {
+            let here = map[&q];
+            for (p, _) in partners(&q) {
+                if map.contains_key(&p) {
+                    continue;
+                }
+                let pending = partners(&p).iter().filter(|(r, _)| !map.contains_key(r)).count();
+                let free_degree = |l: &Location| neighbors(l).iter().filter(|n| free.contains(n)).count();
+                let adjacent: Vec<Location> = neighbors(&here).into_iter().filter(|l| free.contains(l)).collect();
+                let spot = if adjacent.is_empty() {
+                    let hops = petgraph::algo::dijkstra(&graph, index_map[&here], None, |_| 1usize);
+                    locations
+                        .iter()
+                        .filter(|l| free.contains(l))
+                        .min_by_key(|l| {
+                            (index_map.get(l).and_then(|n| hops.get(n)).copied().unwrap_or(usize::MAX), l.get_index())
+                        })
+                        .copied()
+                        .unwrap()
+                } else {
+                    // the smallest free degree that still fits `pending`, else the largest one
+                    adjacent
+                        .into_iter()
+                        .min_by_key(|l| {
+                            let d = free_degree(l);
+                            if d >= pending {
+                                (0, d, l.get_index())
+                            } else {
+                                (1, pending - d, l.get_index())
+                            }
+                        })
+                        .unwrap()
+                };
+                free.remove(&spot);
+                map.insert(p, spot);
+                queue.push_back(p);
+            }
+        }
+    }
+    map
+}
+
+fn randomly_extend_partial_map<T: Architecture>(
+    c: &Circuit,
+    arch: &T,
+    map: &QubitMap,
+    region_constraints: &RegionConstraints,
+    mut rng: &mut StdRng,
+) -> QubitMap {
     let mut extended = map.clone();
-    let mut rng = &mut rand::rng();
-    let unmapped_qubits: Vec<_> = c.qubits.iter().filter(|q| !map.contains_key(q)).collect();
-    let available_locations: Vec<_> = arch
-        .locations()
+    let locations = arch.locations();
+    // place the most constrained qubits first so they get first pick of their regions
+    let constrained: Vec<Qubit> = c
+        .qubits
+        .iter()
+        .filter(|q| !map.contains_key(q) && region_constraints.contains_key(q))
+        .sorted_by_key(|q| (region_constraints[q].len(), q.get_index()))
+        .copied()
+        .collect();
+    for q in &constrained {
+        let free: Vec<_> = locations
+            .iter()
+            .filter(|l| region_constraints[q].contains(l) && has_room(arch, &extended, **l))
+            .collect();
+        match free.choose(&mut rng) {
+            Some(l) => {
+                extended.insert(*q, **l);
+            }
+            None => {
+                // the random picks boxed a region in; match them all instead. Qubits no
+                // placement fits, which `check_region_constraints` rejects up front, are
+                // left to go anywhere below
+                extended = map.clone();
+                extended.extend(match_to_regions(arch, map, &constrained, region_constraints).0);
+                break;
+            }
+        }
+    }
+    let unmapped_qubits: Vec<_> = c
+        .qubits
+        .iter()
+        .filter(|q| !extended.contains_key(q))
+        .sorted_by_key(|q| q.get_index())
+        .collect();
+    // one entry per free slot, so a location with room for two can be chosen twice
+    let available_locations: Vec<_> = locations
         .into_iter()
-        .filter(|v| !map.values().contains(v))
+        .flat_map(|v| {
+            let used = extended.values().filter(|l| **l == v).count();
+            std::iter::repeat_n(v, arch.capacity(v).saturating_sub(used))
+        })
         .collect();
-    let chosen_locations = available_locations.choose_multiple(&mut rng, c.qubits.len());
+    let chosen_locations = available_locations.choose_multiple(&mut rng, unmapped_qubits.len());
     for (q, l) in unmapped_qubits.iter().zip(chosen_locations) {
         extended.insert(**q, *l);
     }
     return extended;
 }
 
-fn incremental_isomorphism_map<T: Architecture>(c: &Circuit, arch: &T) -> Option<QubitMap> {
+fn incremental_isomorphism_map<T: Architecture>(
+    c: &Circuit,
+    arch: &T,
+    region_constraints: &RegionConstraints,
+    rng: &mut StdRng,
+) -> Option<QubitMap> {
+    // no interactions to embed, so any placement is as good as another
+    if c.gates.is_empty() {
+        return Some(randomly_extend_partial_map(c, arch, &HashMap::new(), region_constraints, rng));
+    }
     let mut gates = &c.gates[..1];
     let mut prefix_circuit = circuit_from_gates(gates);
     let mut isom_map = None;
-    let mut candidate = isomorphism_map(&prefix_circuit, arch);
+    let mut candidate = isomorphism_map(&prefix_circuit, arch)
+        .filter(|m| satisfies_constraints(m, region_constraints));
     let mut i = 1;
     while candidate.is_some() && i < c.gates.len() {
         gates = &c.gates[..i];
         prefix_circuit = circuit_from_gates(gates);
-        candidate = isomorphism_map(&prefix_circuit, arch);
+        candidate = isomorphism_map(&prefix_circuit, arch)
+            .filter(|m| satisfies_constraints(m, region_constraints));
         if candidate.is_some() {
             let full_map = candidate
                 .clone()
-                .map(|m| randomly_extend_partial_map(c, arch, &m));
+                .map(|m| randomly_extend_partial_map(c, arch, &m, region_constraints, rng));
             isom_map = full_map;
         }
         i += 1;
@@ -97,16 +344,31 @@ fn incremental_isomorphism_map<T: Architecture>(c: &Circuit, arch: &T) -> Option
     return isom_map;
 }
 
+/// Runs `incremental_isomorphism_map` on a worker thread for at most `timeout`; a zero
+/// timeout skips the search.
 fn incremental_isomorphism_map_with_timeout<T: Architecture + Send + Sync + Clone + 'static>(
     c: &Circuit,
     arch: &T,
     timeout: Duration,
+    region_constraints: &RegionConstraints,
+    rng: &mut StdRng,
 ) -> Option<QubitMap> {
+    let _timer = PhaseTimer::start(Phase::Isomorphism);
+    if timeout.is_zero() {
+        return None;
+    }
     let (tx, rx) = std::sync::mpsc::channel();
     let c_clone = c.clone();
     let arch_clone = arch.clone();
+    let constraints_clone = region_constraints.clone();
+    let mut thread_rng = StdRng::seed_from_u64(rng.random());
     thread::spawn(move || {
-        let result = incremental_isomorphism_map(&c_clone, &arch_clone);
+        let result = incremental_isomorphism_map(
+            &c_clone,
+            &arch_clone,
+            &constraints_clone,
+            &mut thread_rng,
+        );
         let _ = tx.send(result);
     });
 
@@ -116,27 +378,116 @@ fn incremental_isomorphism_map_with_timeout<T: Architecture + Send + Sync + Clon
     }
 }
 
-fn random_neighbor<T: Architecture>(map: &QubitMap, arch: &T) -> QubitMap {
+/// Embeds just `densest_interaction_core(c)` when the full isomorphism search has timed out,
+/// searching until `isom_deadline`. Returns that embedding extended to a full map, along
+/// with `region_constraints` narrowed so each embedded qubit may only sit where it was placed.
+fn core_isomorphism_start<T: Architecture + Send + Sync + Clone + 'static>(
+    c: &Circuit,
+    arch: &T,
+    region_constraints: &RegionConstraints,
+    isom_deadline: Instant,
+    rng: &mut StdRng,
+) -> Option<(QubitMap, RegionConstraints)> {
+    let core = densest_interaction_core(c);
+    let core_gates: Vec<Gate> = c
+        .gates
+        .iter()
+        .filter(|g| g.qubits.iter().all(|q| core.contains(q)))
+        .cloned()
+        .collect();
+    if core_gates.is_empty() {
+        return None;
+    }
+    let core_map = isomorphism_map_with_timeout(
+        &circuit_from_gates(&core_gates),
+        arch,
+        isom_deadline.saturating_duration_since(Instant::now()),
+    )
+    .filter(|m| satisfies_constraints(m, region_constraints))?;
+    let mut pinned = region_constraints.clone();
+    for (q, l) in &core_map {
+        pinned.insert(*q, HashSet::from([*l]));
+    }
+    let start = randomly_extend_partial_map(c, arch, &core_map, &pinned, rng);
+    Some((start, pinned))
+}
+
+/// Where annealing starts and which constraints it respects: the isomorphism map if one was
+/// found, else the pinned core embedding when `isom_core_fallback` is set, searched for
+/// until `isom_deadline`, else `unseeded_annealing_start`.
+fn annealing_start<T: Architecture + Send + Sync + Clone + 'static>(
+    c: &Circuit,
+    arch: &T,
+    isom_map: &Option<QubitMap>,
+    region_constraints: &RegionConstraints,
+    isom_deadline: Instant,
+    rng: &mut StdRng,
+) -> (QubitMap, RegionConstraints) {
+    if let Some(map) = isom_map {
+        return (map.clone(), region_constraints.clone());
+    }
+    if CONFIG.load().isom_core_fallback {
+        if let Some(start) = core_isomorphism_start(c, arch, region_constraints, isom_deadline, rng) {
+            return start;
+        }
+    }
+    (
+        unseeded_annealing_start(c, arch, region_constraints, rng),
+        region_constraints.clone(),
+    )
+}
+
+/// A start for annealing with nothing better to go on: `bfs_initial_map` when
+/// `bfs_anneal_start` is set and the placement meets `region_constraints`, else a random map.
+fn unseeded_annealing_start<T: Architecture>(
+    c: &Circuit,
+    arch: &T,
+    region_constraints: &RegionConstraints,
+    rng: &mut StdRng,
+) -> QubitMap {
+    if CONFIG.load().bfs_anneal_start {
+        let map = bfs_initial_map(c, arch);
+        if satisfies_constraints(&map, region_constraints) {
+            return map;
+        }
+    }
+    random_constrained_map(c, arch, region_constraints, rng)
+}
+
+fn random_neighbor<T: Architecture>(
+    map: &QubitMap,
+    arch: &T,
+    region_constraints: &RegionConstraints,
+    rng: &mut StdRng,
+) -> QubitMap {
     let mut moves: Vec<Box<dyn Fn(&QubitMap) -> QubitMap>> = Vec::new();
-    for q1 in map.keys() {
-        for q2 in map.keys() {
-            if q1 == q2 {
+    // fix the move order so a seeded rng picks the same move every run
+    let qubits: Vec<_> = map.keys().sorted_by_key(|q| q.get_index()).collect();
+    for q1 in &qubits {
+        for q2 in &qubits {
+            if q1 == q2
+                || !allowed_at(region_constraints, q1, &map[q2])
+                || !allowed_at(region_constraints, q2, &map[q1])
+            {
                 continue;
             }
             let swap_keys = |m: &QubitMap| {
                 let mut new_map = m.clone();
-                let loc1 = m.get(q1).unwrap();
-                let loc2 = m.get(q2).unwrap();
-                new_map.insert(*q1, *loc2);
-                new_map.insert(*q2, *loc1);
+                let loc1 = m.get(*q1).unwrap();
+                let loc2 = m.get(*q2).unwrap();
+                new_map.insert(**q1, *loc2);
+                new_map.insert(**q2, *loc1);
                 return new_map;
             };
             moves.push(Box::new(swap_keys));
         }
     }
-    for q in map.keys() {
-        for l in arch.locations() {
-            if !map.values().any(|x| *x == l) {
+    // on a fully occupied architecture only the swaps above are valid
+    let locations = arch.locations();
+    let open: Vec<_> = locations.iter().filter(|l| has_room(arch, map, **l)).copied().collect();
+    for q in qubits.iter().copied() {
+        for l in open.iter().copied() {
+            if map[q] != l && allowed_at(region_constraints, q, &l) {
                 let l = l.clone();
                 let into_open = move |m: &QubitMap| {
                     let mut new_map = m.clone();
@@ -147,31 +498,1175 @@ fn random_neighbor<T: Architecture>(map: &QubitMap, arch: &T) -> QubitMap {
             }
         }
     }
-    let rng = &mut rand::rng();
-    let chosen_move = moves.choose(rng).unwrap();
-    return chosen_move(&map);
+    let next = match moves.choose(rng) {
+        Some(chosen_move) => chosen_move(map),
+        // every qubit is pinned in place by its region
+        None => map.clone(),
+    };
+    assert_injective(arch, &next);
+    next
+}
+
+/// Debug check that no location holds more qubits than its capacity, i.e. that no two
+/// qubits share a location on the usual capacity-one architectures.
+fn assert_injective<A: Architecture>(arch: &A, map: &QubitMap) {
+    if cfg!(debug_assertions) {
+        for (loc, n) in map.values().counts() {
+            assert!(
+                n <= arch.capacity(*loc),
+                "{} qubits share {:?}, which holds {}",
+                n,
+                loc,
+                arch.capacity(*loc)
+            );
+        }
+    }
 }
 
-fn sim_anneal_mapping_search<T: Architecture>(
+/// Runs `chains` annealing chains, the first from `start` and the rest from random maps, and
+/// returns the best map any of them finds.
+#[allow(clippy::too_many_arguments)]
+fn sim_anneal_mapping_search<T: Architecture + Sync>(
+    c: &Circuit,
     start: QubitMap,
     arch: &T,
     initial_temp: f64,
     term_temp: f64,
     cool_rate: f64,
-    heuristic: impl Fn(&QubitMap) -> f64,
+    heuristic: impl Fn(&QubitMap) -> f64 + Sync,
+    region_constraints: &RegionConstraints,
+    chains: usize,
+    rng: &mut StdRng,
 ) -> QubitMap {
-    return simulated_anneal(
-        start,
-        initial_temp,
-        term_temp,
-        cool_rate,
-        |m| random_neighbor(m, arch),
+    let _timer = PhaseTimer::start(Phase::Annealing);
+    let seeds: Vec<u64> = (0..chains.max(1))
+        .map(|_| rng.random())
+        .collect();
+    let chains: Vec<_> = seeds
+        .into_par_iter()
+        .enumerate()
+        .map(|(i, seed)| {
+            let chain_rng = &mut StdRng::seed_from_u64(seed);
+            let chain_start = if i == 0 {
+                start.clone()
+            } else {
+                random_constrained_map(c, arch, region_constraints, chain_rng)
+            };
+            let best = simulated_anneal(
+                chain_start,
+                initial_temp,
+                term_temp,
+                cool_rate,
+                |m, rng| random_neighbor(m, arch, region_constraints, rng),
+                &heuristic,
+                chain_rng,
+            );
+            let cost = heuristic(&best);
+            (best, cost)
+        })
+        .collect();
+    chains
+        .into_iter()
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+        .unwrap()
+        .0
+}
+
+/// Where a routing pass stands after a step: the gates it still has to place, the steps
+/// taken so far and the cost they add up to. `search` tells parallel searches apart.
+#[derive(Clone, Debug, Serialize)]
+pub struct RouteProgress {
+    pub search: usize,
+    pub gates_remaining: usize,
+    pub steps_taken: usize,
+    pub running_cost: f64,
+}
+
+/// A caller's hook for `RouteProgress`, called after each routing step. It runs on the
+/// routing threads, so it should be cheap, and it must not write to stdout, where the
+/// results go.
+pub type Progress<'a> = dyn Fn(RouteProgress) + Sync + 'a;
+
+fn report_progress(progress: Option<&Progress>, step: RouteProgress) {
+    if let Some(progress) = progress {
+        progress(step);
+    }
+}
+
+/// Time spent in each phase of the solves run since the last `take_phase_timings`: the
+/// isomorphism search, the annealing search for a starting map, and routing. Phases run by
+/// parallel searches add up, so a total can exceed the wall-clock time of the solve.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PhaseTimings {
+    pub isomorphism: Duration,
+    pub annealing: Duration,
+    pub routing: Duration,
+}
+
+#[derive(Clone, Copy)]
+enum Phase {
+    Isomorphism,
+    Annealing,
+    Routing,
+}
+
+#[cfg(feature = "phase-timing")]
+static PHASE_TIMINGS: std::sync::Mutex<PhaseTimings> = std::sync::Mutex::new(PhaseTimings {
+    isomorphism: Duration::ZERO,
+    annealing: Duration::ZERO,
+    routing: Duration::ZERO,
+});
+
+/// Returns the phase timings collected so far and starts over from zero. Nothing is
+/// collected unless the solver is built with the `phase-timing` feature; without it the
+/// timings are always zero.
+pub fn take_phase_timings() -> PhaseTimings {
+    #[cfg(feature = "phase-timing")]
+    return std::mem::take(&mut *PHASE_TIMINGS.lock().unwrap());
+    #[cfg(not(feature = "phase-timing"))]
+    return PhaseTimings::default();
+}
+
+/// Adds the time from `start` until it is dropped to its phase. A timer started while
+/// another runs on the same thread adds nothing, so a phase that wraps a whole pass, like a
+/// SABRE refinement, isn't counted again by the routing passes inside it. An empty struct
+/// without the `phase-timing` feature.
+#[cfg(feature = "phase-timing")]
+struct PhaseTimer {
+    phase: Phase,
+    start: Instant,
+    outermost: bool,
+}
+
+#[cfg(feature = "phase-timing")]
+thread_local! {
+    static TIMER_RUNNING: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+#[cfg(not(feature = "phase-timing"))]
+struct PhaseTimer;
+
+impl PhaseTimer {
+    #[cfg(feature = "phase-timing")]
+    fn start(phase: Phase) -> Self {
+        PhaseTimer {
+            phase,
+            start: Instant::now(),
+            outermost: !TIMER_RUNNING.with(|running| running.replace(true)),
+        }
+    }
+
+    #[cfg(not(feature = "phase-timing"))]
+    #[inline(always)]
+    fn start(_phase: Phase) -> Self {
+        return PhaseTimer;
+    }
+}
+
+#[cfg(feature = "phase-timing")]
+impl Drop for PhaseTimer {
+    fn drop(&mut self) {
+        if !self.outermost {
+            return;
+        }
+        TIMER_RUNNING.with(|running| running.set(false));
+        let elapsed = self.start.elapsed();
+        let mut timings = PHASE_TIMINGS.lock().unwrap();
+        match self.phase {
+            Phase::Isomorphism => timings.isomorphism += elapsed,
+            Phase::Annealing => timings.annealing += elapsed,
+            Phase::Routing => timings.routing += elapsed,
+        }
+    }
+}
+
+/// When a solve started now must stop routing, per `solve_timeout`.
+fn solve_deadline() -> Option<Instant> {
+    CONFIG.load().solve_timeout.map(|t| Instant::now() + Duration::from_secs(t))
+}
+
+fn route<
+    A: Architecture,
+    R: Transition<G, A> + Debug,
+    G: GateImplementation + Debug,
+    I: IntoIterator<Item = G>,
+    J: IntoIterator<Item = R>,
+>(
+    c: &Circuit,
+    arch: &A,
+    map: &QubitMap,
+    transitions: &impl Fn(&Step<G>) -> J,
+    implement_gate: &impl Fn(&Step<G>, &A, &Gate) -> I,
+    step_cost: fn(&Step<G>, &A) -> f64,
+    map_eval: &impl Fn(&Circuit, &QubitMap) -> f64,
+    explore_routing_orders: bool,
+    objective: RoutingObjective,
+    crit_table: &[usize],
+    id: usize,
+    deadline: Option<Instant>,
+    progress: Option<&Progress>,
+    rng: &mut StdRng,
+) -> CompilerResult<G> {
+    if let Some(beam_width) = CONFIG.load().routing_beam_width {
+        return route_beam(
+            c,
+            arch,
+            map,
+            transitions,
+            implement_gate,
+            step_cost,
+            map_eval,
+            explore_routing_orders,
+            objective,
+            crit_table,
+            id,
+            beam_width.max(1),
+            deadline,
+            progress,
+            rng,
+        );
+    }
+    let mut step_0 = Step {
+        map: map.clone(),
+        implemented_gates: HashSet::new(),
+    };
+    let mut current_circ = c.clone();
+    let cost = step_cost(&step_0, arch);
+    let executable = &c.get_front_layer();
+    let mut routing_search_cool_rate = CONFIG.load().routing_search_cool_rate;
+    let routing_search_initial_temp = CONFIG.load().routing_search_initial_temp;
+    let routing_search_term_temp = CONFIG.load().routing_search_term_temp;
+    if id < 4 {
+        routing_search_cool_rate = CONFIG.load().limited_search_cool_rates[id];
+    }
+    if explore_routing_orders {
+        step_0.max_step_all_orders(
+            executable,
+            arch,
+            &implement_gate,
+            crit_table,
+            routing_search_initial_temp,
+            routing_search_term_temp,
+            routing_search_cool_rate,
+            rng,
+        );
+    } else {
+        step_0.max_step(executable, arch, &implement_gate);
+    }
+    current_circ.remove_gates(&(step_0.gates()));
+    route_onward(
+        c,
+        current_circ,
+        vec![step_0],
+        Vec::new(),
+        cost,
+        arch,
+        transitions,
+        implement_gate,
+        step_cost,
+        map_eval,
+        explore_routing_orders,
+        objective,
+        crit_table,
+        id,
+        deadline,
+        progress,
+        rng,
+    )
+}
+
+/// The routing loop of `route`: extends `steps`, which together with `trans_taken` and
+/// `cost` so far implement all of `c` but `current_circ`, until nothing is left.
+#[allow(clippy::too_many_arguments)]
+fn route_onward<
+    A: Architecture,
+    R: Transition<G, A> + Debug,
+    G: GateImplementation + Debug,
+    I: IntoIterator<Item = G>,
+    J: IntoIterator<Item = R>,
+>(
+    c: &Circuit,
+    mut current_circ: Circuit,
+    mut steps: Vec<Step<G>>,
+    mut trans_taken: Vec<String>,
+    mut cost: f64,
+    arch: &A,
+    transitions: &impl Fn(&Step<G>) -> J,
+    implement_gate: &impl Fn(&Step<G>, &A, &Gate) -> I,
+    step_cost: fn(&Step<G>, &A) -> f64,
+    map_eval: &impl Fn(&Circuit, &QubitMap) -> f64,
+    explore_routing_orders: bool,
+    objective: RoutingObjective,
+    crit_table: &[usize],
+    id: usize,
+    deadline: Option<Instant>,
+    progress: Option<&Progress>,
+    rng: &mut StdRng,
+) -> CompilerResult<G> {
+    let _timer = PhaseTimer::start(Phase::Routing);
+    let mut gamma_boost = 0.0;
+    let mut partial = false;
+    while current_circ.gates.len() > 0 {
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            eprintln!(
+                "Routing timed out with {} gates left; returning the steps routed so far",
+                current_circ.gates.len()
+            );
+            partial = true;
+            break;
+        }
+        gamma_boost = next_gamma_boost(gamma_boost, &steps);
+        let best = find_best_next_step(
+            &current_circ,
+            arch,
+            &transitions,
+            &implement_gate,
+            steps.last().unwrap(),
+            step_cost,
+            &map_eval,
+            explore_routing_orders,
+            objective,
+            &crit_table,
+            id,
+            CONFIG.load().gamma * (1.0 + gamma_boost),
+            rng,
+        );
+        match best {
+            Some((s, trans, _b)) => {
+                current_circ.remove_gates(&s.gates());
+                cost += step_cost(&s, arch);
+                steps.push(s);
+                trans_taken.push(trans.repr());
+                cost += trans.cost(arch);
+                report_progress(progress, RouteProgress {
+                    search: id,
+                    gates_remaining: current_circ.gates.len(),
+                    steps_taken: steps.len(),
+                    running_cost: cost,
+                });
+            }
+            None => {
+                let unroutable = current_circ.get_front_layer().iter().map(|g| g.id).collect::<Vec<_>>();
+                eprintln!(
+                    "No valid next step found; returning the steps routed so far. Unroutable gates: {:?}",
+                    unroutable
+                );
+                partial = true;
+                break;
+            }
+        }
+    }
+    finish_route(c, steps, trans_taken, cost, partial, arch, implement_gate)
+}
+
+/// Packs routed steps into a `CompilerResult`, recording candidate counts when asked and
+/// checking in debug builds that a complete routing implements every gate of `c` once.
+fn finish_route<A: Architecture, G: GateImplementation + Debug, I: IntoIterator<Item = G>>(
+    c: &Circuit,
+    steps: Vec<Step<G>>,
+    trans_taken: Vec<String>,
+    cost: f64,
+    partial: bool,
+    arch: &A,
+    implement_gate: &impl Fn(&Step<G>, &A, &Gate) -> I,
+) -> CompilerResult<G> {
+    let candidate_counts = if CONFIG.load().record_candidate_counts {
+        count_candidates(&steps, arch, implement_gate)
+    } else {
+        Vec::new()
+    };
+    let res = CompilerResult {
+        steps,
+        transitions: trans_taken,
+        cost,
+        candidate_counts,
+        partial,
+        makespan: None,
+    };
+    if !partial {
+        debug_assert_eq!(res.assert_implements_all(c), Ok(()), "routing dropped or repeated gates");
+    }
+    res
+}
+
+/// Raises the map-heuristic boost to `stall_boost` once the last `stall_window`
+/// steps implemented no gates, and otherwise lets it decay by `stall_decay`.
+pub fn next_gamma_boost<G: GateImplementation>(boost: f64, steps: &[Step<G>]) -> f64 {
+    let window = CONFIG.load().stall_window.max(1);
+    let stalled = steps.len() >= window
+        && steps[steps.len() - window..]
+            .iter()
+            .all(|s| s.implemented_gates.is_empty());
+    if stalled {
+        return CONFIG.load().stall_boost;
+    }
+    boost * CONFIG.load().stall_decay
+}
+
+/// Layers the circuit on `map` without moving anything, one layer per step joined by
+/// map-preserving transitions, with costs charged as `route` charges them. Returns `None`
+/// as soon as some layer can't run in place, leaving the circuit to `route`.
+fn route_in_place<
+    A: Architecture,
+    R: Transition<G, A> + Debug,
+    G: GateImplementation + Debug,
+    I: IntoIterator<Item = G>,
+    J: IntoIterator<Item = R>,
+>(
+    c: &Circuit,
+    arch: &A,
+    map: &QubitMap,
+    transitions: &impl Fn(&Step<G>) -> J,
+    implement_gate: &impl Fn(&Step<G>, &A, &Gate) -> I,
+    step_cost: fn(&Step<G>, &A) -> f64,
+) -> Option<CompilerResult<G>> {
+    let _timer = PhaseTimer::start(Phase::Routing);
+    // with no layers there would be no steps at all; leave it to `route`, which keeps the map
+    if c.gates.is_empty() {
+        return None;
+    }
+    assert_injective(arch, map);
+    let mut steps: Vec<Step<G>> = Vec::new();
+    let mut trans_taken = Vec::new();
+    let mut cost = 0.0;
+    for layer in c.layers() {
+        let mut step = Step {
+            map: map.clone(),
+            implemented_gates: HashSet::new(),
+        };
+        match steps.last() {
+            Some(last) => {
+                let identity = transitions(last)
+                    .into_iter()
+                    .find(|t| t.apply(last).map == *map)?;
+                cost += identity.cost(arch);
+                trans_taken.push(identity.repr());
+                step.max_step(&layer, arch, implement_gate);
+                cost += step_cost(&step, arch);
+            }
+            // `route` charges the first step before placing its gates
+            None => {
+                cost += step_cost(&step, arch);
+                step.max_step(&layer, arch, implement_gate);
+            }
+        }
+        if step.implemented_gates.len() < layer.len() {
+            return None;
+        }
+        steps.push(step);
+    }
+    Some(finish_route(c, steps, trans_taken, cost, false, arch, implement_gate))
+}
+
+/// For each gate of each step, counts the implementations `implement_gate` offers on that
+/// step's map before any of the step's gates are placed.
+fn count_candidates<A: Architecture, G: GateImplementation, I: IntoIterator<Item = G>>(
+    steps: &[Step<G>],
+    arch: &A,
+    implement_gate: &impl Fn(&Step<G>, &A, &Gate) -> I,
+) -> Vec<(usize, usize)> {
+    let mut counts = Vec::new();
+    for step in steps {
+        let empty_step = Step {
+            map: step.map.clone(),
+            implemented_gates: HashSet::new(),
+        };
+        let mut gates = step.gates();
+        gates.sort_by_key(|g| g.id);
+        for gate in gates {
+            let count = implement_gate(&empty_step, arch, &gate).into_iter().count();
+            counts.push((gate.id, count));
+        }
+    }
+    counts
+}
+
+fn find_best_next_step<
+    A: Architecture,
+    R: Transition<G, A> + Debug,
+    G: GateImplementation,
+    I: IntoIterator<Item = G>,
+    J: IntoIterator<Item = R>,
+>(
+    c: &Circuit,
+    arch: &A,
+    transitions: &impl Fn(&Step<G>) -> J,
+    implement_gate: impl Fn(&Step<G>, &A, &Gate) -> I,
+    last_step: &Step<G>,
+    step_cost: fn(&Step<G>, &A) -> f64,
+    map_eval: impl Fn(&Circuit, &QubitMap) -> f64,
+    explore_routing_orders: bool,
+    objective: RoutingObjective,
+    crit_table: &[usize],
+    id: usize,
+    gamma: f64,
+    rng: &mut StdRng,
+) -> Option<(Step<G>, R, f64)> {
+    let mut best_options = Vec::new();
+    let mut best_cost = std::f64::MAX;
+    let mut best_progress = 0;
+    for (next_step, trans, cost) in scored_next_steps(
+        c,
+        arch,
+        transitions,
+        implement_gate,
+        last_step,
+        step_cost,
+        map_eval,
+        explore_routing_orders,
+        crit_table,
+        id,
+        gamma,
+        rng,
+    ) {
+        // println!(
+        //     "executable : {:?}, transition : {:?} , cost : {:?}",
+        //     executable, trans, cost
+        // );
+        // when minimizing depth, a step implementing more gates wins whatever it costs
+        let progress = match objective {
+            RoutingObjective::CostMinimizing => 0,
+            RoutingObjective::DepthMinimizing => next_step.gates().len(),
+        };
+        if progress > best_progress || (progress == best_progress && cost <= best_cost) {
+            if progress > best_progress || cost < best_cost {
+                best_options.clear();
+                best_cost = cost;
+                best_progress = progress;
+            }
+            best_options.push((next_step, trans, cost));
+        }
+    }
+
+    if best_options.is_empty() {
+        None
+    } else {
+        let index = rng.random_range(..best_options.len());
+        Some(best_options.remove(index))
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn scored_next_steps<
+    A: Architecture,
+    R: Transition<G, A> + Debug,
+    G: GateImplementation,
+    I: IntoIterator<Item = G>,
+    J: IntoIterator<Item = R>,
+>(
+    c: &Circuit,
+    arch: &A,
+    transitions: &impl Fn(&Step<G>) -> J,
+    implement_gate: impl Fn(&Step<G>, &A, &Gate) -> I,
+    last_step: &Step<G>,
+    step_cost: fn(&Step<G>, &A) -> f64,
+    map_eval: impl Fn(&Circuit, &QubitMap) -> f64,
+    explore_routing_orders: bool,
+    crit_table: &[usize],
+    id: usize,
+    gamma: f64,
+    rng: &mut StdRng,
+) -> Vec<(Step<G>, R, f64)> {
+    let mut options = Vec::new();
+    let executable = c.get_front_layer();
+    let next_layer = circuit_from_gates(&c.extended_layer());
+    // the `lookahead_window` layers after the extended set
+    let lookahead_layers: Vec<Circuit> = if CONFIG.load().lookahead_weight > 0.0 {
+        let mut rest = c.clone();
+        rest.remove_gates(&executable);
+        rest.remove_gates(&next_layer.gates);
+        rest.layers()
+            .take(CONFIG.load().lookahead_window)
+            .map(|layer| circuit_from_gates(&layer))
+            .collect()
+    } else {
+        vec![]
+    };
+    let mut routing_search_cool_rate = CONFIG.load().routing_search_cool_rate;
+    let routing_search_initial_temp = CONFIG.load().routing_search_initial_temp;
+    let routing_search_term_temp = CONFIG.load().routing_search_term_temp;
+    if id < 4 {
+        routing_search_cool_rate = CONFIG.load().limited_search_cool_rates[id];
+    }
+    let mut candidates: Vec<R> = transitions(last_step).into_iter().collect();
+    if let Some(cap) = CONFIG.load().max_candidate_transitions {
+        if candidates.len() > cap {
+            let front = circuit_from_gates(&executable);
+            // keep the `cap` transitions whose maps suit the front layer best, in their
+            // original order so ties still break the same way
+            let kept: HashSet<usize> = candidates
+                .iter()
+                .map(|t| map_eval(&front, &t.apply(last_step).map))
+                .enumerate()
+                .sorted_by(|a, b| a.1.total_cmp(&b.1))
+                .take(cap)
+                .map(|(i, _)| i)
+                .collect();
+            candidates = candidates
+                .into_iter()
+                .enumerate()
+                .filter(|(i, _)| kept.contains(i))
+                .map(|(_, t)| t)
+                .collect();
+        }
+    }
+    for trans in candidates {
+        let mut next_step = trans.apply(last_step);
+        assert_injective(arch, &next_step.map);
+
+        if explore_routing_orders {
+            next_step.max_step_all_orders(
+                &executable,
+                arch,
+                &implement_gate,
+                crit_table,
+                routing_search_initial_temp,
+                routing_search_term_temp,
+                routing_search_cool_rate,
+                rng,
+            );
+        } else {
+            next_step.max_step(&executable, arch, &implement_gate);
+        }
+        let s_cost = step_cost(&next_step, arch);
+        let t_cost = trans.cost(arch);
+        let front_layer_cost =
+            map_eval(&circuit_from_gates(&executable), &next_step.map) / (executable.len() as f64);
+        // nothing follows the front layer on the last step
+        let next_layer_cost = if next_layer.gates.is_empty() {
+            0.0
+        } else {
+            map_eval(&next_layer, &next_step.map) / (next_layer.gates.len() as f64)
+        };
+        let mut lookahead_cost = 0.0;
+        let mut layer_weight = CONFIG.load().lookahead_weight;
+        for layer in &lookahead_layers {
+            lookahead_cost +=
+                layer_weight * map_eval(layer, &next_step.map) / (layer.gates.len() as f64);
+            layer_weight *= CONFIG.load().lookahead_decay;
+        }
+        let m_cost =
+            front_layer_cost + CONFIG.load().extended_set_weight * next_layer_cost + lookahead_cost;
+        let total_criticality: usize = next_step
+            .gates()
+            .into_iter()
+            .map(|x| crit_table[x.id])
+            .sum();
+        let weighted_vals = std::iter::zip(
+            vec![CONFIG.load().alpha, CONFIG.load().beta, gamma, CONFIG.load().delta],
+            vec![s_cost, t_cost, m_cost, -(total_criticality as f64)],
+        );
+        let cost = normalize_cost(weighted_vals, CONFIG.load().cost_norm);
+        options.push((next_step, trans, cost));
+    }
+    options
+}
+
+/// What `objective` ranks a finished routing by, lowest first: the cost alone, or the
+/// step count with the cost breaking ties.
+fn objective_key<G: GateImplementation>(res: &CompilerResult<G>, objective: RoutingObjective) -> (usize, f64) {
+    match objective {
+        RoutingObjective::CostMinimizing => (0, res.cost),
+        RoutingObjective::DepthMinimizing => (res.steps.len(), res.cost),
+    }
+}
+
+struct BeamEntry<G: GateImplementation> {
+    result: CompilerResult<G>,
+    remaining: Circuit,
+    score: f64,
+    gamma_boost: f64,
+}
+
+#[allow(clippy::too_many_arguments)]
+fn route_beam<
+    A: Architecture,
+    R: Transition<G, A> + Debug,
+    G: GateImplementation + Debug,
+    I: IntoIterator<Item = G>,
+    J: IntoIterator<Item = R>,
+>(
+    c: &Circuit,
+    arch: &A,
+    map: &QubitMap,
+    transitions: &impl Fn(&Step<G>) -> J,
+    implement_gate: &impl Fn(&Step<G>, &A, &Gate) -> I,
+    step_cost: fn(&Step<G>, &A) -> f64,
+    map_eval: &impl Fn(&Circuit, &QubitMap) -> f64,
+    explore_routing_orders: bool,
+    objective: RoutingObjective,
+    crit_table: &[usize],
+    id: usize,
+    beam_width: usize,
+    deadline: Option<Instant>,
+    progress: Option<&Progress>,
+    rng: &mut StdRng,
+) -> CompilerResult<G> {
+    let _timer = PhaseTimer::start(Phase::Routing);
+    let mut step_0 = Step {
+        map: map.clone(),
+        implemented_gates: HashSet::new(),
+    };
+    let mut remaining = c.clone();
+    let cost = step_cost(&step_0, arch);
+    let executable = &c.get_front_layer();
+    let mut routing_search_cool_rate = CONFIG.load().routing_search_cool_rate;
+    if id < 4 {
+        routing_search_cool_rate = CONFIG.load().limited_search_cool_rates[id];
+    }
+    if explore_routing_orders {
+        step_0.max_step_all_orders(
+            executable,
+            arch,
+            &implement_gate,
+            crit_table,
+            CONFIG.load().routing_search_initial_temp,
+            CONFIG.load().routing_search_term_temp,
+            routing_search_cool_rate,
+            rng,
+        );
+    } else {
+        step_0.max_step(executable, arch, &implement_gate);
+    }
+    remaining.remove_gates(&(step_0.gates()));
+    let mut beam = vec![BeamEntry {
+        result: CompilerResult {
+            steps: vec![step_0],
+            transitions: Vec::new(),
+            cost,
+            candidate_counts: Vec::new(),
+            partial: false,
+            makespan: None,
+        },
+        remaining,
+        score: 0.0,
+        gamma_boost: 0.0,
+    }];
+    let mut front = beam[0].result.clone();
+    let mut best_complete: Option<CompilerResult<G>> = None;
+    let mut timed_out = false;
+    while !beam.is_empty() {
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            timed_out = true;
+            break;
+        }
+        let mut candidates = Vec::new();
+        for entry in beam {
+            if entry.remaining.gates.is_empty() {
+                if best_complete
+                    .as_ref()
+                    .is_none_or(|b| objective_key(&entry.result, objective) < objective_key(b, objective))
+                {
+                    best_complete = Some(entry.result);
+                }
+                continue;
+            }
+            let gamma_boost = next_gamma_boost(entry.gamma_boost, &entry.result.steps);
+            for (s, trans, s_score) in scored_next_steps(
+                &entry.remaining,
+                arch,
+                transitions,
+                implement_gate,
+                entry.result.steps.last().unwrap(),
+                step_cost,
+                map_eval,
+                explore_routing_orders,
+                crit_table,
+                id,
+                CONFIG.load().gamma * (1.0 + gamma_boost),
+                rng,
+            ) {
+                let mut remaining = entry.remaining.clone();
+                remaining.remove_gates(&s.gates());
+                let mut steps = entry.result.steps.clone();
+                let mut trans_taken = entry.result.transitions.clone();
+                let cost = entry.result.cost + step_cost(&s, arch) + trans.cost(arch);
+                steps.push(s);
+                trans_taken.push(trans.repr());
+                candidates.push(BeamEntry {
+                    result: CompilerResult {
+                        steps,
+                        transitions: trans_taken,
+                        cost,
+                        candidate_counts: Vec::new(),
+                        partial: false,
+                        makespan: None,
+                    },
+                    remaining,
+                    score: entry.score + s_score,
+                    gamma_boost,
+                });
+            }
+        }
+        // entries of one round have taken the same number of steps, so for depth the ones
+        // with fewest gates left go first
+        let key = |e: &BeamEntry<G>| match objective {
+            RoutingObjective::CostMinimizing => (0, e.score),
+            RoutingObjective::DepthMinimizing => (e.remaining.gates.len(), e.score),
+        };
+        candidates.sort_by(|a, b| {
+            let (a, b) = (key(a), key(b));
+            a.0.cmp(&b.0).then(a.1.total_cmp(&b.1))
+        });
+        let keep = beam_width.min(candidates.len());
+        if keep > 0 {
+            // keep a random few of the entries tied at the cut, as `find_best_next_step`
+            // picks among its ties, so a width of 1 routes like the greedy search
+            let cut = key(&candidates[keep - 1]);
+            let first = candidates.iter().position(|e| key(e) == cut).unwrap();
+            let mut tied: Vec<_> = candidates.drain(first..).take_while(|e| key(e) == cut).collect();
+            for _ in first..keep {
+                let i = rng.random_range(..tied.len());
+                candidates.push(tied.swap_remove(i));
+            }
+        }
+        // the beam's front entry stands for the pass
+        if let Some(entry) = candidates.first() {
+            front = entry.result.clone();
+            report_progress(progress, RouteProgress {
+                search: id,
+                gates_remaining: entry.remaining.gates.len(),
+                steps_taken: entry.result.steps.len(),
+                running_cost: entry.result.cost,
+            });
+        }
+        beam = candidates;
+    }
+    let mut best = match best_complete {
+        Some(best) => best,
+        None => {
+            if timed_out {
+                eprintln!("Routing timed out; returning the steps routed so far");
+            } else {
+                // every entry ran out of next steps before finishing
+                eprintln!("No valid next step found; returning the steps routed so far");
+            }
+            front.partial = true;
+            front
+        }
+    };
+    if CONFIG.load().record_candidate_counts {
+        best.candidate_counts = count_candidates(&best.steps, arch, implement_gate);
+    }
+    if !best.partial {
+        debug_assert_eq!(best.assert_implements_all(c), Ok(()), "routing dropped or repeated gates");
+    }
+    best
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn solve<
+    A: Architecture + Send + Sync + Clone + 'static,
+    R: Transition<G, A> + Debug,
+    G: GateImplementation + Debug,
+    I: IntoIterator<Item = G>,
+    J: IntoIterator<Item = R>,
+>(
+    c: &Circuit,
+    arch: &A,
+    transitions: &impl Fn(&Step<G>) -> J,
+    implement_gate: &impl Fn(&Step<G>, &A, &Gate) -> I,
+    step_cost: fn(&Step<G>, &A) -> f64,
+    mapping_heuristic: Option<fn(&A, &Circuit, &QubitMap) -> f64>,
+    explore_routing_orders: bool,
+    region_constraints: &RegionConstraints,
+    initial_map: &InitialMapStrategy,
+    progress: Option<&Progress>,
+) -> CompilerResult<G> {
+    solve_with_rng(
+        c,
+        arch,
+        transitions,
+        implement_gate,
+        step_cost,
+        mapping_heuristic,
+        explore_routing_orders,
+        CONFIG.load().routing_objective,
+        region_constraints,
+        initial_map,
+        CONFIG.load().parallel_searches,
+        progress,
+        &mut search_rng(0),
+    )
+}
+
+/// Like `solve`, but routing for `objective` instead of the configured `routing_objective`.
+#[allow(clippy::too_many_arguments)]
+pub fn solve_with_objective<
+    A: Architecture + Send + Sync + Clone + 'static,
+    R: Transition<G, A> + Debug,
+    G: GateImplementation + Debug,
+    I: IntoIterator<Item = G>,
+    J: IntoIterator<Item = R>,
+>(
+    c: &Circuit,
+    arch: &A,
+    transitions: &impl Fn(&Step<G>) -> J,
+    implement_gate: &impl Fn(&Step<G>, &A, &Gate) -> I,
+    step_cost: fn(&Step<G>, &A) -> f64,
+    mapping_heuristic: Option<fn(&A, &Circuit, &QubitMap) -> f64>,
+    explore_routing_orders: bool,
+    region_constraints: &RegionConstraints,
+    initial_map: &InitialMapStrategy,
+    objective: RoutingObjective,
+    progress: Option<&Progress>,
+) -> CompilerResult<G> {
+    solve_with_rng(
+        c,
+        arch,
+        transitions,
+        implement_gate,
+        step_cost,
+        mapping_heuristic,
+        explore_routing_orders,
+        objective,
+        region_constraints,
+        initial_map,
+        CONFIG.load().parallel_searches,
+        progress,
+        &mut search_rng(0),
+    )
+}
+
+/// Like `solve`, but routes from the caller's `map` instead of searching for one.
+/// Fails if `map` doesn't fit `arch` (see `check_initial_map`).
+#[allow(clippy::too_many_arguments)]
+pub fn solve_with_map<
+    A: Architecture + Send + Sync + Clone + 'static,
+    R: Transition<G, A> + Debug,
+    G: GateImplementation + Debug,
+    I: IntoIterator<Item = G>,
+    J: IntoIterator<Item = R>,
+>(
+    c: &Circuit,
+    arch: &A,
+    transitions: &impl Fn(&Step<G>) -> J,
+    implement_gate: &impl Fn(&Step<G>, &A, &Gate) -> I,
+    step_cost: fn(&Step<G>, &A) -> f64,
+    mapping_heuristic: Option<fn(&A, &Circuit, &QubitMap) -> f64>,
+    explore_routing_orders: bool,
+    map: &QubitMap,
+    progress: Option<&Progress>,
+) -> Result<CompilerResult<G>, InitialMapError> {
+    check_initial_map(c, arch, map)?;
+    Ok(solve(
+        c,
+        arch,
+        transitions,
+        implement_gate,
+        step_cost,
+        mapping_heuristic,
+        explore_routing_orders,
+        &HashMap::new(),
+        &InitialMapStrategy::Provided(map.clone()),
+        progress,
+    ))
+}
+
+/// Recompiles `c`, an edited version of the circuit `prev` was compiled from, reusing the
+/// leading steps of `prev` that only implement gates from the longest prefix of `c` it
+/// routed unchanged (see `reusable_steps`). The rest of `c` is routed onward from the last
+/// kept step. When no step can be kept, routing starts over from `prev`'s starting map,
+/// with any qubits new to `c` placed at random. Nothing is kept, and qubits are moved back
+/// into their regions, where `prev` started outside `region_constraints`.
+#[allow(clippy::too_many_arguments)]
+pub fn resolve_incremental<
+    A: Architecture,
+    R: Transition<G, A> + Debug,
+    G: GateImplementation + Debug,
+    I: IntoIterator<Item = G>,
+    J: IntoIterator<Item = R>,
+>(
+    prev: &CompilerResult<G>,
+    c: &Circuit,
+    arch: &A,
+    transitions: &impl Fn(&Step<G>) -> J,
+    implement_gate: &impl Fn(&Step<G>, &A, &Gate) -> I,
+    step_cost: fn(&Step<G>, &A) -> f64,
+    mapping_heuristic: Option<fn(&A, &Circuit, &QubitMap) -> f64>,
+    explore_routing_orders: bool,
+    region_constraints: &RegionConstraints,
+    progress: Option<&Progress>,
+) -> CompilerResult<G> {
+    let deadline = solve_deadline();
+    let crit_table = &build_criticality_table(c);
+    let map_eval = |circ: &Circuit, m: &QubitMap| mapping_heuristic.map_or(0.0, |h| h(arch, circ, m));
+    let rng = &mut search_rng(0);
+    let prev_start = prev.steps.first().map(|s| s.map.clone()).unwrap_or_default();
+    let (steps, trans_taken, cost) = if satisfies_constraints(&prev_start, region_constraints) {
+        reusable_steps(prev, c, arch, transitions, step_cost)
+    } else {
+        (Vec::new(), Vec::new(), 0.0)
+    };
+    if steps.is_empty() {
+        let start: QubitMap = prev_start
+            .into_iter()
+            .filter(|(q, l)| c.qubits.contains(q) && allowed_at(region_constraints, q, l))
+            .collect();
+        let map = randomly_extend_partial_map(c, arch, &start, region_constraints, rng);
+        return route(
+            c,
+            arch,
+            &map,
+            transitions,
+            implement_gate,
+            step_cost,
+            &map_eval,
+            explore_routing_orders,
+            CONFIG.load().routing_objective,
+            crit_table,
+            0,
+            deadline,
+            progress,
+            rng,
+        );
+    }
+    let mut current_circ = c.clone();
+    for step in &steps {
+        current_circ.remove_gates(&step.gates());
+    }
+    route_onward(
+        c,
+        current_circ,
+        steps,
+        trans_taken,
+        cost,
+        arch,
+        transitions,
+        implement_gate,
+        step_cost,
+        &map_eval,
+        explore_routing_orders,
+        CONFIG.load().routing_objective,
+        crit_table,
+        0,
+        deadline,
+        progress,
+        rng,
+    )
+}
+
+/// The leading steps of `prev` that `c` can keep, with the transitions between them and
+/// what they cost: steps implementing only gates from the longest prefix of `c` that `prev`
+/// implements identically. Each recorded transition is matched by its `repr` against those
+/// `transitions` offers, and reuse stops at the first one it no longer offers. Nothing is
+/// kept if `c` has qubits `prev` never placed.
+fn reusable_steps<A: Architecture, R: Transition<G, A>, G: GateImplementation, J: IntoIterator<Item = R>>(
+    prev: &CompilerResult<G>,
+    c: &Circuit,
+    arch: &A,
+    transitions: &impl Fn(&Step<G>) -> J,
+    step_cost: fn(&Step<G>, &A) -> f64,
+) -> (Vec<Step<G>>, Vec<String>, f64) {
+    let mut steps: Vec<Step<G>> = Vec::new();
+    let mut trans_taken = Vec::new();
+    let mut cost = 0.0;
+    let placed = prev.steps.first().is_some_and(|s| c.qubits.iter().all(|q| s.map.contains_key(q)));
+    if !placed {
+        return (steps, trans_taken, cost);
+    }
+    // `Gate` equality only looks at ids, so an edited gate that kept its id would match
+    let key = |g: &Gate| (g.id, g.operation.clone(), g.qubits.clone());
+    let routed: HashSet<_> = prev.steps.iter().flat_map(|s| s.implemented_gates.iter().map(|g| key(&g.gate))).collect();
+    let prefix: HashSet<_> = c.gates.iter().map(key).take_while(|k| routed.contains(k)).collect();
+    for (i, step) in prev.steps.iter().enumerate() {
+        if !step.implemented_gates.iter().all(|g| prefix.contains(&key(&g.gate))) {
+            break;
+        }
+        if let Some(last) = steps.last() {
+            let Some(repr) = prev.transitions.get(i - 1) else {
+                break;
+            };
+            let taken = transitions(last)
+                .into_iter()
+                .find(|t| &t.repr() == repr && t.apply(last).map == step.map);
+            let Some(trans) = taken else {
+                break;
+            };
+            cost += trans.cost(arch);
+            trans_taken.push(repr.clone());
+        }
+        cost += step_cost(step, arch);
+        steps.push(step.clone());
+    }
+    (steps, trans_taken, cost)
+}
+
+/// The `Parallel` strategy: an isomorphism map and one annealed over `anneal_chains` chains,
+/// keeping whichever `heuristic` scores lower.
+fn parallel_initial_map<T: Architecture + Send + Sync + Clone + 'static>(
+    c: &Circuit,
+    arch: &T,
+    heuristic: fn(&T, &Circuit, &QubitMap) -> f64,
+    region_constraints: &RegionConstraints,
+    anneal_chains: usize,
+    rng: &mut StdRng,
+) -> QubitMap {
+    let map_h = |m: &QubitMap| heuristic(arch, c, m);
+    let isom_budget = Duration::from_secs(CONFIG.load().isom_search_timeout);
+    let isom_deadline = Instant::now() + isom_budget;
+    // with the core fallback on, the full search leaves it half of the budget
+    let full_search_budget = if CONFIG.load().isom_core_fallback { isom_budget / 2 } else { isom_budget };
+    let isom_map = incremental_isomorphism_map_with_timeout(
+        c,
+        arch,
+        full_search_budget,
+        region_constraints,
+        rng,
+    );
+
+    let isom_cost = isom_map.clone().map(|x| map_h(&x));
+
+    let sa_map = match isom_cost {
+        Some(c) if c == 0.0 => None,
+        _ => {
+            let (start, constraints) =
+                annealing_start(c, arch, &isom_map, region_constraints, isom_deadline, rng);
+            Some(sim_anneal_mapping_search(
+                c,
+                start,
+                arch,
+                CONFIG.load().mapping_search_initial_temp,
+                CONFIG.load().mapping_search_term_temp,
+                CONFIG.load().mapping_search_cool_rate,
+                map_h,
+                &constraints,
+                anneal_chains,
+                rng,
+            ))
+        }
+    };
+    let sa_cost = sa_map.clone().map(|x| map_h(&x));
+    match (isom_cost, sa_cost) {
+        (Some(_), None) => isom_map.unwrap(),
+        (Some(i_c), Some(s_c)) if i_c < s_c => isom_map.unwrap(),
+        _ => sa_map.unwrap(),
+    }
+}
+
+/// Runs only the mapping search `solve` starts with and returns the map it would route
+/// from together with its `heuristic` cost, without routing.
+pub fn plan_mapping<A: Architecture + Send + Sync + Clone + 'static>(
+    c: &Circuit,
+    arch: &A,
+    heuristic: fn(&A, &Circuit, &QubitMap) -> f64,
+    region_constraints: &RegionConstraints,
+) -> (QubitMap, f64) {
+    let map = parallel_initial_map(
+        c,
+        arch,
         heuristic,
+        region_constraints,
+        CONFIG.load().parallel_searches,
+        &mut search_rng(0),
     );
+    let cost = heuristic(arch, c, &map);
+    (map, cost)
 }
 
-fn route<
-    A: Architecture,
+#[allow(clippy::too_many_arguments)]
+fn solve_with_rng<
+    A: Architecture + Send + Sync + Clone + 'static,
     R: Transition<G, A> + Debug,
     G: GateImplementation + Debug,
     I: IntoIterator<Item = G>,
@@ -179,238 +1674,194 @@ fn route<
 >(
     c: &Circuit,
     arch: &A,
-    map: &QubitMap,
     transitions: &impl Fn(&Step<G>) -> J,
     implement_gate: &impl Fn(&Step<G>, &A, &Gate) -> I,
     step_cost: fn(&Step<G>, &A) -> f64,
-    map_eval: &impl Fn(&Circuit, &QubitMap) -> f64,
+    mapping_heuristic: Option<fn(&A, &Circuit, &QubitMap) -> f64>,
     explore_routing_orders: bool,
-    crit_table: &HashMap<usize, usize>,
-    id: usize,
+    objective: RoutingObjective,
+    region_constraints: &RegionConstraints,
+    initial_map: &InitialMapStrategy,
+    anneal_chains: usize,
+    progress: Option<&Progress>,
+    rng: &mut StdRng,
 ) -> CompilerResult<G> {
-    let mut steps = Vec::new();
-    let mut trans_taken = Vec::new();
-    let mut step_0 = Step {
-        map: map.clone(),
-        implemented_gates: HashSet::new(),
+    let deadline = solve_deadline();
+    let crit_table = &build_criticality_table(c);
+    let route_h = |c: &Circuit, m: &QubitMap| match mapping_heuristic {
+        Some(heuristic) => heuristic(arch, c, m),
+        None => 0.0,
     };
-    let mut current_circ = c.clone();
-    let mut cost = step_cost(&step_0, arch);
-    let executable = &c.get_front_layer();
-    let mut routing_search_cool_rate = CONFIG.routing_search_cool_rate;
-    let routing_search_initial_temp = CONFIG.routing_search_initial_temp;
-    let routing_search_term_temp = CONFIG.routing_search_term_temp;
-    if id < 4 {
-        routing_search_cool_rate = CONFIG.limited_search_cool_rates[id];
-    }
-    if explore_routing_orders {
-        step_0.max_step_all_orders(
-            executable,
+    let map = match (initial_map, mapping_heuristic) {
+        (InitialMapStrategy::Provided(map), _) => map.clone(),
+        (InitialMapStrategy::RandomSeeded(seed), _) => {
+            seeded_random_map(c, arch, *seed, region_constraints)
+        }
+        (InitialMapStrategy::Random, _) => random_constrained_map(c, arch, region_constraints, rng),
+        (InitialMapStrategy::Identity, _) => identity_map(c, arch, region_constraints, rng),
+        (InitialMapStrategy::Isomorphism, _) => incremental_isomorphism_map_with_timeout(
+            c,
             arch,
-            &implement_gate,
-            crit_table,
-            routing_search_initial_temp,
-            routing_search_term_temp,
-            routing_search_cool_rate,
-        );
-    } else {
-        step_0.max_step(executable, arch, &implement_gate);
-    }
-    current_circ.remove_gates(&(step_0.gates()));
-    steps.push(step_0);
-    while current_circ.gates.len() > 0 {
-        let best = find_best_next_step(
-            &current_circ,
+            Duration::from_secs(CONFIG.load().isom_search_timeout),
+            region_constraints,
+            rng,
+        )
+        .unwrap_or_else(|| random_constrained_map(c, arch, region_constraints, rng)),
+        (InitialMapStrategy::Anneal, Some(heuristic)) => sim_anneal_mapping_search(
+            c,
+            unseeded_annealing_start(c, arch, region_constraints, rng),
             arch,
-            &transitions,
-            &implement_gate,
-            steps.last().unwrap(),
-            step_cost,
-            &map_eval,
-            explore_routing_orders,
-            &crit_table,
-            id,
-        );
-        match best {
-            Some((s, trans, _b)) => {
-                current_circ.remove_gates(&s.gates());
-                cost += step_cost(&s, arch);
-                steps.push(s);
-                trans_taken.push(trans.repr());
-                cost += trans.cost(arch);
-            }
-            None => {
-                panic!("No valid next step found");
-            }
+            CONFIG.load().mapping_search_initial_temp,
+            CONFIG.load().mapping_search_term_temp,
+            CONFIG.load().mapping_search_cool_rate,
+            |m: &QubitMap| heuristic(arch, c, m),
+            region_constraints,
+            anneal_chains,
+            rng,
+        ),
+        (InitialMapStrategy::Parallel, Some(heuristic)) => {
+            parallel_initial_map(c, arch, heuristic, region_constraints, anneal_chains, rng)
         }
-    }
-    return CompilerResult {
-        steps,
-        transitions: trans_taken,
-        cost,
+        (_, None) => random_constrained_map(c, arch, region_constraints, rng),
     };
+    // println!("locations {:?}, map : {:?}", arch.locations(), map);
+    if let Some(res) = route_in_place(c, arch, &map, transitions, implement_gate, step_cost) {
+        return res;
+    }
+    route(
+        c,
+        arch,
+        &map,
+        transitions,
+        &implement_gate,
+        step_cost,
+        &route_h,
+        explore_routing_orders,
+        objective,
+        crit_table,
+        0,
+        deadline,
+        progress,
+        rng,
+    )
 }
 
-fn find_best_next_step<
-    A: Architecture,
+/// Picks an initial map, refines it with `sabre_iterations` rounds of routing
+/// passes (forward, then reversed when `sabre_reverse` is set), and returns a
+/// final forward routing from the refined map.
+#[allow(clippy::too_many_arguments)]
+pub fn sabre_solve<
+    A: Architecture + Send + Sync + Clone + 'static,
     R: Transition<G, A> + Debug,
-    G: GateImplementation,
+    G: GateImplementation + Debug,
     I: IntoIterator<Item = G>,
-    J: IntoIterator<Item = R>,
 >(
     c: &Circuit,
     arch: &A,
-    transitions: &impl Fn(&Step<G>) -> J,
-    implement_gate: impl Fn(&Step<G>, &A, &Gate) -> I,
-    last_step: &Step<G>,
+    transitions: &impl Fn(&Step<G>) -> Vec<R>,
+    implement_gate: &impl Fn(&Step<G>, &A, &Gate) -> I,
     step_cost: fn(&Step<G>, &A) -> f64,
-    map_eval: impl Fn(&Circuit, &QubitMap) -> f64,
+    mapping_heuristic: Option<fn(&A, &Circuit, &QubitMap) -> f64>,
     explore_routing_orders: bool,
-    crit_table: &HashMap<usize, usize>,
-    id: usize,
-) -> Option<(Step<G>, R, f64)> {
-    let mut best_options = Vec::new();
-    let mut best_cost = std::f64::MAX;
-    let executable = c.layers().next().unwrap_or(vec![]);
-    let next_layer = c.layers().next().unwrap_or(vec![]);
-    let mut routing_search_cool_rate = CONFIG.routing_search_cool_rate;
-    let routing_search_initial_temp = CONFIG.routing_search_initial_temp;
-    let routing_search_term_temp = CONFIG.routing_search_term_temp;
-    if id < 4 {
-        routing_search_cool_rate = CONFIG.limited_search_cool_rates[id];
-    }
-    for trans in transitions(last_step) {
-        let mut next_step = trans.apply(last_step);
-
-        if explore_routing_orders {
-            next_step.max_step_all_orders(
-                &executable,
-                arch,
-                &implement_gate,
-                crit_table,
-                routing_search_initial_temp,
-                routing_search_term_temp,
-                routing_search_cool_rate,
-            );
-        } else {
-            next_step.max_step(&executable, arch, &implement_gate);
-        }
-        let s_cost = step_cost(&next_step, arch);
-        let t_cost = trans.cost(arch);
-        let front_layer_cost =
-            map_eval(&circuit_from_gates(&executable), &next_step.map) / (executable.len() as f64);
-        let next_layer_cost =
-            map_eval(&circuit_from_gates(&next_layer), &next_step.map) / (next_layer.len() as f64);
-        let m_cost = front_layer_cost + CONFIG.extended_set_weight * next_layer_cost;
-        let total_criticality: usize = next_step
-            .gates()
-            .into_iter()
-            .map(|x| crit_table[&x.id])
-            .sum();
-        let weighted_vals = std::iter::zip(
-            vec![CONFIG.alpha, CONFIG.beta, CONFIG.gamma, CONFIG.delta],
-            vec![s_cost, t_cost, m_cost, -(total_criticality as f64)],
-        );
-        let cost = drop_zeros_and_normalize(weighted_vals);
-        // println!(
-        //     "executable : {:?}, transition : {:?} , cost : {:?}",
-        //     executable, trans, cost
-        // );
-        if cost <= best_cost {
-            if cost < best_cost {
-                best_options.clear();
-                best_cost = cost;
-            }
-            best_options.push((next_step, trans, cost));
-        }
-    }
-
-    if best_options.is_empty() {
-        None
-    } else {
-        let index = rand::random_range(..best_options.len());
-        Some(best_options.remove(index))
-    }
+    region_constraints: &RegionConstraints,
+    progress: Option<&Progress>,
+) -> CompilerResult<G> {
+    sabre_solve_with_rng(
+        c,
+        arch,
+        transitions,
+        implement_gate,
+        step_cost,
+        mapping_heuristic,
+        explore_routing_orders,
+        region_constraints,
+        CONFIG.load().parallel_searches,
+        progress,
+        &mut search_rng(0),
+    )
 }
 
-pub fn solve<
+#[allow(clippy::too_many_arguments)]
+fn sabre_solve_with_rng<
     A: Architecture + Send + Sync + Clone + 'static,
     R: Transition<G, A> + Debug,
     G: GateImplementation + Debug,
     I: IntoIterator<Item = G>,
-    J: IntoIterator<Item = R>,
 >(
     c: &Circuit,
     arch: &A,
-    transitions: &impl Fn(&Step<G>) -> J,
+    transitions: &impl Fn(&Step<G>) -> Vec<R>,
     implement_gate: &impl Fn(&Step<G>, &A, &Gate) -> I,
     step_cost: fn(&Step<G>, &A) -> f64,
     mapping_heuristic: Option<fn(&A, &Circuit, &QubitMap) -> f64>,
     explore_routing_orders: bool,
+    region_constraints: &RegionConstraints,
+    anneal_chains: usize,
+    progress: Option<&Progress>,
+    rng: &mut StdRng,
 ) -> CompilerResult<G> {
-    let crit_table = &build_criticality_table(c);
-    match mapping_heuristic {
-        Some(heuristic) => {
-            let map_h = |m: &QubitMap| heuristic(arch, c, m);
-            let route_h = |c: &Circuit, m: &QubitMap| heuristic(arch, c, m);
-            let isom_map = incremental_isomorphism_map_with_timeout(
-                c,
-                arch,
-                Duration::from_secs(CONFIG.isom_search_timeout),
-            );
-
-            let isom_cost = isom_map.clone().map(|x| map_h(&x));
+    let deadline = solve_deadline();
+    let map = match mapping_heuristic {
+        Some(heuristic) => parallel_initial_map(c, arch, heuristic, region_constraints, anneal_chains, rng),
+        None => random_constrained_map(c, arch, region_constraints, rng),
+    };
+    sabre_route_from(
+        c,
+        arch,
+        map,
+        transitions,
+        implement_gate,
+        step_cost,
+        mapping_heuristic,
+        explore_routing_orders,
+        region_constraints,
+        deadline,
+        progress,
+        rng,
+    )
+}
 
-            let sa_map = match isom_cost {
-                Some(c) if c == 0.0 => None,
-                _ => Some(sim_anneal_mapping_search(
-                    isom_map.clone().unwrap_or_else(|| random_map(c, arch)),
-                    arch,
-                    CONFIG.mapping_search_initial_temp,
-                    CONFIG.mapping_search_term_temp,
-                    CONFIG.mapping_search_cool_rate,
-                    map_h,
-                )),
-            };
-            let sa_cost = sa_map.clone().map(|x| map_h(&x));
-            let map = match (isom_cost, sa_cost) {
-                (Some(i_c), None) => isom_map.unwrap(),
-                (Some(i_c), Some(s_c)) if i_c < s_c => isom_map.unwrap(),
-                _ => sa_map.unwrap(),
-            };
-            // println!("locations {:?}, map : {:?}", arch.locations(), map);
-            return route(
-                c,
-                arch,
-                &map,
-                transitions,
-                &implement_gate,
-                step_cost,
-                &route_h,
-                explore_routing_orders,
-                crit_table,
-                0,
-            );
-        }
-        None => {
-            let map = random_map(c, arch);
-            return route(
-                c,
-                arch,
-                &map,
-                transitions,
-                &implement_gate,
-                step_cost,
-                &|_c, _m| 0.0,
-                explore_routing_orders,
-                crit_table,
-                0,
-            );
-        }
-    }
+/// Like `sabre_solve`, but refines `map` instead of searching for a starting map of its own.
+/// Fails if `map` doesn't fit `arch` (see `check_initial_map`).
+#[allow(clippy::too_many_arguments)]
+pub fn sabre_solve_with_map<
+    A: Architecture + Send + Sync + Clone + 'static,
+    R: Transition<G, A> + Debug,
+    G: GateImplementation + Debug,
+    I: IntoIterator<Item = G>,
+>(
+    c: &Circuit,
+    arch: &A,
+    transitions: &impl Fn(&Step<G>) -> Vec<R>,
+    implement_gate: &impl Fn(&Step<G>, &A, &Gate) -> I,
+    step_cost: fn(&Step<G>, &A) -> f64,
+    mapping_heuristic: Option<fn(&A, &Circuit, &QubitMap) -> f64>,
+    explore_routing_orders: bool,
+    map: &QubitMap,
+    progress: Option<&Progress>,
+) -> Result<CompilerResult<G>, InitialMapError> {
+    check_initial_map(c, arch, map)?;
+    Ok(sabre_route_from(
+        c,
+        arch,
+        map.clone(),
+        transitions,
+        implement_gate,
+        step_cost,
+        mapping_heuristic,
+        explore_routing_orders,
+        &HashMap::new(),
+        solve_deadline(),
+        progress,
+        &mut search_rng(0),
+    ))
 }
 
-pub fn sabre_solve<
+/// The SABRE passes of `sabre_solve` from `map` onward: `sabre_iterations` refinement
+/// rounds, then a final forward routing. Refinement stops early rather than start the
+/// final routing outside `region_constraints`.
+#[allow(clippy::too_many_arguments)]
+fn sabre_route_from<
     A: Architecture + Send + Sync + Clone + 'static,
     R: Transition<G, A> + Debug,
     G: GateImplementation + Debug,
@@ -418,47 +1869,28 @@ pub fn sabre_solve<
 >(
     c: &Circuit,
     arch: &A,
+    mut map: QubitMap,
     transitions: &impl Fn(&Step<G>) -> Vec<R>,
     implement_gate: &impl Fn(&Step<G>, &A, &Gate) -> I,
     step_cost: fn(&Step<G>, &A) -> f64,
     mapping_heuristic: Option<fn(&A, &Circuit, &QubitMap) -> f64>,
     explore_routing_orders: bool,
+    region_constraints: &RegionConstraints,
+    deadline: Option<Instant>,
+    progress: Option<&Progress>,
+    rng: &mut StdRng,
 ) -> CompilerResult<G> {
-    match serde_json::to_writer(std::fs::File::create("config_full.json").unwrap(), &*CONFIG) {
-        Ok(_) => (),
-        Err(e) => panic!("Error writing config file {}", e),
+    if CONFIG.load().dump_config {
+        let written = std::fs::File::create("config_full.json")
+            .map_err(|e| e.to_string())
+            .and_then(|file| serde_json::to_writer(file, &**CONFIG.load()).map_err(|e| e.to_string()));
+        if let Err(e) = written {
+            eprintln!("Error writing config_full.json: {}", e);
+        }
     }
+    // the refinement passes count as routing, along with the final pass
+    let _timer = PhaseTimer::start(Phase::Routing);
     let crit_table = &build_criticality_table(c);
-    let mut map = match mapping_heuristic {
-        Some(heuristic) => {
-            let map_h = |m: &QubitMap| heuristic(arch, c, m);
-            let isom_map: Option<HashMap<Qubit, Location>> =
-                incremental_isomorphism_map_with_timeout(
-                    c,
-                    arch,
-                    Duration::from_secs(CONFIG.isom_search_timeout),
-                );
-
-            let isom_cost = isom_map.clone().map(|x| map_h(&x));
-            let sa_map = match isom_cost {
-                Some(c) if c == 0.0 => None,
-                _ => Some(sim_anneal_mapping_search(
-                    isom_map.clone().unwrap_or_else(|| random_map(c, arch)),
-                    arch,
-                    CONFIG.mapping_search_initial_temp,
-                    CONFIG.mapping_search_term_temp,
-                    CONFIG.mapping_search_cool_rate,
-                    map_h,
-                )),
-            };
-            let sa_cost = sa_map.clone().map(|x| map_h(&x));
-            match (isom_cost, sa_cost) {
-                (Some(i_c), Some(s_c)) if i_c < s_c => isom_map.unwrap(),
-                _ => sa_map.unwrap(),
-            }
-        }
-        None => random_map(c, arch),
-    };
     let route_h: Box<dyn Fn(&Circuit, &QubitMap) -> f64> =
         if let Some(ref heuristic) = mapping_heuristic {
             Box::new(|c: &Circuit, m: &QubitMap| heuristic(arch, c, m))
@@ -466,22 +1898,38 @@ pub fn sabre_solve<
             Box::new(|_c: &Circuit, _m: &QubitMap| 0.0)
         };
 
-    for _ in 0..CONFIG.sabre_iterations {
-        for circ in [c, &c.reversed()] {
+    // refine the map with `sabre_iterations` rounds of passes; the final
+    // routing pass below is always forward
+    'refine: for round in 0..CONFIG.load().sabre_iterations {
+        let mut refined = map.clone();
+        for circ in &sabre_passes(c) {
             let res = route(
                 circ,
                 arch,
-                &map,
+                &refined,
                 transitions,
                 &implement_gate,
                 step_cost,
                 &route_h,
                 explore_routing_orders,
+                CONFIG.load().routing_objective,
                 crit_table,
                 0,
+                deadline,
+                progress,
+                rng,
             );
-            map = res.steps.last().unwrap().map.clone();
+            if res.partial {
+                // out of time or stuck; the final routing reports it
+                break 'refine;
+            }
+            refined = res.steps.last().unwrap().map.clone();
+        }
+        log_sabre_drift(round, &map, &refined);
+        if !satisfies_constraints(&refined, region_constraints) {
+            break;
         }
+        map = refined;
     }
     return route(
         c,
@@ -492,11 +1940,31 @@ pub fn sabre_solve<
         step_cost,
         &route_h,
         explore_routing_orders,
+        CONFIG.load().routing_objective,
         crit_table,
         0,
+        deadline,
+        progress,
+        rng,
     );
 }
 
+/// With `sabre_log_drift`, prints how far SABRE refinement round `round` moved the map.
+fn log_sabre_drift(round: usize, before: &QubitMap, after: &QubitMap) {
+    if CONFIG.load().sabre_log_drift {
+        eprintln!("SABRE round {}: map moved by {} swaps", round, map_distance(before, after));
+    }
+}
+
+/// One SABRE refinement round: a forward pass, then a reverse pass unless
+/// `sabre_reverse` is off.
+fn sabre_passes(c: &Circuit) -> Vec<Circuit> {
+    if CONFIG.load().sabre_reverse {
+        return vec![c.clone(), c.reversed()];
+    }
+    vec![c.clone()]
+}
+
 pub fn solve_with_cached_heuristic<
     A: Architecture + Send + Sync + Clone + 'static,
     R: Transition<G, A> + Debug,
@@ -511,7 +1979,11 @@ pub fn solve_with_cached_heuristic<
     mapping_heuristic: Option<fn(&A, &Circuit, &QubitMap) -> f64>,
     delta_on_move: impl Fn(&QubitMap, Move) -> f64,
     explore_routing_orders: bool,
+    region_constraints: &RegionConstraints,
+    progress: Option<&Progress>,
 ) -> CompilerResult<G> {
+    let deadline = solve_deadline();
+    let rng = &mut search_rng(0);
     let crit_table = &build_criticality_table(c);
     let mut map = match mapping_heuristic {
         Some(heuristic) => {
@@ -520,30 +1992,40 @@ pub fn solve_with_cached_heuristic<
                 incremental_isomorphism_map_with_timeout(
                     c,
                     arch,
-                    Duration::from_secs(CONFIG.isom_search_timeout),
+                    Duration::from_secs(CONFIG.load().isom_search_timeout),
+                    region_constraints,
+                    rng,
                 );
 
             let isom_cost = isom_map.clone().map(|x| map_h(&x));
             let sa_map = match isom_cost {
                 Some(c) if c == 0.0 => None,
-                _ => Some(fast_mapping_simulated_anneal(
-                    &isom_map.clone().unwrap_or_else(|| random_map(c, arch)),
-                    arch,
-                    CONFIG.mapping_search_initial_temp,
-                    CONFIG.mapping_search_term_temp,
-                    CONFIG.mapping_search_cool_rate,
-                    map_h,
-                    delta_on_move,
-                )),
+                _ => {
+                    let _timer = PhaseTimer::start(Phase::Annealing);
+                    Some(fast_mapping_simulated_anneal(
+                        &isom_map
+                            .clone()
+                            .unwrap_or_else(|| random_constrained_map(c, arch, region_constraints, rng)),
+                        arch,
+                        CONFIG.load().mapping_search_initial_temp,
+                        CONFIG.load().mapping_search_term_temp,
+                        CONFIG.load().mapping_search_cool_rate,
+                        map_h,
+                        delta_on_move,
+                        region_constraints,
+                        rng,
+                    ))
+                }
             };
 
             let sa_cost = sa_map.clone().map(|x| map_h(&x));
             match (isom_cost, sa_cost) {
+                (Some(_), None) => isom_map.unwrap(),
                 (Some(i_c), Some(s_c)) if i_c < s_c => isom_map.unwrap(),
                 _ => sa_map.unwrap(),
             }
         }
-        None => random_map(c, arch),
+        None => random_constrained_map(c, arch, region_constraints, rng),
     };
     let route_h: Box<dyn Fn(&Circuit, &QubitMap) -> f64> =
         if let Some(ref heuristic) = mapping_heuristic {
@@ -552,22 +2034,39 @@ pub fn solve_with_cached_heuristic<
             Box::new(|_c: &Circuit, _m: &QubitMap| 0.0)
         };
 
-    for _ in 0..CONFIG.sabre_iterations {
-        for circ in [c, &c.reversed()] {
+    let _timer = PhaseTimer::start(Phase::Routing);
+    // refine the map with `sabre_iterations` rounds of passes; the final
+    // routing pass below is always forward
+    'refine: for round in 0..CONFIG.load().sabre_iterations {
+        let mut refined = map.clone();
+        for circ in &sabre_passes(c) {
             let res = route(
                 circ,
                 arch,
-                &map,
+                &refined,
                 transitions,
                 &implement_gate,
                 step_cost,
                 &route_h,
                 explore_routing_orders,
+                CONFIG.load().routing_objective,
                 crit_table,
                 0,
+                deadline,
+                progress,
+                rng,
             );
-            map = res.steps.last().unwrap().map.clone();
+            if res.partial {
+                // out of time or stuck; the final routing reports it
+                break 'refine;
+            }
+            refined = res.steps.last().unwrap().map.clone();
+        }
+        log_sabre_drift(round, &map, &refined);
+        if !satisfies_constraints(&refined, region_constraints) {
+            break;
         }
+        map = refined;
     }
     return route(
         c,
@@ -578,11 +2077,16 @@ pub fn solve_with_cached_heuristic<
         step_cost,
         &route_h,
         explore_routing_orders,
+        CONFIG.load().routing_objective,
         crit_table,
         0,
+        deadline,
+        progress,
+        rng,
     );
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn solve_parallel<
     A: Architecture + Send + Sync + Clone + 'static,
     R: Transition<G, A> + Debug,
@@ -596,11 +2100,13 @@ pub fn solve_parallel<
     step_cost: fn(&Step<G>, &A) -> f64,
     mapping_heuristic: Option<fn(&A, &Circuit, &QubitMap) -> f64>,
     explore_routing_orders: bool,
+    region_constraints: &RegionConstraints,
+    progress: Option<&Progress>,
 ) -> CompilerResult<G> {
-    (0..CONFIG.parallel_searches)
+    (0..CONFIG.load().parallel_searches)
         .into_par_iter()
-        .map(|_| {
-            solve(
+        .map(|i| {
+            solve_with_rng(
                 c,
                 arch,
                 transitions,
@@ -608,17 +2114,28 @@ pub fn solve_parallel<
                 step_cost,
                 mapping_heuristic,
                 explore_routing_orders,
+                CONFIG.load().routing_objective,
+                region_constraints,
+                &InitialMapStrategy::Parallel,
+                // the searches themselves are the restarts
+                1,
+                progress,
+                &mut search_rng(i as u64),
             )
         })
         .min_by(|a, b| {
+            // a timed-out result is cheap only because it stopped early
             // if cost is f64, handle NaN/partial_cmp
-            a.cost
-                .partial_cmp(&b.cost)
-                .unwrap_or(std::cmp::Ordering::Equal)
+            a.partial.cmp(&b.partial).then(
+                objective_key(a, CONFIG.load().routing_objective)
+                    .partial_cmp(&objective_key(b, CONFIG.load().routing_objective))
+                    .unwrap_or(std::cmp::Ordering::Equal),
+            )
         })
         .expect("num_trials should be > 0")
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn sabre_solve_parallel<
     A: Architecture + Send + Sync + Clone + 'static,
     R: Transition<G, A> + Debug,
@@ -632,11 +2149,13 @@ pub fn sabre_solve_parallel<
     step_cost: fn(&Step<G>, &A) -> f64,
     mapping_heuristic: Option<fn(&A, &Circuit, &QubitMap) -> f64>,
     explore_routing_orders: bool,
+    region_constraints: &RegionConstraints,
+    progress: Option<&Progress>,
 ) -> CompilerResult<G> {
-    (0..CONFIG.parallel_searches)
+    (0..CONFIG.load().parallel_searches)
         .into_par_iter()
-        .map(|_| {
-            sabre_solve(
+        .map(|i| {
+            sabre_solve_with_rng(
                 c,
                 arch,
                 transitions,
@@ -644,17 +2163,30 @@ pub fn sabre_solve_parallel<
                 step_cost,
                 mapping_heuristic,
                 explore_routing_orders,
+                region_constraints,
+                // the searches themselves are the restarts
+                1,
+                progress,
+                &mut search_rng(i as u64),
             )
         })
         .min_by(|a, b| {
+            // a timed-out result is cheap only because it stopped early
             // if cost is f64, handle NaN/partial_cmp
-            a.cost
-                .partial_cmp(&b.cost)
-                .unwrap_or(std::cmp::Ordering::Equal)
+            a.partial.cmp(&b.partial).then(
+                objective_key(a, CONFIG.load().routing_objective)
+                    .partial_cmp(&objective_key(b, CONFIG.load().routing_objective))
+                    .unwrap_or(std::cmp::Ordering::Equal),
+            )
         })
         .expect("num_trials should be > 0")
 }
 
+/// Anneals over initial maps, fully routing each candidate to score it by its true
+/// cost. Starts from the isomorphism map when one is found in time, and returns the
+/// cheapest result seen; each improvement is also printed as it is found. SIGINT and
+/// SIGTERM stop the search early with the best result so far. `id` seeds the chain's rng.
+/// Every candidate map keeps its qubits within `region_constraints`.
 pub fn solve_joint_optimize<
     A: Architecture + Send + Sync + Clone + 'static,
     R: Transition<G, A> + Debug,
@@ -669,9 +2201,12 @@ pub fn solve_joint_optimize<
     step_cost: fn(&Step<G>, &A) -> f64,
     mapping_heuristic: Option<fn(&A, &Circuit, &QubitMap) -> f64>,
     explore_routing_orders: bool,
+    region_constraints: &RegionConstraints,
     id: usize,
+    progress: Option<&Progress>,
 ) -> CompilerResult<G> {
-    let start = Instant::now();
+    let deadline = solve_deadline();
+    let rng = &mut search_rng(id as u64);
     // register SIGINT/SIGTERM handler
     let terminate = Arc::new(AtomicBool::new(false));
     flag::register(SIGINT, Arc::clone(&terminate)).expect("Failed to register SIGINT handler");
@@ -680,9 +2215,11 @@ pub fn solve_joint_optimize<
     let isom_map: Option<HashMap<Qubit, Location>> = incremental_isomorphism_map_with_timeout(
         c,
         arch,
-        Duration::from_secs(CONFIG.isom_search_timeout),
+        Duration::from_secs(CONFIG.load().isom_search_timeout),
+        region_constraints,
+        rng,
     );
-    let start_map = isom_map.unwrap_or_else(|| random_map(c, arch));
+    let start_map = isom_map.unwrap_or_else(|| random_constrained_map(c, arch, region_constraints, rng));
     let crit_table = &build_criticality_table(c);
     let route_h: Box<dyn Fn(&Circuit, &QubitMap) -> f64> =
         if let Some(ref heuristic) = mapping_heuristic {
@@ -701,23 +2238,21 @@ pub fn solve_joint_optimize<
         step_cost,
         &route_h,
         explore_routing_orders,
+        CONFIG.load().routing_objective,
         crit_table,
         id,
+        deadline,
+        progress,
+        rng,
     );
     let mut best_cost = best_res.cost;
     let mut current_map = start_map;
     let mut current_cost = best_cost;
-    let mut temp = CONFIG.mapping_search_initial_temp;
+    let mut temp = CONFIG.load().mapping_search_initial_temp;
+    let objective = CONFIG.load().routing_objective;
 
-    let _ = serde_json::to_writer(std::io::stdout(), &best_res).map_err(IOError::OutputErr);
-    let current_time = Instant::now();
-    println!(
-        "\nElapsed time: {}",
-        Duration::as_secs(&(current_time - start))
-    );
-    println!("Thread: {}", id);
     // simulated annealing loop
-    while temp > CONFIG.mapping_search_term_temp {
+    while temp > CONFIG.load().mapping_search_term_temp {
         // check for SIGINT/SIGTERM
         if terminate.load(Ordering::Relaxed) {
             eprintln!(
@@ -728,7 +2263,7 @@ pub fn solve_joint_optimize<
             break;
         }
 
-        let next = random_neighbor(&current_map, arch);
+        let next = random_neighbor(&current_map, arch, region_constraints, rng);
         let next_res = route(
             c,
             arch,
@@ -738,38 +2273,44 @@ pub fn solve_joint_optimize<
             step_cost,
             &route_h,
             explore_routing_orders,
+            CONFIG.load().routing_objective,
             crit_table,
             id,
+            deadline,
+            progress,
+            rng,
         );
+        if next_res.partial {
+            // later passes would run out of time too, and a partial result is cheap
+            // only because it stopped early
+            eprintln!("Routing timed out in thread {}; returning best solution so far", id);
+            break;
+        }
         let next_cost = next_res.cost;
 
         let delta_curr = next_cost - current_cost;
-        let delta_best = next_cost - best_cost;
-        let accept = rand::random::<f64>() < (-delta_curr / temp).exp();
+        let accept = rng.random::<f64>() < (-delta_curr / temp).exp();
 
-        if delta_best < 0.0 {
+        // the best result is ranked the way `solve_with_objective` ranks it
+        if objective_key(&next_res, objective) < objective_key(&best_res, objective) {
             best_res = next_res;
             best_cost = next_cost;
             current_map = next;
             current_cost = next_cost;
-            let _ = serde_json::to_writer(std::io::stdout(), &best_res).map_err(IOError::OutputErr);
-            let current_time = Instant::now();
-            println!(
-                "\nElapsed time: {}",
-                Duration::as_secs(&(current_time - start))
-            );
-            println!("Thread: {}", id);
         } else if accept {
             current_map = next;
             current_cost = next_cost;
         }
 
-        temp *= CONFIG.mapping_search_cool_rate;
+        temp *= CONFIG.load().mapping_search_cool_rate;
     }
 
     best_res
 }
 
+/// Runs `parallel_searches` independent `solve_joint_optimize` chains and returns the
+/// cheapest result among them.
+#[allow(clippy::too_many_arguments)]
 pub fn solve_joint_optimize_parallel<
     A: Architecture + Send + Sync + Clone + 'static,
     R: Transition<G, A> + Debug,
@@ -784,8 +2325,10 @@ pub fn solve_joint_optimize_parallel<
     step_cost: fn(&Step<G>, &A) -> f64,
     mapping_heuristic: Option<fn(&A, &Circuit, &QubitMap) -> f64>,
     explore_routing_orders: bool,
+    region_constraints: &RegionConstraints,
+    progress: Option<&Progress>,
 ) -> CompilerResult<G> {
-    (0..CONFIG.parallel_searches)
+    (0..CONFIG.load().parallel_searches)
         .into_par_iter()
         .enumerate()
         .map(|(id, _)| {
@@ -797,14 +2340,19 @@ pub fn solve_joint_optimize_parallel<
                 step_cost,
                 mapping_heuristic,
                 explore_routing_orders,
+                region_constraints,
                 id,
+                progress,
             )
         })
         .min_by(|a, b| {
+            // a timed-out result is cheap only because it stopped early
             // if cost is f64, handle NaN/partial_cmp
-            a.cost
-                .partial_cmp(&b.cost)
-                .unwrap_or(std::cmp::Ordering::Equal)
+            a.partial.cmp(&b.partial).then(
+                objective_key(a, CONFIG.load().routing_objective)
+                    .partial_cmp(&objective_key(b, CONFIG.load().routing_objective))
+                    .unwrap_or(std::cmp::Ordering::Equal),
+            )
         })
         .expect("num_trials should be > 0")
 }
@@ -1,9 +1,40 @@
+use std::io::Write;
+use std::sync::Arc;
 use std::{default, fs};
 
+use arc_swap::ArcSwap;
+
 use once_cell::sync::Lazy;
 
 use serde::{Deserialize, Serialize};
-#[derive(Serialize, Deserialize, Debug)]
+
+/// How a candidate step's costs `v_i` are combined with the weights `w_i` (`alpha`,
+/// `beta`, `gamma`, `delta`) into the one score routing compares.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CostNorm {
+    /// `sum(w_i * v_i) / sum(w_i over v_i != 0)`, so terms that are zero for every candidate
+    /// don't dilute the rest; `0.0` when no term is nonzero.
+    DropZeros,
+    /// `sum(softmax(w)_i * v_i)`, with `softmax(w)_i = exp(w_i) / sum(exp(w_j))`.
+    Softmax,
+    /// `sum(w_i * v_i)`, the weights taken as they are. A SWAP can then cost more than the
+    /// map cost it saves, so routing may stand still for good unless `stall_boost` is set.
+    Fixed,
+}
+
+/// What a routing pass minimizes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RoutingObjective {
+    /// The accumulated step and transition cost.
+    CostMinimizing,
+    /// The number of steps, with cost breaking ties. Routing is greedy, so each step is
+    /// the candidate implementing the most gates, and the cheapest among those.
+    DepthMinimizing,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SolverConfig {
     #[serde(default = "default_alpha")]
     pub alpha: f64,
@@ -17,9 +48,46 @@ pub struct SolverConfig {
     #[serde(default = "default_delta")]
     pub delta: f64,
 
+    /// How `alpha` to `delta` weight the step, transition, map and criticality costs.
+    #[serde(default = "default_cost_norm")]
+    pub cost_norm: CostNorm,
+
+    #[serde(default = "default_routing_objective")]
+    pub routing_objective: RoutingObjective,
+
     #[serde(default = "default_extended_set_weight")]
     pub extended_set_weight: f64,
 
+    /// Weight of the map cost of the first of the `lookahead_window` layers after the
+    /// extended set; each further layer counts `lookahead_decay` times the one before. The
+    /// default `0` routes on the front layer and extended set alone.
+    #[serde(default = "default_lookahead_weight")]
+    pub lookahead_weight: f64,
+
+    #[serde(default = "default_lookahead_decay")]
+    pub lookahead_decay: f64,
+
+    #[serde(default = "default_lookahead_window")]
+    pub lookahead_window: usize,
+
+    /// Transitions per routing step that get a full `max_step` evaluation; the rest are
+    /// dropped after pre-ranking by the mapping heuristic on the front layer. `None`
+    /// evaluates every transition.
+    #[serde(default = "default_max_candidate_transitions")]
+    pub max_candidate_transitions: Option<usize>,
+
+    /// Consecutive gate-free routing steps that count as a stall.
+    #[serde(default = "default_stall_window")]
+    pub stall_window: usize,
+
+    /// Relative increase of `gamma` after a stall; the default zero keeps the weight fixed.
+    #[serde(default = "default_stall_boost")]
+    pub stall_boost: f64,
+
+    /// Factor the boost shrinks by on each step without a stall.
+    #[serde(default = "default_stall_decay")]
+    pub stall_decay: f64,
+
     #[serde(default = "default_mapping_search_initial_temp")]
     pub mapping_search_initial_temp: f64,
 
@@ -32,6 +100,55 @@ pub struct SolverConfig {
     #[serde(default = "default_exhaustive_search_threshold")]
     pub exhaustive_search_threshold: usize,
 
+    /// Most qubits `nisq_solve_optimal` takes on; larger circuits are refused.
+    #[serde(default = "default_optimal_routing_max_qubits")]
+    pub optimal_routing_max_qubits: usize,
+
+    /// Most search states `nisq_solve_optimal` explores before giving up.
+    #[serde(default = "default_optimal_routing_max_states")]
+    pub optimal_routing_max_states: usize,
+
+    /// Let gates that commute on their shared qubits (CX gates sharing a control, T on a
+    /// CX control) enter the front layer together instead of in circuit order.
+    #[serde(default = "default_commutation_aware_front_layer")]
+    pub commutation_aware_front_layer: bool,
+
+    /// Furthest apart (in hops) two qubits may be for nisq to run a CX between
+    /// them without routing; 1 requires adjacency.
+    #[serde(default = "default_max_gate_distance")]
+    pub max_gate_distance: usize,
+
+    /// Step cost of a nisq CX per hop beyond adjacency.
+    #[serde(default = "default_virtual_gate_cost")]
+    pub virtual_gate_cost: f64,
+
+    /// Candidate terminal locations kept per Steiner terminal group, nearest first,
+    /// which bounds the terminal sets tried for each gate; `None` keeps them all.
+    #[serde(default = "default_steiner_max_candidates")]
+    pub steiner_max_candidates: Option<usize>,
+
+    /// Routes offered per gate by routers that enumerate the k shortest paths.
+    #[serde(default = "default_paths_per_gate")]
+    pub paths_per_gate: usize,
+
+    /// Most paths the depth-first route enumeration yields per gate.
+    #[serde(default = "default_path_enumeration_cap")]
+    pub path_enumeration_cap: usize,
+
+    /// Longest route, in locations, the depth-first enumeration explores; `None` lets each
+    /// router pick a bound from its device's dimensions.
+    #[serde(default = "default_path_enumeration_max_length")]
+    pub path_enumeration_max_length: Option<usize>,
+
+    /// Farthest (Manhattan, in sites) RAA moves an atom after a gate; `None` allows any
+    /// free site.
+    #[serde(default = "default_raa_shuttle_radius")]
+    pub raa_shuttle_radius: Option<usize>,
+
+    /// Offer RAA transitions that shift a whole AOD row or column at once.
+    #[serde(default = "default_raa_line_shifts")]
+    pub raa_line_shifts: bool,
+
     #[serde(default = "default_routing_search_initial_temp")]
     pub routing_search_initial_temp: f64,
 
@@ -41,15 +158,63 @@ pub struct SolverConfig {
     #[serde(default = "default_routing_search_cool_rate")]
     pub routing_search_cool_rate: f64,
 
+    /// Routes by beam search keeping this many partial routings, instead of greedily; a
+    /// width of 1 routes as the greedy search does.
+    #[serde(default = "default_routing_beam_width")]
+    pub routing_beam_width: Option<usize>,
+
+    #[serde(default = "default_record_candidate_counts")]
+    pub record_candidate_counts: bool,
+
+    #[serde(default = "default_seed")]
+    pub seed: Option<u64>,
+
+    /// Significant figures kept when serializing costs; `None` writes them exactly.
+    #[serde(default = "default_output_precision")]
+    pub output_precision: Option<usize>,
+
     #[serde(default = "default_sabre_iterations")]
     pub sabre_iterations: usize,
 
+    /// Include a reversed-circuit pass in each SABRE refinement round.
+    #[serde(default = "default_sabre_reverse")]
+    pub sabre_reverse: bool,
+
+    /// Print to stderr how many swaps apart the maps before and after each SABRE
+    /// refinement round are (see `utils::map_distance`).
+    #[serde(default = "default_sabre_log_drift")]
+    pub sabre_log_drift: bool,
+
+    /// Seconds the isomorphism search may run before falling back to annealing. `0` skips
+    /// the search entirely.
     #[serde(default = "default_isom_search_timeout")]
     pub isom_search_timeout: u64,
 
+    /// When the isomorphism search times out, embed just the densest connected part of the
+    /// interaction graph, pin those qubits, and anneal the rest around them. The full search
+    /// then gets half of `isom_search_timeout` and the core search whatever is left.
+    #[serde(default = "default_isom_core_fallback")]
+    pub isom_core_fallback: bool,
+
+    /// Start annealing from `backend::bfs_initial_map` instead of a random map when no
+    /// isomorphism map (or core embedding) is available.
+    #[serde(default = "default_bfs_anneal_start")]
+    pub bfs_anneal_start: bool,
+
+    /// Seconds from the start of a solve, initial map search included, after which
+    /// routing stops and returns the steps routed so far as a partial result; `None`
+    /// never stops early.
+    #[serde(default = "default_solve_timeout")]
+    pub solve_timeout: Option<u64>,
+
     #[serde(default = "default_parallel_searches")]
     pub parallel_searches: usize,
 
+    /// Write the full config, defaults included, to `config_full.json` when a SABRE
+    /// solve starts.
+    #[serde(default = "default_dump_config")]
+    pub dump_config: bool,
+
     #[serde(default = "default_limited_search_cool_rates")]
     pub limited_search_cool_rates: [f64; 4],
 }
@@ -61,17 +226,47 @@ impl Default for SolverConfig {
             beta: default_beta(),
             gamma: default_gamma(),
             delta: default_delta(),
+            cost_norm: default_cost_norm(),
+            routing_objective: default_routing_objective(),
             extended_set_weight: default_extended_set_weight(),
+            lookahead_weight: default_lookahead_weight(),
+            lookahead_decay: default_lookahead_decay(),
+            lookahead_window: default_lookahead_window(),
+            max_candidate_transitions: default_max_candidate_transitions(),
+            stall_window: default_stall_window(),
+            stall_boost: default_stall_boost(),
+            stall_decay: default_stall_decay(),
             mapping_search_initial_temp: default_mapping_search_initial_temp(),
             mapping_search_term_temp: default_mapping_search_term_temp(),
             mapping_search_cool_rate: default_mapping_search_cool_rate(),
             exhaustive_search_threshold: default_exhaustive_search_threshold(),
+            optimal_routing_max_qubits: default_optimal_routing_max_qubits(),
+            optimal_routing_max_states: default_optimal_routing_max_states(),
+            commutation_aware_front_layer: default_commutation_aware_front_layer(),
+            max_gate_distance: default_max_gate_distance(),
+            virtual_gate_cost: default_virtual_gate_cost(),
+            steiner_max_candidates: default_steiner_max_candidates(),
+            paths_per_gate: default_paths_per_gate(),
+            path_enumeration_cap: default_path_enumeration_cap(),
+            path_enumeration_max_length: default_path_enumeration_max_length(),
+            raa_shuttle_radius: default_raa_shuttle_radius(),
+            raa_line_shifts: default_raa_line_shifts(),
             routing_search_initial_temp: default_routing_search_initial_temp(),
             routing_search_term_temp: default_routing_search_term_temp(),
             routing_search_cool_rate: default_routing_search_cool_rate(),
+            routing_beam_width: default_routing_beam_width(),
+            record_candidate_counts: default_record_candidate_counts(),
+            seed: default_seed(),
+            output_precision: default_output_precision(),
             sabre_iterations: default_sabre_iterations(),
+            sabre_reverse: default_sabre_reverse(),
+            sabre_log_drift: default_sabre_log_drift(),
             isom_search_timeout: default_isom_search_timeout(),
+            isom_core_fallback: default_isom_core_fallback(),
+            bfs_anneal_start: default_bfs_anneal_start(),
+            solve_timeout: default_solve_timeout(),
             parallel_searches: default_parallel_searches(),
+            dump_config: default_dump_config(),
             limited_search_cool_rates: default_limited_search_cool_rates(),
         };
     }
@@ -92,10 +287,46 @@ fn default_delta() -> f64 {
     return 1.0;
 }
 
+fn default_cost_norm() -> CostNorm {
+    CostNorm::DropZeros
+}
+
+fn default_routing_objective() -> RoutingObjective {
+    RoutingObjective::CostMinimizing
+}
+
 fn default_extended_set_weight() -> f64 {
     return 0.5;
 }
 
+fn default_lookahead_weight() -> f64 {
+    0.0
+}
+
+fn default_lookahead_decay() -> f64 {
+    0.5
+}
+
+fn default_lookahead_window() -> usize {
+    4
+}
+
+fn default_max_candidate_transitions() -> Option<usize> {
+    None
+}
+
+fn default_stall_window() -> usize {
+    2
+}
+
+fn default_stall_boost() -> f64 {
+    0.0
+}
+
+fn default_stall_decay() -> f64 {
+    0.5
+}
+
 fn default_mapping_search_initial_temp() -> f64 {
     return 10.0;
 }
@@ -112,6 +343,50 @@ fn default_exhaustive_search_threshold() -> usize {
     return 8;
 }
 
+fn default_optimal_routing_max_qubits() -> usize {
+    8
+}
+
+fn default_optimal_routing_max_states() -> usize {
+    200_000
+}
+
+fn default_commutation_aware_front_layer() -> bool {
+    false
+}
+
+fn default_max_gate_distance() -> usize {
+    1
+}
+
+fn default_virtual_gate_cost() -> f64 {
+    0.5
+}
+
+fn default_steiner_max_candidates() -> Option<usize> {
+    None
+}
+
+fn default_paths_per_gate() -> usize {
+    3
+}
+
+fn default_path_enumeration_cap() -> usize {
+    1000
+}
+
+fn default_path_enumeration_max_length() -> Option<usize> {
+    None
+}
+
+fn default_raa_shuttle_radius() -> Option<usize> {
+    None
+}
+
+fn default_raa_line_shifts() -> bool {
+    false
+}
+
 fn default_routing_search_initial_temp() -> f64 {
     return 10.0;
 }
@@ -124,22 +399,102 @@ fn default_routing_search_cool_rate() -> f64 {
     return 0.999;
 }
 
+fn default_routing_beam_width() -> Option<usize> {
+    None
+}
+
+fn default_record_candidate_counts() -> bool {
+    false
+}
+
+fn default_seed() -> Option<u64> {
+    None
+}
+
+fn default_output_precision() -> Option<usize> {
+    None
+}
+
 fn default_sabre_iterations() -> usize {
     return 3;
 }
 
+fn default_sabre_reverse() -> bool {
+    true
+}
+
+fn default_sabre_log_drift() -> bool {
+    false
+}
+
 fn default_isom_search_timeout() -> u64 {
     return 300;
 }
 
+fn default_isom_core_fallback() -> bool {
+    false
+}
+
+fn default_bfs_anneal_start() -> bool {
+    false
+}
+
+fn default_solve_timeout() -> Option<u64> {
+    None
+}
+
 fn default_parallel_searches() -> usize {
     return 16;
 }
 
+fn default_dump_config() -> bool {
+    false
+}
+
 fn default_limited_search_cool_rates() -> [f64; 4] {
     return [0.0, 0.349, 0.99, 0.9];
 }
-pub static CONFIG: Lazy<SolverConfig> = Lazy::new(|| {
-    let data = fs::read_to_string("config.json").unwrap_or_else(|_| "".to_string());
-    serde_json::from_str(&data).unwrap_or_else(|_| SolverConfig::default())
-});
+/// Reads the solver config from `path`, saying on `log` whenever it falls back
+/// to the defaults: an info line if the file is absent, a warning if it is unusable.
+pub fn load_config(path: &str, log: &mut impl Write) -> SolverConfig {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let _ = writeln!(log, "info: no {} found, using default solver config", path);
+            return SolverConfig::default();
+        }
+        Err(e) => {
+            let _ = writeln!(log, "warning: could not read {} ({}), using default solver config", path, e);
+            return SolverConfig::default();
+        }
+    };
+    let config = match serde_json::from_str(&data) {
+        Ok(config) => config,
+        Err(e) => {
+            let _ = writeln!(log, "warning: malformed {} ({}), using default solver config", path, e);
+            return SolverConfig::default();
+        }
+    };
+    // misspelled keys would otherwise silently leave their setting at its default
+    if let (Ok(serde_json::Value::Object(given)), Ok(serde_json::Value::Object(known))) = (
+        serde_json::from_str::<serde_json::Value>(&data),
+        serde_json::to_value(SolverConfig::default()),
+    ) {
+        for key in given.keys().filter(|k| !known.contains_key(*k)) {
+            let _ = writeln!(log, "warning: unknown key `{}` in {} is ignored", key, path);
+        }
+    }
+    config
+}
+
+/// The solver config: the one `set_config` last installed, else `config.json` in the
+/// working directory, read on first use. `CONFIG.load()` takes a lock-free snapshot.
+pub static CONFIG: Lazy<ArcSwap<SolverConfig>> =
+    Lazy::new(|| ArcSwap::from_pointee(load_config("config.json", &mut std::io::stderr())));
+
+/// Makes every later `CONFIG.load()` see `config`, e.g. to try settings from code without
+/// writing a `config.json`. Solves already running may see either config; the one replaced
+/// is freed once no snapshot of it is left.
+pub fn set_config(config: SolverConfig) {
+    CONFIG.store(Arc::new(config));
+}
@@ -1,11 +1,17 @@
 use crate::config::CONFIG;
+use crate::utils::all_pairs_distances;
+use crate::utils::match_to_regions;
 use crate::utils::simulated_anneal;
 use crate::utils::swap_random_array_elements;
 use itertools::Itertools;
+use petgraph::algo::connected_components;
 use petgraph::graph::NodeIndex;
 use petgraph::Graph;
+use rand::rngs::StdRng;
 use serde::Deserialize;
 use serde::Serialize;
+use serde::ser::SerializeStruct;
+use serde::Serializer;
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::hash::Hash;
@@ -15,7 +21,7 @@ use std::ops::Index;
 use std::ops::Mul;
 use std::ops::Sub;
 
-#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug, Serialize)]
+#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct Qubit(usize);
 impl Qubit {
     pub fn new(i: usize) -> Self {
@@ -103,6 +109,41 @@ impl Div<usize> for Location {
 
 pub type QubitMap = HashMap<Qubit, Location>;
 
+/// Allowed initial locations for each constrained qubit; unlisted qubits may go anywhere.
+pub type RegionConstraints = HashMap<Qubit, HashSet<Location>>;
+
+/// Where `solve` takes its initial map from.
+#[derive(Clone, Debug)]
+pub enum InitialMapStrategy {
+    /// Incremental subgraph isomorphism, falling back to a random map.
+    Isomorphism,
+    /// Simulated annealing over the mapping heuristic from a random map.
+    Anneal,
+    /// Both isomorphism and annealing, keeping whichever map scores lower.
+    Parallel,
+    /// A random map drawn from a fixed seed, with no placement search.
+    RandomSeeded(u64),
+    /// A random map drawn from the solver's own rng, with no placement search.
+    Random,
+    /// Qubit `i` on the `i`-th location in index order, with no placement search.
+    Identity,
+    Provided(QubitMap),
+}
+
+impl InitialMapStrategy {
+    /// The strategy named on the command line by `--init`: `random`, `identity`,
+    /// `isomorphism` or `anneal`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "random" => Some(InitialMapStrategy::Random),
+            "identity" => Some(InitialMapStrategy::Identity),
+            "isomorphism" => Some(InitialMapStrategy::Isomorphism),
+            "anneal" => Some(InitialMapStrategy::Anneal),
+            _ => None,
+        }
+    }
+}
+
 impl Location {
     pub fn new(i: usize) -> Self {
         return Location(i);
@@ -112,7 +153,7 @@ impl Location {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum PauliTerm {
     PauliI,
     PauliX,
@@ -120,10 +161,13 @@ pub enum PauliTerm {
     PauliZ,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Operation {
     CX,
+    /// Toffoli: flips `qubits[2]` when both controls `qubits[0]` and `qubits[1]` are set.
+    CCX,
     T,
+    SWAP,
     PauliRot {
         axis: Vec<PauliTerm>,
         angle: (isize, usize),
@@ -132,26 +176,64 @@ pub enum Operation {
         sign: bool,
         axis: Vec<PauliTerm>,
     },
+    /// Mid-circuit measurement of the gate's qubit into classical bit `creg_bit`.
+    Measure {
+        creg_bit: usize,
+    },
+    /// `inner`, applied only when the classical bits `cond_bits` read as `value`.
+    Conditioned {
+        cond_bits: Vec<usize>,
+        value: usize,
+        inner: Box<Operation>,
+    },
+}
+
+impl Operation {
+    /// The hardware gate kind this operation needs; a conditioned operation needs its
+    /// inner one.
+    pub fn gate_type(&self) -> GateType {
+        match self {
+            Operation::CX => GateType::CX,
+            Operation::CCX => GateType::CCX,
+            Operation::T => GateType::T,
+            Operation::SWAP => GateType::SWAP,
+            Operation::PauliRot { .. } => GateType::PauliRot,
+            Operation::PauliMeasurement { .. } => GateType::PauliMeasurement,
+            Operation::Measure { .. } => GateType::Measure,
+            Operation::Conditioned { inner, .. } => inner.gate_type(),
+        }
+    }
 }
+
+/// Payload-free view of `Operation`, for architectures to list the gates they support.
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize)]
 pub enum GateType {
     CX,
+    CCX,
     T,
+    SWAP,
     PauliRot,
     PauliMeasurement,
+    Measure,
 }
 
-#[derive(Clone, Debug, Eq, Hash, Serialize)]
+#[derive(Clone, Debug, Eq, Hash, Serialize, Deserialize)]
 pub struct Gate {
     pub operation: Operation,
     pub qubits: Vec<Qubit>,
+    #[serde(default)]
     pub id: usize,
 }
 
 impl Gate {
     fn filter_by_pauli_term(&self, term: &PauliTerm) -> Vec<Qubit> {
-        match &self.operation {
-            Operation::CX | Operation::T => vec![],
+        match self.base_operation() {
+            Operation::CX
+            | Operation::CCX
+            | Operation::T
+            | Operation::SWAP
+            | Operation::Measure { .. } => vec![],
+            Operation::Conditioned { .. } => unreachable!(),
             Operation::PauliRot { axis, .. } | Operation::PauliMeasurement { axis, .. } => (0
                 ..axis.len())
                 .filter(|i| axis[*i] == *term)
@@ -172,17 +254,92 @@ impl Gate {
         self.filter_by_pauli_term(&PauliTerm::PauliZ)
     }
 
+    /// A conditioned gate runs on hardware as its inner operation.
     pub fn gate_type(&self) -> GateType {
-        match &self.operation {
-            Operation::CX => GateType::CX,
-            Operation::T => GateType::T,
-            Operation::PauliRot { axis, angle } => GateType::PauliRot,
-            Operation::PauliMeasurement { sign, axis } => GateType::PauliMeasurement,
+        self.operation.gate_type()
+    }
+
+    /// The gate is diagonal in the computational basis on `q` (a CX or CCX control, or a
+    /// T), so it commutes there with any other gate that is.
+    fn diagonal_on(&self, q: &Qubit) -> bool {
+        match self.base_operation() {
+            Operation::CX => self.qubits[0] == *q,
+            Operation::CCX => self.qubits[..2].contains(q),
+            Operation::T => true,
+            _ => false,
+        }
+    }
+
+    /// The operation with any classical conditions stripped off.
+    pub fn base_operation(&self) -> &Operation {
+        let mut op = &self.operation;
+        while let Operation::Conditioned { inner, .. } = op {
+            op = inner;
+        }
+        op
+    }
+
+    /// Classical bits this gate reads (its conditions) and writes (its measurement).
+    pub fn classical_bits(&self) -> (Vec<usize>, Vec<usize>) {
+        let mut reads = Vec::new();
+        let mut op = &self.operation;
+        while let Operation::Conditioned { cond_bits, inner, .. } = op {
+            reads.extend(cond_bits);
+            op = inner;
         }
+        let writes = match op {
+            Operation::Measure { creg_bit } => vec![*creg_bit],
+            _ => vec![],
+        };
+        (reads, writes)
     }
 }
 
-#[derive(Clone, Debug)]
+/// Tracks which qubits and classical bits earlier gates hold, so a gate is only
+/// ready once nothing before it touches its qubits, writes a bit it reads, or
+/// touches a bit it writes.
+#[derive(Default)]
+struct DependencyFrontier {
+    qubits: HashSet<Qubit>,
+    read_bits: HashSet<usize>,
+    written_bits: HashSet<usize>,
+    /// Let a gate past qubits that every earlier gate acts on diagonally, when it acts
+    /// on them diagonally too.
+    commute: bool,
+    /// Qubits some earlier gate acts on non-diagonally.
+    non_diagonal: HashSet<Qubit>,
+}
+
+impl DependencyFrontier {
+    fn commuting() -> Self {
+        DependencyFrontier {
+            commute: true,
+            ..Default::default()
+        }
+    }
+    fn is_ready(&self, gate: &Gate) -> bool {
+        let (reads, writes) = gate.classical_bits();
+        let qubit_free = |q: &Qubit| {
+            !self.qubits.contains(q)
+                || (self.commute && gate.diagonal_on(q) && !self.non_diagonal.contains(q))
+        };
+        gate.qubits.iter().all(qubit_free)
+            && reads.iter().all(|b| !self.written_bits.contains(b))
+            && writes
+                .iter()
+                .all(|b| !self.written_bits.contains(b) && !self.read_bits.contains(b))
+    }
+    fn block(&mut self, gate: &Gate) {
+        let (reads, writes) = gate.classical_bits();
+        self.qubits.extend(&gate.qubits);
+        self.non_diagonal
+            .extend(gate.qubits.iter().filter(|q| !gate.diagonal_on(q)));
+        self.read_bits.extend(reads);
+        self.written_bits.extend(writes);
+    }
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct Circuit {
     pub gates: Vec<Gate>,
     pub qubits: HashSet<Qubit>,
@@ -195,6 +352,35 @@ impl PartialEq for Gate {
 }
 
 impl Circuit {
+    /// An empty circuit, to be filled with `add_gate`, `add_cx` and `add_t`.
+    pub fn new() -> Self {
+        Circuit {
+            gates: Vec::new(),
+            qubits: HashSet::new(),
+        }
+    }
+
+    /// Appends `operation` on `qubits` and returns its id, one past the largest id in
+    /// the circuit so ids stay unique after `remove_gates`.
+    pub fn add_gate(&mut self, operation: Operation, qubits: Vec<Qubit>) -> usize {
+        let id = self.gates.iter().map(|g| g.id + 1).max().unwrap_or(0);
+        self.qubits.extend(qubits.iter().copied());
+        self.gates.push(Gate {
+            operation,
+            qubits,
+            id,
+        });
+        id
+    }
+
+    pub fn add_cx(&mut self, ctrl: Qubit, tar: Qubit) -> usize {
+        self.add_gate(Operation::CX, vec![ctrl, tar])
+    }
+
+    pub fn add_t(&mut self, q: Qubit) -> usize {
+        self.add_gate(Operation::T, vec![q])
+    }
+
     pub fn layers(&self) -> Layers {
         Layers {
             remaining: self.gates.clone(),
@@ -202,18 +388,33 @@ impl Circuit {
     }
 
     pub fn get_front_layer(&self) -> Vec<Gate> {
-        let mut blocked_qubits: HashSet<Qubit> = HashSet::new();
+        self.front_layer(CONFIG.load().commutation_aware_front_layer)
+    }
+
+    /// Gates with no unfinished predecessor. With `commute`, a gate need not wait for
+    /// earlier gates it commutes with (CX gates sharing only a control, T on a CX
+    /// control), so the layer may hold several gates on one qubit.
+    pub fn front_layer(&self, commute: bool) -> Vec<Gate> {
+        let mut blocked = if commute {
+            DependencyFrontier::commuting()
+        } else {
+            DependencyFrontier::default()
+        };
         let mut gates = Vec::new();
         for g in &self.gates {
-            let gate_qubits = &g.qubits;
-            let not_blocked = gate_qubits.iter().all(|q| !blocked_qubits.contains(q));
-            if not_blocked {
+            if blocked.is_ready(g) {
                 gates.push(g.clone());
             }
-            blocked_qubits.extend(gate_qubits);
+            blocked.block(g);
         }
         return gates;
     }
+    /// The extended set: gates that become ready once the front layer has run.
+    pub fn extended_layer(&self) -> Vec<Gate> {
+        let mut rest = self.clone();
+        rest.remove_gates(&self.get_front_layer());
+        rest.get_front_layer()
+    }
     pub fn remove_gates(&mut self, gates: &Vec<Gate>) {
         self.gates.retain(|g| !gates.contains(g));
     }
@@ -222,6 +423,215 @@ impl Circuit {
         copy.gates.reverse();
         return copy;
     }
+
+    /// Checks everything that would make compiling this circuit onto `arch` fail,
+    /// reporting every problem found rather than stopping at the first.
+    pub fn validate_against<A: Architecture>(&self, arch: &A) -> Result<(), Vec<PreflightError>> {
+        let mut errors: Vec<PreflightError> = location_shortage(self, arch).into_iter().collect();
+        let supported = arch.supported_gate_types();
+        let mut seen_ids = HashSet::new();
+        for gate in &self.gates {
+            if !seen_ids.insert(gate.id) {
+                errors.push(PreflightError::DuplicateGateId(gate.id));
+            }
+            errors.extend(gate_shape_errors(gate, &supported));
+        }
+        let graph = arch.graph().0;
+        if graph.node_count() > 0 && connected_components(&graph) != 1 {
+            errors.push(PreflightError::DisconnectedArchitecture);
+        }
+        if errors.is_empty() {
+            return Ok(());
+        }
+        Err(errors)
+    }
+}
+
+/// Checks that `arch` has room for every qubit of `c` and that every gate is one `arch` can
+/// route, with the right number of qubits, so a solver can refuse the circuit before routing
+/// starts. Unlike `Circuit::validate_against` it doesn't check gate ids or connectivity.
+pub fn validate_circuit<A: Architecture>(c: &Circuit, arch: &A) -> Result<(), Vec<PreflightError>> {
+    let supported = arch.supported_gate_types();
+    let mut errors: Vec<PreflightError> = location_shortage(c, arch).into_iter().collect();
+    errors.extend(c.gates.iter().flat_map(|g| gate_shape_errors(g, &supported)));
+    if errors.is_empty() {
+        return Ok(());
+    }
+    Err(errors)
+}
+
+/// Checks that every qubit of `c` can start within its region in `region_constraints` at
+/// once, so `solve` can honor them all; constraints on qubits outside `c` are ignored.
+pub fn check_region_constraints<A: Architecture>(
+    c: &Circuit,
+    arch: &A,
+    region_constraints: &RegionConstraints,
+) -> Result<(), Vec<PreflightError>> {
+    let constrained: Vec<Qubit> = c
+        .qubits
+        .iter()
+        .filter(|q| region_constraints.contains_key(q))
+        .copied()
+        .sorted_by_key(|q| q.get_index())
+        .collect();
+    let (_, mut unplaced) = match_to_regions(arch, &HashMap::new(), &constrained, region_constraints);
+    if unplaced.is_empty() {
+        return Ok(());
+    }
+    unplaced.sort_by_key(|q| q.get_index());
+    Err(vec![PreflightError::InfeasibleRegions(unplaced)])
+}
+
+/// `TooFewLocations` if `arch` can't hold every qubit of `c` at once, counting each
+/// location as many times as its capacity.
+fn location_shortage<A: Architecture>(c: &Circuit, arch: &A) -> Option<PreflightError> {
+    let locations: usize = arch.locations().into_iter().map(|l| arch.capacity(l)).sum();
+    if c.qubits.len() > locations {
+        return Some(PreflightError::TooFewLocations {
+            qubits: c.qubits.len(),
+            locations,
+        });
+    }
+    None
+}
+
+/// The problems with `gate` itself: an operation outside `supported`, or a qubit count
+/// that doesn't fit its operation.
+fn gate_shape_errors(gate: &Gate, supported: &[GateType]) -> Vec<PreflightError> {
+    let mut errors = Vec::new();
+    if !supported.contains(&gate.gate_type()) {
+        errors.push(PreflightError::UnsupportedOperation {
+            gate_id: gate.id,
+            gate_type: gate.gate_type(),
+        });
+    }
+    match gate.base_operation() {
+        Operation::CX
+        | Operation::CCX
+        | Operation::SWAP
+        | Operation::T
+        | Operation::Measure { .. } => {
+            let expected = match gate.base_operation() {
+                Operation::CCX => 3,
+                Operation::CX | Operation::SWAP => 2,
+                _ => 1,
+            };
+            if gate.qubits.len() != expected {
+                errors.push(PreflightError::WrongArity {
+                    gate_id: gate.id,
+                    expected,
+                    found: gate.qubits.len(),
+                });
+            }
+        }
+        Operation::PauliRot { axis, .. } | Operation::PauliMeasurement { axis, .. } => {
+            if let Operation::PauliRot { angle: (_, 0), .. } = gate.base_operation() {
+                errors.push(PreflightError::ZeroDenominator(gate.id));
+            }
+            // axes are padded with identities to the circuit width; only the rest act
+            let pauli_terms = axis.iter().filter(|t| **t != PauliTerm::PauliI).count();
+            if pauli_terms != gate.qubits.len() {
+                errors.push(PreflightError::PauliAxisMismatch {
+                    gate_id: gate.id,
+                    pauli_terms,
+                    qubits: gate.qubits.len(),
+                });
+            }
+        }
+        Operation::Conditioned { .. } => unreachable!(),
+    }
+    errors
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PreflightError {
+    TooFewLocations {
+        qubits: usize,
+        locations: usize,
+    },
+    UnsupportedOperation {
+        gate_id: usize,
+        gate_type: GateType,
+    },
+    WrongArity {
+        gate_id: usize,
+        expected: usize,
+        found: usize,
+    },
+    DuplicateGateId(usize),
+    /// A Pauli gate whose non-identity terms don't match its qubits in number.
+    PauliAxisMismatch {
+        gate_id: usize,
+        pauli_terms: usize,
+        qubits: usize,
+    },
+    /// A Pauli rotation whose angle has denominator 0.
+    ZeroDenominator(usize),
+    DisconnectedArchitecture,
+    /// Qubits that can't all start within their regions at once, sorted by index.
+    InfeasibleRegions(Vec<Qubit>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    MissingGate(usize),
+    DuplicateGate(usize),
+    UnknownGate(usize),
+    OutOfOrder {
+        gate_id: usize,
+        step: usize,
+    },
+    UnmappedQubit {
+        gate_id: usize,
+        qubit: Qubit,
+    },
+    NotAdjacent {
+        gate_id: usize,
+        from: Location,
+        to: Location,
+    },
+    DisconnectedEndpoint {
+        gate_id: usize,
+        qubit: Qubit,
+    },
+    PathThroughQubit {
+        gate_id: usize,
+        location: Location,
+    },
+    PathBlocked {
+        gate_id: usize,
+        location: Location,
+    },
+    PathOverlap {
+        gate_id: usize,
+        other_gate_id: usize,
+        location: Location,
+    },
+    OverCapacity {
+        step: usize,
+        location: Location,
+    },
+}
+
+/// Gate ids, each sorted, that a result leaves out, implements more than once,
+/// or implements without them being in the circuit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GateCoverageError {
+    pub missing: Vec<usize>,
+    pub duplicated: Vec<usize>,
+    pub unknown: Vec<usize>,
+}
+
+/// Ways a caller-supplied initial map doesn't fit its circuit and architecture,
+/// each sorted by index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InitialMapError {
+    /// Circuit qubits the map leaves unplaced.
+    pub unmapped: Vec<Qubit>,
+    /// Qubits placed on locations the architecture doesn't have.
+    pub unknown_locations: Vec<(Qubit, Location)>,
+    /// Locations given more qubits than their capacity.
+    pub over_capacity: Vec<Location>,
 }
 
 pub struct Layers {
@@ -236,22 +646,20 @@ impl Iterator for Layers {
             return None;
         }
 
-        let mut blocked_qubits: HashSet<Qubit> = HashSet::new();
+        let mut blocked = DependencyFrontier::default();
         let mut this_layer = Vec::new();
         let mut rest = Vec::new();
 
         // drain all remaining, partition into layer vs. rest
         for gate in self.remaining.drain(..) {
-            let gate_qubits = &gate.qubits;
-            let not_blocked = gate_qubits.iter().all(|q| !blocked_qubits.contains(q));
-            if not_blocked {
-                // none of its qubits are blocked → include in this layer
+            if blocked.is_ready(&gate) {
+                // nothing earlier holds its qubits or bits → include in this layer
                 this_layer.push(gate.clone());
             } else {
                 // had a conflict → defer to next iteration
                 rest.push(gate.clone());
             }
-            blocked_qubits.extend(gate_qubits);
+            blocked.block(&gate);
         }
 
         // keep the leftovers for the next round
@@ -273,14 +681,56 @@ pub fn circuit_from_gates(gates: &[Gate]) -> Circuit {
     };
 }
 
-pub trait GateImplementation: Clone + Serialize + Hash + Eq + Debug {}
+pub trait GateImplementation: Clone + Serialize + Hash + Eq + Debug {
+    /// Chain of adjacent locations the gate occupies for its step, if it is
+    /// routed along the architecture graph. Used by `utils::verify`.
+    fn path(&self) -> Option<Vec<Location>> {
+        None
+    }
+    /// Locations the gate holds for its step, which other gates in the step must route
+    /// around; its `path` unless the implementation says otherwise.
+    fn occupied_locations(&self) -> Vec<Location> {
+        self.path().unwrap_or_default()
+    }
+}
 
 #[derive(Clone, Debug, Serialize)]
 pub struct Step<T: GateImplementation> {
+    #[serde(serialize_with = "serialize_map_sorted")]
     pub map: QubitMap,
+    #[serde(serialize_with = "serialize_gates_sorted")]
     pub implemented_gates: HashSet<ImplementedGate<T>>,
 }
 
+// hash iteration order differs between runs, so sort to keep seeded output byte-identical
+fn serialize_map_sorted<S: Serializer>(map: &QubitMap, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(map.iter().sorted_by_key(|(q, _)| q.get_index()))
+}
+
+fn serialize_gates_sorted<T: GateImplementation, S: Serializer>(
+    gates: &HashSet<ImplementedGate<T>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(gates.iter().sorted_by_key(|g| g.gate.id))
+}
+
+/// Rounds `x` to `sig_figs` significant figures, going through decimal text so
+/// the result is the closest double to the rounded value.
+pub fn round_significant(x: f64, sig_figs: usize) -> f64 {
+    if x == 0.0 || !x.is_finite() {
+        return x;
+    }
+    format!("{:.*e}", sig_figs.max(1) - 1, x).parse().unwrap()
+}
+
+/// `cost` as written to output: rounded to `output_precision` significant figures, if set.
+pub fn rounded_for_output(cost: f64) -> f64 {
+    match CONFIG.load().output_precision {
+        Some(sig_figs) => round_significant(cost, sig_figs),
+        None => cost,
+    }
+}
+
 impl<G: GateImplementation> Step<G> {
     pub fn max_step<A: Architecture, I: IntoIterator<Item = G>>(
         &mut self,
@@ -289,11 +739,17 @@ impl<G: GateImplementation> Step<G> {
         implement_gate: &impl Fn(&Step<G>, &A, &Gate) -> I,
     ) {
         assert!(self.implemented_gates.is_empty());
+        let mut busy = HashSet::new();
         for gate in executable {
+            // a commutation-aware front layer can hold several gates on one qubit
+            if gate.qubits.iter().any(|q| busy.contains(q)) {
+                continue;
+            }
             let implementation = implement_gate(self, arch, gate).into_iter().next();
             match implementation {
                 None => continue,
                 Some(implementation) => {
+                    busy.extend(gate.qubits.iter().copied());
                     self.implemented_gates.insert(ImplementedGate {
                         gate: gate.clone(),
                         implementation,
@@ -303,32 +759,48 @@ impl<G: GateImplementation> Step<G> {
         }
     }
 
+    /// Packs `executable` into this step like `max_step`, but over several gate orders,
+    /// keeping the packing with the highest total criticality and then the most gates.
+    /// Below `exhaustive_search_threshold` gates every order is tried; above it the
+    /// orders are annealed.
     pub fn max_step_all_orders<A: Architecture, I: IntoIterator<Item = G>>(
         &mut self,
         executable: &Vec<Gate>,
         arch: &A,
         implement_gate: impl Fn(&Step<G>, &A, &Gate) -> I,
-        crit_table: &HashMap<usize, usize>,
+        crit_table: &[usize],
         routing_search_initial_temp: f64,
         routing_search_term_temp: f64,
         routing_search_cool_rate: f64,
+        rng: &mut StdRng,
     ) {
         assert!(self.implemented_gates.is_empty());
-        let mut best_total_criticality = 0;
-        let orders = executable.iter().cloned().permutations(executable.len());
-        if executable.len() < CONFIG.exhaustive_search_threshold {
-            for order in orders {
+        if executable.len() < CONFIG.load().exhaustive_search_threshold {
+            // most critical first, so the first order tried is the greedy one and, of
+            // packings that score the same, the one it finds is kept
+            let by_criticality: Vec<Gate> = executable
+                .iter()
+                .sorted_by_key(|g| (std::cmp::Reverse(crit_table[g.id]), g.id))
+                .cloned()
+                .collect();
+            let mut best_score = None;
+            // many orders pack the same gates the same way; score each packing once
+            let mut seen = HashSet::new();
+            for order in by_criticality.iter().cloned().permutations(executable.len()) {
                 let mut step = Step {
                     map: self.map.clone(),
                     implemented_gates: HashSet::new(),
                 };
                 step.max_step(&order, arch, &implement_gate);
-                let candidate_total_criticality: usize =
-                    step.gates().into_iter().map(|x| crit_table[&x.id]).sum();
-
-                if candidate_total_criticality > best_total_criticality {
+                let packing: Vec<_> = step.implemented_gates.iter().cloned().sorted_by_key(|g| g.gate.id).collect();
+                if !seen.insert(packing) {
+                    continue;
+                }
+                let total_criticality: usize = step.gates().into_iter().map(|x| crit_table[x.id]).sum();
+                let score = (total_criticality, step.implemented_gates.len());
+                if best_score.is_none_or(|best| score > best) {
                     *self = step;
-                    best_total_criticality = candidate_total_criticality;
+                    best_score = Some(score);
                 }
                 if self.implemented_gates.len() == executable.len() {
                     return;
@@ -344,7 +816,7 @@ impl<G: GateImplementation> Step<G> {
                 return step
                     .gates()
                     .into_iter()
-                    .map(|x| crit_table[&x.id])
+                    .map(|x| crit_table[x.id])
                     .sum::<usize>() as f64;
             };
             let random_neighbor = swap_random_array_elements;
@@ -355,6 +827,7 @@ impl<G: GateImplementation> Step<G> {
                 routing_search_cool_rate,
                 random_neighbor,
                 cost_function,
+                rng,
             );
             let mut step = Step {
                 map: self.map.clone(),
@@ -389,10 +862,12 @@ impl<G: GateImplementation> Step<G> {
             }
         }
     }
+    /// The gates implemented in this step, in id order.
     pub fn gates(&self) -> Vec<Gate> {
         return self
             .implemented_gates
             .iter()
+            .sorted_by_key(|gi| gi.gate.id)
             .map(|gi| gi.gate.clone())
             .collect();
     }
@@ -401,6 +876,22 @@ impl<G: GateImplementation> Step<G> {
         return &self.map;
     }
 
+    /// Locations a new gate in this step cannot route through: those holding a qubit,
+    /// the architecture's `blocked_locations`, and those the step's gates occupy. Sorted
+    /// by index, since removing them from a graph in another order renumbers its nodes
+    /// differently and so changes which of two equal paths is found.
+    pub fn blocked_locations<A: Architecture>(&self, arch: &A) -> Vec<Location> {
+        self
+            .map
+            .values()
+            .copied()
+            .chain(arch.blocked_locations())
+            .chain(self.implemented_gates.iter().flat_map(|g| g.implementation.occupied_locations()))
+            .sorted_by_key(|l| l.get_index())
+            .dedup()
+            .collect()
+    }
+
     pub fn implemented_gates(&self) -> HashSet<ImplementedGate<G>> {
         return self.implemented_gates.clone();
     }
@@ -415,6 +906,46 @@ pub trait Transition<T: GateImplementation, A: Architecture> {
 pub trait Architecture {
     fn locations(&self) -> Vec<Location>;
     fn graph(&self) -> (Graph<Location, ()>, HashMap<Location, NodeIndex>);
+    /// How many locations qubits can be mapped to.
+    fn num_locations(&self) -> usize {
+        self.locations().len()
+    }
+    /// Most neighbors any location has in `graph()`, counting a coupling in either
+    /// direction once.
+    fn max_degree(&self) -> usize {
+        let graph = self.graph().0;
+        graph
+            .node_indices()
+            .map(|n| graph.neighbors_undirected(n).unique().count())
+            .max()
+            .unwrap_or(0)
+    }
+    /// Shortest hop count between every connected pair of locations in `graph()`.
+    fn distance_matrix(&self) -> HashMap<(Location, Location), usize> {
+        all_pairs_distances(&self.graph().0)
+    }
+    /// `graph()` with a routing weight on each edge; unit weights unless the
+    /// architecture knows its per-edge error rates.
+    fn weighted_graph(&self) -> Graph<Location, f64> {
+        self.graph().0.map(|_, loc| *loc, |_, _| 1.0)
+    }
+    /// Locations no routed path may pass through.
+    fn blocked_locations(&self) -> Vec<Location> {
+        vec![]
+    }
+    /// How many qubits `loc` can hold at once, e.g. ions sharing a trap.
+    fn capacity(&self, _loc: Location) -> usize {
+        1
+    }
+    fn supported_gate_types(&self) -> Vec<GateType> {
+        vec![
+            GateType::CX,
+            GateType::T,
+            GateType::PauliRot,
+            GateType::PauliMeasurement,
+            GateType::Measure,
+        ]
+    }
 }
 
 #[derive(Debug, Serialize, Clone, Hash, PartialEq, Eq)]
@@ -423,9 +954,151 @@ pub struct ImplementedGate<T: GateImplementation> {
     pub implementation: T,
 }
 
-#[derive(Debug, Serialize)]
+/// Summary counts for a `CompilerResult`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ResultStats {
+    pub num_steps: usize,
+    pub total_gates_implemented: usize,
+    /// Transitions that change the map, i.e. the inserted SWAPs or moves.
+    pub num_nontrivial_transitions: usize,
+    #[serde(serialize_with = "serialize_rounded")]
+    pub cost: f64,
+}
+
+fn serialize_rounded<S: Serializer>(cost: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(rounded_for_output(*cost))
+}
+
+#[derive(Debug, Clone)]
 pub struct CompilerResult<T: GateImplementation> {
     pub steps: Vec<Step<T>>,
     pub transitions: Vec<String>,
     pub cost: f64,
+    /// `(gate id, candidate implementations)` per routed gate, filled only when
+    /// `record_candidate_counts` is set.
+    pub candidate_counts: Vec<(usize, usize)>,
+    /// Routing stopped before every gate was implemented, at `solve_timeout` or because no
+    /// step could make progress.
+    pub partial: bool,
+    /// Wall-clock time of the steps in seconds, for architectures that model it.
+    pub makespan: Option<f64>,
+}
+
+impl<T: GateImplementation> CompilerResult<T> {
+    /// The SWAPs between logical qubits that carry each step's map into the next,
+    /// in order. Moves into unoccupied locations only relabel free space, so they
+    /// add no gate.
+    pub fn swap_circuit(&self) -> Circuit {
+        let mut gates = Vec::new();
+        let mut qubits = HashSet::new();
+        for (before, after) in self.steps.iter().tuple_windows() {
+            let mut current = before.map.clone();
+            for q in after.map.keys().sorted_by_key(|q| q.get_index()) {
+                let target = after.map[q];
+                if current[q] == target {
+                    continue;
+                }
+                let occupant = current.iter().find(|(_, l)| **l == target).map(|(p, _)| *p);
+                if let Some(p) = occupant {
+                    gates.push(Gate {
+                        operation: Operation::SWAP,
+                        qubits: vec![*q, p],
+                        id: gates.len(),
+                    });
+                    qubits.insert(*q);
+                    qubits.insert(p);
+                    current.insert(p, current[q]);
+                }
+                current.insert(*q, target);
+            }
+        }
+        Circuit { gates, qubits }
+    }
+
+    /// Checks that the steps implement each gate of `circ` exactly once. Unlike
+    /// `utils::verify`, it ignores order and placement, so it is cheap enough
+    /// to run on every routed result in debug builds.
+    pub fn assert_implements_all(&self, circ: &Circuit) -> Result<(), GateCoverageError> {
+        let counts = self
+            .steps
+            .iter()
+            .flat_map(|s| s.implemented_gates.iter().map(|g| g.gate.id))
+            .counts();
+        let expected: HashSet<usize> = circ.gates.iter().map(|g| g.id).collect();
+        let error = GateCoverageError {
+            missing: expected.iter().filter(|id| !counts.contains_key(id)).copied().sorted().collect(),
+            duplicated: counts.iter().filter(|(_, n)| **n > 1).map(|(id, _)| *id).sorted().collect(),
+            unknown: counts.keys().filter(|id| !expected.contains(id)).copied().sorted().collect(),
+        };
+        if error.missing.is_empty() && error.duplicated.is_empty() && error.unknown.is_empty() {
+            return Ok(());
+        }
+        Err(error)
+    }
+
+    pub fn stats(&self) -> ResultStats {
+        ResultStats {
+            num_steps: self.steps.len(),
+            total_gates_implemented: self.steps.iter().map(|s| s.implemented_gates.len()).sum(),
+            num_nontrivial_transitions: self
+                .steps
+                .iter()
+                .tuple_windows()
+                .filter(|(before, after)| before.map != after.map)
+                .count(),
+            cost: self.cost,
+        }
+    }
+
+    /// Gate id to the locations its implementation held in the step that executed it
+    /// (see `GateImplementation::occupied_locations`).
+    pub fn gate_footprints(&self) -> HashMap<usize, Vec<Location>> {
+        self
+            .steps
+            .iter()
+            .flat_map(|s| s.implemented_gates.iter())
+            .map(|g| (g.gate.id, g.implementation.occupied_locations()))
+            .collect()
+    }
+
+    /// Per step, the `(qubit index, location index)` placement sorted by qubit.
+    pub fn placement_timeline(&self) -> Vec<Vec<(usize, usize)>> {
+        self
+            .steps
+            .iter()
+            .map(|step| {
+                step.map
+                    .iter()
+                    .map(|(q, l)| (q.get_index(), l.get_index()))
+                    .sorted()
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+impl<T: GateImplementation> Serialize for CompilerResult<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("CompilerResult", 8)?;
+        state.serialize_field("steps", &self.steps)?;
+        state.serialize_field("transitions", &self.transitions)?;
+        state.serialize_field("cost", &rounded_for_output(self.cost))?;
+        if self.candidate_counts.is_empty() {
+            state.skip_field("candidate_counts")?;
+        } else {
+            state.serialize_field("candidate_counts", &self.candidate_counts)?;
+        }
+        if self.partial {
+            state.serialize_field("partial", &self.partial)?;
+        } else {
+            state.skip_field("partial")?;
+        }
+        match self.makespan {
+            Some(makespan) => state.serialize_field("makespan", &rounded_for_output(makespan))?,
+            None => state.skip_field("makespan")?,
+        }
+        state.serialize_field("placement_timeline", &self.placement_timeline())?;
+        state.serialize_field("stats", &self.stats())?;
+        state.end()
+    }
 }
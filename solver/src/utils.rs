@@ -1,98 +1,51 @@
+use crate::config::{CostNorm, CONFIG};
 use crate::structures::*;
+use flate2::read::GzDecoder;
 
-use itertools::{max, Itertools};
+use itertools::{max, Itertools, MultiProduct};
 use petgraph::graph::{Node, NodeIndex};
-use petgraph::Direction::Outgoing;
+use petgraph::visit::{EdgeFiltered, EdgeRef};
+use petgraph::Direction::{Incoming, Outgoing};
 use petgraph::Graph;
+use rand::rngs::StdRng;
 use rand::seq::IndexedRandom;
-use rand::Rng;
+use rand::{Rng, SeedableRng};
 use regex::Regex;
 use rustworkx_core::steiner_tree::steiner_tree;
+use serde::Deserialize;
 use serde_json::Value;
-use std::collections::{HashMap, HashSet};
+use std::cell::RefCell;
+use std::collections::{hash_map::Entry, HashMap, HashSet, VecDeque};
 use std::fs::File;
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, Write};
 use std::iter::from_fn;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 #[derive(Debug)]
 pub enum IOError {
     InputErr,
+    ParseErr(serde_json::Error),
     OutputErr(serde_json::Error),
+    /// The circuit has gates the chosen solver can't route.
+    PreflightErr(Vec<PreflightError>),
 }
 
+/// The CX gates of an OpenQASM file; shorthand for `extract_gates(filename, &["CX"])`.
 pub fn extract_cnots(filename: &str) -> Circuit {
-    let file = File::open(filename).unwrap();
-    let lines = io::BufReader::new(file).lines();
-    let mut gates = Vec::new();
-    let mut qubits = HashSet::new();
-    let mut id = 0;
-    let cx_re = Regex::new(r"cx\s+q\[(\d+)\],\s*q\[(\d+)\];").unwrap();
-    for line in lines {
-        let line_str = line.unwrap();
-        let cx_caps = cx_re.captures(&line_str);
-        match cx_caps {
-            None => continue,
-            Some(c) => {
-                let q1 = Qubit::new(c.get(1).unwrap().as_str().parse::<usize>().unwrap());
-                let q2 = Qubit::new(c.get(2).unwrap().as_str().parse::<usize>().unwrap());
-                qubits.insert(q1);
-                qubits.insert(q2);
-                let gate = Gate {
-                    operation: Operation::CX,
-                    qubits: vec![q1, q2],
-                    id,
-                };
-                gates.push(gate);
-                id += 1;
-            }
-        }
-    }
-    return Circuit { gates, qubits };
+    extract_gates(filename, &["CX"])
+}
+
+/// The CX, CCX and measurement gates of an OpenQASM file, which the NISQ router handles;
+/// shorthand for `extract_gates(filename, &["CX", "CCX", "Measure"])`.
+pub fn extract_nisq_gates(filename: &str) -> Circuit {
+    extract_gates(filename, &["CX", "CCX", "Measure"])
 }
 
+/// The CX, T/Tdg and measurement gates of an OpenQASM file; shorthand for
+/// `extract_gates(filename, &["CX", "T", "Measure"])`.
 pub fn extract_scmr_gates(filename: &str) -> Circuit {
-    let file = File::open(filename).unwrap();
-    let lines = io::BufReader::new(file).lines();
-    let mut gates = Vec::new();
-    let mut qubits = HashSet::new();
-    let mut id = 0;
-    let cx_re = Regex::new(r"cx\s+q\[(\d+)\],\s*q\[(\d+)\];").unwrap();
-    let t_re = Regex::new(r"(t|tdg)\s+q\[(\d+)\];").unwrap();
-    for line in lines {
-        let line_str = line.unwrap();
-        let cx_caps = cx_re.captures(&line_str);
-        let t_caps = t_re.captures(&line_str);
-        match cx_caps {
-            None => match t_caps {
-                None => continue,
-                Some(c) => {
-                    let q = Qubit::new(c.get(2).unwrap().as_str().parse::<usize>().unwrap());
-                    qubits.insert(q);
-                    let gate = Gate {
-                        operation: Operation::T,
-                        qubits: vec![q],
-                        id,
-                    };
-                    gates.push(gate);
-                    id += 1;
-                }
-            },
-            Some(c) => {
-                let q1 = Qubit::new(c.get(1).unwrap().as_str().parse::<usize>().unwrap());
-                let q2 = Qubit::new(c.get(2).unwrap().as_str().parse::<usize>().unwrap());
-                qubits.insert(q1);
-                qubits.insert(q2);
-                let gate = Gate {
-                    operation: Operation::CX,
-                    qubits: vec![q1, q2],
-                    id,
-                };
-                gates.push(gate);
-                id += 1;
-            }
-        }
-    }
-    return Circuit { gates, qubits };
+    extract_gates(filename, &["CX", "T", "Measure"])
 }
 
 fn parse_pauli_term(c: char) -> PauliTerm {
@@ -104,18 +57,44 @@ fn parse_pauli_term(c: char) -> PauliTerm {
         _ => panic!("Invalid Pauli term"),
     }
 }
+/// Lines of `filename`, decompressed first if it is gzipped (judged by its magic bytes, so
+/// the extension doesn't matter).
+fn circuit_lines(filename: &str) -> io::Lines<Box<dyn BufRead>> {
+    try_circuit_lines(filename).unwrap()
+}
+
+fn try_circuit_lines(filename: &str) -> io::Result<io::Lines<Box<dyn BufRead>>> {
+    let mut reader = io::BufReader::new(File::open(filename)?);
+    let gzipped = reader.fill_buf()?.starts_with(&[0x1f, 0x8b]);
+    let reader: Box<dyn BufRead> = if gzipped {
+        Box::new(io::BufReader::new(GzDecoder::new(reader)))
+    } else {
+        Box::new(reader)
+    };
+    Ok(reader.lines())
+}
+
 type GateHandler = Box<dyn FnMut(&regex::Captures, &mut HashSet<Qubit>, usize) -> Gate>;
 
 pub fn extract_gates(filename: &str, gate_types: &[&str]) -> Circuit {
-    let file = File::open(filename).unwrap();
-    let lines = io::BufReader::new(file).lines();
+    try_extract_gates(filename, gate_types).expect("Malformed or unsupported circuit")
+}
+
+/// `extract_gates`, failing with `IOError::InputErr` on an unreadable file and on a
+/// classical register used before its `creg` declaration, indexed past its size, or
+/// measured whole (`measure q -> c;`) into a register of another size. Measurements are
+/// skipped when `gate_types` lacks `"Measure"`, and then a requested gate under an `if`
+/// is an error, since the bits it tests are never written.
+pub fn try_extract_gates(filename: &str, gate_types: &[&str]) -> Result<Circuit, IOError> {
+    let lines = try_circuit_lines(filename).map_err(|_| IOError::InputErr)?;
     let mut gates = Vec::new();
     let mut qubits = HashSet::new();
     let mut id = 0;
     let mut patterns: Vec<(Regex, GateHandler)> = vec![];
     if gate_types.contains(&"CX") {
         let cx_pattern = (
-            Regex::new(r"cx\s+q\[(\d+)\],\s*q\[(\d+)\];").unwrap(),
+            // `\b` keeps the tail of a `ccx` from reading as a CX
+            Regex::new(r"\bcx\s+q\[(\d+)\],\s*q\[(\d+)\];").unwrap(),
             Box::new(|c: &regex::Captures, qubits: &mut HashSet<Qubit>, id| {
                 let q1 = Qubit::new(c.get(1).unwrap().as_str().parse::<usize>().unwrap());
                 let q2 = Qubit::new(c.get(2).unwrap().as_str().parse::<usize>().unwrap());
@@ -130,6 +109,23 @@ pub fn extract_gates(filename: &str, gate_types: &[&str]) -> Circuit {
         );
         patterns.push(cx_pattern);
     }
+    if gate_types.contains(&"CCX") {
+        let ccx_pattern = (
+            Regex::new(r"\bccx\s+q\[(\d+)\],\s*q\[(\d+)\],\s*q\[(\d+)\];").unwrap(),
+            Box::new(|c: &regex::Captures, qubits: &mut HashSet<Qubit>, id| {
+                let gate_qubits: Vec<Qubit> = (1..=3)
+                    .map(|i| Qubit::new(c.get(i).unwrap().as_str().parse::<usize>().unwrap()))
+                    .collect();
+                qubits.extend(gate_qubits.iter());
+                Gate {
+                    operation: Operation::CCX,
+                    qubits: gate_qubits,
+                    id,
+                }
+            }) as GateHandler,
+        );
+        patterns.push(ccx_pattern);
+    }
     if gate_types.contains(&"T") {
         let t_pattern = (
             Regex::new(r"(t|tdg)\s+q\[(\d+)\];").unwrap(),
@@ -196,18 +192,225 @@ pub fn extract_gates(filename: &str, gate_types: &[&str]) -> Circuit {
         patterns.push(paul_rot_pattern);
         patterns.push(paul_meas_pattern);
     }
+    // classical registers are flattened into one bit index space, in declaration order
+    let qreg_re = Regex::new(r"qreg\s+q\[(\d+)\];").unwrap();
+    let creg_re = Regex::new(r"creg\s+(\w+)\[(\d+)\];").unwrap();
+    let if_re = Regex::new(r"if\s*\(\s*(\w+)\s*==\s*(\d+)\s*\)\s*(.*)").unwrap();
+    let measure_re = Regex::new(r"measure\s+q\[(\d+)\]\s*->\s*(\w+)\[(\d+)\];").unwrap();
+    // the register form measures qubit i into bit i
+    let measure_all_re = Regex::new(r"measure\s+q\s*->\s*(\w+)\s*;").unwrap();
+    let with_measure = gate_types.contains(&"Measure");
+    let mut qreg_size = None;
+    let mut cregs: HashMap<String, (usize, usize)> = HashMap::new();
+    let mut creg_bits = 0;
     for line in lines {
-        let line_str = line.unwrap();
+        let line_str = line.map_err(|_| IOError::InputErr)?;
+        if let Some(c) = qreg_re.captures(&line_str) {
+            qreg_size = Some(c.get(1).unwrap().as_str().parse::<usize>().unwrap());
+            continue;
+        }
+        if let Some(c) = creg_re.captures(&line_str) {
+            let size = c.get(2).unwrap().as_str().parse::<usize>().unwrap();
+            cregs.insert(c.get(1).unwrap().as_str().to_string(), (creg_bits, size));
+            creg_bits += size;
+            continue;
+        }
+        let (condition, body) = match if_re.captures(&line_str) {
+            Some(c) => {
+                let value = c.get(2).unwrap().as_str().parse::<usize>().unwrap();
+                (Some((c.get(1).unwrap().as_str().to_string(), value)), c.get(3).unwrap().as_str().to_string())
+            }
+            None => (None, line_str),
+        };
+        let mut line_gates = Vec::new();
+        if with_measure {
+            let mut measured = vec![];
+            if let Some(c) = measure_re.captures(&body) {
+                let q = c.get(1).unwrap().as_str().parse::<usize>().unwrap();
+                let (offset, size) = cregs.get(c.get(2).unwrap().as_str()).ok_or(IOError::InputErr)?;
+                let bit = c.get(3).unwrap().as_str().parse::<usize>().unwrap();
+                if bit >= *size {
+                    return Err(IOError::InputErr);
+                }
+                measured.push((q, offset + bit));
+            } else if let Some(c) = measure_all_re.captures(&body) {
+                let (offset, size) = cregs.get(c.get(1).unwrap().as_str()).ok_or(IOError::InputErr)?;
+                if qreg_size != Some(*size) {
+                    return Err(IOError::InputErr);
+                }
+                measured.extend((0..*size).map(|i| (i, offset + i)));
+            }
+            for (q, creg_bit) in measured {
+                let q = Qubit::new(q);
+                qubits.insert(q);
+                line_gates.push(Gate {
+                    operation: Operation::Measure { creg_bit },
+                    qubits: vec![q],
+                    id,
+                });
+                id += 1;
+            }
+        }
         for (regex, handler) in &mut patterns {
-            if let Some(caps) = regex.captures(&line_str) {
+            if let Some(caps) = regex.captures(&body) {
                 let gate = handler(&caps, &mut qubits, id);
-                gates.push(gate);
+                line_gates.push(gate);
                 id += 1;
             }
         }
+        match &condition {
+            Some(_) if !line_gates.is_empty() && !with_measure => return Err(IOError::InputErr),
+            Some((creg, value)) if !line_gates.is_empty() => {
+                let (offset, size) = cregs.get(creg).ok_or(IOError::InputErr)?;
+                let cond_bits: Vec<usize> = (*offset..offset + size).collect();
+                for gate in &mut line_gates {
+                    gate.operation = Operation::Conditioned {
+                        cond_bits: cond_bits.clone(),
+                        value: *value,
+                        inner: Box::new(gate.operation.clone()),
+                    };
+                }
+            }
+            _ => {}
+        }
+        gates.extend(line_gates);
+    }
+
+    Ok(Circuit { gates, qubits })
+}
+
+/// A parsed `extract_pbc` line: its angle (`None` for a measurement), sign and axis.
+type PbcLine = (Option<(isize, usize)>, bool, Vec<(Qubit, PauliTerm)>);
+
+/// Reads a Pauli-based computation, one instruction per line: `Rot[1/8] X0 Z2` rotates
+/// by the given angle (in units of pi) and `M[-] Y1 X3` measures with the given sign
+/// (`+` if empty). Every axis is padded with `PauliI` to the circuit's qubit count, and
+/// lines matching neither form are skipped.
+pub fn extract_pbc(filename: &str) -> Circuit {
+    let lines = circuit_lines(filename);
+    let rot_re = Regex::new(r"^\s*Rot\[\s*(-?\d+)\s*/\s*(\d+)\s*\]((?:\s+[IXYZ]\d+)+)\s*;?\s*$").unwrap();
+    let meas_re = Regex::new(r"^\s*M\[\s*([+-]?)\s*\]((?:\s+[IXYZ]\d+)+)\s*;?\s*$").unwrap();
+    let mut parsed: Vec<PbcLine> = Vec::new();
+    for line in lines {
+        let line_str = line.unwrap();
+        let (angle, sign, terms_str) = if let Some(c) = rot_re.captures(&line_str) {
+            let numerator = c.get(1).unwrap().as_str().parse::<isize>().unwrap();
+            let denominator = c.get(2).unwrap().as_str().parse::<usize>().unwrap();
+            (Some((numerator, denominator)), true, c.get(3).unwrap().as_str().to_string())
+        } else if let Some(c) = meas_re.captures(&line_str) {
+            (None, c.get(1).unwrap().as_str() != "-", c.get(2).unwrap().as_str().to_string())
+        } else {
+            continue;
+        };
+        let mut terms: Vec<(Qubit, PauliTerm)> = Vec::new();
+        for term in terms_str.split_whitespace() {
+            let q = Qubit::new(term[1..].parse::<usize>().unwrap());
+            assert!(
+                terms.iter().all(|(p, _)| *p != q),
+                "Qubit {} appears twice in `{}`",
+                q.get_index(),
+                line_str
+            );
+            terms.push((q, parse_pauli_term(term.chars().next().unwrap())));
+        }
+        parsed.push((angle, sign, terms));
+    }
+    let num_qubits = parsed
+        .iter()
+        .flat_map(|(_, _, terms)| terms.iter().map(|(q, _)| q.get_index() + 1))
+        .max()
+        .unwrap_or(0);
+    let mut gates = Vec::new();
+    let mut qubits = HashSet::new();
+    for (id, (angle, sign, terms)) in parsed.into_iter().enumerate() {
+        let mut axis = vec![PauliTerm::PauliI; num_qubits];
+        for (q, term) in &terms {
+            axis[q.get_index()] = term.clone();
+        }
+        let gate_qubits: Vec<Qubit> = (0..num_qubits)
+            .filter(|i| axis[*i] != PauliTerm::PauliI)
+            .map(Qubit::new)
+            .collect();
+        qubits.extend(gate_qubits.iter());
+        let operation = match angle {
+            Some(angle) => Operation::PauliRot { axis, angle },
+            None => Operation::PauliMeasurement { sign, axis },
+        };
+        gates.push(Gate {
+            operation,
+            qubits: gate_qubits,
+            id,
+        });
+    }
+    Circuit { gates, qubits }
+}
+
+#[derive(Deserialize)]
+struct JsonCircuit {
+    gates: Vec<Gate>,
+}
+
+/// Parses a circuit written as `{ "gates": [...] }`, each gate in the form
+/// `Gate` serializes to. Gate ids are reassigned by position.
+pub fn circuit_from_json(text: &str) -> Result<Circuit, serde_json::Error> {
+    let parsed: JsonCircuit = serde_json::from_str(text)?;
+    let gates: Vec<_> = parsed
+        .gates
+        .into_iter()
+        .enumerate()
+        .map(|(id, gate)| Gate { id, ..gate })
+        .collect();
+    Ok(circuit_from_gates(&gates))
+}
+
+/// Compiles one JSON circuit per line of `input` and writes each result to
+/// `output` as one line, in input order. Blank lines are skipped.
+pub fn compile_jsonl<G: GateImplementation>(
+    input: impl BufRead,
+    mut output: impl Write,
+    compile: impl Fn(&Circuit) -> Result<CompilerResult<G>, IOError>,
+) -> Result<(), IOError> {
+    for line in input.lines() {
+        let line = line.map_err(|_| IOError::InputErr)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let circ = circuit_from_json(&line).map_err(IOError::ParseErr)?;
+        let res = compile(&circ)?;
+        serde_json::to_writer(&mut output, &res).map_err(IOError::OutputErr)?;
+        writeln!(output)
+            .and_then(|_| output.flush())
+            .map_err(|e| IOError::OutputErr(serde_json::Error::io(e)))?;
+    }
+    Ok(())
+}
+
+/// Hop counts between every connected pair of locations, from a unit-weight search per node.
+pub fn all_pairs_distances(graph: &Graph<Location, ()>) -> HashMap<(Location, Location), usize> {
+    let mut distances = HashMap::new();
+    for source in graph.node_indices() {
+        for (target, d) in petgraph::algo::dijkstra(graph, source, None, |_| 1) {
+            distances.insert((graph[source], graph[target]), d);
+        }
+    }
+    distances
+}
+
+/// Cheapest summed edge weight between every connected pair of locations.
+pub fn all_pairs_weighted_distances(graph: &Graph<Location, f64>) -> HashMap<(Location, Location), f64> {
+    let mut distances = HashMap::new();
+    for source in graph.node_indices() {
+        for (target, d) in petgraph::algo::dijkstra(graph, source, None, |e| *e.weight()) {
+            distances.insert((graph[source], graph[target]), d);
+        }
     }
+    distances
+}
 
-    return Circuit { gates, qubits };
+/// Routing weight of an edge with two-qubit error rate `error`: summing these
+/// along a path gives the negative log of the path's success probability.
+pub fn fidelity_weight(error: f64) -> f64 {
+    -(1.0 - error).ln()
 }
 
 pub fn path_graph(n: usize) -> Graph<Location, ()> {
@@ -216,13 +419,110 @@ pub fn path_graph(n: usize) -> Graph<Location, ()> {
     for i in 0..n {
         nodes.push(g.add_node(Location::new(i)));
     }
-    for i in 0..n - 1 {
-        g.add_edge(nodes[i], nodes[i + 1], ());
-        g.add_edge(nodes[i + 1], nodes[i], ());
+    for i in 1..n {
+        g.add_edge(nodes[i - 1], nodes[i], ());
+        g.add_edge(nodes[i], nodes[i - 1], ());
     }
     return g;
 }
 
+/// Complete graph on `n` locations: every pair is coupled in both directions.
+pub fn complete_graph(n: usize) -> Graph<Location, ()> {
+    let mut g = Graph::new();
+    let nodes: Vec<NodeIndex> = (0..n).map(|i| g.add_node(Location::new(i))).collect();
+    for (a, b) in nodes.iter().tuple_combinations() {
+        g.add_edge(*a, *b, ());
+        g.add_edge(*b, *a, ());
+    }
+    g
+}
+
+/// `rows` x `cols` grid with row-major locations; `periodic` adds the
+/// wraparound edges that make it a torus.
+pub fn grid_graph(rows: usize, cols: usize, periodic: bool) -> Graph<Location, ()> {
+    let mut g = Graph::new();
+    let nodes: Vec<NodeIndex> = (0..rows * cols)
+        .map(|i| g.add_node(Location::new(i)))
+        .collect();
+    let mut link = |a: usize, b: usize| {
+        // update_edge keeps 2-wide wraparounds from doubling an existing edge
+        if a != b {
+            g.update_edge(nodes[a], nodes[b], ());
+            g.update_edge(nodes[b], nodes[a], ());
+        }
+    };
+    for r in 0..rows {
+        for c in 0..cols {
+            if c + 1 < cols || (periodic && cols > 1) {
+                link(r * cols + c, r * cols + (c + 1) % cols);
+            }
+            if r + 1 < rows || (periodic && rows > 1) {
+                link(r * cols + c, ((r + 1) % rows) * cols + c);
+            }
+        }
+    }
+    g
+}
+
+/// Heavy-hex lattice of the distance-`d` heavy-hex code: `d` rows of
+/// `2d - 1` qubits alternating data and flag qubits, joined by degree-2 bridge
+/// qubits. Bridges attach to alternate flags in successive gaps, with one
+/// boundary bridge per gap between the end data qubits, alternating left and
+/// right. Locations are numbered in reading order, each row followed by the
+/// bridges below it. That gives (5d^2 - 2d - 1)/2 qubits and 3d^2 - 2d - 1
+/// couplings.
+pub fn heavy_hex_graph(d: usize) -> Graph<Location, ()> {
+    assert!(d % 2 == 1, "heavy-hex code distance must be odd");
+    let row_len = 2 * d - 1;
+    let mut g = Graph::new();
+    fn link(g: &mut Graph<Location, ()>, a: NodeIndex, b: NodeIndex) {
+        g.add_edge(a, b, ());
+        g.add_edge(b, a, ());
+    }
+    fn add(g: &mut Graph<Location, ()>) -> NodeIndex {
+        g.add_node(Location::new(g.node_count()))
+    }
+    let mut pending_bridges: Vec<(usize, NodeIndex)> = Vec::new();
+    for r in 0..d {
+        let row: Vec<NodeIndex> = (0..row_len).map(|_| add(&mut g)).collect();
+        for k in 1..row_len {
+            link(&mut g, row[k - 1], row[k]);
+        }
+        for (k, bridge) in pending_bridges.drain(..) {
+            link(&mut g, row[k], bridge);
+        }
+        if r + 1 < d {
+            // even gaps bridge at the left boundary and flags 1, 3, ...;
+            // odd gaps bridge at flags 0, 2, ... and the right boundary
+            let columns: Vec<usize> = if r % 2 == 0 {
+                std::iter::once(0).chain((3..row_len).step_by(4)).collect()
+            } else {
+                (1..row_len - 1).step_by(4).chain(std::iter::once(row_len - 1)).collect()
+            };
+            for k in columns {
+                let bridge = add(&mut g);
+                link(&mut g, row[k], bridge);
+                pending_bridges.push((k, bridge));
+            }
+        }
+    }
+    g
+}
+
+/// Combines `(weight, value)` pairs into one cost as `mode` describes.
+pub fn normalize_cost<T: IntoIterator<Item = (f64, f64)> + Clone>(weighted_values: T, mode: CostNorm) -> f64 {
+    match mode {
+        CostNorm::DropZeros => drop_zeros_and_normalize(weighted_values),
+        CostNorm::Softmax => {
+            let total: f64 = weighted_values.clone().into_iter().map(|(w, _)| w.exp()).sum();
+            weighted_values.into_iter().map(|(w, v)| w.exp() / total * v).sum()
+        }
+        CostNorm::Fixed => weighted_values.into_iter().map(|(w, v)| w * v).sum(),
+    }
+}
+
+/// The weighted sum of the values divided by the total weight of the nonzero values only
+/// (`CostNorm::DropZeros`); `0.0` when that total is zero, e.g. when every value is.
 pub fn drop_zeros_and_normalize<T: IntoIterator<Item = (f64, f64)> + Clone>(
     weighted_values: T,
 ) -> f64 {
@@ -233,6 +533,9 @@ pub fn drop_zeros_and_normalize<T: IntoIterator<Item = (f64, f64)> + Clone>(
             total_weight += w;
         }
     }
+    if total_weight == 0.0 {
+        return 0.0;
+    }
     for (w, v) in weighted_values.clone() {
         {
             let normalized = w / total_weight;
@@ -242,67 +545,261 @@ pub fn drop_zeros_and_normalize<T: IntoIterator<Item = (f64, f64)> + Clone>(
     return weighted_sum;
 }
 
-fn graph_from_edge_vec(edges: Vec<(Location, Location)>) -> Graph<Location, ()> {
+/// An undirected graph with a node for every location in `edges`, in the order they first
+/// appear, followed by the locations below `num_nodes` that no edge mentions, so those are
+/// kept as isolated nodes. Fails if an edge leaves `0..num_nodes`.
+pub fn graph_from_edge_vec_with_nodes(
+    num_nodes: usize,
+    edges: Vec<(Location, Location)>,
+) -> Result<Graph<Location, ()>, String> {
+    if let Some(l) = edges.iter().flat_map(|(a, b)| [a, b]).find(|l| l.get_index() >= num_nodes) {
+        return Err(format!("location {} is outside num_nodes {}", l.get_index(), num_nodes));
+    }
     let mut nodes = HashMap::new();
     let mut g = Graph::new();
-    for (a, b) in &edges {
-        if !nodes.contains_key(a) {
-            nodes.insert(a, g.add_node(*a));
+    for (a, b) in edges {
+        let na = *nodes.entry(a).or_insert_with(|| g.add_node(a));
+        let nb = *nodes.entry(b).or_insert_with(|| g.add_node(b));
+        // edges are undirected
+        g.update_edge(na, nb, ());
+        g.update_edge(nb, na, ());
+    }
+    add_isolated_locations(&mut g, num_nodes);
+    Ok(g)
+}
+
+/// Adds the locations below `num_nodes` that `graph` doesn't have yet, in index order.
+pub fn add_isolated_locations<E>(graph: &mut Graph<Location, E>, num_nodes: usize) {
+    let present: HashSet<Location> = graph.node_weights().copied().collect();
+    for l in (0..num_nodes).map(Location::new) {
+        if !present.contains(&l) {
+            graph.add_node(l);
         }
-        if !nodes.contains_key(b) {
-            nodes.insert(b, g.add_node(*b));
+    }
+}
+
+/// Reads an undirected graph from an architecture file, `{"graph": ..., "num_nodes": n}`,
+/// or from a bare `graph` entry (see `ArchEdges`). `num_nodes` declares locations
+/// `0..num_nodes`, so locations without any edge can still be listed; without it the
+/// graph has one more node than the largest location mentioned.
+pub fn graph_from_file(filename: &str) -> Result<Graph<Location, ()>, IOError> {
+    let file = File::open(filename).map_err(|_| IOError::InputErr)?;
+    let parsed: Value = serde_json::from_reader(file).map_err(IOError::ParseErr)?;
+    let graph = match parsed.get("graph") {
+        Some(graph) => {
+            let num_nodes = declared_num_nodes(&parsed).map_err(|_| IOError::InputErr)?;
+            graph_from_json_entry(graph.clone(), num_nodes)
         }
-        // edges are undirected
-        g.update_edge(nodes[a], nodes[b], ());
-        g.update_edge(nodes[b], nodes[a], ());
+        None => graph_from_json_entry(parsed, None),
+    };
+    graph.map_err(|_| IOError::InputErr)
+}
+
+/// The `num_nodes` of an architecture file, also accepted under its alias `qubit_count`.
+pub fn declared_num_nodes(file: &Value) -> Result<Option<usize>, String> {
+    match file.get("num_nodes").or_else(|| file.get("qubit_count")) {
+        Some(n) => match n.as_u64() {
+            Some(n) => Ok(Some(n as usize)),
+            None => Err(format!("num_nodes {} is not a non-negative integer", n)),
+        },
+        None => Ok(None),
     }
-    return g;
 }
 
-pub fn graph_from_file(filename: &str) -> Graph<Location, ()> {
+/// Reads an edge list like `graph_from_file`, where each edge may carry a
+/// third element giving its two-qubit error rate. Weighted edges get
+/// `fidelity_weight(error)`; edges without a rate get the mean weight of the
+/// rated ones, or unit weight if none is rated.
+pub fn weighted_graph_from_file(filename: &str) -> Graph<Location, f64> {
     let file = File::open(filename).unwrap();
     let parsed: Value = serde_json::from_reader(file).unwrap();
-    let edges = parsed
-        .as_array()
-        .expect("Expected an array of arrays")
-        .iter()
-        .map(|inner| {
-            let array = inner.as_array().expect("Inner element is not an array");
-            if array.len() != 2 {
-                panic!("Each edge must have exactly 2 elements");
-            }
-            let first = array[0]
-                .as_u64()
-                .expect("Element is not a positive integer") as usize;
-            let second = array[1]
-                .as_u64()
-                .expect("Element is not a positive integer") as usize;
-            (Location::new(first), Location::new(second))
-        })
-        .collect();
-    return graph_from_edge_vec(edges);
+    weighted_graph_from_json_entry(parsed)
+}
+
+pub fn weighted_graph_from_json_entry(entry: Value) -> Graph<Location, f64> {
+    weighted_graph_from_edges(weighted_edges_from_json_entry(entry).expect("Malformed graph"))
+}
+
+/// Builds the undirected graph of `weighted_graph_from_file` from already parsed edges.
+pub fn weighted_graph_from_edges(edges: Vec<(Location, Location, Option<f64>)>) -> Graph<Location, f64> {
+    let rated: Vec<f64> = edges.iter().filter_map(|(_, _, error)| error.map(fidelity_weight)).collect();
+    let unrated_weight = if rated.is_empty() {
+        1.0
+    } else {
+        rated.iter().sum::<f64>() / rated.len() as f64
+    };
+    let mut nodes = HashMap::new();
+    let mut g = Graph::new();
+    for (a, b, error) in edges {
+        let na = *nodes.entry(a).or_insert_with(|| g.add_node(a));
+        let nb = *nodes.entry(b).or_insert_with(|| g.add_node(b));
+        let weight = error.map_or(unrated_weight, fidelity_weight);
+        // edges are undirected
+        g.update_edge(na, nb, weight);
+        g.update_edge(nb, na, weight);
+    }
+    g
+}
+
+/// The `graph` entry of an architecture file: edges as a list, `[[0, 1], [1, 2, 0.01]]`
+/// (an optional third element is the two-qubit error rate), or as neighbour lists,
+/// `{"0": [1], "1": [0, 2]}`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ArchEdges {
+    List(Vec<ArchEdge>),
+    Adjacency(std::collections::BTreeMap<String, Vec<usize>>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ArchEdge {
+    Plain(usize, usize),
+    Weighted(usize, usize, f64),
+}
+
+impl ArchEdges {
+    /// The listed edges; neighbour lists come out in ascending key order.
+    pub fn edges(&self) -> Result<Vec<(Location, Location, Option<f64>)>, String> {
+        match self {
+            ArchEdges::List(edges) => Ok(edges
+                .iter()
+                .map(|e| match e {
+                    ArchEdge::Plain(a, b) => (Location::new(*a), Location::new(*b), None),
+                    ArchEdge::Weighted(a, b, error) => (Location::new(*a), Location::new(*b), Some(*error)),
+                })
+                .collect()),
+            ArchEdges::Adjacency(neighbors) => {
+                let mut keyed = Vec::new();
+                for (key, bs) in neighbors {
+                    let a = key
+                        .parse::<usize>()
+                        .map_err(|_| format!("location key `{}` is not a non-negative integer", key))?;
+                    keyed.push((a, bs));
+                }
+                keyed.sort_by_key(|(a, _)| *a);
+                Ok(keyed
+                    .into_iter()
+                    .flat_map(|(a, bs)| bs.iter().map(move |b| (Location::new(a), Location::new(*b), None)))
+                    .collect())
+            }
+        }
+    }
+}
+
+/// The edges of a `graph` entry, see `ArchEdges`.
+fn weighted_edges_from_json_entry(entry: Value) -> Result<Vec<(Location, Location, Option<f64>)>, String> {
+    let edges: ArchEdges = serde_json::from_value(entry)
+        .map_err(|_| "expected an array of edges or an object of neighbour lists".to_string())?;
+    edges.edges()
+}
+
+fn edges_from_json_entry(entry: Value) -> Result<Vec<(Location, Location)>, String> {
+    Ok(weighted_edges_from_json_entry(entry)?
+        .into_iter()
+        .map(|(a, b, _)| (a, b))
+        .collect())
 }
 
-pub fn graph_from_json_entry(entry: Value) -> Graph<Location, ()> {
-    let edges = entry
-        .as_array()
-        .expect("Expected an array of arrays")
+/// The graph of `graph_from_file`, from an already parsed `graph` entry and the file's
+/// `num_nodes`, if any.
+pub fn graph_from_json_entry(entry: Value, num_nodes: Option<usize>) -> Result<Graph<Location, ()>, String> {
+    let edges = edges_from_json_entry(entry)?;
+    let num_nodes = num_nodes.unwrap_or_else(|| {
+        edges
+            .iter()
+            .flat_map(|(a, b)| [a.get_index(), b.get_index()])
+            .max()
+            .map_or(0, |m| m + 1)
+    });
+    graph_from_edge_vec_with_nodes(num_nodes, edges)
+}
+
+/// Like `graph_from_json_entry`, but keeps each edge only in the listed
+/// (control -> target) direction.
+pub fn directed_graph_from_json_entry(entry: Value) -> Graph<Location, ()> {
+    directed_graph_from_edges(edges_from_json_entry(entry).expect("Malformed graph"))
+}
+
+/// Builds the graph of `directed_graph_from_json_entry` from already parsed edges.
+pub fn directed_graph_from_edges(edges: Vec<(Location, Location)>) -> Graph<Location, ()> {
+    let mut nodes = HashMap::new();
+    let mut g = Graph::new();
+    for (a, b) in edges {
+        let na = *nodes.entry(a).or_insert_with(|| g.add_node(a));
+        let nb = *nodes.entry(b).or_insert_with(|| g.add_node(b));
+        g.update_edge(na, nb, ());
+    }
+    g
+}
+
+/// Reads region constraints from a JSON object mapping each constrained qubit to the
+/// locations it may start on, e.g. `{"0": [0, 1], "3": [4, 5, 6]}`.
+pub fn region_constraints_from_file(filename: &str) -> Result<RegionConstraints, IOError> {
+    let file = File::open(filename).map_err(|_| IOError::InputErr)?;
+    let parsed: HashMap<String, Vec<usize>> = serde_json::from_reader(file).map_err(IOError::ParseErr)?;
+    let mut constraints = RegionConstraints::new();
+    for (qubit, region) in parsed {
+        let q = qubit.parse::<usize>().map_err(|_| IOError::InputErr)?;
+        constraints.insert(Qubit::new(q), region.into_iter().map(Location::new).collect());
+    }
+    Ok(constraints)
+}
+
+/// Places `qubits` in their regions around the qubits `map` already holds, by augmenting-path
+/// matching over the free capacity of each location, so every qubit is placed whenever some
+/// placement fits them all. Qubits without a constraint may go anywhere. Returns the
+/// placements found and the qubits left without a spot.
+pub fn match_to_regions<A: Architecture>(
+    arch: &A,
+    map: &QubitMap,
+    qubits: &[Qubit],
+    region_constraints: &RegionConstraints,
+) -> (QubitMap, Vec<Qubit>) {
+    // one slot per free unit of capacity
+    let slots: Vec<Location> = arch
+        .locations()
+        .into_iter()
+        .sorted_by_key(|l| l.get_index())
+        .flat_map(|l| {
+            let used = map.values().filter(|m| **m == l).count();
+            std::iter::repeat_n(l, arch.capacity(l).saturating_sub(used))
+        })
+        .collect();
+    let options: Vec<Vec<usize>> = qubits
         .iter()
-        .map(|inner| {
-            let array = inner.as_array().expect("Inner element is not an array");
-            if array.len() != 2 {
-                panic!("Each edge must have exactly 2 elements");
-            }
-            let first = array[0]
-                .as_u64()
-                .expect("Element is not a positive integer") as usize;
-            let second = array[1]
-                .as_u64()
-                .expect("Element is not a positive integer") as usize;
-            (Location::new(first), Location::new(second))
+        .map(|q| {
+            (0..slots.len())
+                .filter(|s| region_constraints.get(q).is_none_or(|r| r.contains(&slots[*s])))
+                .collect()
         })
         .collect();
-    return graph_from_edge_vec(edges);
+    fn augment(i: usize, options: &[Vec<usize>], owner: &mut [Option<usize>], seen: &mut [bool]) -> bool {
+        for &s in &options[i] {
+            if seen[s] {
+                continue;
+            }
+            seen[s] = true;
+            if owner[s].is_none_or(|j| augment(j, options, owner, seen)) {
+                owner[s] = Some(i);
+                return true;
+            }
+        }
+        false
+    }
+    let mut owner = vec![None; slots.len()];
+    let mut unplaced = Vec::new();
+    for (i, q) in qubits.iter().enumerate() {
+        if !augment(i, &options, &mut owner, &mut vec![false; slots.len()]) {
+            unplaced.push(*q);
+        }
+    }
+    let placed = owner
+        .iter()
+        .enumerate()
+        .filter_map(|(s, i)| i.map(|i| (qubits[i], slots[s])))
+        .collect();
+    (placed, unplaced)
 }
 
 pub fn vertical_neighbors(loc: Location, width: usize, height: usize) -> Vec<Location> {
@@ -327,6 +824,88 @@ pub fn horizontal_neighbors(loc: Location, width: usize) -> Vec<Location> {
     return neighbors;
 }
 
+/// `vertical_neighbors` restricted to the cells in `valid`, for layouts whose routing
+/// graph doesn't cover the whole `width` x `height` grid.
+pub fn vertical_neighbors_within(
+    loc: Location,
+    width: usize,
+    height: usize,
+    valid: &HashSet<Location>,
+) -> Vec<Location> {
+    vertical_neighbors(loc, width, height)
+        .into_iter()
+        .filter(|n| valid.contains(n))
+        .collect()
+}
+
+/// `horizontal_neighbors` restricted to the cells in `valid`.
+pub fn horizontal_neighbors_within(loc: Location, width: usize, valid: &HashSet<Location>) -> Vec<Location> {
+    horizontal_neighbors(loc, width)
+        .into_iter()
+        .filter(|n| valid.contains(n))
+        .collect()
+}
+
+/// Draws `step` on a `height` x `width` grid, one text row per lattice row: `Q` for a
+/// mapped qubit, `M` for a magic state, `#` for a cell some gate's path or tree holds
+/// and `.` for a free cell.
+pub fn render_step_ascii<G: GateImplementation>(
+    step: &Step<G>,
+    width: usize,
+    height: usize,
+    magic_states: &[Location],
+) -> String {
+    let mut grid = vec![vec!['.'; width]; height];
+    let mut mark = |loc: &Location, c: char| {
+        let (row, col) = (loc.get_index() / width, loc.get_index() % width);
+        if row < height {
+            grid[row][col] = c;
+        }
+    };
+    for gate in &step.implemented_gates {
+        for loc in gate.implementation.occupied_locations() {
+            mark(&loc, '#');
+        }
+    }
+    for loc in magic_states {
+        mark(loc, 'M');
+    }
+    for loc in step.map.values() {
+        mark(loc, 'Q');
+    }
+    grid.iter().map(|row| row.iter().collect::<String>()).join("\n")
+}
+
+/// Fewest swaps that turn `a` into `b`, counting a swap with a free location as one.
+/// Qubits moved in a closed cycle of `k` locations take `k - 1` swaps; a chain that ends
+/// at a location `a` leaves free takes one per qubit. Qubits missing from either map
+/// are ignored.
+pub fn map_distance(a: &QubitMap, b: &QubitMap) -> usize {
+    let next: HashMap<Location, Location> = a
+        .iter()
+        .filter_map(|(q, from)| b.get(q).filter(|to| *to != from).map(|to| (*from, *to)))
+        .collect();
+    let targets: HashSet<Location> = next.values().copied().collect();
+    let mut visited = HashSet::new();
+    let mut swaps = 0;
+    // chains first, from the locations nothing moves into, so what is left is cycles
+    let heads = next.keys().filter(|l| !targets.contains(l)).sorted_by_key(|l| l.get_index());
+    let rest = next.keys().sorted_by_key(|l| l.get_index());
+    for start in heads.chain(rest) {
+        if visited.contains(start) {
+            continue;
+        }
+        let mut length = 0;
+        let mut at = *start;
+        while let Some(to) = next.get(&at).filter(|_| visited.insert(at)) {
+            length += 1;
+            at = *to;
+        }
+        swaps += if at == *start { length - 1 } else { length };
+    }
+    swaps
+}
+
 pub fn swap_keys(
     map: &HashMap<Qubit, Location>,
     loc1: Location,
@@ -365,20 +944,36 @@ pub fn values<T: Clone, U: Clone>(map: &HashMap<T, U>) -> Vec<U> {
     map.values().cloned().collect()
 }
 
+/// Removes the nodes at `blocked` from `graph`, keeping `loc_to_node` in step. Removing a
+/// node moves the graph's last node into its index, so that node's entry is repointed.
+/// Locations not in the graph (or already removed) are skipped.
+pub fn remove_locations<E>(
+    graph: &mut Graph<Location, E>,
+    loc_to_node: &mut HashMap<Location, NodeIndex>,
+    blocked: &[Location],
+) {
+    for loc in blocked {
+        let Some(node) = loc_to_node.remove(loc) else {
+            continue;
+        };
+        graph.remove_node(node);
+        if let Some(moved) = graph.node_weight(node) {
+            loc_to_node.insert(*moved, node);
+        }
+    }
+}
+
 pub fn shortest_path<A: Architecture>(
     arch: &A,
     starts: Vec<Location>,
     ends: Vec<Location>,
     blocked: Vec<Location>,
 ) -> Option<Vec<Location>> {
-    let (mut graph, mut loc_to_node) = arch.graph();
-    for loc in blocked.iter() {
-        let old_last = graph[graph.node_indices().last().unwrap()];
-        graph.remove_node(loc_to_node[loc]);
-        loc_to_node.insert(old_last, loc_to_node[loc]);
-        loc_to_node.remove(loc);
-    }
-    let mut best: Option<(i32, Vec<NodeIndex>)> = None;
+    let mut graph = arch.weighted_graph();
+    let mut loc_to_node: HashMap<Location, NodeIndex> =
+        graph.node_indices().map(|n| (graph[n], n)).collect();
+    remove_locations(&mut graph, &mut loc_to_node, &blocked);
+    let mut best: Option<(f64, Vec<NodeIndex>)> = None;
     for start in &starts {
         for end in &ends {
             if loc_to_node.contains_key(start) && loc_to_node.contains_key(end) {
@@ -386,8 +981,8 @@ pub fn shortest_path<A: Architecture>(
                     &graph,
                     loc_to_node[&start],
                     |finish| finish == loc_to_node[&end],
-                    |_e| 1,
-                    |_| 0,
+                    |e| *e.weight(),
+                    |_| 0.0,
                 );
                 if best.is_none()
                     || ((&res).is_some() && &res.as_ref().unwrap().0 < &best.as_ref().unwrap().0)
@@ -403,26 +998,131 @@ pub fn shortest_path<A: Architecture>(
     }
 }
 
+/// Up to `k` cheapest loopless paths from any of `starts` to any of `ends` avoiding
+/// `blocked`, cheapest first, by Yen's algorithm. The starts hang off a virtual root, so
+/// paths from different starts compete on cost like any other deviation.
+pub fn k_shortest_paths<A: Architecture>(
+    arch: &A,
+    starts: Vec<Location>,
+    ends: Vec<Location>,
+    blocked: Vec<Location>,
+    k: usize,
+) -> Vec<Vec<Location>> {
+    let graph = arch.weighted_graph();
+    let loc_to_node: HashMap<Location, NodeIndex> =
+        graph.node_indices().map(|n| (graph[n], n)).collect();
+    let blocked: HashSet<NodeIndex> = blocked.iter().filter_map(|l| loc_to_node.get(l)).cloned().collect();
+    let starts: Vec<NodeIndex> = starts
+        .iter()
+        .filter_map(|l| loc_to_node.get(l))
+        .filter(|n| !blocked.contains(n))
+        .cloned()
+        .collect();
+    let targets: HashSet<NodeIndex> = ends.iter().filter_map(|l| loc_to_node.get(l)).cloned().collect();
+    let path_cost = |path: &[NodeIndex]| -> f64 {
+        path
+            .windows(2)
+            .map(|w| graph[graph.find_edge(w[0], w[1]).unwrap()])
+            .sum()
+    };
+    // cheapest path from `sources` that avoids the given nodes and edges
+    let cheapest = |sources: &[NodeIndex],
+                    removed_nodes: &HashSet<NodeIndex>,
+                    removed_edges: &HashSet<(NodeIndex, NodeIndex)>| {
+        let filtered = EdgeFiltered::from_fn(&graph, |e| {
+            !removed_nodes.contains(&e.target()) && !removed_edges.contains(&(e.source(), e.target()))
+        });
+        sources
+            .iter()
+            .filter(|s| !removed_nodes.contains(s))
+            .filter_map(|s| {
+                petgraph::algo::astar(&filtered, *s, |n| targets.contains(&n), |e| *e.weight(), |_| 0.0)
+            })
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+    };
+    let mut found: Vec<(f64, Vec<NodeIndex>)> = Vec::new();
+    let mut candidates: Vec<(f64, Vec<NodeIndex>)> = Vec::new();
+    if k > 0 {
+        candidates.extend(cheapest(&starts, &blocked, &HashSet::new()));
+    }
+    while found.len() < k && !candidates.is_empty() {
+        let best = (0..candidates.len())
+            .min_by(|a, b| candidates[*a].0.total_cmp(&candidates[*b].0))
+            .unwrap();
+        let (cost, path) = candidates.swap_remove(best);
+        found.push((cost, path.clone()));
+        // deviation j leaves `path` after its first j nodes; j = 0 is a different start
+        for j in 0..path.len() {
+            let root = &path[..j];
+            let mut removed_nodes = blocked.clone();
+            removed_nodes.extend(root[..j.saturating_sub(1)].iter());
+            let mut removed_edges = HashSet::new();
+            let mut sources = vec![];
+            if j == 0 {
+                let used_starts: HashSet<NodeIndex> = found.iter().map(|(_, p)| p[0]).collect();
+                sources.extend(starts.iter().filter(|s| !used_starts.contains(s)));
+            } else {
+                sources.push(path[j - 1]);
+                for (_, p) in &found {
+                    if p.len() > j && p[..j] == *root {
+                        removed_edges.insert((p[j - 1], p[j]));
+                    }
+                }
+            }
+            if let Some((spur_cost, spur)) = cheapest(&sources, &removed_nodes, &removed_edges) {
+                let mut total: Vec<NodeIndex> = root[..j.saturating_sub(1)].to_vec();
+                total.extend(spur);
+                let in_found = found.iter().any(|(_, p)| *p == total);
+                if !in_found && !candidates.iter().any(|(_, p)| *p == total) {
+                    candidates.push((path_cost(root) + spur_cost, total));
+                }
+            }
+        }
+    }
+    found
+        .into_iter()
+        .map(|(_, path)| path.into_iter().map(|n| graph[n]).collect())
+        .collect()
+}
+
 pub fn identity_application<T: GateImplementation>(step: &Step<T>) -> Step<T> {
     return Step {
         implemented_gates: HashSet::new(),
         map: step.map.clone(),
     };
 }
+/// Simple paths from any of `starts` to any of `ends` avoiding `blocked`, in depth-first
+/// order, bounded by `path_enumeration_cap` and `path_enumeration_max_length`.
 pub fn all_paths<A: Architecture>(
     arch: &A,
     starts: Vec<Location>,
     ends: Vec<Location>,
     blocked: Vec<Location>,
+) -> impl Iterator<Item = Vec<Location>> {
+    all_paths_capped(
+        arch,
+        starts,
+        ends,
+        blocked,
+        CONFIG.load().path_enumeration_cap,
+        CONFIG.load().path_enumeration_max_length,
+    )
+}
+
+/// Like `all_paths`, but the search stops after `max_paths` paths and never extends a
+/// path past `max_length` locations (default: the whole graph), which keeps it tractable
+/// on large grids at the cost of possibly missing the best routes.
+pub fn all_paths_capped<A: Architecture>(
+    arch: &A,
+    starts: Vec<Location>,
+    ends: Vec<Location>,
+    blocked: Vec<Location>,
+    max_paths: usize,
+    max_length: Option<usize>,
 ) -> impl Iterator<Item = Vec<Location>> {
     let (mut graph, mut loc_to_node) = arch.graph();
-    let max_length = graph.node_count();
-    for loc in blocked.iter() {
-        let old_last = graph[graph.node_indices().last().unwrap()];
-        graph.remove_node(loc_to_node[loc]);
-        loc_to_node.insert(old_last, loc_to_node[loc]);
-        loc_to_node.remove(loc);
-    }
+    let max_length = max_length.map_or(graph.node_count(), |m| m.min(graph.node_count()));
+    remove_locations(&mut graph, &mut loc_to_node, &blocked);
 
     let unblocked_starts: Vec<_> = starts
         .iter()
@@ -434,6 +1134,19 @@ pub fn all_paths<A: Architecture>(
         .filter(|x| loc_to_node.contains_key(x))
         .cloned()
         .collect();
+    // hops from each location to the nearest end, to drop branches that cannot finish in time
+    let mut hops_to_end: HashMap<NodeIndex, usize> =
+        unblocked_ends.iter().map(|l| (loc_to_node[l], 0)).collect();
+    let mut frontier: VecDeque<NodeIndex> = hops_to_end.keys().cloned().collect();
+    while let Some(node) = frontier.pop_front() {
+        let hops = hops_to_end[&node] + 1;
+        for prev in graph.neighbors_directed(node, Incoming) {
+            if let Entry::Vacant(slot) = hops_to_end.entry(prev) {
+                slot.insert(hops);
+                frontier.push_back(prev);
+            }
+        }
+    }
     let mut start_counter = 0;
     let mut visited = Vec::new();
     let mut stack: Vec<std::vec::IntoIter<NodeIndex>> = Vec::new();
@@ -460,7 +1173,11 @@ pub fn all_paths<A: Architecture>(
                             } else {
                                 return Some(path);
                             }
-                        } else if !visited.contains(&loc) {
+                        } else if !visited.contains(&loc)
+                            && hops_to_end
+                                .get(&child)
+                                .is_some_and(|h| visited.len() + 1 + h <= max_length)
+                        {
                             visited.push(loc);
                             let neighbors: Vec<_> =
                                 graph.neighbors_directed(child, Outgoing).collect();
@@ -496,6 +1213,7 @@ pub fn all_paths<A: Architecture>(
         }
         None
     })
+    .take(max_paths)
 }
 
 // pub fn steiner_trees<A: Architecture>(
@@ -532,94 +1250,165 @@ pub fn all_paths<A: Architecture>(
 //     return impls;
 // }
 
+/// Steiner trees already found, keyed by the sorted blocked locations and the
+/// terminal set; `None` records a terminal set with no tree.
+pub type SteinerMemo = HashMap<(Vec<Location>, Vec<Location>), Option<Vec<Location>>>;
+
+static STEINER_TREES_EVALUATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of Steiner trees actually computed so far, memo hits excluded.
+pub fn steiner_trees_evaluated() -> usize {
+    STEINER_TREES_EVALUATED.load(Ordering::Relaxed)
+}
+
 pub struct SteinerTreesIter {
     graph: Graph<Location, ()>,
     loc_to_node: HashMap<Location, NodeIndex>,
-    terminal_sets: Vec<Vec<Location>>,
+    terminal_sets: MultiProduct<std::vec::IntoIter<Location>>,
+    blocked: Vec<Location>,
+    memo: Rc<RefCell<SteinerMemo>>,
+}
+
+impl SteinerTreesIter {
+    fn tree(&self, terminal_set: Vec<Location>) -> Option<Vec<Location>> {
+        let key = (self.blocked.clone(), terminal_set);
+        if let Some(tree) = self.memo.borrow().get(&key) {
+            return tree.clone();
+        }
+        STEINER_TREES_EVALUATED.fetch_add(1, Ordering::Relaxed);
+        let indices: Vec<NodeIndex> = key.1.iter().map(|x| self.loc_to_node[x]).collect();
+        let tree = match steiner_tree(&self.graph, &indices, |_| Ok::<f64, ()>(1.0)) {
+            Ok(Some(tree)) => Some(
+                tree.used_node_indices
+                    .into_iter()
+                    .map(|n| self.graph[NodeIndex::new(n)])
+                    .collect(),
+            ),
+            _ => None,
+        };
+        self.memo.borrow_mut().insert(key, tree.clone());
+        tree
+    }
 }
 
 impl Iterator for SteinerTreesIter {
     type Item = Vec<Location>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        for terminal_set in &self.terminal_sets {
-            let indices: Vec<NodeIndex> = terminal_set
-                .into_iter()
-                .map(|x| self.loc_to_node[&x])
-                .collect();
-
-            let steiner_tree_res = steiner_tree(&self.graph, &indices, |_| Ok::<f64, ()>(1.0));
-
-            if let Ok(Some(tree)) = steiner_tree_res {
-                let locations = tree
-                    .used_node_indices
-                    .into_iter()
-                    .map(|n| &self.graph[NodeIndex::new(n)])
-                    .cloned()
-                    .collect();
-                return Some(locations);
+        while let Some(terminal_set) = self.terminal_sets.next() {
+            if let Some(tree) = self.tree(terminal_set) {
+                return Some(tree);
             }
         }
         None
     }
 }
 
+/// Lazily yields a Steiner tree for each way of picking one location from every
+/// group in `terminals`, avoiding `blocked`. When `steiner_max_candidates` is set,
+/// only that many unblocked locations of each group are tried, so groups should be
+/// ordered by preference.
 pub fn steiner_trees<A: Architecture>(
     arch: &A,
     terminals: Vec<Vec<Location>>,
     blocked: Vec<Location>,
+) -> SteinerTreesIter {
+    steiner_trees_memo(arch, terminals, blocked, Rc::new(RefCell::new(HashMap::new())))
+}
+
+/// Like `steiner_trees`, but trees are looked up in and added to `memo`.
+pub fn steiner_trees_memo<A: Architecture>(
+    arch: &A,
+    terminals: Vec<Vec<Location>>,
+    blocked: Vec<Location>,
+    memo: Rc<RefCell<SteinerMemo>>,
 ) -> SteinerTreesIter {
     let (mut graph, mut loc_to_node) = arch.graph();
 
-    // Remove blocked locations
-    for loc in blocked.iter() {
-        if let Some(node_idx) = loc_to_node.get(loc) {
-            let old_last = graph[graph.node_indices().last().unwrap()];
-            graph.remove_node(*node_idx);
-            loc_to_node.insert(old_last, *node_idx);
-            loc_to_node.remove(loc);
-        }
-    }
+    remove_locations(&mut graph, &mut loc_to_node, &blocked);
 
-    // Create lazy iterator for terminal combinations
+    let max_candidates = CONFIG.load().steiner_max_candidates.map_or(usize::MAX, |n| n.max(1));
     let terminal_sets = terminals
         .into_iter()
-        .multi_cartesian_product()
-        .filter(|v| v.iter().all(|l| loc_to_node.contains_key(l)))
-        .collect();
+        .map(|group| {
+            group
+                .into_iter()
+                .filter(|l| loc_to_node.contains_key(l))
+                .take(max_candidates)
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+        .multi_cartesian_product();
 
     SteinerTreesIter {
         graph,
         loc_to_node,
         terminal_sets,
+        blocked: blocked.into_iter().sorted_by_key(|l| l.get_index()).dedup().collect(),
+        memo,
     }
 }
 
-pub fn build_criticality_table(c: &Circuit) -> HashMap<usize, usize> {
+/// Depth of every gate's layer, indexed by gate id.
+pub fn build_criticality_table(c: &Circuit) -> Vec<usize> {
     let mut qubit_table: HashMap<usize, usize> = HashMap::new();
-    let mut gate_table: HashMap<usize, usize> = HashMap::new();
+    let table_len = c.gates.iter().map(|g| g.id + 1).max().unwrap_or(0);
+    let mut gate_table = vec![0; table_len];
     for gate in &c.gates {
-        let d = max(c.qubits.iter().map(|x| qubit_table.get(&x.get_index())))
-            .flatten()
-            .copied()
-            .unwrap_or_default();
-        gate_table.insert(gate.id, d + 1);
-        for q in &c.qubits {
-            qubit_table.insert(q.get_index(), d + 1);
-        }
+        gate_table[gate.id] = advance_qubit_depths(&mut qubit_table, gate);
     }
     gate_table
 }
 
+/// Criticality of a single gate, streamed over the circuit without building the full table.
+pub fn gate_criticality(c: &Circuit, gate_id: usize) -> Option<usize> {
+    let mut qubit_table: HashMap<usize, usize> = HashMap::new();
+    for gate in &c.gates {
+        let d = advance_qubit_depths(&mut qubit_table, gate);
+        if gate.id == gate_id {
+            return Some(d);
+        }
+    }
+    None
+}
+
+fn advance_qubit_depths(qubit_table: &mut HashMap<usize, usize>, gate: &Gate) -> usize {
+    let d = max(gate.qubits.iter().map(|x| qubit_table.get(&x.get_index())))
+        .flatten()
+        .copied()
+        .unwrap_or_default()
+        + 1;
+    for q in &gate.qubits {
+        qubit_table.insert(q.get_index(), d);
+    }
+    d
+}
+
+/// Adds one to the weight of the edge between `a` and `b`, in both directions.
+fn count_interaction(g: &mut Graph<Qubit, usize>, a: NodeIndex, b: NodeIndex) {
+    for (from, to) in [(a, b), (b, a)] {
+        match g.find_edge(from, to) {
+            Some(e) => g[e] += 1,
+            None => {
+                g.add_edge(from, to, 1);
+            }
+        }
+    }
+}
+
+/// Qubits joined in both directions wherever some gate acts on both, weighted by the
+/// number of such gates (CX/SWAP/CCX, or multi-qubit Pauli rotations and measurements).
 pub fn build_interaction_graph(c: &Circuit) -> Graph<Qubit, usize> {
     let mut nodes = HashMap::new();
     let mut g = Graph::new();
-    for qubit in &c.qubits {
+    // add nodes in index order so the isomorphism search is reproducible under a seed
+    for qubit in c.qubits.iter().sorted_by_key(|q| q.get_index()) {
         nodes.insert(*qubit, g.add_node(*qubit));
     }
     for gate in &c.gates {
-        match &gate.operation {
-            Operation::CX => {
+        match gate.base_operation() {
+            Operation::CX | Operation::SWAP => {
                 let (ctrl, tar) = (gate.qubits[0], gate.qubits[1]);
                 let (ctrl_loc, tar_loc) = (
                     nodes
@@ -629,60 +1418,305 @@ pub fn build_interaction_graph(c: &Circuit) -> Graph<Qubit, usize> {
                         .get(&tar)
                         .expect("fetching target index in interaction graph"),
                 );
-                g.update_edge(*ctrl_loc, *tar_loc, 0);
-                g.update_edge(*tar_loc, *ctrl_loc, 0);
+                count_interaction(&mut g, *ctrl_loc, *tar_loc);
+            }
+            Operation::CCX => {
+                let acted_on: Vec<NodeIndex> = gate
+                    .qubits
+                    .iter()
+                    .map(|q| *nodes.get(q).expect("fetching node index in interaction graph"))
+                    .collect();
+                for (i_loc, j_loc) in acted_on.into_iter().tuple_combinations() {
+                    count_interaction(&mut g, i_loc, j_loc);
+                }
             }
-            Operation::T => continue,
+            Operation::T | Operation::Measure { .. } => continue,
+            Operation::Conditioned { .. } => unreachable!(),
             Operation::PauliRot { axis, angle: _ }
             | Operation::PauliMeasurement { sign: _, axis } => {
-                // Iterate through all pairs of indices where the axis isn't PauliI
-                for (i, term_i) in axis.iter().enumerate() {
-                    if *term_i == PauliTerm::PauliI {
-                        continue; // Skip PauliI operations as they don't create interactions
-                    }
+                // every pair of qubits the axis acts on nontrivially interacts once
+                let acted_on: Vec<NodeIndex> = (0..axis.len())
+                    .filter(|i| axis[*i] != PauliTerm::PauliI)
+                    .map(|i| {
+                        *nodes
+                            .get(&Qubit::new(i))
+                            .expect("fetching node index in interaction graph")
+                    })
+                    .collect();
+                for (i_loc, j_loc) in acted_on.into_iter().tuple_combinations() {
+                    count_interaction(&mut g, i_loc, j_loc);
+                }
+            }
+        }
+    }
+    return g;
+}
 
-                    let i_loc = *nodes
-                        .get(&Qubit::new(i))
-                        .expect("fetching node index in interaction graph");
+/// `graph` as a JSON list of `[qubit, qubit, gate count]` edges, each pair once with the
+/// smaller qubit first, sorted.
+pub fn to_edge_list_json(graph: &Graph<Qubit, usize>) -> String {
+    let edges: Vec<(usize, usize, usize)> = graph
+        .edge_indices()
+        .map(|e| {
+            let (a, b) = graph.edge_endpoints(e).unwrap();
+            (graph[a].get_index(), graph[b].get_index(), graph[e])
+        })
+        .filter(|(a, b, _)| a < b)
+        .sorted()
+        .collect();
+    serde_json::to_string(&edges).unwrap()
+}
 
-                    // For each other non-PauliI operation, add an edge between the qubits
-                    for (j, term_j) in axis.iter().enumerate() {
-                        if i == j || *term_j == PauliTerm::PauliI {
-                            continue; // Skip self-connections and PauliI operations
-                        }
+/// Qubits of the densest part of `c`'s interaction graph: keep the qubits whose gate count
+/// is at least the average over interacting qubits, then take the connected piece of what
+/// remains with the most interactions inside it. Empty when no two kept qubits interact.
+pub fn densest_interaction_core(c: &Circuit) -> HashSet<Qubit> {
+    let g = build_interaction_graph(c);
+    let degree = |n: NodeIndex| g.edges(n).map(|e| *e.weight()).sum::<usize>();
+    let interacting: Vec<NodeIndex> = g.node_indices().filter(|n| degree(*n) > 0).collect();
+    let total: usize = interacting.iter().map(|n| degree(*n)).sum();
+    let dense = g.filter_map(
+        |n, q| (degree(n) * interacting.len() >= total && degree(n) > 0).then_some(*q),
+        |_, w| Some(*w),
+    );
+    let inner_weight =
+        |component: &Vec<NodeIndex>| component.iter().flat_map(|n| dense.edges(*n)).map(|e| *e.weight()).sum::<usize>();
+    let core = petgraph::algo::kosaraju_scc(&dense)
+        .into_iter()
+        .filter(|component| inner_weight(component) > 0)
+        // ties go to the component holding the lowest qubit, so the choice is reproducible
+        .max_by_key(|component| {
+            let lowest = component.iter().map(|n| dense[*n].get_index()).min();
+            (inner_weight(component), std::cmp::Reverse(lowest))
+        });
+    core
+        .map(|component| component.iter().map(|n| dense[*n]).collect())
+        .unwrap_or_default()
+}
 
-                        // Get or create the node for qubit j
+/// GraphViz rendering of `arch`'s coupling graph with each location labeled by
+/// the qubit `map` places there. Interacting pairs that sit on a physical edge
+/// highlight that edge in blue; pairs that need routing get a dashed red edge.
+pub fn placement_to_dot<A: Architecture>(c: &Circuit, arch: &A, map: &QubitMap) -> String {
+    let (graph, _) = arch.graph();
+    let placed: HashMap<Location, Qubit> = map.iter().map(|(q, l)| (*l, *q)).collect();
+    let interaction_graph = build_interaction_graph(c);
+    let mut interacting = HashSet::new();
+    for edge in interaction_graph.edge_indices() {
+        let (a, b) = interaction_graph.edge_endpoints(edge).unwrap();
+        let (qa, qb) = (interaction_graph[a], interaction_graph[b]);
+        if let (Some(la), Some(lb)) = (map.get(&qa), map.get(&qb)) {
+            interacting.insert(ordered_pair(*la, *lb));
+        }
+    }
+    let physical: HashSet<(Location, Location)> = graph
+        .edge_indices()
+        .map(|e| {
+            let (a, b) = graph.edge_endpoints(e).unwrap();
+            ordered_pair(graph[a], graph[b])
+        })
+        .collect();
 
-                        let j_loc = *nodes
-                            .get(&Qubit::new(j))
-                            .expect("fetching node index in interaction graph");
+    let mut dot = String::from("graph placement {\n    node [shape=circle];\n");
+    for loc in graph.node_weights().sorted_by_key(|l| l.get_index()) {
+        match placed.get(loc) {
+            Some(q) => dot.push_str(&format!(
+                "    L{} [label=\"q{}\"];\n",
+                loc.get_index(),
+                q.get_index()
+            )),
+            None => dot.push_str(&format!(
+                "    L{} [label=\"\", style=dashed];\n",
+                loc.get_index()
+            )),
+        }
+    }
+    for (a, b) in physical.iter().sorted_by_key(|(a, b)| (a.get_index(), b.get_index())) {
+        let style = if interacting.contains(&(*a, *b)) {
+            " [color=blue, penwidth=2]"
+        } else {
+            ""
+        };
+        dot.push_str(&format!("    L{} -- L{}{};\n", a.get_index(), b.get_index(), style));
+    }
+    for (a, b) in interacting
+        .iter()
+        .filter(|pair| !physical.contains(pair))
+        .sorted_by_key(|(a, b)| (a.get_index(), b.get_index()))
+    {
+        dot.push_str(&format!(
+            "    L{} -- L{} [color=red, style=dashed, constraint=false];\n",
+            a.get_index(),
+            b.get_index()
+        ));
+    }
+    dot.push_str("}\n");
+    dot
+}
 
-                        // Update the edges in both directions
-                        g.update_edge(i_loc, j_loc, 0);
-                        g.update_edge(j_loc, i_loc, 0);
-                    }
+/// Whether `loc` holds fewer qubits under `map` than its capacity.
+pub fn has_room<A: Architecture>(arch: &A, map: &QubitMap, loc: Location) -> bool {
+    map.values().filter(|l| **l == loc).count() < arch.capacity(loc)
+}
+
+/// Replays `res` against `circ` on `arch`, checking that no location holds more
+/// qubits than its capacity, that every gate runs once, after its predecessors,
+/// on mapped qubits, and that routed paths follow graph edges, touch their
+/// operands (at an end, for gates on one or two qubits), avoid other qubits and
+/// blocked locations, and are vertex-disjoint within a step.
+pub fn verify<A: Architecture, G: GateImplementation>(
+    res: &CompilerResult<G>,
+    circ: &Circuit,
+    arch: &A,
+) -> Result<(), VerifyError> {
+    let (graph, index_map) = arch.graph();
+    let adjacent = |a: &Location, b: &Location| match (index_map.get(a), index_map.get(b)) {
+        (Some(na), Some(nb)) => graph.contains_edge(*na, *nb),
+        _ => false,
+    };
+    let blocked: HashSet<Location> = arch.blocked_locations().into_iter().collect();
+    let mut pending: HashMap<Qubit, VecDeque<usize>> = HashMap::new();
+    for gate in &circ.gates {
+        for q in &gate.qubits {
+            pending.entry(*q).or_default().push_back(gate.id);
+        }
+    }
+    let known: HashSet<usize> = circ.gates.iter().map(|g| g.id).collect();
+    let mut seen = HashSet::new();
+    for (step_index, step) in res.steps.iter().enumerate() {
+        let mut occupied: HashMap<Location, Vec<Qubit>> = HashMap::new();
+        for (q, l) in &step.map {
+            occupied.entry(*l).or_default().push(*q);
+        }
+        for (loc, qubits) in occupied.iter().sorted_by_key(|(l, _)| l.get_index()) {
+            if qubits.len() > arch.capacity(*loc) {
+                return Err(VerifyError::OverCapacity {
+                    step: step_index,
+                    location: *loc,
+                });
+            }
+        }
+        let mut used: HashMap<Location, usize> = HashMap::new();
+        for implemented in step.implemented_gates.iter().sorted_by_key(|g| g.gate.id) {
+            let gate = &implemented.gate;
+            if !known.contains(&gate.id) {
+                return Err(VerifyError::UnknownGate(gate.id));
+            }
+            if !seen.insert(gate.id) {
+                return Err(VerifyError::DuplicateGate(gate.id));
+            }
+            for q in &gate.qubits {
+                if pending.get(q).and_then(|ids| ids.front()) != Some(&gate.id) {
+                    return Err(VerifyError::OutOfOrder {
+                        gate_id: gate.id,
+                        step: step_index,
+                    });
+                }
+                if !step.map.contains_key(q) {
+                    return Err(VerifyError::UnmappedQubit {
+                        gate_id: gate.id,
+                        qubit: *q,
+                    });
+                }
+            }
+            let Some(path) = implemented.implementation.path() else {
+                continue;
+            };
+            for (from, to) in path.iter().tuple_windows() {
+                if !adjacent(from, to) {
+                    return Err(VerifyError::NotAdjacent {
+                        gate_id: gate.id,
+                        from: *from,
+                        to: *to,
+                    });
                 }
             }
+            let ends = [path.first(), path.last()];
+            for q in &gate.qubits {
+                let loc = step.map[q];
+                // a route joining three or more operands passes them anywhere along the way
+                let touching: Vec<&Location> = if gate.qubits.len() > 2 {
+                    path.iter().collect()
+                } else {
+                    ends.iter().flatten().copied().collect()
+                };
+                if !touching.iter().any(|e| **e == loc || adjacent(e, &loc)) {
+                    return Err(VerifyError::DisconnectedEndpoint {
+                        gate_id: gate.id,
+                        qubit: *q,
+                    });
+                }
+            }
+            for loc in &path {
+                if blocked.contains(loc) {
+                    return Err(VerifyError::PathBlocked {
+                        gate_id: gate.id,
+                        location: *loc,
+                    });
+                }
+                if occupied
+                    .get(loc)
+                    .is_some_and(|qs| qs.iter().any(|q| !gate.qubits.contains(q)))
+                {
+                    return Err(VerifyError::PathThroughQubit {
+                        gate_id: gate.id,
+                        location: *loc,
+                    });
+                }
+                if let Some(other) = used.insert(*loc, gate.id) {
+                    return Err(VerifyError::PathOverlap {
+                        gate_id: gate.id,
+                        other_gate_id: other,
+                        location: *loc,
+                    });
+                }
+            }
+        }
+        for gate in step.gates() {
+            for q in &gate.qubits {
+                pending.get_mut(q).unwrap().pop_front();
+            }
         }
     }
-    return g;
+    if let Some(missing) = circ.gates.iter().map(|g| g.id).filter(|id| !seen.contains(id)).min() {
+        return Err(VerifyError::MissingGate(missing));
+    }
+    Ok(())
 }
-pub fn circuit_to_layers(c: &mut Circuit) -> Vec<Vec<Gate>> {
-    let mut layers = vec![];
-    while !c.gates.is_empty() {
-        let l = c.get_front_layer();
-        c.remove_gates(&l);
-        layers.push(l);
+
+fn ordered_pair(a: Location, b: Location) -> (Location, Location) {
+    if a.get_index() <= b.get_index() {
+        return (a, b);
     }
-    return layers;
+    (b, a)
+}
+
+/// ASAP layering: each gate lands in the first layer after every earlier gate it
+/// depends on, through shared qubits or classical bits.
+pub fn circuit_to_layers(c: &Circuit) -> Vec<Vec<Gate>> {
+    c.layers().collect()
 }
+
+/// Number of ASAP layers in `c`.
+pub fn circuit_depth(c: &Circuit) -> usize {
+    c.layers().count()
+}
+/// Rng for one search; with `seed` set, each stream is reproducible, otherwise it is seeded from entropy.
+pub fn search_rng(stream: u64) -> StdRng {
+    match CONFIG.load().seed {
+        Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(stream)),
+        None => StdRng::from_os_rng(),
+    }
+}
+
 pub fn simulated_anneal<T: Clone>(
     start: T,
     initial_temp: f64,
     term_temp: f64,
     cool_rate: f64,
-    random_neighbor: impl Fn(&T) -> T,
+    random_neighbor: impl Fn(&T, &mut StdRng) -> T,
     cost_function: impl Fn(&T) -> f64,
+    rng: &mut StdRng,
 ) -> T {
     let mut best = start.clone();
     let mut best_cost = cost_function(&best);
@@ -690,11 +1724,11 @@ pub fn simulated_anneal<T: Clone>(
     let mut curr_cost = cost_function(&current);
     let mut temp = initial_temp;
     while temp > term_temp {
-        let next = random_neighbor(&current);
+        let next = random_neighbor(&current, rng);
         let next_cost = cost_function(&next);
         let delta_curr = next_cost - curr_cost;
         let delta_best = next_cost - best_cost;
-        let rand: f64 = rand::random();
+        let rand: f64 = rng.random();
         if delta_best < 0.0 {
             best = next.clone();
             best_cost = next_cost;
@@ -715,28 +1749,36 @@ pub enum Move {
     IntoOpen(Qubit, Location),
 }
 
-fn random_move<A: Architecture>(map: &QubitMap, arch: &A) -> Move {
+/// A random move that keeps every qubit within its region, or `None` if every qubit is
+/// pinned in place.
+fn random_move<A: Architecture>(
+    map: &QubitMap,
+    arch: &A,
+    region_constraints: &RegionConstraints,
+    rng: &mut StdRng,
+) -> Option<Move> {
+    let allowed = |q: &Qubit, l: &Location| region_constraints.get(q).is_none_or(|r| r.contains(l));
     let mut moves = vec![];
-    for q1 in map.keys() {
-        for q2 in map.keys() {
-            if q1 == q2 {
+    let qubits: Vec<_> = map.keys().sorted_by_key(|q| q.get_index()).collect();
+    for q1 in &qubits {
+        for q2 in &qubits {
+            if q1 == q2 || !allowed(q1, &map[*q2]) || !allowed(q2, &map[*q1]) {
                 continue;
             }
-            moves.push(Move::Swap(*q1, *q2));
+            moves.push(Move::Swap(**q1, **q2));
         }
     }
-    for q in map.keys() {
+    for q in &qubits {
         for l in arch.locations() {
-            if !map.values().any(|x| *x == l) {
-                moves.push(Move::IntoOpen(*q, l));
+            if map[*q] != l && has_room(arch, map, l) && allowed(q, &l) {
+                moves.push(Move::IntoOpen(**q, l));
             }
         }
     }
-    let rng = &mut rand::rng();
-    let chosen_move = *moves.choose(rng).unwrap();
-    return chosen_move;
+    moves.choose(rng).copied()
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn fast_mapping_simulated_anneal<A: Architecture>(
     start: &QubitMap,
     arch: &A,
@@ -745,6 +1787,8 @@ pub fn fast_mapping_simulated_anneal<A: Architecture>(
     cool_rate: f64,
     cost_function: impl Fn(&QubitMap) -> f64,
     delta_on_move: impl Fn(&QubitMap, Move) -> f64,
+    region_constraints: &RegionConstraints,
+    rng: &mut StdRng,
 ) -> QubitMap {
     let mut best = start.clone();
     let mut best_cost = cost_function(&best);
@@ -753,7 +1797,9 @@ pub fn fast_mapping_simulated_anneal<A: Architecture>(
     let mut temp = initial_temp;
     let mut best_to_curr = 0.0;
     while temp > term_temp {
-        let next_move = random_move(&current, arch);
+        let Some(next_move) = random_move(&current, arch, region_constraints, rng) else {
+            break;
+        };
         let next: HashMap<Qubit, Location> = match next_move {
             Move::Swap(q1, q2) => {
                 let mut new_map = current.clone();
@@ -771,7 +1817,7 @@ pub fn fast_mapping_simulated_anneal<A: Architecture>(
         };
         let delta_curr = delta_on_move(&current, next_move);
         let delta_best = delta_curr + best_to_curr;
-        let rand: f64 = rand::random();
+        let rand: f64 = rng.random();
         if delta_best < 0.0 {
             best = next.clone();
             best_cost = best_cost + delta_best;
@@ -788,9 +1834,7 @@ pub fn fast_mapping_simulated_anneal<A: Architecture>(
     return best;
 }
 
-pub fn swap_random_array_elements<T: Clone>(array: &Vec<T>) -> Vec<T> {
-    let mut rng = rand::rng();
-
+pub fn swap_random_array_elements<T: Clone>(array: &Vec<T>, rng: &mut StdRng) -> Vec<T> {
     let idx1 = rng.random_range(0..array.len());
     let mut idx2 = rng.random_range(0..array.len() - 1);
 